@@ -0,0 +1,154 @@
+use std::fmt::Display;
+use std::ops::Range;
+use std::path::Path;
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::error::{Error, Result};
+use crate::inspect::field;
+use crate::layout::RecordDesign;
+use crate::modelo_720::decode_latin1;
+use crate::parsers::to_utf8;
+use crate::provenance::{load_sources, record_identifier, Provenance};
+
+static REGISTRO_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)\b(?:registro|l[ií]nea)\s*(?:n[º°o]\.?|n[úu]m(?:ero|\.)?)?\s*:?\s*(\d+)")
+        .unwrap()
+});
+static POSICION_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)\bposici[oó]n(?:es)?\s*:?\s*(\d+)(?:\s*(?:-|a|al|hasta)\s*(\d+))?").unwrap()
+});
+
+/// Field of the record design at the positions an error of the AEAT points to.
+#[derive(Debug, Clone)]
+pub struct AeatField {
+    pub name: &'static str,
+    /// Positions of the field in the record, starting at 0.
+    pub range: Range<usize>,
+    pub value: String,
+}
+
+/// Error of the response of the AEAT to an upload, located in our 720 file.
+#[derive(Debug, Clone)]
+pub struct AeatError {
+    /// Number of the registro in the file, the header being the first one, as the AEAT counts them.
+    pub registro: usize,
+    /// Clave and ISIN or account of the registro, to tell which asset it is.
+    pub description: String,
+    /// Positions the error gives, starting at 0.
+    pub positions: Option<Range<usize>>,
+    /// Fields of the record design at `positions`.
+    pub fields: Vec<AeatField>,
+    /// Line of the response, as the AEAT wrote it.
+    pub message: String,
+    /// Where the registro was read from, when the file has its sources next to it.
+    pub sources: Vec<Provenance>,
+}
+
+impl Display for AeatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "registro {} ({})", self.registro, self.description)?;
+        match &self.positions {
+            None => {}
+            Some(positions) if self.fields.is_empty() => write!(
+                f,
+                ", positions {}-{} beyond the last field",
+                positions.start + 1,
+                positions.end
+            )?,
+            Some(_) => {
+                for x in self.fields.iter() {
+                    write!(
+                        f,
+                        ", {} ({}-{}) = \"{}\"",
+                        x.name,
+                        x.range.start + 1,
+                        x.range.end,
+                        x.value.trim_end()
+                    )?;
+                }
+            }
+        }
+        write!(f, ": {}", self.message)?;
+        for source in self.sources.iter() {
+            write!(f, " (from {})", source)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the file of errors the AEAT returns after a failed upload of `file` and locates each error in
+/// it, translating its registro and positions into the fields of our record design. Lines of the response
+/// that don't refer to a registro, such as titles, are left out. Registros are numbered from 1 for the
+/// header, and positions from 1 and inclusive, as in the record design.
+pub fn compare_aeat_response(file: &Path, response: &Path) -> Result<Vec<AeatError>> {
+    let contents = decode_latin1(&std::fs::read(file).map_err(|e| Error::io(file, e))?);
+    let records: Vec<Vec<char>> = contents.lines().map(|x| x.chars().collect()).collect();
+    let design = RecordDesign::of_contents(&contents);
+    let sources = load_sources(file)?;
+    let response_contents = to_utf8(std::fs::read(response).map_err(|e| Error::io(response, e))?);
+    let response_contents = String::from_utf8_lossy(&response_contents);
+
+    let mut errors = Vec::new();
+    for line in response_contents.lines() {
+        let Some(registro) = REGISTRO_REGEX
+            .captures(line)
+            .and_then(|x| x[1].parse::<usize>().ok())
+        else {
+            continue;
+        };
+        let Some(chars) = registro.checked_sub(1).and_then(|x| records.get(x)) else {
+            return Err(Error::InvalidInput(format!(
+                "{} refers to registro {}, but {} only has {}",
+                response.display(),
+                registro,
+                file.display(),
+                records.len()
+            )));
+        };
+        let (fields, description, registro_sources) = match chars.first() {
+            Some('1') => (design.registro1_fields(), "header".to_string(), Vec::new()),
+            _ => {
+                let identifier = record_identifier(chars);
+                (
+                    design.registro2_fields(),
+                    format!("clave {} {}", field(chars, &(101..103)), identifier)
+                        .trim_end()
+                        .to_string(),
+                    sources.get(&identifier).cloned().unwrap_or_default(),
+                )
+            }
+        };
+        let positions = POSICION_REGEX.captures(line).and_then(|x| {
+            let start: usize = x[1].parse().ok()?;
+            let end: usize = x
+                .get(2)
+                .and_then(|x| x.as_str().parse().ok())
+                .unwrap_or(start);
+            Some(start.saturating_sub(1)..end.max(start))
+        });
+        let fields = fields
+            .iter()
+            .filter(|(_, range)| {
+                positions
+                    .as_ref()
+                    .is_some_and(|x| range.start < x.end && x.start < range.end)
+            })
+            .map(|(name, range)| AeatField {
+                name,
+                range: range.clone(),
+                value: field(chars, range),
+            })
+            .collect();
+        errors.push(AeatError {
+            registro,
+            description,
+            positions,
+            fields,
+            message: line.trim().to_string(),
+            sources: registro_sources,
+        });
+    }
+    Ok(errors)
+}
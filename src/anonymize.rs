@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rust_decimal::Decimal;
+
+use crate::modelo_720::{IdentificacionCuenta, Modelo720, Nif, Telefono, Titularidad};
+use crate::validation::{iban_with_check_digits, looks_like_iban};
+
+const NOMBRES: [&str; 10] = [
+    "ANA", "CARLOS", "ELENA", "JAVIER", "LAURA", "MANUEL", "MARIA", "PABLO", "SARA", "SERGIO",
+];
+const APELLIDOS: [&str; 10] = [
+    "ALONSO",
+    "DIAZ",
+    "FERNANDEZ",
+    "GARCIA",
+    "GOMEZ",
+    "LOPEZ",
+    "MARTIN",
+    "PEREZ",
+    "RUIZ",
+    "SANCHEZ",
+];
+
+/// Replaces the personal data of a declaration so it can be shared, e.g. in a bug report: NIFs, names,
+/// the phone and account numbers are swapped for made-up ones and every valuation is scaled by a random
+/// factor between 0.5 and 1.5. The address of real estate, which is the declarante's own, and the
+/// description of other kinds of titularidad get random letters and digits. The same NIF, name or text is
+/// always replaced by the same one, so the declaration keeps its structure, and the header totals are
+/// recomputed from the new valuations. The sources of the registros are dropped. Entities, ISINs and
+/// everything else are kept as they are.
+pub fn anonymize_modelo_720(modelo720: &mut Modelo720, rng: &mut impl Rng) {
+    let mut nifs: HashMap<Nif, Nif> = HashMap::new();
+    let mut names: HashMap<String, String> = HashMap::new();
+    let mut nif = |rng: &mut _, nif: &Nif| {
+        nifs.entry(nif.clone())
+            .or_insert_with(|| fake_nif(rng))
+            .clone()
+    };
+    let mut name = |rng: &mut _, name: &str| {
+        if name.trim().is_empty() {
+            return String::new();
+        }
+        names
+            .entry(name.trim().to_string())
+            .or_insert_with(|| fake_name(rng))
+            .clone()
+    };
+    let mut texts: HashMap<String, String> = HashMap::new();
+    let mut text = |rng: &mut _, text: &Option<String>| {
+        let text = text.as_deref()?.trim();
+        let fake = texts
+            .entry(text.to_string())
+            .or_insert_with(|| fake_like(rng, text));
+        Some(fake.clone())
+    };
+
+    let header = &mut modelo720.header;
+    header.nif_declarante = nif(rng, &header.nif_declarante);
+    header.nombre = name(rng, &header.nombre);
+    header.nombre_persona_contacto = name(rng, &header.nombre_persona_contacto);
+    header.telefono = Telefono::new(&format!("6{:08}", rng.gen_range(0..100_000_000))).unwrap();
+
+    for entry in modelo720.entries.iter_mut() {
+        entry.nif_declarante = nif(rng, &entry.nif_declarante);
+        entry.nif_declarado = nif(rng, &entry.nif_declarado);
+        entry.nif_representante_legal = entry.nif_representante_legal.as_ref().map(|x| nif(rng, x));
+        entry.nombre = name(rng, &entry.nombre);
+        if let Titularidad::Otros(what) = &entry.tipo_titularidad {
+            let what = text(rng, &Some(what.clone())).unwrap_or_default();
+            entry.tipo_titularidad = Titularidad::Otros(what);
+        }
+        if entry.tipo_bien.clave() == 'B' {
+            entry.nif_pais_residencia_fiscal = text(rng, &entry.nif_pais_residencia_fiscal);
+            entry.nombre_via_publica_entidad = text(rng, &entry.nombre_via_publica_entidad);
+            entry.complemento_entidad = text(rng, &entry.complemento_entidad);
+            entry.poblacion_entidad = text(rng, &entry.poblacion_entidad);
+            entry.provincia_entidad = text(rng, &entry.provincia_entidad);
+            entry.codigo_postal_entidad = text(rng, &entry.codigo_postal_entidad);
+        }
+        if let Some(account) = &entry.codigo_cuenta {
+            let iban = matches!(
+                entry.clave_identificacion_cuenta,
+                Some(IdentificacionCuenta::Iban)
+            ) && looks_like_iban(account);
+            entry.codigo_cuenta = Some(fake_account(rng, account, iban));
+        }
+        let factor = Decimal::new(rng.gen_range(50..=150), 2);
+        entry.valoracion1.0 = (entry.valoracion1.0 * factor).round_dp(2);
+        entry.valoracion2.0 = (entry.valoracion2.0 * factor).round_dp(2);
+    }
+    // Paths of the statements may well hold names too.
+    modelo720.sources.clear();
+    modelo720.recompute_totals();
+}
+
+fn fake_nif(rng: &mut impl Rng) -> Nif {
+    Nif::dni(rng.gen_range(0..100_000_000))
+}
+
+fn fake_name(rng: &mut impl Rng) -> String {
+    format!(
+        "{} {} {}",
+        APELLIDOS.choose(rng).unwrap(),
+        APELLIDOS.choose(rng).unwrap(),
+        NOMBRES.choose(rng).unwrap()
+    )
+}
+
+/// Text with every letter and digit replaced by a random one, keeping its length and layout.
+fn fake_like(rng: &mut impl Rng, text: &str) -> String {
+    text.chars()
+        .map(|x| {
+            if x.is_ascii_digit() {
+                char::from(b'0' + rng.gen_range(0..10))
+            } else if x.is_alphabetic() {
+                char::from(b'A' + rng.gen_range(0..26))
+            } else {
+                x
+            }
+        })
+        .collect()
+}
+
+/// Account number with every letter and digit replaced by a random one, keeping its length and layout. IBANs
+/// keep their country and get new check digits, so they are still valid.
+fn fake_account(rng: &mut impl Rng, account: &str, iban: bool) -> String {
+    let account = account.trim();
+    if !iban {
+        return fake_like(rng, account);
+    }
+    let account: String = account.chars().filter(|x| !x.is_whitespace()).collect();
+    let bban = fake_like(rng, &account[4..]);
+    iban_with_check_digits(&account[..2].to_uppercase(), &bban)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modelo_720::{Registro2, TipoBien, TipoInmueble};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn the_address_of_real_estate_is_replaced() {
+        let nif = Nif::new("12345678Z").unwrap();
+        let mut registro =
+            Registro2::new(2024, nif.clone(), "DOE JOHN".to_string(), "FR".to_string());
+        registro.tipo_bien = TipoBien::BienInmbueble(TipoInmueble::Titularidad);
+        registro.tipo_titularidad = Titularidad::Otros("NUDA PROPIEDAD".to_string());
+        registro.nif_pais_residencia_fiscal = Some("FR123456789".to_string());
+        registro.nombre_via_publica_entidad = Some("RUE DE RIVOLI 12".to_string());
+        registro.complemento_entidad = Some("3E ETAGE".to_string());
+        registro.poblacion_entidad = Some("PARIS".to_string());
+        registro.provincia_entidad = Some("ILE-DE-FRANCE".to_string());
+        registro.codigo_postal_entidad = Some("75001".to_string());
+        let mut modelo720 = Modelo720::new(
+            2024,
+            &nif,
+            "DOE JOHN",
+            Telefono::new("600000000").unwrap(),
+            vec![registro.clone(), registro],
+        );
+        anonymize_modelo_720(&mut modelo720, &mut StdRng::seed_from_u64(0));
+
+        let [first, second] = &modelo720.entries[..] else {
+            panic!("expected two registros");
+        };
+        let Titularidad::Otros(what) = &first.tipo_titularidad else {
+            panic!("expected titularidad 8");
+        };
+        assert_ne!(what, "NUDA PROPIEDAD");
+        assert_eq!(what.len(), "NUDA PROPIEDAD".len());
+        let fields = |x: &Registro2| {
+            [
+                x.nif_pais_residencia_fiscal.clone(),
+                x.nombre_via_publica_entidad.clone(),
+                x.complemento_entidad.clone(),
+                x.poblacion_entidad.clone(),
+                x.provincia_entidad.clone(),
+                x.codigo_postal_entidad.clone(),
+            ]
+        };
+        let originals = [
+            "FR123456789",
+            "RUE DE RIVOLI 12",
+            "3E ETAGE",
+            "PARIS",
+            "ILE-DE-FRANCE",
+            "75001",
+        ];
+        for (field, original) in fields(first).iter().zip(originals) {
+            let field = field.as_deref().unwrap();
+            assert_ne!(field, original);
+            assert_eq!(field.len(), original.len());
+        }
+        // The same property is replaced the same way in every registro.
+        assert_eq!(fields(first), fields(second));
+        assert!(matches!(&second.tipo_titularidad, Titularidad::Otros(x) if x == what));
+    }
+}
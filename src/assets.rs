@@ -1,40 +1,165 @@
-use std::rc::Rc;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
 
+use chrono::NaiveDate;
+use clap::ValueEnum;
 use rust_decimal::Decimal;
 
-use crate::modelo_720::{Origen, Registro2, Shares, TipoBien, TipoValor};
+use crate::classification::Classification;
+use crate::countries::is_valid_country;
+use crate::currency::{ExchangeRates, Rounding};
+use crate::error::{self, Error};
+use crate::modelo_720::{
+    ClaseInmueble, ClaveIdentificacion, ClaveIdentificacionValores, Date, IdentificacionCuenta,
+    Modelo720, Nif, Origen, Registro2, RepresentacionValores, Shares, TipoBien, TipoCuenta,
+    TipoInmueble, TipoSeguro, TipoValor,
+};
+use crate::modelo_721::{Monedas, Registro2Modelo721};
+use crate::provenance::Provenance;
+
+/// Transmission of an asset during the year, declared as the valuation of its extinction registro.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sale {
+    pub date: NaiveDate,
+    /// Amount received in euros.
+    pub proceeds: Decimal,
+}
+
+/// How the valores of an asset are identified in its registro.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Identification {
+    Isin(String),
+    /// Any other code, such as the registration number of unlisted shares or the reference of a private
+    /// placement, declared with clave de identificación 2.
+    Other(String),
+    /// Assets declared without an identificación de valores, such as accounts or real estate.
+    None,
+}
+
+impl Identification {
+    pub fn clave(&self) -> ClaveIdentificacionValores {
+        ClaveIdentificacionValores(match self {
+            Identification::Isin(_) => Some(ClaveIdentificacion::Isin),
+            Identification::Other(_) => Some(ClaveIdentificacion::Otra),
+            Identification::None => None,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Identification::Isin(x) | Identification::Other(x) => x,
+            Identification::None => "",
+        }
+    }
+
+    /// The identificación de valores of the registro.
+    pub fn value(&self) -> Option<String> {
+        match self {
+            Identification::None => None,
+            x => Some(x.as_str().to_string()),
+        }
+    }
+}
 
 pub struct AssetDifference {
     pub valuation: Decimal,
     pub shares: Shares,
 }
 
-pub trait AssetWithValuation {
+pub trait AssetWithValuation: Send + Sync {
     fn isin(&self) -> &str;
-    fn valuation(&self) -> Decimal;
+    /// Valuation in the `currency` the asset is reported in.
+    fn native_valuation(&self) -> Decimal;
     fn shares(&self) -> Shares;
     fn country_of_deposit(&self) -> &str;
     fn description(&self) -> &str;
     fn modelo_720_code(&self) -> TipoBien;
 
+    /// ISO 4217 code of the currency of `native_valuation` and `second_valuation`.
+    fn currency(&self) -> &str {
+        "EUR"
+    }
+
+    /// Valuation in euros. Assets in other currencies only have one once converted by [`Portfolio::in_euros`].
+    fn valuation(&self) -> Decimal {
+        self.native_valuation()
+    }
+
+    /// Issuer or manager of the asset, declared as the identificación de la entidad.
+    /// Defaults to the description for assets where both are the same.
+    fn entity_name(&self) -> &str {
+        self.description()
+    }
+
+    /// Valuation of each share, zero for assets without shares such as accounts or closed positions.
     fn price_per_share(&self) -> Decimal {
-        self.valuation() / self.shares().0
+        let shares = self.shares().0;
+        if shares.is_zero() {
+            return Decimal::ZERO;
+        }
+        self.valuation() / shares
+    }
+
+    /// Whether the registro should declare a number of valores. Assets such as accounts only have a valuation.
+    fn declares_shares(&self) -> bool {
+        true
+    }
+
+    /// Valoración 2 of the registro, only meaningful for some claves such as the Q4 average balance of accounts.
+    /// In the same currency as `valuation`.
+    fn second_valuation(&self) -> Option<Decimal> {
+        None
+    }
+
+    /// Country of the issuer. The ISIN prefix, except for international ones such as XS or EU which aren't
+    /// countries. Those are declared with the country where they are deposited.
+    fn entity_country(&self) -> &str {
+        self.isin()
+            .get(..2)
+            .filter(|x| is_valid_country(x))
+            .unwrap_or(self.country_of_deposit())
+    }
+
+    /// Entity the asset is deposited at, such as the broker, when known. Holdings of the same ISIN at
+    /// different ones are declared apart.
+    fn depositary(&self) -> Option<&str> {
+        None
+    }
+
+    /// Date the asset stopped being held during the year, for accounts cancelled before the end of it. Their
+    /// valuation is then the balance on that date.
+    fn closing_date(&self) -> Option<NaiveDate> {
+        None
+    }
+
+    /// Tax identification number of the entity in its country of residence, e.g. the registration number of a
+    /// Latvian company, declared when known.
+    fn entity_nif(&self) -> Option<&str> {
+        None
+    }
+
+    /// How the asset is identified in its registro, by default its `isin`.
+    fn identification(&self) -> Identification {
+        Identification::Isin(self.isin().to_string())
     }
 
-    fn modelo_720_registro(&self, ejercicio: i16, nif: &str, name: &str) -> Registro2 {
+    fn modelo_720_registro(&self, ejercicio: i16, nif: &Nif, name: &str) -> Registro2 {
         let registro = Registro2::new(
             ejercicio,
-            nif.to_string(),
+            nif.clone(),
             name.to_string(),
             self.country_of_deposit().to_string(),
         );
+        let identification = self.identification();
         Registro2 {
-            clave_representacion_valores: Some('A'),
-            clave_identificacion: Some(1),
-            identificacion_valores: Some(self.isin().to_string()),
+            clave_representacion_valores: Some(RepresentacionValores::Anotaciones),
+            clave_identificacion: identification.clave(),
+            identificacion_valores: identification.value(),
             tipo_bien: self.modelo_720_code(),
-            identificacion_entidad: Some(self.description().to_uppercase()),
-            codigo_pais_entidad: Some(self.isin()[..2].to_string()),
+            identificacion_entidad: Some(self.entity_name().to_uppercase()),
+            nif_pais_residencia_fiscal: self.entity_nif().map(str::to_uppercase),
+            codigo_pais_entidad: Some(self.entity_country().to_string()),
             origen_bien_derecho: Origen::Modificacion,
             ..registro
         }
@@ -43,10 +168,15 @@ pub trait AssetWithValuation {
 
 pub struct Etf {
     pub isin: String,
-    pub euro_valuation: Decimal,
+    /// Value of the position in `currency`.
+    pub position_value: Decimal,
     pub shares: Decimal,
     pub deposit_country: String,
+    pub currency: String,
     pub description: String,
+    pub entity_name: Option<String>,
+    /// Broker the position is held at.
+    pub depositary: Option<String>,
 }
 
 impl AssetWithValuation for Etf {
@@ -54,8 +184,12 @@ impl AssetWithValuation for Etf {
         &self.isin
     }
 
-    fn valuation(&self) -> Decimal {
-        self.euro_valuation
+    fn depositary(&self) -> Option<&str> {
+        self.depositary.as_deref()
+    }
+
+    fn native_valuation(&self) -> Decimal {
+        self.position_value
     }
 
     fn shares(&self) -> Shares {
@@ -73,6 +207,272 @@ impl AssetWithValuation for Etf {
     fn modelo_720_code(&self) -> TipoBien {
         TipoBien::AccionInstitucionInversionColectiva
     }
+
+    fn currency(&self) -> &str {
+        &self.currency
+    }
+
+    fn entity_name(&self) -> &str {
+        self.entity_name.as_deref().unwrap_or(&self.description)
+    }
+}
+
+/// Listed bonds are valued at their market value while unlisted ones are valued at their nominal value.
+#[derive(Clone, Copy, Debug)]
+pub enum BondListing {
+    Listed { market_value: Decimal },
+    Unlisted,
+}
+
+pub struct Bond {
+    /// ISIN of the bond, or another code for bonds without one such as private placements.
+    pub identification: Identification,
+    pub description: String,
+    pub issuer: Option<String>,
+    /// Tax identification number of the issuer in its country of residence.
+    pub entity_nif: Option<String>,
+    /// Broker or bank the bond is held at.
+    pub depositary: Option<String>,
+    pub deposit_country: String,
+    /// Currency of the nominal and market values.
+    pub currency: String,
+    /// Number of nominal units held, declared as the número de valores.
+    pub nominal_units: Decimal,
+    pub nominal_value_per_unit: Decimal,
+    pub listing: BondListing,
+}
+
+impl AssetWithValuation for Bond {
+    fn isin(&self) -> &str {
+        self.identification.as_str()
+    }
+
+    fn depositary(&self) -> Option<&str> {
+        self.depositary.as_deref()
+    }
+
+    fn entity_nif(&self) -> Option<&str> {
+        self.entity_nif.as_deref()
+    }
+
+    fn identification(&self) -> Identification {
+        self.identification.clone()
+    }
+
+    fn native_valuation(&self) -> Decimal {
+        match self.listing {
+            BondListing::Listed { market_value } => market_value,
+            BondListing::Unlisted => self.nominal_units * self.nominal_value_per_unit,
+        }
+    }
+
+    fn shares(&self) -> Shares {
+        Shares(self.nominal_units)
+    }
+
+    fn country_of_deposit(&self) -> &str {
+        &self.deposit_country
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn modelo_720_code(&self) -> TipoBien {
+        TipoBien::Valores(TipoValor::CesionDeCapitalesATerceros)
+    }
+
+    fn currency(&self) -> &str {
+        &self.currency
+    }
+
+    fn entity_name(&self) -> &str {
+        self.issuer.as_deref().unwrap_or(&self.description)
+    }
+}
+
+/// Shares of a company that isn't listed, and as such has no ISIN.
+pub struct UnlistedShares {
+    /// Free-form identification of the shares, such as the company's registration number.
+    pub identifier: String,
+    pub company_name: String,
+    /// Tax identification number of the company in its country of residence.
+    pub entity_nif: Option<String>,
+    pub company_address: Address,
+    pub country: String,
+    pub shares: Decimal,
+    /// Theoretical book value of the shares held, from the company's last approved balance sheet.
+    pub book_value: Decimal,
+    pub acquisition_date: Option<NaiveDate>,
+}
+
+impl AssetWithValuation for UnlistedShares {
+    fn isin(&self) -> &str {
+        &self.identifier
+    }
+
+    fn entity_nif(&self) -> Option<&str> {
+        self.entity_nif.as_deref()
+    }
+
+    fn identification(&self) -> Identification {
+        Identification::Other(self.identifier.to_uppercase())
+    }
+
+    fn native_valuation(&self) -> Decimal {
+        self.book_value
+    }
+
+    fn shares(&self) -> Shares {
+        Shares(self.shares)
+    }
+
+    fn country_of_deposit(&self) -> &str {
+        &self.country
+    }
+
+    fn description(&self) -> &str {
+        &self.company_name
+    }
+
+    fn modelo_720_code(&self) -> TipoBien {
+        TipoBien::Valores(TipoValor::ParticipacionEnEntidadJuridica)
+    }
+
+    fn modelo_720_registro(&self, ejercicio: i16, nif: &Nif, name: &str) -> Registro2 {
+        Registro2 {
+            // Not represented through book entries and identified by something other than an ISIN.
+            clave_representacion_valores: Some(RepresentacionValores::SinAnotaciones),
+            clave_identificacion: self.identification().clave(),
+            identificacion_valores: self.identification().value(),
+            fecha_incorporacion: Date(self.acquisition_date),
+            ..entity_registro(
+                self,
+                &self.company_address,
+                &self.country,
+                ejercicio,
+                nif,
+                name,
+            )
+        }
+    }
+}
+
+/// Consolidated rights in a foreign pension scheme.
+pub struct PensionPlan {
+    /// Contract or membership number of the plan.
+    pub identifier: String,
+    pub administrator: String,
+    /// Tax identification number of the administrator in its country of residence.
+    pub entity_nif: Option<String>,
+    pub administrator_address: Address,
+    pub country: String,
+    pub consolidated_rights: Decimal,
+    pub membership_date: Option<NaiveDate>,
+}
+
+impl AssetWithValuation for PensionPlan {
+    fn isin(&self) -> &str {
+        &self.identifier
+    }
+
+    fn entity_nif(&self) -> Option<&str> {
+        self.entity_nif.as_deref()
+    }
+
+    fn identification(&self) -> Identification {
+        Identification::Other(self.identifier.to_uppercase())
+    }
+
+    fn native_valuation(&self) -> Decimal {
+        self.consolidated_rights
+    }
+
+    fn shares(&self) -> Shares {
+        Shares(Decimal::ONE)
+    }
+
+    fn country_of_deposit(&self) -> &str {
+        &self.country
+    }
+
+    fn description(&self) -> &str {
+        &self.administrator
+    }
+
+    fn modelo_720_code(&self) -> TipoBien {
+        // Pension schemes are rights contributed for their management to a legal instrument.
+        TipoBien::Valores(TipoValor::AportadosParaGestion)
+    }
+
+    fn declares_shares(&self) -> bool {
+        false
+    }
+
+    fn modelo_720_registro(&self, ejercicio: i16, nif: &Nif, name: &str) -> Registro2 {
+        Registro2 {
+            clave_representacion_valores: Some(RepresentacionValores::SinAnotaciones),
+            clave_identificacion: self.identification().clave(),
+            identificacion_valores: self.identification().value(),
+            fecha_incorporacion: Date(self.membership_date),
+            ..entity_registro(
+                self,
+                &self.administrator_address,
+                &self.country,
+                ejercicio,
+                nif,
+                name,
+            )
+        }
+    }
+}
+
+/// Virtual currency held by a custodian abroad. These go in the 721 instead of the 720, so it isn't part of a [`Portfolio`].
+#[derive(Clone, Debug)]
+pub struct CryptoAsset {
+    /// Ticker of the currency, e.g. `BTC`.
+    pub symbol: String,
+    pub name: String,
+    pub custodian: String,
+    pub custodian_nif: Option<String>,
+    pub custodian_address: Address,
+    pub custodian_country: String,
+    pub units: Decimal,
+    /// Value in euros at December 31, `None` until it's priced with [`crate::pricing::value_at_year_end`].
+    pub valuation: Option<Decimal>,
+    pub acquisition_date: Option<NaiveDate>,
+}
+
+impl CryptoAsset {
+    /// The same currency held with two custodians is declared twice, so both identify the holding.
+    pub fn key(&self) -> (String, String) {
+        (self.custodian.to_uppercase(), self.symbol.to_uppercase())
+    }
+
+    pub fn modelo_721_registro(&self, ejercicio: i16, nif: &Nif, name: &str) -> Registro2Modelo721 {
+        let registro = Registro2Modelo721::new(
+            ejercicio,
+            nif.clone(),
+            name.to_string(),
+            format!("{} ({})", self.name, self.symbol).to_uppercase(),
+        );
+        let uppercase = |x: &Option<String>| x.as_ref().map(|x| x.to_uppercase());
+        Registro2Modelo721 {
+            identificacion_entidad: Some(self.custodian.to_uppercase()),
+            nif_pais_residencia_fiscal: uppercase(&self.custodian_nif),
+            nombre_via_publica_entidad: uppercase(&self.custodian_address.street),
+            complemento_entidad: uppercase(&self.custodian_address.complement),
+            poblacion_entidad: uppercase(&self.custodian_address.city),
+            provincia_entidad: uppercase(&self.custodian_address.province),
+            codigo_postal_entidad: uppercase(&self.custodian_address.zip_code),
+            codigo_pais_entidad: Some(self.custodian_country.to_string()),
+            fecha_incorporacion: Date(self.acquisition_date),
+            valoracion: self.valuation.unwrap_or_default().into(),
+            numero_monedas: Monedas(self.units),
+            origen_bien_derecho: Origen::Modificacion,
+            ..registro
+        }
+    }
 }
 
 pub fn asset_difference(
@@ -88,6 +488,7 @@ pub fn asset_difference(
 pub struct MintosNote {
     pub isin: String,
     pub euro_valuation: Decimal,
+    pub deposit_country: String,
     description: String,
 }
 
@@ -97,6 +498,7 @@ impl MintosNote {
             description: format!("MINTOS NOTE {}", isin),
             isin,
             euro_valuation,
+            deposit_country: "LV".to_string(),
         }
     }
 }
@@ -106,7 +508,11 @@ impl AssetWithValuation for MintosNote {
         &self.isin
     }
 
-    fn valuation(&self) -> Decimal {
+    fn depositary(&self) -> Option<&str> {
+        Some("MINTOS")
+    }
+
+    fn native_valuation(&self) -> Decimal {
         self.euro_valuation
     }
 
@@ -116,7 +522,7 @@ impl AssetWithValuation for MintosNote {
     }
 
     fn country_of_deposit(&self) -> &str {
-        "LV"
+        &self.deposit_country
     }
 
     fn description(&self) -> &str {
@@ -128,52 +534,1353 @@ impl AssetWithValuation for MintosNote {
     }
 }
 
-impl AssetWithValuation for &'_ Rc<dyn AssetWithValuation> {
+pub struct BankAccount {
+    pub tipo_cuenta: TipoCuenta,
+    pub identificacion_cuenta: IdentificacionCuenta,
+    pub bic: String,
+    pub account_code: String,
+    pub entity_name: String,
+    /// Tax identification number of the entity in its country of residence.
+    pub entity_nif: Option<String>,
+    pub country: String,
+    pub opening_date: Option<NaiveDate>,
+    /// Date the account was cancelled, for accounts cancelled during the year.
+    pub closing_date: Option<NaiveDate>,
+    /// Balance at the 31st of December, or on the closing date for cancelled accounts, declared as valoracion 1.
+    pub year_end_balance: Decimal,
+    /// Average balance of the last quarter, declared as valoracion 2. Zero for cancelled accounts.
+    pub q4_average_balance: Decimal,
+    /// Currency of the balances.
+    pub currency: String,
+}
+
+impl AssetWithValuation for BankAccount {
+    // Accounts have no ISIN, the account code is what identifies them between portfolios.
     fn isin(&self) -> &str {
-        self.as_ref().isin()
+        &self.account_code
     }
 
-    fn valuation(&self) -> Decimal {
-        self.as_ref().valuation()
+    fn closing_date(&self) -> Option<NaiveDate> {
+        self.closing_date
+    }
+
+    fn entity_nif(&self) -> Option<&str> {
+        self.entity_nif.as_deref()
+    }
+
+    fn identification(&self) -> Identification {
+        Identification::None
+    }
+
+    fn native_valuation(&self) -> Decimal {
+        self.year_end_balance
     }
 
     fn shares(&self) -> Shares {
-        self.as_ref().shares()
+        // An account is a single unit, so any change in balance is a revaluation rather than an acquisition.
+        Shares(Decimal::ONE)
     }
 
     fn country_of_deposit(&self) -> &str {
-        self.as_ref().country_of_deposit()
+        &self.country
     }
 
     fn description(&self) -> &str {
-        self.as_ref().description()
+        &self.entity_name
     }
 
     fn modelo_720_code(&self) -> TipoBien {
-        self.as_ref().modelo_720_code()
+        TipoBien::Cuenta(self.tipo_cuenta)
+    }
+
+    fn currency(&self) -> &str {
+        &self.currency
+    }
+
+    fn declares_shares(&self) -> bool {
+        false
+    }
+
+    fn second_valuation(&self) -> Option<Decimal> {
+        Some(self.q4_average_balance)
+    }
+
+    fn modelo_720_registro(&self, ejercicio: i16, nif: &Nif, name: &str) -> Registro2 {
+        let registro = Registro2::new(
+            ejercicio,
+            nif.clone(),
+            name.to_string(),
+            self.country_of_deposit().to_string(),
+        );
+        Registro2 {
+            tipo_bien: self.modelo_720_code(),
+            clave_identificacion_cuenta: Some(self.identificacion_cuenta),
+            codigo_bic: Some(self.bic.to_uppercase()),
+            codigo_cuenta: Some(self.account_code.to_uppercase()),
+            identificacion_entidad: Some(self.entity_name().to_uppercase()),
+            nif_pais_residencia_fiscal: self.entity_nif().map(str::to_uppercase),
+            codigo_pais_entidad: Some(self.country.to_string()),
+            fecha_incorporacion: Date(self.opening_date),
+            origen_bien_derecho: Origen::Modificacion,
+            valoracion1: self.year_end_balance.into(),
+            valoracion2: self.second_valuation().unwrap_or_default().into(),
+            ..registro
+        }
     }
 }
 
-#[derive(Default)]
-pub struct Portfolio {
-    // TODO: This should definitely be private
-    pub assets: Vec<Rc<dyn AssetWithValuation>>,
+#[derive(Clone, Debug, Default)]
+pub struct Address {
+    pub street: Option<String>,
+    pub complement: Option<String>,
+    pub city: Option<String>,
+    pub province: Option<String>,
+    pub zip_code: Option<String>,
 }
 
-impl Portfolio {
-    pub fn from_assets(assets: Vec<Rc<dyn AssetWithValuation>>) -> Portfolio {
-        let mut result = Portfolio { assets };
-        result
-            .assets
-            .sort_by(|a, b| a.isin().partial_cmp(b.isin()).unwrap());
-        result
+/// Registro of an asset whose entity is given by hand along with its `address`, such as the company of
+/// unlisted shares or an insurer, with the fields all of them declare alike. Each kind of asset adds its own.
+fn entity_registro(
+    asset: &dyn AssetWithValuation,
+    address: &Address,
+    entity_country: &str,
+    ejercicio: i16,
+    nif: &Nif,
+    name: &str,
+) -> Registro2 {
+    let registro = Registro2::new(
+        ejercicio,
+        nif.clone(),
+        name.to_string(),
+        asset.country_of_deposit().to_string(),
+    );
+    let uppercase = |x: &Option<String>| x.as_ref().map(|x| x.to_uppercase());
+    Registro2 {
+        tipo_bien: asset.modelo_720_code(),
+        identificacion_entidad: Some(asset.entity_name().to_uppercase()),
+        nif_pais_residencia_fiscal: asset.entity_nif().map(str::to_uppercase),
+        nombre_via_publica_entidad: uppercase(&address.street),
+        complemento_entidad: uppercase(&address.complement),
+        poblacion_entidad: uppercase(&address.city),
+        provincia_entidad: uppercase(&address.province),
+        codigo_postal_entidad: uppercase(&address.zip_code),
+        codigo_pais_entidad: Some(entity_country.to_string()),
+        origen_bien_derecho: Origen::Modificacion,
+        ..registro
     }
+}
 
-    pub fn merge(mut self, other: Portfolio) -> Self {
-        self.assets.extend_from_slice(&other.assets);
-        self.assets
-            .sort_by(|a, b| a.isin().partial_cmp(b.isin()).unwrap());
-        // TODO: Add safety check
-        self
+/// Declares the `percentage` of `asset` owned. It's checked when the asset is read, so it's only out of range
+/// for assets built by hand, which are then declared as owned in full.
+fn with_ownership_percentage(
+    mut registro: Registro2,
+    asset: &dyn AssetWithValuation,
+    percentage: Decimal,
+) -> Registro2 {
+    if let Err(e) = registro.set_ownership_percentage(percentage) {
+        eprintln!(
+            "Warning: {}: {}, declared as owned in full",
+            asset.isin(),
+            e
+        );
+    }
+    registro
+}
+
+pub struct RealEstate {
+    /// Identifies the property between portfolios, usually its cadastral reference.
+    pub reference: String,
+    pub description: String,
+    pub tipo_inmueble: TipoInmueble,
+    pub clase: ClaseInmueble,
+    pub tipo_derecho_real: Option<String>,
+    pub address: Address,
+    pub country: String,
+    pub acquisition_date: Option<NaiveDate>,
+    pub acquisition_value: Decimal,
+    /// Percentage of the property owned, from 0 to 100.
+    pub ownership_percentage: Decimal,
+}
+
+impl AssetWithValuation for RealEstate {
+    fn isin(&self) -> &str {
+        &self.reference
+    }
+
+    fn identification(&self) -> Identification {
+        Identification::None
+    }
+
+    fn native_valuation(&self) -> Decimal {
+        self.acquisition_value
+    }
+
+    fn shares(&self) -> Shares {
+        Shares(Decimal::ONE)
+    }
+
+    fn country_of_deposit(&self) -> &str {
+        &self.country
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn modelo_720_code(&self) -> TipoBien {
+        TipoBien::BienInmbueble(self.tipo_inmueble)
+    }
+
+    fn declares_shares(&self) -> bool {
+        false
+    }
+
+    fn modelo_720_registro(&self, ejercicio: i16, nif: &Nif, name: &str) -> Registro2 {
+        let registro = Registro2 {
+            tipo_derecho_real_sobre_inmueble: self
+                .tipo_derecho_real
+                .as_ref()
+                .map(|x| x.to_uppercase()),
+            // The address is the property's, there is no entity.
+            identificacion_entidad: None,
+            fecha_incorporacion: Date(self.acquisition_date),
+            valoracion1: self.acquisition_value.into(),
+            clave_tipo_bien_inmueble: Some(self.clase),
+            ..entity_registro(self, &self.address, &self.country, ejercicio, nif, name)
+        };
+        with_ownership_percentage(registro, self, self.ownership_percentage)
+    }
+}
+
+pub struct Insurance {
+    pub policy_number: String,
+    pub tipo_seguro: TipoSeguro,
+    pub insurer: String,
+    /// Tax identification number of the insurer in its country of residence.
+    pub entity_nif: Option<String>,
+    pub insurer_address: Address,
+    pub country: String,
+    pub subscription_date: Option<NaiveDate>,
+    /// Surrender value at the 31st of December, or the capitalization value for annuities.
+    pub surrender_value: Decimal,
+}
+
+impl AssetWithValuation for Insurance {
+    fn isin(&self) -> &str {
+        &self.policy_number
+    }
+
+    fn entity_nif(&self) -> Option<&str> {
+        self.entity_nif.as_deref()
+    }
+
+    fn identification(&self) -> Identification {
+        Identification::None
+    }
+
+    fn native_valuation(&self) -> Decimal {
+        self.surrender_value
+    }
+
+    fn shares(&self) -> Shares {
+        Shares(Decimal::ONE)
+    }
+
+    fn country_of_deposit(&self) -> &str {
+        &self.country
+    }
+
+    fn description(&self) -> &str {
+        &self.insurer
+    }
+
+    fn modelo_720_code(&self) -> TipoBien {
+        TipoBien::Seguro(self.tipo_seguro)
+    }
+
+    fn declares_shares(&self) -> bool {
+        false
+    }
+
+    fn modelo_720_registro(&self, ejercicio: i16, nif: &Nif, name: &str) -> Registro2 {
+        Registro2 {
+            fecha_incorporacion: Date(self.subscription_date),
+            valoracion1: self.surrender_value.into(),
+            ..entity_registro(
+                self,
+                &self.insurer_address,
+                &self.country,
+                ejercicio,
+                nif,
+                name,
+            )
+        }
+    }
+}
+
+// Kept in a module of its own so that its methods don't clash with those of `AssetWithValuation` when
+// called on an asset.
+mod wrapper {
+    use chrono::NaiveDate;
+    use rust_decimal::Decimal;
+
+    use super::{AssetWithValuation, Identification};
+    use crate::modelo_720::{Nif, Registro2, Shares, TipoBien};
+
+    /// Asset that wraps another to change some of what it reports, such as its ISIN or its valuation, and
+    /// reports everything else as the wrapped asset does. Only the methods that change are implemented.
+    pub trait AssetWrapper: Send + Sync {
+        fn inner(&self) -> &dyn AssetWithValuation;
+
+        fn isin(&self) -> &str {
+            self.inner().isin()
+        }
+
+        fn native_valuation(&self) -> Decimal {
+            self.inner().native_valuation()
+        }
+
+        fn shares(&self) -> Shares {
+            self.inner().shares()
+        }
+
+        fn country_of_deposit(&self) -> &str {
+            self.inner().country_of_deposit()
+        }
+
+        fn description(&self) -> &str {
+            self.inner().description()
+        }
+
+        fn modelo_720_code(&self) -> TipoBien {
+            self.inner().modelo_720_code()
+        }
+
+        fn currency(&self) -> &str {
+            self.inner().currency()
+        }
+
+        fn valuation(&self) -> Decimal {
+            self.inner().valuation()
+        }
+
+        fn entity_name(&self) -> &str {
+            self.inner().entity_name()
+        }
+
+        fn declares_shares(&self) -> bool {
+            self.inner().declares_shares()
+        }
+
+        fn second_valuation(&self) -> Option<Decimal> {
+            self.inner().second_valuation()
+        }
+
+        fn entity_country(&self) -> &str {
+            self.inner().entity_country()
+        }
+
+        fn depositary(&self) -> Option<&str> {
+            self.inner().depositary()
+        }
+
+        fn closing_date(&self) -> Option<NaiveDate> {
+            self.inner().closing_date()
+        }
+
+        fn entity_nif(&self) -> Option<&str> {
+            self.inner().entity_nif()
+        }
+
+        fn identification(&self) -> Identification {
+            self.inner().identification()
+        }
+
+        fn modelo_720_registro(&self, ejercicio: i16, nif: &Nif, name: &str) -> Registro2 {
+            self.inner().modelo_720_registro(ejercicio, nif, name)
+        }
+    }
+
+    // The price per share is left to the default, computed from what the wrapper reports.
+    impl<T: AssetWrapper> AssetWithValuation for T {
+        fn isin(&self) -> &str {
+            AssetWrapper::isin(self)
+        }
+
+        fn native_valuation(&self) -> Decimal {
+            AssetWrapper::native_valuation(self)
+        }
+
+        fn shares(&self) -> Shares {
+            AssetWrapper::shares(self)
+        }
+
+        fn country_of_deposit(&self) -> &str {
+            AssetWrapper::country_of_deposit(self)
+        }
+
+        fn description(&self) -> &str {
+            AssetWrapper::description(self)
+        }
+
+        fn modelo_720_code(&self) -> TipoBien {
+            AssetWrapper::modelo_720_code(self)
+        }
+
+        fn currency(&self) -> &str {
+            AssetWrapper::currency(self)
+        }
+
+        fn valuation(&self) -> Decimal {
+            AssetWrapper::valuation(self)
+        }
+
+        fn entity_name(&self) -> &str {
+            AssetWrapper::entity_name(self)
+        }
+
+        fn declares_shares(&self) -> bool {
+            AssetWrapper::declares_shares(self)
+        }
+
+        fn second_valuation(&self) -> Option<Decimal> {
+            AssetWrapper::second_valuation(self)
+        }
+
+        fn entity_country(&self) -> &str {
+            AssetWrapper::entity_country(self)
+        }
+
+        fn depositary(&self) -> Option<&str> {
+            AssetWrapper::depositary(self)
+        }
+
+        fn closing_date(&self) -> Option<NaiveDate> {
+            AssetWrapper::closing_date(self)
+        }
+
+        fn entity_nif(&self) -> Option<&str> {
+            AssetWrapper::entity_nif(self)
+        }
+
+        fn identification(&self) -> Identification {
+            AssetWrapper::identification(self)
+        }
+
+        fn modelo_720_registro(&self, ejercicio: i16, nif: &Nif, name: &str) -> Registro2 {
+            AssetWrapper::modelo_720_registro(self, ejercicio, nif, name)
+        }
+    }
+}
+
+impl wrapper::AssetWrapper for &'_ Arc<dyn AssetWithValuation> {
+    fn inner(&self) -> &dyn AssetWithValuation {
+        self.as_ref()
+    }
+}
+
+/// Asset as it's known at the end of the year after corporate actions: with a new ISIN, e.g. after a fund
+/// merger or a change of domicile, and with its shares multiplied by the splits. It's matched with the
+/// current position as a continuation of the same holding instead of a sale and a new acquisition.
+pub struct AdjustedAsset {
+    pub asset: Arc<dyn AssetWithValuation>,
+    pub isin: String,
+    /// New shares per old share of the splits during the year, e.g. 2 for a 2:1 split.
+    pub share_ratio: Decimal,
+}
+
+/// Split of the shares of an asset, with `ratio` new shares for each old one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StockSplit {
+    pub isin: String,
+    pub date: NaiveDate,
+    pub ratio: Decimal,
+}
+
+impl wrapper::AssetWrapper for AdjustedAsset {
+    fn inner(&self) -> &dyn AssetWithValuation {
+        self.asset.as_ref()
+    }
+
+    fn isin(&self) -> &str {
+        &self.isin
+    }
+
+    // Only ISINs change with corporate actions.
+    fn identification(&self) -> Identification {
+        match self.asset.identification() {
+            Identification::Isin(_) => Identification::Isin(self.isin.clone()),
+            x => x,
+        }
+    }
+
+    fn shares(&self) -> Shares {
+        Shares(self.asset.shares().0 * self.share_ratio)
+    }
+
+    // A change of domicile moves the issuer to the country of the new ISIN.
+    fn entity_country(&self) -> &str {
+        if self.isin == self.asset.isin() {
+            return self.asset.entity_country();
+        }
+        self.isin
+            .get(..2)
+            .filter(|x| is_valid_country(x))
+            .unwrap_or(self.asset.entity_country())
+    }
+
+    fn modelo_720_registro(&self, ejercicio: i16, nif: &Nif, name: &str) -> Registro2 {
+        let registro = self.asset.modelo_720_registro(ejercicio, nif, name);
+        Registro2 {
+            identificacion_valores: match self.asset.identification() {
+                Identification::Isin(_) => Some(self.isin.clone()),
+                _ => registro.identificacion_valores,
+            },
+            codigo_pais_entidad: registro
+                .codigo_pais_entidad
+                .as_ref()
+                .map(|_| AssetWithValuation::entity_country(self).to_string()),
+            ..registro
+        }
+    }
+}
+
+/// Asset whose depositary is only known from the same holding in another portfolio.
+pub struct DepositedAsset {
+    pub asset: Arc<dyn AssetWithValuation>,
+    pub depositary: String,
+}
+
+impl wrapper::AssetWrapper for DepositedAsset {
+    fn inner(&self) -> &dyn AssetWithValuation {
+        self.asset.as_ref()
+    }
+
+    fn depositary(&self) -> Option<&str> {
+        Some(&self.depositary)
+    }
+}
+
+/// Asset reported in a currency other than the euro, with its valuations converted at the year-end rate.
+pub struct ConvertedAsset {
+    pub asset: Arc<dyn AssetWithValuation>,
+    pub valuation: Decimal,
+    pub second_valuation: Option<Decimal>,
+}
+
+impl wrapper::AssetWrapper for ConvertedAsset {
+    fn inner(&self) -> &dyn AssetWithValuation {
+        self.asset.as_ref()
+    }
+
+    fn valuation(&self) -> Decimal {
+        self.valuation
+    }
+
+    fn second_valuation(&self) -> Option<Decimal> {
+        self.second_valuation
+    }
+
+    fn modelo_720_registro(&self, ejercicio: i16, nif: &Nif, name: &str) -> Registro2 {
+        Registro2 {
+            valoracion1: self.valuation.into(),
+            valoracion2: self.second_valuation.unwrap_or_default().into(),
+            ..self.asset.modelo_720_registro(ejercicio, nif, name)
+        }
+    }
+}
+
+/// Asset valued below zero in its statement, such as an overdrawn margin account, declared as valued at zero.
+pub struct ClampedAsset {
+    pub asset: Arc<dyn AssetWithValuation>,
+}
+
+impl wrapper::AssetWrapper for ClampedAsset {
+    fn inner(&self) -> &dyn AssetWithValuation {
+        self.asset.as_ref()
+    }
+
+    fn native_valuation(&self) -> Decimal {
+        self.asset.native_valuation().max(Decimal::ZERO)
+    }
+
+    fn valuation(&self) -> Decimal {
+        self.asset.valuation().max(Decimal::ZERO)
+    }
+
+    fn second_valuation(&self) -> Option<Decimal> {
+        self.asset.second_valuation().map(|x| x.max(Decimal::ZERO))
+    }
+
+    fn modelo_720_registro(&self, ejercicio: i16, nif: &Nif, name: &str) -> Registro2 {
+        Registro2 {
+            valoracion1: AssetWithValuation::valuation(self).into(),
+            valoracion2: AssetWithValuation::second_valuation(self)
+                .unwrap_or_default()
+                .into(),
+            ..self.asset.modelo_720_registro(ejercicio, nif, name)
+        }
+    }
+}
+
+/// Asset declared with another clave than the one its kind of asset gets, e.g. an ETC bought as a fund, which
+/// is debt of its issuer rather than an investment fund.
+pub struct ClassifiedAsset {
+    pub asset: Arc<dyn AssetWithValuation>,
+    pub clave: TipoBien,
+}
+
+impl wrapper::AssetWrapper for ClassifiedAsset {
+    fn inner(&self) -> &dyn AssetWithValuation {
+        self.asset.as_ref()
+    }
+
+    fn modelo_720_code(&self) -> TipoBien {
+        self.clave
+    }
+
+    fn modelo_720_registro(&self, ejercicio: i16, nif: &Nif, name: &str) -> Registro2 {
+        Registro2 {
+            tipo_bien: self.clave,
+            ..self.asset.modelo_720_registro(ejercicio, nif, name)
+        }
+    }
+}
+
+/// Asset as declared in a submitted 720 file, so last year's declaration can stand in for last year's statement.
+pub struct DeclaredAsset {
+    pub registro: Registro2,
+}
+
+impl DeclaredAsset {
+    /// Only registros with an ISIN or account code can be matched with the current holdings.
+    pub fn from_registro(registro: Registro2) -> Option<DeclaredAsset> {
+        registro.identifier()?;
+        Some(DeclaredAsset { registro })
+    }
+}
+
+impl AssetWithValuation for DeclaredAsset {
+    fn isin(&self) -> &str {
+        self.registro.identifier().unwrap_or_default().trim()
+    }
+
+    // The entidad of an account is the bank holding it. That of other assets is their issuer, while where
+    // they were deposited isn't declared and is taken from the same holding in the statement.
+    fn depositary(&self) -> Option<&str> {
+        match self.registro.tipo_bien {
+            TipoBien::Cuenta(_) => Some(self.entity_name()).filter(|x| !x.is_empty()),
+            _ => None,
+        }
+    }
+
+    fn closing_date(&self) -> Option<NaiveDate> {
+        self.registro.fecha_extincion.0
+    }
+
+    fn entity_nif(&self) -> Option<&str> {
+        self.registro.nif_pais_residencia_fiscal.as_deref()
+    }
+
+    fn identification(&self) -> Identification {
+        let value = self
+            .registro
+            .identificacion_valores
+            .as_deref()
+            .map(str::trim);
+        match (self.registro.clave_identificacion.0, value) {
+            (Some(ClaveIdentificacion::Isin), Some(x)) => Identification::Isin(x.to_string()),
+            (Some(ClaveIdentificacion::Otra), Some(x)) => Identification::Other(x.to_string()),
+            _ => Identification::None,
+        }
+    }
+
+    fn native_valuation(&self) -> Decimal {
+        self.registro.valoracion1.0
+    }
+
+    fn shares(&self) -> Shares {
+        // Assets declared without valores are a single unit, as in their own asset types.
+        self.registro.numero_valores.unwrap_or(Shares(Decimal::ONE))
+    }
+
+    fn country_of_deposit(&self) -> &str {
+        &self.registro.codigo_pais
+    }
+
+    fn description(&self) -> &str {
+        self.registro
+            .identificacion_entidad
+            .as_deref()
+            .unwrap_or_default()
+            .trim()
+    }
+
+    fn modelo_720_code(&self) -> TipoBien {
+        self.registro.tipo_bien
+    }
+
+    // Valuations are always declared in euros.
+    fn currency(&self) -> &str {
+        "EUR"
+    }
+
+    fn entity_name(&self) -> &str {
+        self.description()
+    }
+
+    fn declares_shares(&self) -> bool {
+        self.registro.numero_valores.is_some()
+    }
+
+    fn second_valuation(&self) -> Option<Decimal> {
+        Some(self.registro.valoracion2.0).filter(|x| !x.is_zero())
+    }
+
+    fn modelo_720_registro(&self, ejercicio: i16, nif: &Nif, name: &str) -> Registro2 {
+        let registro = Registro2::new(
+            ejercicio,
+            nif.clone(),
+            name.to_string(),
+            self.country_of_deposit().to_string(),
+        );
+        Registro2 {
+            ejercicio: registro.ejercicio,
+            nif_declarante: registro.nif_declarante,
+            nif_declarado: registro.nif_declarado,
+            nombre: registro.nombre,
+            origen_bien_derecho: Origen::Modificacion,
+            fecha_extincion: Date(None),
+            ..self.registro.clone()
+        }
+    }
+}
+
+/// Security, fund or insurance described field by field in a manual file, for what no other kind of asset
+/// covers.
+pub struct OtherAsset {
+    pub clave: TipoBien,
+    /// Code matching the asset between years, also declared as its identificación de valores for securities
+    /// and funds.
+    pub identifier: String,
+    pub identification: Identification,
+    pub description: String,
+    pub entity_name: String,
+    /// Tax identification number of the entity in its country of residence.
+    pub entity_nif: Option<String>,
+    pub entity_address: Address,
+    pub entity_country: String,
+    pub country: String,
+    /// Number of valores, for the assets declared with one.
+    pub shares: Option<Decimal>,
+    /// Valuation in `currency`, declared as valoración 1.
+    pub valuation: Decimal,
+    pub second_valuation: Option<Decimal>,
+    pub currency: String,
+    pub acquisition_date: Option<NaiveDate>,
+    /// Percentage of the asset owned by the declarante.
+    pub ownership_percentage: Decimal,
+}
+
+impl AssetWithValuation for OtherAsset {
+    fn isin(&self) -> &str {
+        &self.identifier
+    }
+
+    fn entity_nif(&self) -> Option<&str> {
+        self.entity_nif.as_deref()
+    }
+
+    fn identification(&self) -> Identification {
+        self.identification.clone()
+    }
+
+    fn native_valuation(&self) -> Decimal {
+        self.valuation
+    }
+
+    fn shares(&self) -> Shares {
+        // Assets declared without valores are a single unit, as in their own asset types.
+        Shares(self.shares.unwrap_or(Decimal::ONE))
+    }
+
+    fn country_of_deposit(&self) -> &str {
+        &self.country
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn modelo_720_code(&self) -> TipoBien {
+        self.clave
+    }
+
+    fn currency(&self) -> &str {
+        &self.currency
+    }
+
+    fn entity_name(&self) -> &str {
+        &self.entity_name
+    }
+
+    fn entity_country(&self) -> &str {
+        &self.entity_country
+    }
+
+    fn declares_shares(&self) -> bool {
+        self.shares.is_some()
+    }
+
+    fn second_valuation(&self) -> Option<Decimal> {
+        self.second_valuation
+    }
+
+    fn modelo_720_registro(&self, ejercicio: i16, nif: &Nif, name: &str) -> Registro2 {
+        let identification = self.identification();
+        let registro = Registro2 {
+            clave_representacion_valores: identification
+                .clave()
+                .0
+                .map(|_| RepresentacionValores::Anotaciones),
+            clave_identificacion: identification.clave(),
+            identificacion_valores: identification.value(),
+            fecha_incorporacion: Date(self.acquisition_date),
+            ..entity_registro(
+                self,
+                &self.entity_address,
+                &self.entity_country,
+                ejercicio,
+                nif,
+                name,
+            )
+        };
+        with_ownership_percentage(registro, self, self.ownership_percentage)
+    }
+}
+
+/// Shares of an asset bought together, consumed first in first out when part of the position is sold.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lot {
+    pub date: Option<NaiveDate>,
+    pub shares: Decimal,
+    /// Acquisition cost in euros.
+    pub cost: Decimal,
+}
+
+/// Lots as read from a file, keyed by ISIN and the depositary they were bought at when known.
+pub type LotsByIsin = HashMap<(String, Option<String>), Vec<Lot>>;
+
+/// Takes `shares` from the oldest lots, splitting the last one consumed if needed. The lots returned are
+/// the ones sold, with their cost in proportion to the shares taken.
+pub fn consume_fifo(lots: &[Lot], shares: Decimal) -> Vec<Lot> {
+    let mut sorted: Vec<&Lot> = lots.iter().collect();
+    sorted.sort_by_key(|x| x.date);
+    let mut remaining = shares;
+    let mut consumed = Vec::new();
+    for lot in sorted {
+        if remaining <= Decimal::ZERO {
+            break;
+        }
+        let taken = remaining.min(lot.shares);
+        consumed.push(Lot {
+            date: lot.date,
+            shares: taken,
+            cost: lot.cost * taken / lot.shares,
+        });
+        remaining -= taken;
+    }
+    consumed
+}
+
+/// Takes `shares` from the newest lots, splitting the last one taken if needed. Since sales consume the oldest
+/// lots first, these are the lots still held out of those given, returned oldest first.
+pub fn latest_lots(lots: &[Lot], shares: Decimal) -> Vec<Lot> {
+    let mut sorted: Vec<&Lot> = lots.iter().collect();
+    sorted.sort_by_key(|x| std::cmp::Reverse(x.date));
+    let mut remaining = shares;
+    let mut taken_lots = Vec::new();
+    for lot in sorted {
+        if remaining <= Decimal::ZERO {
+            break;
+        }
+        let taken = remaining.min(lot.shares);
+        taken_lots.push(Lot {
+            date: lot.date,
+            shares: taken,
+            cost: lot.cost * taken / lot.shares,
+        });
+        remaining -= taken;
+    }
+    taken_lots.reverse();
+    taken_lots
+}
+
+/// What to do with the assets of a statement valued at zero or less, which make for nonsense registros.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ValuationPolicy {
+    /// Declare every asset, negative valuations with their sign.
+    #[default]
+    Signed,
+    /// Leave out the assets valued at zero, declaring negative valuations with their sign.
+    DropZero,
+    /// Leave out the assets valued at zero and fail on negative valuations.
+    Strict,
+    /// Declare every asset, negative valuations as zero.
+    Clamp,
+}
+
+#[derive(Default)]
+pub struct Portfolio {
+    // TODO: This should definitely be private
+    pub assets: Vec<Arc<dyn AssetWithValuation>>,
+    /// Acquisition lots of each holding, keyed by its [`holding_key`] with the ISIN in uppercase. Holdings
+    /// without lots are treated as a single position.
+    pub lots: HashMap<(String, String, String), Vec<Lot>>,
+    /// Where each asset was read from, keyed by ISIN in uppercase like the registro identifiers. Aggregated
+    /// assets have one per holding.
+    pub sources: HashMap<String, Vec<Provenance>>,
+}
+
+impl Portfolio {
+    /// Portfolio of the assets of a statement, leaving out, rejecting or clamping those valued at zero or less
+    /// as `policy` says. The assets left out or clamped are reported.
+    pub fn from_assets(
+        assets: Vec<Arc<dyn AssetWithValuation>>,
+        policy: ValuationPolicy,
+    ) -> error::Result<Portfolio> {
+        let mut dropped = Vec::new();
+        let mut clamped = Vec::new();
+        let mut kept: Vec<Arc<dyn AssetWithValuation>> = Vec::with_capacity(assets.len());
+        for asset in assets {
+            let valuation = asset.native_valuation();
+            let drops_zero = matches!(policy, ValuationPolicy::DropZero | ValuationPolicy::Strict);
+            if valuation.is_zero() && drops_zero {
+                dropped.push(asset.isin().to_string());
+                continue;
+            }
+            if valuation < Decimal::ZERO {
+                match policy {
+                    ValuationPolicy::Strict => {
+                        return Err(Error::InvalidInput(format!(
+                            "{} {} has a negative valuation of {}",
+                            asset.isin(),
+                            asset.description(),
+                            valuation
+                        )))
+                    }
+                    ValuationPolicy::Clamp => {
+                        clamped.push(asset.isin().to_string());
+                        kept.push(Arc::new(ClampedAsset { asset }));
+                        continue;
+                    }
+                    ValuationPolicy::Signed | ValuationPolicy::DropZero => {}
+                }
+            }
+            kept.push(asset);
+        }
+        if !dropped.is_empty() {
+            eprintln!(
+                "Leaving out {} assets valued at zero: {}",
+                dropped.len(),
+                dropped.join(", ")
+            );
+        }
+        if !clamped.is_empty() {
+            eprintln!(
+                "Declaring {} assets with a negative valuation as zero: {}",
+                clamped.len(),
+                clamped.join(", ")
+            );
+        }
+        Ok(Portfolio::sorted(kept))
+    }
+
+    fn sorted(assets: Vec<Arc<dyn AssetWithValuation>>) -> Portfolio {
+        let mut result = Portfolio {
+            assets,
+            lots: HashMap::new(),
+            sources: HashMap::new(),
+        };
+        result.sort();
+        result
+    }
+
+    fn sort(&mut self) {
+        self.assets
+            .sort_by(|a, b| holding_key(a.as_ref()).cmp(&holding_key(b.as_ref())));
+    }
+
+    /// ISINs held more than once in the same deposit country at the same depositary, e.g. in two accounts.
+    pub fn duplicates(&self) -> Vec<String> {
+        let mut result: Vec<String> = self
+            .assets
+            .windows(2)
+            .filter(|x| holding_key(x[0].as_ref()) == holding_key(x[1].as_ref()))
+            .map(|x| match x[0].depositary() {
+                Some(depositary) => format!(
+                    "{} deposited in {} at {}",
+                    x[0].isin(),
+                    x[0].country_of_deposit(),
+                    depositary
+                ),
+                None => format!("{} deposited in {}", x[0].isin(), x[0].country_of_deposit()),
+            })
+            .collect();
+        result.dedup();
+        result
+    }
+
+    /// Sums the holdings of the same ISIN in the same deposit country at the same depositary into a single
+    /// asset.
+    pub fn aggregated(self) -> Self {
+        let mut assets: Vec<Arc<dyn AssetWithValuation>> = Vec::new();
+        let mut group: Vec<Arc<dyn AssetWithValuation>> = Vec::new();
+        for asset in self.assets {
+            let same = group
+                .last()
+                .is_some_and(|x| holding_key(x.as_ref()) == holding_key(asset.as_ref()));
+            if !same {
+                assets.extend(aggregate(std::mem::take(&mut group)));
+            }
+            group.push(asset);
+        }
+        assets.extend(aggregate(group));
+        Portfolio {
+            lots: self.lots,
+            ..Portfolio::sorted(assets).with_sources(self.sources)
+        }
+    }
+
+    /// The assets sorted as holdings, those without a known depositary taking the one of the same holding in
+    /// `other` when it is held at a single one there. Last year's declaration doesn't say where each asset
+    /// was deposited, so this is what matches it with the holdings of a statement.
+    pub fn assets_with_depositaries_of(
+        &self,
+        other: &Portfolio,
+    ) -> Vec<Arc<dyn AssetWithValuation>> {
+        let mut depositaries: HashMap<(&str, &str), Option<&str>> = HashMap::new();
+        for asset in other.assets.iter().map(Arc::as_ref) {
+            if let Some(depositary) = asset.depositary() {
+                depositaries
+                    .entry((asset.isin(), asset.country_of_deposit()))
+                    .and_modify(|x| {
+                        if *x != Some(depositary) {
+                            *x = None;
+                        }
+                    })
+                    .or_insert(Some(depositary));
+            }
+        }
+        let mut assets: Vec<Arc<dyn AssetWithValuation>> = self
+            .assets
+            .iter()
+            .map(|asset| {
+                let depositary = depositaries
+                    .get(&(asset.isin(), asset.country_of_deposit()))
+                    .copied()
+                    .flatten();
+                match (asset.depositary(), depositary) {
+                    (None, Some(depositary)) => Arc::new(DepositedAsset {
+                        asset: asset.clone(),
+                        depositary: depositary.to_string(),
+                    }),
+                    _ => asset.clone(),
+                }
+            })
+            .collect();
+        assets.sort_by(|a, b| holding_key(a.as_ref()).cmp(&holding_key(b.as_ref())));
+        assets
+    }
+
+    /// Holdings at the end of the year of a submitted declaration: everything declared except what was
+    /// extinguished. Registros that can't be identified, such as real estate, are returned apart.
+    pub fn from_modelo_720(modelo720: &Modelo720) -> (Portfolio, Vec<Registro2>) {
+        let mut assets: Vec<Arc<dyn AssetWithValuation>> = Vec::new();
+        let mut unidentified = Vec::new();
+        for registro in modelo720.entries.iter().cloned() {
+            if matches!(registro.origen_bien_derecho, Origen::Extincion) {
+                continue;
+            }
+            match DeclaredAsset::from_registro(registro.clone()) {
+                Some(asset) => assets.push(Arc::new(asset)),
+                None => unidentified.push(registro),
+            }
+        }
+        (Portfolio::sorted(assets).aggregated(), unidentified)
+    }
+
+    /// Gives each holding the lots of its ISIN, keyed by ISIN and the depositary they were bought at when
+    /// known. Lots of an ISIN held at several depositaries can only be told apart by it.
+    pub fn with_lots(mut self, lots: LotsByIsin) -> error::Result<Self> {
+        for ((isin, depositary), lots) in lots {
+            let mut holdings: Vec<(String, String, String)> = self
+                .assets
+                .iter()
+                .filter(|x| x.isin().eq_ignore_ascii_case(&isin))
+                .filter(|x| match (x.depositary(), &depositary) {
+                    (Some(held), Some(bought)) => held.eq_ignore_ascii_case(bought),
+                    _ => true,
+                })
+                .map(|x| owned_holding_key(x.as_ref()))
+                .collect();
+            holdings.sort();
+            holdings.dedup();
+            match holdings.as_slice() {
+                [] => {}
+                [holding] => self.lots.entry(holding.clone()).or_default().extend(lots),
+                _ => {
+                    return Err(Error::InvalidInput(format!(
+                        "the lots of {} can't be told apart between its holdings at {}, give the depositary of each",
+                        isin,
+                        holdings
+                            .iter()
+                            .map(|(_, country, depositary)| format!("{} {}", depositary, country)
+                                .trim()
+                                .to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )))
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn with_sources(mut self, sources: HashMap<String, Vec<Provenance>>) -> Self {
+        self.sources = sources;
+        self
+    }
+
+    /// Gives the assets that don't have a source yet `file` as their source, for statements without a row
+    /// per asset.
+    pub fn with_source_file(mut self, broker: &str, file: &Path) -> Self {
+        for asset in self.assets.iter() {
+            self.sources
+                .entry(asset.isin().to_uppercase())
+                .or_insert_with(|| vec![Provenance::new(broker, file, None)]);
+        }
+        self
+    }
+
+    /// Price per share in euros of the assets declared with a number of shares, keyed by ISIN in uppercase.
+    pub fn prices_per_share(&self) -> HashMap<String, Decimal> {
+        self.assets
+            .iter()
+            .filter(|x| x.declares_shares() && !x.shares().0.is_zero())
+            .map(|x| (x.isin().to_uppercase(), x.price_per_share()))
+            .collect()
+    }
+
+    pub fn sources_of(&self, isin: &str) -> &[Provenance] {
+        self.sources
+            .get(&isin.to_uppercase())
+            .map(|x| x.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Renames the assets whose ISIN changed, given as old to new ISIN, together with their lots.
+    pub fn with_isin_changes(self, changes: &HashMap<String, String>) -> Self {
+        self.adjusted(|isin| changes.get(isin).cloned(), |_| None)
+    }
+
+    /// Declares the assets that `classification` gives a clave with it instead of the one of their kind of asset.
+    pub fn with_classification(self, classification: &Classification) -> Self {
+        let assets = self
+            .assets
+            .into_iter()
+            .map(|asset| match classification.clave_of(asset.as_ref()) {
+                Some(clave) => {
+                    Arc::new(ClassifiedAsset { asset, clave }) as Arc<dyn AssetWithValuation>
+                }
+                None => asset,
+            })
+            .collect();
+        Portfolio { assets, ..self }
+    }
+
+    /// Converts the assets not reported in euros with the year-end `rates`, rounding the result with `rounding`.
+    pub fn in_euros(
+        self,
+        rates: Option<&ExchangeRates>,
+        rounding: Rounding,
+    ) -> error::Result<Self> {
+        let mut assets: Vec<Arc<dyn AssetWithValuation>> = Vec::with_capacity(self.assets.len());
+        for asset in self.assets {
+            if asset.currency() == "EUR" {
+                assets.push(asset);
+                continue;
+            }
+            let Some(rates) = rates else {
+                return Err(Error::Usage(format!(
+                    "{} is valued in {}, pass --fetch-rates or --offline-rates to convert it",
+                    asset.isin(),
+                    asset.currency()
+                )));
+            };
+            let convert = |amount| {
+                rates
+                    .to_euros(amount, asset.currency())
+                    .map(|x| rounding.apply(x))
+            };
+            let valuation = convert(asset.native_valuation())?;
+            let second_valuation = asset.second_valuation().map(convert).transpose()?;
+            assets.push(Arc::new(ConvertedAsset {
+                asset,
+                valuation,
+                second_valuation,
+            }));
+        }
+        Ok(Portfolio {
+            assets,
+            lots: self.lots,
+            sources: self.sources,
+        })
+    }
+
+    /// Multiplies the shares of the assets and their lots by the ratio of their splits.
+    pub fn with_splits(self, splits: &[StockSplit]) -> Self {
+        let mut ratios: HashMap<&str, Decimal> = HashMap::new();
+        for split in splits {
+            *ratios.entry(&split.isin).or_insert(Decimal::ONE) *= split.ratio;
+        }
+        self.adjusted(|_| None, |isin| ratios.get(isin).copied())
+    }
+
+    fn adjusted(
+        self,
+        new_isin: impl Fn(&str) -> Option<String>,
+        share_ratio: impl Fn(&str) -> Option<Decimal>,
+    ) -> Self {
+        let assets = self
+            .assets
+            .into_iter()
+            .map(
+                |asset| match (new_isin(asset.isin()), share_ratio(asset.isin())) {
+                    (None, None) => asset,
+                    (isin, ratio) => Arc::new(AdjustedAsset {
+                        isin: isin.unwrap_or(asset.isin().to_string()),
+                        share_ratio: ratio.unwrap_or(Decimal::ONE),
+                        asset,
+                    }) as Arc<dyn AssetWithValuation>,
+                },
+            )
+            .collect();
+        let lots = self
+            .lots
+            .into_iter()
+            .map(|((isin, country, depositary), lots)| {
+                let ratio = share_ratio(&isin).unwrap_or(Decimal::ONE);
+                let lots = lots
+                    .into_iter()
+                    .map(|lot| Lot {
+                        shares: lot.shares * ratio,
+                        ..lot
+                    })
+                    .collect();
+                ((new_isin(&isin).unwrap_or(isin), country, depositary), lots)
+            })
+            .collect();
+        let sources = self
+            .sources
+            .into_iter()
+            .map(|(isin, sources)| (new_isin(&isin).unwrap_or(isin), sources))
+            .collect();
+        Portfolio {
+            lots,
+            ..Portfolio::sorted(assets).with_sources(sources)
+        }
+    }
+
+    /// Acquisition lots of the holding of `asset`. A holding whose depositary was only learned from the other
+    /// portfolio has the lots it was given without one.
+    pub fn lots_of<T: AssetWithValuation + ?Sized>(&self, asset: &T) -> &[Lot] {
+        let (isin, country, depositary) = owned_holding_key(asset);
+        self.lots
+            .get(&(isin.clone(), country.clone(), depositary))
+            .or_else(|| self.lots.get(&(isin, country, String::new())))
+            .map(|x| x.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Combines the holdings of two portfolios, such as from different brokers, adding up the positions both
+    /// have in the same ISIN and deposit country.
+    pub fn merge(mut self, other: Portfolio) -> Self {
+        self.assets.extend_from_slice(&other.assets);
+        for (holding, lots) in other.lots {
+            self.lots.entry(holding).or_default().extend(lots);
+        }
+        for (isin, sources) in other.sources {
+            self.sources.entry(isin).or_default().extend(sources);
+        }
+        self.sort();
+        self.aggregated()
+    }
+}
+
+/// What tells holdings apart: the same ISIN deposited in different countries or at different depositaries is
+/// a different holding, declared in a registro of its own.
+pub(crate) fn holding_key<T: AssetWithValuation + ?Sized>(asset: &T) -> (&str, &str, &str) {
+    (
+        asset.isin(),
+        asset.country_of_deposit(),
+        asset.depositary().unwrap_or_default(),
+    )
+}
+
+// The holding key of `asset` as lots are keyed.
+fn owned_holding_key<T: AssetWithValuation + ?Sized>(asset: &T) -> (String, String, String) {
+    let (isin, country, depositary) = holding_key(asset);
+    (
+        isin.to_uppercase(),
+        country.to_string(),
+        depositary.to_string(),
+    )
+}
+
+fn aggregate(mut group: Vec<Arc<dyn AssetWithValuation>>) -> Option<Arc<dyn AssetWithValuation>> {
+    match group.len() {
+        0 => None,
+        1 => group.pop(),
+        _ => Some(Arc::new(AggregatedAsset { assets: group })),
+    }
+}
+
+/// Several holdings of the same ISIN in the same deposit country, declared as a single one.
+pub struct AggregatedAsset {
+    pub assets: Vec<Arc<dyn AssetWithValuation>>,
+}
+
+// Everything but the amounts is that of the first holding.
+impl wrapper::AssetWrapper for AggregatedAsset {
+    fn inner(&self) -> &dyn AssetWithValuation {
+        self.assets[0].as_ref()
+    }
+
+    // Holdings of the same ISIN are quoted in the same currency.
+    fn native_valuation(&self) -> Decimal {
+        self.assets
+            .iter()
+            .map(|x| x.as_ref().native_valuation())
+            .sum()
+    }
+
+    fn valuation(&self) -> Decimal {
+        self.assets.iter().map(|x| x.as_ref().valuation()).sum()
+    }
+
+    fn shares(&self) -> Shares {
+        Shares(self.assets.iter().map(|x| x.as_ref().shares().0).sum())
+    }
+
+    fn second_valuation(&self) -> Option<Decimal> {
+        self.assets
+            .iter()
+            .map(|x| x.as_ref().second_valuation())
+            .reduce(|a, b| match (a, b) {
+                (Some(a), Some(b)) => Some(a + b),
+                (a, b) => a.or(b),
+            })
+            .flatten()
     }
 }
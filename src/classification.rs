@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::assets::AssetWithValuation;
+use crate::modelo_720::{TipoBien, TipoValor};
+
+// Exchange traded commodities are bought like ETFs, but they are notes issued by a vehicle rather than shares
+// of an investment fund, so they are declared as securities representing the transfer of own capital to third
+// parties.
+const ETC: TipoBien = TipoBien::Valores(TipoValor::CesionDeCapitalesATerceros);
+
+// ISINs commonly held that don't get the clave of their kind of asset.
+const BUNDLED: &[(&str, TipoBien)] = &[
+    // iShares Physical Gold
+    ("IE00B4ND3602", ETC),
+    // Invesco Physical Gold
+    ("IE00B579F325", ETC),
+    // WisdomTree Physical Gold
+    ("JE00B1VS3770", ETC),
+    // Xetra-Gold
+    ("DE000A0S9GB0", ETC),
+    // EUWAX Gold II
+    ("DE000EWG0LD1", ETC),
+];
+
+/// Clave declared for the assets meeting every condition given. Conditions left out match any asset.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ClassificationRule {
+    /// Start of the ISIN or account code, e.g. `IE` or `XS`.
+    pub isin_prefix: Option<String>,
+    /// Text found in the description of the asset.
+    pub description: Option<String>,
+    /// Broker or entity the asset is deposited at, e.g. `INTERACTIVE BROKERS`.
+    pub depositary: Option<String>,
+    pub clave: TipoBien,
+}
+
+impl ClassificationRule {
+    pub fn has_conditions(&self) -> bool {
+        self.isin_prefix.is_some() || self.description.is_some() || self.depositary.is_some()
+    }
+
+    fn matches<T: AssetWithValuation + ?Sized>(&self, asset: &T) -> bool {
+        let uppercase = |x: &str| x.trim().to_uppercase();
+        let isin = self
+            .isin_prefix
+            .as_deref()
+            .is_none_or(|x| asset.isin().to_uppercase().starts_with(&uppercase(x)));
+        let description = self
+            .description
+            .as_deref()
+            .is_none_or(|x| asset.description().to_uppercase().contains(&uppercase(x)));
+        let depositary = self
+            .depositary
+            .as_deref()
+            .is_none_or(|x| asset.depositary().map(uppercase) == Some(uppercase(x)));
+        isin && description && depositary
+    }
+}
+
+/// Claves to declare assets with instead of the one of their kind of asset, e.g. for ETCs bought as funds.
+/// The clave given for an ISIN or account code wins over the rules, which are tried in order.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Classification {
+    /// Clave of specific ISINs or account codes.
+    #[serde(default, rename = "classification")]
+    pub identifiers: HashMap<String, TipoBien>,
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<ClassificationRule>,
+}
+
+impl Classification {
+    /// The bundled classification of the ISINs whose clave differs from the one of their kind of asset.
+    pub fn bundled() -> Classification {
+        Classification {
+            identifiers: BUNDLED
+                .iter()
+                .map(|(isin, clave)| (isin.to_string(), *clave))
+                .collect(),
+            rules: Vec::new(),
+        }
+    }
+
+    /// Adds the claves of `other`, which win over the ones already known.
+    pub fn extend(&mut self, other: Classification) {
+        self.identifiers.extend(other.identifiers);
+        self.rules.splice(0..0, other.rules);
+    }
+
+    /// Clave to declare `asset` with, if it isn't the one of its kind of asset.
+    pub fn clave_of<T: AssetWithValuation + ?Sized>(&self, asset: &T) -> Option<TipoBien> {
+        self.identifiers
+            .get(&asset.isin().to_uppercase())
+            .copied()
+            .or_else(|| {
+                self.rules
+                    .iter()
+                    .find(|x| x.matches(asset))
+                    .map(|x| x.clave)
+            })
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use chrono::NaiveDate;
+use clap::ValueEnum;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::parsers::{csv_reader_from, deserialize_decimal};
+
+const ECB_RATES_URL: &str = "https://data-api.ecb.europa.eu/service/data/EXR/D..EUR.SP00.A";
+
+/// ECB euro foreign exchange reference rates, as units of each currency per euro.
+#[derive(Clone, Debug, Default)]
+pub struct ExchangeRates {
+    rates: HashMap<String, BTreeMap<NaiveDate, Decimal>>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct RateRecord {
+    date: NaiveDate,
+    currency: String,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    rate: Decimal,
+}
+
+// Only the columns of the ECB CSV data format that we need.
+#[derive(Debug, Deserialize)]
+struct EcbRecord {
+    #[serde(rename = "CURRENCY")]
+    currency: String,
+    #[serde(rename = "TIME_PERIOD")]
+    date: NaiveDate,
+    #[serde(rename = "OBS_VALUE")]
+    rate: Option<Decimal>,
+}
+
+/// How amounts converted to euros are rounded to cents.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Rounding {
+    /// Halves away from zero, the usual rounding of amounts in euros.
+    #[default]
+    HalfUp,
+    /// Halves to the nearest even cent, to avoid a bias when adding up many conversions.
+    HalfEven,
+    /// Drops the fractions of a cent.
+    Truncate,
+}
+
+impl Rounding {
+    pub fn apply(&self, amount: Decimal) -> Decimal {
+        let strategy = match self {
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+            Rounding::Truncate => RoundingStrategy::ToZero,
+        };
+        amount.round_dp_with_strategy(2, strategy)
+    }
+}
+
+impl ExchangeRates {
+    fn insert(&mut self, date: NaiveDate, currency: &str, rate: Decimal) {
+        self.rates
+            .entry(currency.trim().to_uppercase())
+            .or_default()
+            .insert(date, rate);
+    }
+
+    /// Reads a CSV file with `date,currency,rate` columns, as written by [`ExchangeRates::save_csv`].
+    pub fn from_csv(path: &Path) -> Result<ExchangeRates> {
+        let mut reader = csv_reader_from(std::fs::read(path).map_err(|e| Error::io(path, e))?);
+        let mut result = ExchangeRates::default();
+        for (index, row) in reader.deserialize().enumerate() {
+            let record: RateRecord = row.map_err(|e| Error::csv(path, e))?;
+            if record.rate <= Decimal::ZERO {
+                return Err(Error::Csv {
+                    path: path.to_path_buf(),
+                    line: index as u64 + 2,
+                    message: "rate must be positive".to_string(),
+                });
+            }
+            result.insert(record.date, &record.currency, record.rate);
+        }
+        Ok(result)
+    }
+
+    pub fn save_csv(&self, path: &Path) -> Result<()> {
+        let mut writer = csv::Writer::from_path(path).map_err(|e| Error::csv(path, e))?;
+        let mut currencies: Vec<&String> = self.rates.keys().collect();
+        currencies.sort();
+        for currency in currencies {
+            for (date, rate) in self.rates[currency].iter() {
+                writer
+                    .serialize(RateRecord {
+                        date: *date,
+                        currency: currency.clone(),
+                        rate: *rate,
+                    })
+                    .map_err(|e| Error::csv(path, e))?;
+            }
+        }
+        writer.flush().map_err(|e| Error::io(path, e))
+    }
+
+    /// Downloads the reference rates of the last days of `year` and of the year before, so both
+    /// statements of a declaration can be converted.
+    pub fn fetch_ecb(year: i32) -> Result<ExchangeRates> {
+        let url = format!(
+            "{}?startPeriod={}-12-15&endPeriod={}-12-31&format=csvdata",
+            ECB_RATES_URL,
+            year - 1,
+            year
+        );
+        let response = ureq::get(&url).call().map_err(|e| Error::Download {
+            url: url.clone(),
+            message: e.to_string(),
+        })?;
+        let mut reader = csv::Reader::from_reader(response.into_reader());
+        let mut result = ExchangeRates::default();
+        for row in reader.deserialize() {
+            let record: EcbRecord = row.map_err(|e| Error::Download {
+                url: url.clone(),
+                message: e.to_string(),
+            })?;
+            if let Some(rate) = record.rate {
+                result.insert(record.date, &record.currency, rate);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Keeps, for each currency, the last rate published on or before December 31 of `year`. The ECB
+    /// doesn't publish rates on December 31 itself most years, so this is usually the one of the 30th.
+    pub fn at_year_end(&self, year: i32) -> ExchangeRates {
+        let year_end = NaiveDate::from_ymd_opt(year, 12, 31).unwrap();
+        let rates = self
+            .rates
+            .iter()
+            .filter_map(|(currency, rates)| {
+                let (date, rate) = rates.range(..=year_end).next_back()?;
+                Some((currency.clone(), BTreeMap::from([(*date, *rate)])))
+            })
+            .collect();
+        ExchangeRates { rates }
+    }
+
+    /// Converts `amount` in `currency` to euros with the latest rate known for the currency.
+    pub fn to_euros(&self, amount: Decimal, currency: &str) -> Result<Decimal> {
+        let currency = currency.trim().to_uppercase();
+        if currency == "EUR" {
+            return Ok(amount);
+        }
+        let rate = self
+            .rates
+            .get(&currency)
+            .and_then(|x| x.values().next_back())
+            .ok_or_else(|| Error::InvalidInput(format!("no exchange rate for {}", currency)))?;
+        Ok(amount / rate)
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::assets::Portfolio;
+use crate::error::{Error, Result};
+use crate::modelo_720::TipoBien;
+
+/// Year-end position in a foreign security, with the data the Modelo D-6 asks for. The D-6 itself is filed
+/// through the AFORIX program of the Ministerio de Industria, Comercio y Turismo, so this is what to copy
+/// into it rather than a file to submit.
+#[derive(Debug, Clone, Serialize)]
+pub struct D6Position {
+    pub isin: String,
+    pub description: String,
+    /// Issuer of the security, as the identificación de la entidad of the 720.
+    pub issuer: String,
+    pub issuer_country: String,
+    pub deposit_country: String,
+    /// Clave de bien of the same asset in the 720, V or I.
+    pub clave: char,
+    pub shares: Decimal,
+    /// Valuation at the end of the year, in euros.
+    pub valuation: Decimal,
+    pub currency: String,
+    /// Valuation at the end of the year in `currency`.
+    pub native_valuation: Decimal,
+}
+
+/// Positions of `portfolio` to declare in the D-6: the securities and the shares of funds, leaving out
+/// accounts and every other kind of asset. `entity_countries` overrides the issuer country of some ISINs,
+/// as in the 720. Sorted by ISIN.
+pub fn d6_positions(
+    portfolio: &Portfolio,
+    entity_countries: &HashMap<String, String>,
+) -> Vec<D6Position> {
+    let mut positions: Vec<D6Position> = portfolio
+        .assets
+        .iter()
+        .filter(|x| {
+            matches!(
+                x.modelo_720_code(),
+                TipoBien::Valores(_) | TipoBien::AccionInstitucionInversionColectiva
+            )
+        })
+        .map(|asset| D6Position {
+            isin: asset.isin().to_string(),
+            description: asset.description().to_string(),
+            issuer: asset.entity_name().to_uppercase(),
+            issuer_country: entity_countries
+                .get(asset.isin())
+                .map(String::as_str)
+                .unwrap_or(asset.entity_country())
+                .to_uppercase(),
+            deposit_country: asset.country_of_deposit().to_string(),
+            clave: asset.modelo_720_code().clave(),
+            shares: asset.shares().0,
+            valuation: asset.valuation().round_dp(2),
+            currency: asset.currency().to_string(),
+            native_valuation: asset.native_valuation().round_dp(2),
+        })
+        .collect();
+    positions.sort_by(|a, b| a.isin.cmp(&b.isin));
+    positions
+}
+
+pub fn save_d6_csv(positions: &[D6Position], path: &Path) -> Result<()> {
+    let mut writer = csv::Writer::from_path(path).map_err(|e| Error::csv(path, e))?;
+    for position in positions {
+        writer
+            .serialize(position)
+            .map_err(|e| Error::csv(path, e))?;
+    }
+    writer.flush().map_err(|e| Error::io(path, e))
+}
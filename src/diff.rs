@@ -0,0 +1,97 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use rust_decimal::Decimal;
+
+use crate::modelo_720::{Modelo720, Registro2};
+
+/// Values of the registros sharing the same asset and origen, added up.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RegistroValues {
+    pub valoracion1: Decimal,
+    pub valoracion2: Decimal,
+    pub valores: Option<Decimal>,
+}
+
+impl Display for RegistroValues {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "valoración 1 {}, valoración 2 {}",
+            self.valoracion1, self.valoracion2
+        )?;
+        if let Some(valores) = self.valores {
+            write!(f, ", {} valores", valores)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum RegistroChange {
+    Added(String, RegistroValues),
+    Removed(String, RegistroValues),
+    Changed(String, RegistroValues, RegistroValues),
+}
+
+impl Display for RegistroChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegistroChange::Added(key, values) => write!(f, "+ {}: {}", key, values),
+            RegistroChange::Removed(key, values) => write!(f, "- {}: {}", key, values),
+            RegistroChange::Changed(key, old, new) => write!(f, "~ {}: {} -> {}", key, old, new),
+        }
+    }
+}
+
+/// The asset a registro declares, by its clave and identifier, falling back to the entity address for assets
+/// without an identifier such as real estate.
+pub fn asset_key(registro: &Registro2) -> String {
+    let asset = registro
+        .identifier()
+        .or(registro.nombre_via_publica_entidad.as_deref())
+        .or(registro.identificacion_entidad.as_deref())
+        .unwrap_or_default();
+    format!("{} {}", registro.tipo_bien.code(), asset.trim())
+}
+
+/// Registros are matched by the asset they declare and by their origen, since an asset can be both kept and
+/// sold.
+pub fn registro_key(registro: &Registro2) -> String {
+    format!(
+        "{} ({})",
+        asset_key(registro),
+        registro.origen_bien_derecho.code()
+    )
+}
+
+fn values_by_key(modelo720: &Modelo720) -> BTreeMap<String, RegistroValues> {
+    let mut values: BTreeMap<String, RegistroValues> = BTreeMap::new();
+    for registro in modelo720.entries.iter() {
+        let entry = values.entry(registro_key(registro)).or_default();
+        entry.valoracion1 += registro.valoracion1.rounded_to_cents().0;
+        entry.valoracion2 += registro.valoracion2.rounded_to_cents().0;
+        if let Some(valores) = registro.numero_valores {
+            entry.valores = Some(entry.valores.unwrap_or_default() + valores.0);
+        }
+    }
+    values
+}
+
+/// Compares the registros of two declarations, e.g. a draft against the file that was actually submitted.
+pub fn diff_modelo_720(old: &Modelo720, new: &Modelo720) -> Vec<RegistroChange> {
+    let old_values = values_by_key(old);
+    let mut new_values = values_by_key(new);
+    let mut changes = Vec::new();
+    for (key, old) in old_values {
+        match new_values.remove(&key) {
+            Some(new) if new != old => changes.push(RegistroChange::Changed(key, old, new)),
+            Some(_) => {}
+            None => changes.push(RegistroChange::Removed(key, old)),
+        }
+    }
+    for (key, new) in new_values {
+        changes.push(RegistroChange::Added(key, new));
+    }
+    changes
+}
@@ -0,0 +1,187 @@
+use serde::Deserialize;
+
+use crate::modelo_720::Modelo720;
+
+/// Address of a financial entity, used to fill the entity fields of the registros that brokers don't report.
+#[derive(Clone, Debug, Deserialize)]
+pub struct EntityAddress {
+    pub name: String,
+    /// Text found in the identificación de la entidad of the registros of this entity, e.g. a fund brand.
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    pub street: String,
+    pub complement: Option<String>,
+    pub city: String,
+    pub province: Option<String>,
+    pub zip_code: String,
+    pub country: String,
+    /// Tax identification number of the entity in its country of residence.
+    pub nif: Option<String>,
+}
+
+impl EntityAddress {
+    fn matches(&self, identificacion_entidad: &str) -> bool {
+        let identificacion_entidad = identificacion_entidad.to_uppercase();
+        std::iter::once(&self.name)
+            .chain(self.aliases.iter())
+            .any(|x| identificacion_entidad.contains(&x.to_uppercase()))
+    }
+}
+
+// Name, aliases, street, city, ZIP code and country.
+type BundledEntity = (
+    &'static str,
+    &'static [&'static str],
+    &'static str,
+    &'static str,
+    &'static str,
+    &'static str,
+);
+
+// The entities most commonly declared.
+const BUNDLED: &[BundledEntity] = &[
+    (
+        "INTERACTIVE BROKERS IRELAND LIMITED",
+        &["INTERACTIVE BROKERS", "IBKR"],
+        "10 EARLSFORT TERRACE",
+        "DUBLIN 2",
+        "D02 T380",
+        "IE",
+    ),
+    (
+        "MINTOS MARKETPLACE AS",
+        &["MINTOS"],
+        "SKANSTES IELA 50",
+        "RIGA",
+        "LV-1013",
+        "LV",
+    ),
+    (
+        "FLATEXDEGIRO BANK AG",
+        &["DEGIRO"],
+        "RHEINSTRASSE 105-107",
+        "FRANKFURT AM MAIN",
+        "60329",
+        "DE",
+    ),
+    (
+        "TRADE REPUBLIC BANK GMBH",
+        &["TRADE REPUBLIC"],
+        "BRUNNENSTRASSE 19-21",
+        "BERLIN",
+        "10119",
+        "DE",
+    ),
+    (
+        "BLACKROCK ASSET MANAGEMENT IRELAND LIMITED",
+        &["BLACKROCK", "ISHARES"],
+        "1ST FLOOR, 2 BALLSBRIDGE PARK, BALLSBRIDGE",
+        "DUBLIN 4",
+        "D04 YW83",
+        "IE",
+    ),
+    (
+        "VANGUARD GROUP (IRELAND) LIMITED",
+        &["VANGUARD"],
+        "70 SIR JOHN ROGERSON'S QUAY",
+        "DUBLIN 2",
+        "D02 R296",
+        "IE",
+    ),
+    (
+        "AMUNDI LUXEMBOURG S.A.",
+        &["AMUNDI", "LYXOR"],
+        "5 ALLEE SCHEFFER",
+        "LUXEMBOURG",
+        "L-2520",
+        "LU",
+    ),
+    (
+        "DWS INVESTMENT S.A.",
+        &["XTRACKERS"],
+        "2 BOULEVARD KONRAD ADENAUER",
+        "LUXEMBOURG",
+        "L-1115",
+        "LU",
+    ),
+    (
+        "REVOLUT BANK UAB",
+        &["REVOLUT"],
+        "KONSTITUCIJOS AVE. 21B",
+        "VILNIUS",
+        "08130",
+        "LT",
+    ),
+    (
+        "WISE EUROPE SA",
+        &["WISE EUROPE", "TRANSFERWISE"],
+        "RUE DU TRONE 100",
+        "BRUSSELS",
+        "1050",
+        "BE",
+    ),
+    (
+        "N26 BANK SE",
+        &["N26"],
+        "VOLTAIRESTRASSE 8",
+        "BERLIN",
+        "10179",
+        "DE",
+    ),
+];
+
+pub fn bundled_entities() -> Vec<EntityAddress> {
+    BUNDLED
+        .iter()
+        .map(
+            |(name, aliases, street, city, zip_code, country)| EntityAddress {
+                name: name.to_string(),
+                aliases: aliases.iter().map(|x| x.to_string()).collect(),
+                street: street.to_string(),
+                complement: None,
+                city: city.to_string(),
+                province: None,
+                zip_code: zip_code.to_string(),
+                country: country.to_string(),
+                nif: None,
+            },
+        )
+        .collect()
+}
+
+/// Fills the address and the NIF in the country of residence of the entity of the registros that have none,
+/// from the first of `entities` whose name or aliases appear in the identificación de la entidad. Entities in
+/// a different country than the one declared for the registro are skipped, as they are a different branch.
+pub fn fill_entity_addresses(modelo720: &mut Modelo720, entities: &[EntityAddress]) {
+    for entry in modelo720.entries.iter_mut() {
+        if entry.nombre_via_publica_entidad.is_some() && entry.nif_pais_residencia_fiscal.is_some()
+        {
+            continue;
+        }
+        let Some(identificacion_entidad) = entry.identificacion_entidad.as_deref() else {
+            continue;
+        };
+        let entity = entities.iter().find(|x| {
+            x.matches(identificacion_entidad)
+                && entry
+                    .codigo_pais_entidad
+                    .as_ref()
+                    .is_none_or(|country| *country == x.country)
+        });
+        let Some(entity) = entity else {
+            continue;
+        };
+        let uppercase = |x: &Option<String>| x.as_ref().map(|x| x.to_uppercase());
+        if entry.nif_pais_residencia_fiscal.is_none() {
+            entry.nif_pais_residencia_fiscal = uppercase(&entity.nif);
+        }
+        if entry.nombre_via_publica_entidad.is_none() {
+            entry.nombre_via_publica_entidad = Some(entity.street.to_uppercase());
+            entry.complemento_entidad = uppercase(&entity.complement);
+            entry.poblacion_entidad = Some(entity.city.to_uppercase());
+            entry.provincia_entidad = uppercase(&entity.province);
+            entry.codigo_postal_entidad = Some(entity.zip_code.to_uppercase());
+            entry.codigo_pais_entidad = Some(entity.country.to_uppercase());
+        }
+    }
+}
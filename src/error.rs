@@ -0,0 +1,120 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Everything that can go wrong reading the inputs or writing a declaration, with the file, line and field
+/// where it happened so it can be fixed.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// A file couldn't be read or written.
+    #[error("{}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    /// A row of a CSV file is malformed.
+    #[error("{}: line {line}: {message}", path.display())]
+    Csv {
+        path: PathBuf,
+        line: u64,
+        message: String,
+    },
+    /// A record of a fixed-width declaration doesn't follow the record design.
+    #[error("{}: line {line}, {field}: {message}", path.display())]
+    Record {
+        path: PathBuf,
+        line: usize,
+        field: String,
+        message: String,
+    },
+    /// A JSON plan couldn't be read or written.
+    #[error("{}: {source}", path.display())]
+    Json {
+        path: PathBuf,
+        #[source]
+        source: serde_json::Error,
+    },
+    /// A spreadsheet couldn't be written.
+    #[error("{}: {source}", path.display())]
+    Xlsx {
+        path: PathBuf,
+        #[source]
+        source: rust_xlsxwriter::XlsxError,
+    },
+    /// An input file is well formed but its contents aren't valid. The message starts with where.
+    #[error("{0}")]
+    InvalidInput(String),
+    /// Something needed couldn't be downloaded.
+    #[error("{url}: {message}")]
+    Download { url: String, message: String },
+    /// The arguments given can't be used together or miss something.
+    #[error("{0}")]
+    Usage(String),
+    /// A declaration doesn't follow the record design, as reported by `validate`.
+    #[error("{}: {count} problems found", path.display())]
+    Invalid { path: PathBuf, count: usize },
+}
+
+impl Error {
+    pub fn io(path: &Path, source: std::io::Error) -> Error {
+        Error::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    pub fn csv(path: &Path, error: csv::Error) -> Error {
+        let line = error.position().map(|x| x.line()).unwrap_or_default();
+        let message = match error.kind() {
+            // Already says which field, without repeating the position.
+            csv::ErrorKind::Deserialize { err, .. } => err.to_string(),
+            _ => error.to_string(),
+        };
+        Error::Csv {
+            path: path.to_path_buf(),
+            line,
+            message,
+        }
+    }
+
+    /// Exit code of the CLI for the error, following the BSD sysexits convention.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Invalid { .. } => 1,
+            Error::Usage(_) => 64,
+            Error::Csv { .. }
+            | Error::Record { .. }
+            | Error::Json { .. }
+            | Error::InvalidInput(_) => 65,
+            Error::Download { .. } => 69,
+            Error::Io { .. } | Error::Xlsx { .. } => 74,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Lenient reading: the records that can't be read are skipped and their errors collected, to be reported all
+/// together once everything else has been read. Clones share the same errors.
+#[derive(Clone, Debug, Default)]
+pub struct Lenient {
+    errors: Arc<Mutex<Vec<Error>>>,
+}
+
+impl Lenient {
+    /// The value of `result`, or `None` after collecting its error.
+    pub fn recover<T>(&self, result: Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.errors.lock().unwrap().push(e);
+                None
+            }
+        }
+    }
+
+    /// The errors collected so far, leaving none behind.
+    pub fn take_errors(&self) -> Vec<Error> {
+        std::mem::take(&mut self.errors.lock().unwrap())
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use rust_decimal::Decimal;
+
+use crate::assets::Portfolio;
+use crate::modelo_720::{TipoBien, TipoValor};
+
+/// Category of foreign assets of the annual summary of the ETE (Declaración de transacciones económicas
+/// y saldos de activos y pasivos financieros con el exterior) of the Banco de España.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EteCategoria {
+    Depositos,
+    Acciones,
+    FondosDeInversion,
+    RentaFija,
+    Seguros,
+    Inmuebles,
+    Otros,
+}
+
+impl EteCategoria {
+    pub fn of(tipo_bien: &TipoBien) -> EteCategoria {
+        match tipo_bien {
+            TipoBien::Cuenta(_) => EteCategoria::Depositos,
+            TipoBien::Valores(TipoValor::ParticipacionEnEntidadJuridica) => EteCategoria::Acciones,
+            TipoBien::Valores(TipoValor::CesionDeCapitalesATerceros) => EteCategoria::RentaFija,
+            TipoBien::Valores(TipoValor::AportadosParaGestion) => EteCategoria::Otros,
+            TipoBien::AccionInstitucionInversionColectiva => EteCategoria::FondosDeInversion,
+            TipoBien::Seguro(_) => EteCategoria::Seguros,
+            TipoBien::BienInmbueble(_) => EteCategoria::Inmuebles,
+        }
+    }
+}
+
+impl Display for EteCategoria {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EteCategoria::Depositos => write!(f, "Depósitos"),
+            EteCategoria::Acciones => write!(f, "Acciones y otras participaciones de capital"),
+            EteCategoria::FondosDeInversion => write!(f, "Participaciones en fondos de inversión"),
+            EteCategoria::RentaFija => write!(f, "Valores representativos de deuda"),
+            EteCategoria::Seguros => write!(f, "Seguros"),
+            EteCategoria::Inmuebles => write!(f, "Inmuebles"),
+            EteCategoria::Otros => write!(f, "Otros activos"),
+        }
+    }
+}
+
+/// How often the ETE must be filed, given by the largest of the balances of the year.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EteFrecuencia {
+    /// Below every threshold, only filed when the Banco de España asks for it.
+    SoloARequerimiento,
+    Anual,
+    Trimestral,
+    Mensual,
+}
+
+impl EteFrecuencia {
+    pub fn for_balance(balance: Decimal) -> EteFrecuencia {
+        if balance >= Decimal::new(300_000_000, 0) {
+            EteFrecuencia::Mensual
+        } else if balance >= Decimal::new(100_000_000, 0) {
+            EteFrecuencia::Trimestral
+        } else if balance >= Decimal::new(50_000_000, 0) {
+            EteFrecuencia::Anual
+        } else {
+            EteFrecuencia::SoloARequerimiento
+        }
+    }
+}
+
+impl Display for EteFrecuencia {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EteFrecuencia::SoloARequerimiento => write!(
+                f,
+                "below 50.000.000 €, only needs to be filed if the Banco de España asks for it"
+            ),
+            EteFrecuencia::Anual => write!(f, "must be filed yearly"),
+            EteFrecuencia::Trimestral => write!(f, "must be filed quarterly"),
+            EteFrecuencia::Mensual => write!(f, "must be filed monthly"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct EteSaldo {
+    /// Balance at the end of the previous year.
+    pub inicial: Decimal,
+    /// Balance at the end of the year.
+    pub final_: Decimal,
+}
+
+impl EteSaldo {
+    pub fn variacion(&self) -> Decimal {
+        self.final_ - self.inicial
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EteSummary {
+    pub saldos: BTreeMap<EteCategoria, EteSaldo>,
+    pub frecuencia: EteFrecuencia,
+}
+
+impl EteSummary {
+    pub fn total(&self) -> EteSaldo {
+        EteSaldo {
+            inicial: self.saldos.values().map(|x| x.inicial).sum(),
+            final_: self.saldos.values().map(|x| x.final_).sum(),
+        }
+    }
+}
+
+/// Aggregates the balances of `previous` and `current`, the portfolios at the end of the previous year
+/// and of the year, by ETE category. Statements only report balances and not every transaction, so the
+/// frequency is given by the balances alone and transactions above the thresholds have to be checked
+/// by hand.
+pub fn ete_summary(current: &Portfolio, previous: &Portfolio) -> EteSummary {
+    let mut saldos: BTreeMap<EteCategoria, EteSaldo> = BTreeMap::new();
+    for asset in previous.assets.iter() {
+        saldos
+            .entry(EteCategoria::of(&asset.modelo_720_code()))
+            .or_default()
+            .inicial += asset.valuation();
+    }
+    for asset in current.assets.iter() {
+        saldos
+            .entry(EteCategoria::of(&asset.modelo_720_code()))
+            .or_default()
+            .final_ += asset.valuation();
+    }
+    let inicial: Decimal = saldos.values().map(|x| x.inicial).sum();
+    let final_: Decimal = saldos.values().map(|x| x.final_).sum();
+    EteSummary {
+        saldos,
+        frecuencia: EteFrecuencia::for_balance(inicial.max(final_)),
+    }
+}
+
+impl Display for EteSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (categoria, saldo) in self.saldos.iter() {
+            writeln!(
+                f,
+                "{}: {} → {} ({:+})",
+                categoria,
+                saldo.inicial.round_dp(2),
+                saldo.final_.round_dp(2),
+                saldo.variacion().round_dp(2)
+            )?;
+        }
+        let total = self.total();
+        writeln!(
+            f,
+            "Total: {} → {} ({:+})",
+            total.inicial.round_dp(2),
+            total.final_.round_dp(2),
+            total.variacion().round_dp(2)
+        )?;
+        write!(f, "ETE {}", self.frecuencia)
+    }
+}
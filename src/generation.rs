@@ -0,0 +1,568 @@
+use std::{cmp::Ordering, collections::BTreeMap, fmt::Display, sync::Arc};
+
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+
+use crate::{
+    assets::{
+        asset_difference, consume_fifo, holding_key, latest_lots, AssetWithValuation, CryptoAsset,
+        Lot, Portfolio,
+    },
+    modelo_720::{Date, Modelo720, Nif, Origen, Registro2, Shares, Telefono, TipoBien},
+    modelo_721::Modelo721,
+};
+
+struct FullJoinIterator<I: Iterator> {
+    is_initialized: bool,
+    left: I,
+    last_left: Option<I::Item>,
+    right: I,
+    last_right: Option<I::Item>,
+}
+
+trait Keyed {
+    fn key(&self) -> (&str, &str, &str);
+}
+
+// Must match the order of the portfolio assets.
+impl<T: AssetWithValuation> Keyed for T {
+    fn key(&self) -> (&str, &str, &str) {
+        holding_key(self)
+    }
+}
+
+impl<T, I: Iterator<Item = T>> FullJoinIterator<I>
+where
+    T: Keyed + Clone,
+{
+    fn new(left: I, right: I) -> Self {
+        FullJoinIterator {
+            is_initialized: false,
+            left,
+            last_left: None,
+            right,
+            last_right: None,
+        }
+    }
+}
+
+enum JoinResult<I: Iterator> {
+    OuterLeft(I::Item),
+    Inner(I::Item, I::Item),
+    OuterRight(I::Item),
+}
+
+impl<T, I: Iterator<Item = T>> Iterator for FullJoinIterator<I>
+where
+    T: Keyed + Clone,
+{
+    type Item = JoinResult<I>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.is_initialized {
+            self.last_left = self.left.next();
+            self.last_right = self.right.next();
+            self.is_initialized = true;
+        }
+        match (self.last_left.clone(), self.last_right.clone()) {
+            (None, None) => None,
+            (None, Some(right)) => {
+                self.last_right = self.right.next();
+                Some(JoinResult::OuterRight(right))
+            }
+            (Some(left), None) => {
+                self.last_left = self.left.next();
+                Some(JoinResult::OuterLeft(left))
+            }
+            (Some(left), Some(right)) => {
+                if left.key() < right.key() {
+                    self.last_left = self.left.next();
+                    Some(JoinResult::OuterLeft(left))
+                } else if left.key() == right.key() {
+                    self.last_left = self.left.next();
+                    self.last_right = self.right.next();
+                    Some(JoinResult::Inner(left, right))
+                } else {
+                    self.last_right = self.right.next();
+                    Some(JoinResult::OuterRight(right))
+                }
+            }
+        }
+    }
+}
+
+/// How a holding changed between the previous and the current portfolio.
+pub enum PortfolioChange {
+    NewAcquisition(Arc<dyn AssetWithValuation>),
+    Changed(Arc<dyn AssetWithValuation>, Arc<dyn AssetWithValuation>),
+    Sold(Arc<dyn AssetWithValuation>),
+}
+
+impl PortfolioChange {
+    /// The asset as held at the end of the year, or the year before for what was sold.
+    fn asset(&self) -> &Arc<dyn AssetWithValuation> {
+        match self {
+            PortfolioChange::NewAcquisition(asset) | PortfolioChange::Sold(asset) => asset,
+            PortfolioChange::Changed(new_value, _) => new_value,
+        }
+    }
+}
+
+/// Matches the holdings of both portfolios by ISIN, country of deposit and depositary. Holdings whose
+/// depositary isn't known take the one of the other portfolio.
+pub fn portfolio_changes(current: &Portfolio, previous: &Portfolio) -> Vec<PortfolioChange> {
+    let current_assets = current.assets_with_depositaries_of(previous);
+    let previous_assets = previous.assets_with_depositaries_of(current);
+    let left = current_assets.iter();
+    let right = previous_assets.iter();
+    FullJoinIterator::new(left, right)
+        .map(|result| match result {
+            JoinResult::OuterLeft(left) => PortfolioChange::NewAcquisition(left.clone()),
+            JoinResult::Inner(left, right) => PortfolioChange::Changed(left.clone(), right.clone()),
+            JoinResult::OuterRight(right) => PortfolioChange::Sold(right.clone()),
+        })
+        .collect()
+}
+
+impl Display for PortfolioChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PortfolioChange::NewAcquisition(asset) => write!(
+                f,
+                "+ {} {}: acquired {} shares valued {}",
+                asset.isin(),
+                asset.description(),
+                asset.shares().0,
+                asset.valuation()
+            ),
+            PortfolioChange::Sold(asset) => write!(
+                f,
+                "- {} {}: sold {} shares valued {}",
+                asset.isin(),
+                asset.description(),
+                asset.shares().0,
+                asset.valuation()
+            ),
+            PortfolioChange::Changed(new_value, old_value) => {
+                let diff = asset_difference(new_value.as_ref(), old_value.as_ref());
+                let action = match diff.shares.0.cmp(&Decimal::ZERO) {
+                    Ordering::Greater => "bought more",
+                    Ordering::Less => "sold part",
+                    Ordering::Equal => "revalued",
+                };
+                write!(
+                    f,
+                    "~ {} {}: {}, shares {} -> {} ({:+}), value {} -> {} ({:+})",
+                    new_value.isin(),
+                    new_value.description(),
+                    action,
+                    old_value.shares().0,
+                    new_value.shares().0,
+                    diff.shares.0,
+                    old_value.valuation(),
+                    new_value.valuation(),
+                    diff.valuation
+                )
+            }
+        }
+    }
+}
+
+/// Computes the registros of the 720 of `ejercicio` from the holdings at the end of it and of the year
+/// before: acquisitions (A), revaluations of what was kept (M) and sales (C).
+pub fn compute_modelo720(
+    ejercicio: i16,
+    nif: &Nif,
+    name: &str,
+    phone: Telefono,
+    current: &Portfolio,
+    previous: &Portfolio,
+) -> Modelo720 {
+    let entries = portfolio_changes(current, previous)
+        .into_iter()
+        .flat_map(|change| match change {
+            change if matches!(change.asset().modelo_720_code(), TipoBien::Cuenta(_)) => {
+                account_registros(change, ejercicio, nif, name)
+            }
+            PortfolioChange::NewAcquisition(acquisition) => {
+                let mut registro = acquisition.modelo_720_registro(ejercicio, nif, name);
+                registro.origen_bien_derecho = Origen::Adquisicion;
+                registro.numero_valores =
+                    acquisition.declares_shares().then(|| acquisition.shares());
+                registro.valoracion1 = acquisition.valuation().into();
+                registro.valoracion2 = acquisition.second_valuation().unwrap_or_default().into();
+                let purchases = current.lots_of(acquisition.as_ref());
+                if purchases.is_empty() {
+                    vec![registro]
+                } else {
+                    acquisition_registros(
+                        registro,
+                        acquisition.declares_shares(),
+                        purchases,
+                        acquisition.shares().0,
+                        acquisition.price_per_share(),
+                    )
+                }
+            }
+            PortfolioChange::Changed(new_value, old_value) => {
+                let diff = asset_difference(new_value.as_ref(), old_value.as_ref());
+
+                let current_price_per_share = new_value.price_per_share();
+                if diff.shares.0 > Decimal::ZERO {
+                    // If we have more shares then we modify the value of what we have and add a new entry for the acquisition.
+                    let mut previous_registro = old_value.modelo_720_registro(ejercicio, nif, name);
+                    previous_registro.origen_bien_derecho = Origen::Modificacion;
+                    previous_registro.numero_valores =
+                        old_value.declares_shares().then(|| old_value.shares());
+                    previous_registro.valoracion1 =
+                        (old_value.shares().0 * current_price_per_share).into();
+                    previous_registro.valoracion2 =
+                        new_value.second_valuation().unwrap_or_default().into();
+
+                    let mut new_registro = new_value.modelo_720_registro(ejercicio, nif, name);
+                    new_registro.origen_bien_derecho = Origen::Adquisicion;
+                    new_registro.numero_valores =
+                        new_value.declares_shares().then_some(diff.shares);
+                    new_registro.valoracion1 = (diff.shares.0 * current_price_per_share).into();
+                    // The second valuation covers the whole holding and is already declared on the previous registro.
+                    new_registro.valoracion2 = Decimal::ZERO.into();
+
+                    let mut result = vec![previous_registro];
+                    result.extend(acquisition_registros(
+                        new_registro,
+                        new_value.declares_shares(),
+                        current.lots_of(new_value.as_ref()),
+                        diff.shares.0,
+                        current_price_per_share,
+                    ));
+                    result
+                } else if diff.shares.0 == Decimal::ZERO {
+                    // If instead there are no new shares then we just revalue what we have.
+                    let mut current_registro = new_value.modelo_720_registro(ejercicio, nif, name);
+                    current_registro.origen_bien_derecho = Origen::Modificacion;
+                    current_registro.numero_valores =
+                        new_value.declares_shares().then(|| new_value.shares());
+                    current_registro.valoracion1 =
+                        (new_value.shares().0 * current_price_per_share).into();
+                    current_registro.valoracion2 =
+                        new_value.second_valuation().unwrap_or_default().into();
+                    vec![current_registro]
+                } else {
+                    // If we have less shares then we revalue what remains and then add an entry for the sale. Total sales are already handled in registro2Sold.
+                    let mut current_registro = new_value.modelo_720_registro(ejercicio, nif, name);
+                    current_registro.origen_bien_derecho = Origen::Modificacion;
+                    current_registro.numero_valores =
+                        new_value.declares_shares().then(|| new_value.shares());
+                    current_registro.valoracion1 =
+                        (new_value.shares().0 * current_price_per_share).into();
+                    current_registro.valoracion2 =
+                        new_value.second_valuation().unwrap_or_default().into();
+
+                    let mut sale_registro = current_registro.clone();
+                    sale_registro.origen_bien_derecho = Origen::Extincion;
+                    sale_registro.valoracion2 = Decimal::ZERO.into();
+                    let mut result = vec![current_registro];
+                    result.extend(extinction_registros(
+                        sale_registro,
+                        new_value.declares_shares(),
+                        previous.lots_of(old_value.as_ref()),
+                        diff.shares.0.abs(),
+                        current_price_per_share,
+                    ));
+                    result
+                }
+            }
+            PortfolioChange::Sold(old_value) => {
+                let mut registro = old_value.modelo_720_registro(ejercicio, nif, name);
+                registro.origen_bien_derecho = Origen::Extincion;
+                registro.numero_valores = old_value.declares_shares().then(|| old_value.shares());
+                registro.valoracion1 = old_value.valuation().into();
+                registro.valoracion2 = old_value.second_valuation().unwrap_or_default().into();
+                let lots = previous.lots_of(old_value.as_ref());
+                if lots.is_empty() {
+                    vec![registro]
+                } else {
+                    extinction_registros(
+                        registro,
+                        old_value.declares_shares(),
+                        lots,
+                        old_value.shares().0,
+                        old_value.price_per_share(),
+                    )
+                }
+            }
+        })
+        .collect();
+    let mut result = Modelo720::new(ejercicio, nif, name, phone, entries);
+    // Extinctions of what was sold in full are only in the previous statement.
+    result.sources = result
+        .entries
+        .iter()
+        .filter_map(|x| x.identifier())
+        .filter_map(|x| {
+            let identifier = x.trim();
+            let sources = match current.sources_of(identifier) {
+                [] => previous.sources_of(identifier),
+                sources => sources,
+            };
+            (!sources.is_empty()).then(|| (identifier.to_string(), sources.to_vec()))
+        })
+        .collect();
+    result
+}
+
+/// Registros of an account, which is never partly acquired or sold like securities: it is opened during the
+/// year (A), kept (M) or cancelled (C). Cancelled accounts are declared with their closing date and their
+/// balance on it, even when they were opened during the year too. Accounts missing from the current statement
+/// are taken as cancelled with the last balance known, and left without a closing date for `--sales` or an
+/// override to give it.
+fn account_registros(
+    change: PortfolioChange,
+    ejercicio: i16,
+    nif: &Nif,
+    name: &str,
+) -> Vec<Registro2> {
+    let (asset, origen) = match change {
+        PortfolioChange::NewAcquisition(asset) => (asset, Origen::Adquisicion),
+        PortfolioChange::Changed(asset, _) => (asset, Origen::Modificacion),
+        PortfolioChange::Sold(asset) => (asset, Origen::Extincion),
+    };
+    let mut registro = asset.modelo_720_registro(ejercicio, nif, name);
+    let closing_date = asset.closing_date();
+    registro.origen_bien_derecho = match closing_date {
+        Some(_) => Origen::Extincion,
+        None => origen,
+    };
+    registro.fecha_extincion = Date(closing_date);
+    registro.numero_valores = None;
+    registro.valoracion1 = asset.valuation().into();
+    // The average balance of the last quarter is only declared for the accounts still open.
+    registro.valoracion2 = match registro.origen_bien_derecho {
+        Origen::Extincion => Decimal::ZERO,
+        _ => asset.second_valuation().unwrap_or_default(),
+    }
+    .into();
+    vec![registro]
+}
+
+/// Extinction registros for selling `shares` of an asset. With acquisition lots there is one per lot consumed
+/// first in first out, dated on its acquisition, otherwise a single one for the whole sale.
+fn extinction_registros(
+    registro: Registro2,
+    declares_shares: bool,
+    lots: &[Lot],
+    shares: Decimal,
+    price_per_share: Decimal,
+) -> Vec<Registro2> {
+    let consumed = consume_fifo(lots, shares);
+    registros_per_lot(registro, declares_shares, consumed, shares, price_per_share)
+}
+
+/// Acquisition registros for buying `shares` of an asset during the year. With the purchases of the year there
+/// is one per purchase still held, dated on its trade date, otherwise a single one for the whole acquisition.
+/// The second valuation covers the whole holding, so it's only kept on the first registro.
+fn acquisition_registros(
+    registro: Registro2,
+    declares_shares: bool,
+    purchases: &[Lot],
+    shares: Decimal,
+    price_per_share: Decimal,
+) -> Vec<Registro2> {
+    let held = latest_lots(purchases, shares);
+    let mut result = registros_per_lot(registro, declares_shares, held, shares, price_per_share);
+    for registro in result.iter_mut().skip(1) {
+        registro.valoracion2 = Decimal::ZERO.into();
+    }
+    result
+}
+
+/// Splits the registro of `shares` of an asset into one per lot, dated on it and valued at `price_per_share`.
+fn registros_per_lot(
+    registro: Registro2,
+    declares_shares: bool,
+    lots: Vec<Lot>,
+    shares: Decimal,
+    price_per_share: Decimal,
+) -> Vec<Registro2> {
+    let remaining = shares - lots.iter().map(|x| x.shares).sum::<Decimal>();
+    let mut parts: Vec<(Decimal, Option<NaiveDate>)> =
+        lots.into_iter().map(|x| (x.shares, x.date)).collect();
+    // Shares not covered by the lots given are still declared, just without a known acquisition date.
+    if remaining > Decimal::ZERO {
+        parts.push((remaining, None));
+    }
+    parts
+        .into_iter()
+        .map(|(shares, date)| {
+            let mut result = registro.clone();
+            result.numero_valores = declares_shares.then_some(Shares(shares));
+            result.valoracion1 = (shares * price_per_share).into();
+            if date.is_some() {
+                result.fecha_incorporacion = Date(date);
+            }
+            result
+        })
+        .collect()
+}
+
+/// Computes the registros of the 721 of `ejercicio` from the virtual currencies held at the end of it and
+/// of the year before.
+pub fn compute_modelo721(
+    ejercicio: i16,
+    nif: &Nif,
+    name: &str,
+    phone: Telefono,
+    current: &[CryptoAsset],
+    previous: &[CryptoAsset],
+) -> Modelo721 {
+    let mut holdings: BTreeMap<_, (Option<&CryptoAsset>, Option<&CryptoAsset>)> = BTreeMap::new();
+    for holding in current {
+        holdings.entry(holding.key()).or_default().0 = Some(holding);
+    }
+    for holding in previous {
+        holdings.entry(holding.key()).or_default().1 = Some(holding);
+    }
+    let entries = holdings
+        .into_values()
+        .filter_map(|pair| match pair {
+            (Some(current), None) => {
+                let mut registro = current.modelo_721_registro(ejercicio, nif, name);
+                registro.origen_bien_derecho = Origen::Adquisicion;
+                Some(registro)
+            }
+            (Some(current), Some(_)) => {
+                let mut registro = current.modelo_721_registro(ejercicio, nif, name);
+                registro.origen_bien_derecho = Origen::Modificacion;
+                Some(registro)
+            }
+            (None, Some(previous)) => {
+                let mut registro = previous.modelo_721_registro(ejercicio, nif, name);
+                registro.origen_bien_derecho = Origen::Extincion;
+                Some(registro)
+            }
+            (None, None) => None,
+        })
+        .collect();
+    Modelo721::new(ejercicio, nif, name, phone, entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assets::{BankAccount, Etf, ValuationPolicy};
+    use crate::modelo_720::{IdentificacionCuenta, TipoCuenta};
+
+    fn fund(isin: &str, position_value: Decimal, shares: Decimal) -> Arc<dyn AssetWithValuation> {
+        Arc::new(Etf {
+            isin: isin.to_string(),
+            position_value,
+            shares,
+            deposit_country: "IE".to_string(),
+            currency: "EUR".to_string(),
+            description: "FUND".to_string(),
+            entity_name: None,
+            depositary: None,
+        })
+    }
+
+    // A margin account, overdrawn both at year end and on average.
+    fn margin_account() -> Arc<dyn AssetWithValuation> {
+        Arc::new(BankAccount {
+            tipo_cuenta: TipoCuenta::Credito,
+            identificacion_cuenta: IdentificacionCuenta::Otra,
+            bic: "IBKRUS33".to_string(),
+            account_code: "U1234567".to_string(),
+            entity_name: "INTERACTIVE BROKERS".to_string(),
+            entity_nif: None,
+            country: "US".to_string(),
+            opening_date: None,
+            closing_date: None,
+            year_end_balance: Decimal::new(-25050, 2),
+            q4_average_balance: Decimal::new(-100, 0),
+            currency: "EUR".to_string(),
+        })
+    }
+
+    fn declaration(policy: ValuationPolicy) -> crate::error::Result<Modelo720> {
+        let assets = vec![
+            fund("IE00B4L5Y983", Decimal::new(1000, 0), Decimal::new(10, 0)),
+            // A position closed during the year, left with neither shares nor value.
+            fund("IE00B3RBWM25", Decimal::ZERO, Decimal::ZERO),
+            margin_account(),
+        ];
+        let current = Portfolio::from_assets(assets, policy)?;
+        Ok(compute_modelo720(
+            2024,
+            &Nif::new("12345678Z").unwrap(),
+            "DOE JOHN",
+            Telefono::new("600000000").unwrap(),
+            &current,
+            &Portfolio::default(),
+        ))
+    }
+
+    fn header_sums(modelo720: &Modelo720) -> (Decimal, Decimal) {
+        (
+            modelo720.header.suma_valoracion1.0,
+            modelo720.header.suma_valoracion2.0,
+        )
+    }
+
+    #[test]
+    fn signed_policy_sums_negative_valuations() {
+        let mut modelo720 = declaration(ValuationPolicy::Signed).unwrap();
+        assert_eq!(modelo720.header.numero_registros_tipo2, 3);
+        assert_eq!(
+            header_sums(&modelo720),
+            (Decimal::new(74950, 2), Decimal::new(-100, 0))
+        );
+
+        modelo720.recompute_totals();
+        assert_eq!(
+            header_sums(&modelo720),
+            (Decimal::new(74950, 2), Decimal::new(-100, 0))
+        );
+        let bytes = modelo720.to_bytes().unwrap();
+        assert_eq!(&bytes[144..162], b" 00000000000074950");
+        assert_eq!(&bytes[162..180], b"N00000000000010000");
+    }
+
+    #[test]
+    fn drop_zero_policy_leaves_out_zero_valuations() {
+        let modelo720 = declaration(ValuationPolicy::DropZero).unwrap();
+        assert_eq!(modelo720.header.numero_registros_tipo2, 2);
+        assert_eq!(
+            header_sums(&modelo720),
+            (Decimal::new(74950, 2), Decimal::new(-100, 0))
+        );
+    }
+
+    #[test]
+    fn clamp_policy_sums_negative_valuations_as_zero() {
+        let modelo720 = declaration(ValuationPolicy::Clamp).unwrap();
+        assert_eq!(modelo720.header.numero_registros_tipo2, 3);
+        assert_eq!(
+            header_sums(&modelo720),
+            (Decimal::new(1000, 0), Decimal::ZERO)
+        );
+    }
+
+    #[test]
+    fn strict_policy_rejects_negative_valuations() {
+        assert!(declaration(ValuationPolicy::Strict).is_err());
+    }
+
+    #[test]
+    fn registros_with_negative_valuations_sum_with_their_sign() {
+        let mut modelo720 = declaration(ValuationPolicy::Signed).unwrap();
+        modelo720.entries[0].valoracion1.0 = Decimal::new(-123456, 3);
+        modelo720.recompute_totals();
+        // Each valoración is rounded to cents before being added, as it is written.
+        let expected = modelo720.entries[1..]
+            .iter()
+            .map(|x| x.valoracion1.0)
+            .sum::<Decimal>()
+            - Decimal::new(12346, 2);
+        assert_eq!(modelo720.header.suma_valoracion1.0, expected);
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::ops::Range;
+use std::path::Path;
+
+use rust_decimal::Decimal;
+
+use crate::error::{Error, Result};
+use crate::layout::RecordDesign;
+use crate::modelo_720::decode_latin1;
+use crate::provenance::{load_sources, record_identifier};
+use crate::validation::parse_amount;
+
+#[derive(Default)]
+pub(crate) struct Subtotal {
+    pub(crate) registros: usize,
+    pub(crate) valoracion1: Decimal,
+    pub(crate) valoracion2: Decimal,
+}
+
+pub(crate) fn field(chars: &[char], range: &Range<usize>) -> String {
+    chars
+        .get(range.clone())
+        .map(|x| x.iter().collect())
+        .unwrap_or_default()
+}
+
+fn render_record(
+    output: &mut String,
+    title: &str,
+    chars: &[char],
+    fields: &[(&str, Range<usize>)],
+) -> std::fmt::Result {
+    writeln!(output, "{}", title)?;
+    for (name, range) in fields {
+        let value = field(chars, range);
+        if !value.trim().is_empty() {
+            writeln!(
+                output,
+                "  {:<66} {:>3}-{:<3} {}",
+                name,
+                range.start + 1,
+                range.end,
+                value.trim_end()
+            )?;
+        }
+    }
+    writeln!(output)
+}
+
+/// Appends the number of registros and valuations of each clave, and the total of the file.
+pub(crate) fn write_subtotals(
+    output: &mut String,
+    subtotals: &BTreeMap<String, Subtotal>,
+) -> std::fmt::Result {
+    let mut total = Subtotal::default();
+    for (clave, subtotal) in subtotals.iter() {
+        writeln!(
+            output,
+            "Clave {}: {} registros, valoración 1 {}, valoración 2 {}",
+            clave, subtotal.registros, subtotal.valoracion1, subtotal.valoracion2
+        )?;
+        total.registros += subtotal.registros;
+        total.valoracion1 += subtotal.valoracion1;
+        total.valoracion2 += subtotal.valoracion2;
+    }
+    writeln!(
+        output,
+        "Total: {} registros, valoración 1 {}, valoración 2 {}",
+        total.registros, total.valoracion1, total.valoracion2
+    )
+}
+
+/// Renders a 720 file field by field with the names of the record design, followed by the totals
+/// and subtotals of each clave. Blank fields are left out. Registros are titled with where they were read
+/// from when the file has its sources next to it.
+pub fn inspect_modelo_720_file(path: &Path) -> Result<String> {
+    let contents = decode_latin1(&std::fs::read(path).map_err(|e| Error::io(path, e))?);
+    let mut output = String::new();
+    let mut subtotals: BTreeMap<String, Subtotal> = BTreeMap::new();
+    let design = RecordDesign::of_contents(&contents);
+    let sources = load_sources(path)?;
+    for (index, line) in contents.lines().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        if index == 0 {
+            render_record(
+                &mut output,
+                "Registro de tipo 1",
+                &chars,
+                design.registro1_fields(),
+            )
+            .unwrap();
+            continue;
+        }
+        let mut title = format!("Registro de tipo 2 número {}", index);
+        if let Some(sources) = sources.get(&record_identifier(&chars)) {
+            let sources: Vec<String> = sources.iter().map(|x| x.to_string()).collect();
+            write!(title, " ({})", sources.join("; ")).unwrap();
+        }
+        render_record(&mut output, &title, &chars, design.registro2_fields()).unwrap();
+        let subtotal = subtotals.entry(field(&chars, &(101..102))).or_default();
+        subtotal.registros += 1;
+        subtotal.valoracion1 += parse_amount(&field(&chars, &(431..446))).unwrap_or_default();
+        subtotal.valoracion2 += parse_amount(&field(&chars, &(446..461))).unwrap_or_default();
+    }
+
+    write_subtotals(&mut output, &subtotals).unwrap();
+    Ok(output)
+}
@@ -0,0 +1,132 @@
+use std::ops::Range;
+
+use crate::error::{Error, Result};
+
+/// Record designs of the Modelo 720 published by the AEAT. Each declaration is written and read with the
+/// design in force for its ejercicio, so when the AEAT revises it the new design gets a variant with its own
+/// fields, and declarations of earlier ejercicios keep their layout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordDesign {
+    /// Orden HAP/72/2013, in force since the first ejercicio of the 720, 2012.
+    Orden72_2013,
+}
+
+/// Name and position of every field of a record, as in the record design.
+pub type Fields = &'static [(&'static str, Range<usize>)];
+
+impl RecordDesign {
+    pub const LATEST: RecordDesign = RecordDesign::Orden72_2013;
+
+    /// The design in force for `ejercicio`, or `None` for the years before the 720 existed.
+    pub fn for_ejercicio(ejercicio: i16) -> Option<RecordDesign> {
+        match ejercicio {
+            2012.. => Some(RecordDesign::Orden72_2013),
+            _ => None,
+        }
+    }
+
+    /// Like [`RecordDesign::for_ejercicio`], failing for the years without a design.
+    pub fn required_for(ejercicio: i16) -> Result<RecordDesign> {
+        RecordDesign::for_ejercicio(ejercicio).ok_or_else(|| {
+            Error::InvalidInput(format!(
+                "there is no record design of the 720 for ejercicio {}, the first one was 2012",
+                ejercicio
+            ))
+        })
+    }
+
+    /// The design of a 720 file going by the ejercicio of its header, or the latest one when it can't be
+    /// read, so broken files can still be looked at field by field.
+    pub fn of_contents(contents: &str) -> RecordDesign {
+        contents
+            .get(4..8)
+            .and_then(|x| x.parse().ok())
+            .and_then(RecordDesign::for_ejercicio)
+            .unwrap_or(RecordDesign::LATEST)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RecordDesign::Orden72_2013 => "Orden HAP/72/2013",
+        }
+    }
+
+    pub fn registro1_fields(&self) -> Fields {
+        match self {
+            RecordDesign::Orden72_2013 => ORDEN_72_2013_REGISTRO1,
+        }
+    }
+
+    pub fn registro2_fields(&self) -> Fields {
+        match self {
+            RecordDesign::Orden72_2013 => ORDEN_72_2013_REGISTRO2,
+        }
+    }
+}
+
+// Same names as in the fixed_width attributes of Registro1 and Registro2, which follow this design.
+const ORDEN_72_2013_REGISTRO1: &[(&str, Range<usize>)] = &[
+    ("TIPO DE REGISTRO", 0..1),
+    ("MODELO DECLARACIÓN", 1..4),
+    ("EJERCICIO", 4..8),
+    ("NIF DEL DECLARANTE", 8..17),
+    (
+        "APELLIDOS Y NOMBRE, RAZÓN SOCIAL O DENOMINACIÓN DEL DECLARANTE",
+        17..57,
+    ),
+    ("TIPO DE SOPORTE", 57..58),
+    ("TELEFONO PERSONA CONTACTO", 58..67),
+    ("APELLIDOS Y NOMBRE PERSONA CONTACTO", 67..107),
+    ("NÚMERO IDENTIFICATIVO DE LA DECLARACIÓN", 107..120),
+    ("DECLARACIÓN COMPLEMENTARIA", 120..121),
+    ("DECLARACIÓN SUSTITUTIVA", 121..122),
+    ("NÚMERO IDENTIFICATIVO DE LA DECLARACIÓN ANTERIOR", 122..135),
+    ("NÚMERO TOTAL DE REGISTROS DECLARADOS", 135..144),
+    ("SUMA TOTAL DE VALORACIÓN 1", 144..162),
+    ("SUMA TOTAL DE VALORACIÓN 2", 162..180),
+];
+
+const ORDEN_72_2013_REGISTRO2: &[(&str, Range<usize>)] = &[
+    ("TIPO DE REGISTRO", 0..1),
+    ("MODELO DECLARACIÓN", 1..4),
+    ("EJERCICIO", 4..8),
+    ("NIF DEL DECLARANTE", 8..17),
+    ("NIF DEL DECLARADO", 17..26),
+    ("N.I.F. DEL REPRESENTANTE LEGAL", 26..35),
+    (
+        "APELLIDOS Y NOMBRE, RAZÓN SOCIAL O DENOMINACIÓN DEL DECLARADO",
+        35..75,
+    ),
+    (
+        "CLAVE DE CONDICIÓN DEL DECLARANTE Y TIPO DE TITULARIDAD",
+        75..101,
+    ),
+    ("CLAVE Y SUBCLAVE TIPO DE BIEN O DERECHO", 101..103),
+    ("TIPO DE DERECHO REAL SOBRE INMUEBLE", 103..128),
+    ("CÓDIGO DE PAÍS", 128..130),
+    ("CLAVE DE IDENTIFICACIÓN", 130..131),
+    ("IDENTIFICACIÓN DE VALORES", 131..143),
+    ("CLAVE IDENTIFICACIÓN DE CUENTA", 143..144),
+    ("CÓDIGO BIC", 144..155),
+    ("CÓDIGO DE CUENTA", 155..189),
+    ("IDENTIFICACIÓN DE LA ENTIDAD", 189..230),
+    (
+        "NÚMERO DE IDENTIFICACIÓN FISCAL EN EL PAÍS DE RESIDENCIA FISCAL",
+        230..250,
+    ),
+    ("NOMBRE VÍA PUBLICA Y NÚMERO DE CASA", 250..302),
+    ("COMPLEMENTO", 302..342),
+    ("POBLACIÓN/CIUDAD", 342..372),
+    ("PROVINCIA/REGIÓN/ESTADO", 372..402),
+    ("CÓDIGO POSTAL (ZIP CODE)", 402..412),
+    ("CÓDIGO PAÍS", 412..414),
+    ("FECHA DE INCORPORACIÓN", 414..422),
+    ("ORIGEN DEL BIEN O DERECHO", 422..423),
+    ("FECHA DE EXTINCIÓN", 423..431),
+    ("VALORACIÓN 1", 431..446),
+    ("VALORACIÓN 2", 446..461),
+    ("CLAVE DE REPRESENTACIÓN DE VALORES", 461..462),
+    ("NÚMERO DE VALORES", 462..474),
+    ("CLAVE TIPO DE BIEN INMUEBLE", 474..475),
+    ("PORCENTAJE DE PARTICIPACIÓN", 475..480),
+];
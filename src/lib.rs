@@ -1,3 +1,62 @@
+//! Generation of the Modelo 720 and 721 informative declarations from broker statements.
+//!
+//! The binary is a thin layer over this library, which can be embedded in other tooling:
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! use modelo_720_rust::{
+//!     compute_modelo720, parse_ibkr_statement, Nif, ParseOptions, Portfolio, Telefono,
+//! };
+//!
+//! # fn main() -> modelo_720_rust::error::Result<()> {
+//! let options = ParseOptions::default();
+//! let previous = Portfolio::default();
+//! let current = parse_ibkr_statement(Path::new("ibkr-2024.csv"), &options)?;
+//! let nif = Nif::new("12345678Z").unwrap();
+//! let phone = Telefono::new("+34 600 000 000").unwrap();
+//! let modelo720 = compute_modelo720(2024, &nif, "DOE JOHN", phone, &current, &previous);
+//! modelo720.save_to_file(Path::new("declaration.720"))?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Statements in other currencies have to be converted with [`Portfolio::in_euros`] before computing
+//! the declaration.
+
+pub mod aeat;
+pub mod anonymize;
 pub mod assets;
+pub mod classification;
+pub mod countries;
+pub mod currency;
+pub mod d6;
+pub mod diff;
+pub mod entities;
+pub mod error;
+pub mod ete;
+pub mod generation;
+pub mod inspect;
+pub mod layout;
+pub mod modelo_714;
 pub mod modelo_720;
+pub mod modelo_721;
+pub mod obligation;
 pub mod parsers;
+pub mod pricing;
+pub mod provenance;
+pub mod report;
+pub mod state;
+pub mod summary;
+pub mod validation;
+pub mod xlsx;
+
+pub use assets::{AssetWithValuation, CryptoAsset, Portfolio};
+pub use error::{Error, Result};
+pub use generation::{compute_modelo720, compute_modelo721, portfolio_changes, PortfolioChange};
+pub use modelo_720::{Modelo720, Modelo720Reader, Modelo720Writer, Nif, Registro2, Telefono};
+pub use modelo_721::Modelo721;
+pub use parsers::{
+    parse_crypto_holdings, parse_ibkr_statement, parse_manual_assets, parse_mintos_statement,
+    ParseOptions, SupportedBrokers,
+};
@@ -1,196 +1,278 @@
 use std::{
+    collections::{btree_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
     path::{Path, PathBuf},
-    rc::Rc,
 };
 
-use clap::{command, Parser, Subcommand};
+use chrono::Datelike;
+use clap::{Parser, Subcommand};
 use modelo_720_rust::{
-    assets::{asset_difference, AssetWithValuation, Portfolio},
-    modelo_720::{Modelo720, Origen, Shares},
-    parsers::{parse_ibkr_statement, parse_mintos_statement, SupportedBrokers},
+    aeat::compare_aeat_response,
+    anonymize::anonymize_modelo_720,
+    assets::{Portfolio, StockSplit, ValuationPolicy},
+    classification::Classification,
+    currency::{ExchangeRates, Rounding},
+    d6::{d6_positions, save_d6_csv},
+    diff::{diff_modelo_720, registro_key},
+    entities::{bundled_entities, fill_entity_addresses},
+    error::{self, Error, Lenient},
+    ete::ete_summary,
+    generation::{compute_modelo720, compute_modelo721, portfolio_changes, PortfolioChange},
+    inspect::inspect_modelo_720_file,
+    layout::RecordDesign,
+    modelo_714::{modelo_714_registros, save_modelo_714_csv},
+    modelo_720::{
+        is_modelo_720_file, Dedupe, Modelo720, Modelo720Reader, Modelo720Writer, Nif, Origen,
+        SortOrder, Telefono, TipoSoporte, Titularidad,
+    },
+    obligation::{evaluate_obligation, retain_obligated, retain_required},
+    parsers::{
+        parse_acquisition_dates, parse_asset_overrides, parse_classification,
+        parse_country_mapping, parse_crypto_holdings, parse_entities, parse_ibkr_statement,
+        parse_ibkr_trades, parse_isin_changes, parse_lots, parse_manual_assets,
+        parse_mintos_statement, parse_profile, parse_sales, parse_splits, ParseOptions, Profile,
+        SupportedBrokers,
+    },
+    pricing::{value_at_year_end, PriceFile, PriceSource},
+    report::report_modelo_720_file,
+    state::{State, DEFAULT_STATE_PATH},
+    summary::{totals_by_clave, Summary},
+    validation::{
+        duplicate_declarant_warnings, origin_issues, shares_precision_warnings,
+        undated_extinction_warnings, validate_modelo_720, validate_modelo_720_file,
+        valuation_warnings, ValidationIssue, ValidationReport,
+    },
+    xlsx::save_modelo_720_as_xlsx,
 };
+use rand::{rngs::StdRng, SeedableRng};
+use rayon::prelude::*;
 use rust_decimal::Decimal;
 
-struct FullJoinIterator<I: Iterator> {
-    is_initialized: bool,
-    left: I,
-    last_left: Option<I::Item>,
-    right: I,
-    last_right: Option<I::Item>,
-}
+#[derive(Debug, Clone, Subcommand)]
+enum Commands {
+    /// Merges the registros of several 720 files of the same declarante and ejercicio into one.
+    Concat {
+        #[arg(short, long = "input", required = true, num_args = 1..)]
+        inputs: Vec<PathBuf>,
 
-trait Keyed {
-    fn key<'a>(&'a self) -> &'a [u8];
-}
+        /// Keep the registros of the first or last file when several hold the same asset and origen, instead
+        /// of failing
+        #[arg(long, value_enum)]
+        dedupe: Option<Dedupe>,
 
-impl<T: AssetWithValuation> Keyed for T {
-    fn key<'a>(&'a self) -> &'a [u8] {
-        self.isin().as_bytes()
-    }
-}
+        /// Merge files of different ejercicios or declarantes, keeping the header of the first one
+        #[arg(long)]
+        force: bool,
 
-impl<T, I: Iterator<Item = T>> FullJoinIterator<I>
-where
-    T: Keyed + Clone,
-{
-    fn new(left: I, right: I) -> Self {
-        FullJoinIterator {
-            is_initialized: false,
-            left,
-            last_left: None,
-            right,
-            last_right: None,
-        }
-    }
-}
+        #[arg(short, long)]
+        out: PathBuf,
+    },
+    /// Splits a 720 file into one file per clave de bien (C, V, I, S, B), each with its own totals. The
+    /// files are named after `out` with the clave appended, e.g. `declaration-V.720`.
+    Split {
+        #[arg(long)]
+        file: PathBuf,
 
-enum JoinResult<I: Iterator> {
-    OuterLeft(I::Item),
-    Inner(I::Item, I::Item),
-    OuterRight(I::Item),
-}
+        #[arg(short, long)]
+        out: PathBuf,
+    },
+    /// Recounts the registros of a (possibly hand-edited) 720 file and rewrites the header totals.
+    FixTotals {
+        #[arg(long)]
+        file: PathBuf,
 
-impl<T, I: Iterator<Item = T>> Iterator for FullJoinIterator<I>
-where
-    T: Keyed + Clone,
-{
-    type Item = JoinResult<I>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if !self.is_initialized {
-            self.last_left = self.left.next();
-            self.last_right = self.right.next();
-            self.is_initialized = true;
-        }
-        match (self.last_left.clone(), self.last_right.clone()) {
-            (None, None) => None,
-            (None, Some(right)) => {
-                self.last_right = self.right.next();
-                Some(JoinResult::OuterRight(right))
-            }
-            (Some(left), None) => {
-                self.last_left = self.left.next();
-                Some(JoinResult::OuterLeft(left))
-            }
-            (Some(left), Some(right)) => {
-                if left.key() < right.key() {
-                    self.last_left = self.left.next();
-                    Some(JoinResult::OuterLeft(left))
-                } else if left.key() == right.key() {
-                    self.last_left = self.left.next();
-                    self.last_right = self.right.next();
-                    Some(JoinResult::Inner(left, right))
-                } else {
-                    self.last_right = self.right.next();
-                    Some(JoinResult::OuterRight(right))
-                }
-            }
-        }
-    }
-}
+        #[arg(short, long)]
+        out: PathBuf,
+    },
+    Generate(GenerateArgs),
+    /// Generates the 720 of every client profile in --profiles-dir, each written to --out-dir named after
+    /// its profile.
+    Batch {
+        #[arg(long, value_parser = parse_fiscal_year)]
+        fiscal_year: i16,
 
-enum PortfolioChange {
-    NewAcquisition(Rc<dyn AssetWithValuation>),
-    Changed(Rc<dyn AssetWithValuation>, Rc<dyn AssetWithValuation>),
-    Sold(Rc<dyn AssetWithValuation>),
-}
+        /// Directory with a TOML file per client profile
+        #[arg(long, env = "MODELO720_PROFILES_DIR", default_value = "profiles")]
+        profiles_dir: PathBuf,
 
-fn compute_modelo720(
-    ejercicio: i16,
-    nif: &str,
-    name: &str,
-    phone: i64,
-    current: &Portfolio,
-    previous: &Portfolio,
-) -> Modelo720 {
-    let left = current.assets.iter();
-    let right = previous.assets.iter();
-    let iterator = FullJoinIterator::new(left, right);
-    let entries = iterator
-        .map(|result| match result {
-            JoinResult::OuterLeft(left) => PortfolioChange::NewAcquisition(left.clone()),
-            JoinResult::Inner(left, right) => PortfolioChange::Changed(left.clone(), right.clone()),
-            JoinResult::OuterRight(right) => PortfolioChange::Sold(right.clone()),
-        })
-        .flat_map(|change| match change {
-            PortfolioChange::NewAcquisition(acquisition) => {
-                let mut registro = acquisition.modelo_720_registro(ejercicio, nif, name);
-                registro.origen_bien_derecho = Origen::Adquisicion;
-                registro.numero_valores = Some(acquisition.shares());
-                registro.valoracion1 = acquisition.valuation().into();
-                vec![registro]
-            }
-            PortfolioChange::Changed(new_value, old_value) => {
-                let diff = asset_difference(new_value.as_ref(), old_value.as_ref());
-
-                let current_price_per_share = new_value.price_per_share();
-                if diff.shares.0 > Decimal::ZERO {
-                    // If we have more shares then we modify the value of what we have and add a new entry for the acquisition.
-                    let mut previous_registro = old_value.modelo_720_registro(ejercicio, nif, name);
-                    previous_registro.origen_bien_derecho = Origen::Modificacion;
-                    previous_registro.numero_valores = Some(old_value.shares());
-                    previous_registro.valoracion1 =
-                        (old_value.shares().0 * current_price_per_share).into();
-
-                    let mut new_registro = new_value.modelo_720_registro(ejercicio, nif, name);
-                    new_registro.origen_bien_derecho = Origen::Adquisicion;
-                    new_registro.numero_valores = Some(diff.shares);
-                    new_registro.valoracion1 = (diff.shares.0 * current_price_per_share).into();
-
-                    vec![previous_registro, new_registro]
-                } else if diff.shares.0 == Decimal::ZERO {
-                    // If instead there are no new shares then we just revalue what we have.
-                    let mut current_registro = new_value.modelo_720_registro(ejercicio, nif, name);
-                    current_registro.origen_bien_derecho = Origen::Modificacion;
-                    current_registro.numero_valores = Some(new_value.shares());
-                    current_registro.valoracion1 =
-                        (new_value.shares().0 * current_price_per_share).into();
-                    vec![current_registro]
-                } else {
-                    // If we have less shares then we revalue what remains and then add an entry for the sale. Total sales are already handled in registro2Sold.
-                    let mut current_registro = new_value.modelo_720_registro(ejercicio, nif, name);
-                    current_registro.origen_bien_derecho = Origen::Modificacion;
-                    current_registro.numero_valores = Some(new_value.shares());
-                    current_registro.valoracion1 =
-                        (new_value.shares().0 * current_price_per_share).into();
-
-                    let mut sale_registro = current_registro.clone();
-                    sale_registro.origen_bien_derecho = Origen::Extincion;
-                    sale_registro.numero_valores = Some(Shares(diff.shares.0.abs()));
-                    sale_registro.valoracion1 = (sale_registro.numero_valores.unwrap().0.abs()
-                        * current_price_per_share)
-                        .into();
-                    vec![current_registro, sale_registro]
-                }
-            }
-            PortfolioChange::Sold(old_value) => {
-                let mut registro = old_value.modelo_720_registro(ejercicio, nif, name);
-                registro.origen_bien_derecho = Origen::Extincion;
-                registro.numero_valores = Some(old_value.shares());
-                registro.valoracion1 = old_value.valuation().into();
-                vec![registro]
-            }
-        })
-        .collect();
-    Modelo720::new(ejercicio, nif, name, phone, entries)
-}
+        #[arg(long)]
+        out_dir: PathBuf,
+
+        /// Fail on unknown keys in hand-written input files instead of warning about them
+        #[arg(long)]
+        strict: bool,
+
+        /// Download the ECB year-end reference rates to convert the positions not reported in euros
+        #[arg(long, conflicts_with = "offline_rates")]
+        fetch_rates: bool,
+
+        /// File where --fetch-rates keeps the downloaded rates, so they are only downloaded once
+        #[arg(long, requires = "fetch_rates")]
+        rates_cache: Option<PathBuf>,
+
+        /// CSV file with date,currency,rate columns (units per euro) to convert the positions not reported
+        /// in euros
+        #[arg(long)]
+        offline_rates: Option<PathBuf>,
+
+        /// Leave out the bloques that don't need to be declared
+        #[arg(long)]
+        only_if_obligated: bool,
+    },
+    /// Prints what changed between the previous and current statements, before generating anything.
+    Reconcile(StatementArgs),
+    /// Writes the year-end positions in foreign securities of the current statement as a CSV with the data
+    /// the Modelo D-6 asks for: ISIN, issuer, shares, valuation and countries of the issuer and the deposit.
+    GenerateD6 {
+        #[command(flatten)]
+        statements: StatementArgs,
+
+        /// CSV file where the positions are written
+        #[arg(short, long)]
+        out: PathBuf,
+    },
+    /// Aggregates the balances of both statements into the categories of the annual summary of the ETE of the
+    /// Banco de España, and tells how often it must be filed, if at all.
+    Ete(StatementArgs),
+    /// Computes the declaration and writes it as an editable JSON plan instead of a 720 file.
+    Plan(GenerateArgs),
+    /// Serializes a (possibly edited) JSON plan into the final 720 file.
+    Apply {
+        #[arg(long)]
+        plan: PathBuf,
+
+        #[arg(short, long)]
+        out: PathBuf,
+    },
+    /// Writes an existing 720 file as JSON so it can be reviewed and edited by hand.
+    Export {
+        #[arg(long)]
+        file: PathBuf,
+
+        #[command(flatten)]
+        claves: ClaveFilter,
+
+        /// What to export the file for: a JSON to edit and import back, or a CSV of the assets held at
+        /// the end of the year to transcribe into the wealth tax return (Modelo 714)
+        #[arg(long = "for", value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+
+        #[arg(short, long)]
+        out: PathBuf,
+    },
+    /// Writes the assets held at the end of the year according to a declaration and its complementarias as
+    /// a CSV, with each asset once and the values of the latest declaration that holds it.
+    ExtractPortfolio {
+        /// The original declaration followed by its complementarias, in the order they were submitted
+        #[arg(long = "file", required = true, num_args = 1..)]
+        files: Vec<PathBuf>,
+
+        #[command(flatten)]
+        claves: ClaveFilter,
+
+        #[arg(short, long)]
+        out: PathBuf,
+    },
+    /// Prints the registros and valuations of each clave de bien and the registros of each origen of a
+    /// declaration and its complementarias, counting each asset once.
+    Stats {
+        /// The original declaration followed by its complementarias, in the order they were submitted
+        #[arg(long = "file", required = true, num_args = 1..)]
+        files: Vec<PathBuf>,
+    },
+    /// Writes an existing 720 file as an Excel workbook for review, with a column per field.
+    ExportXlsx {
+        #[arg(long)]
+        file: PathBuf,
 
-#[derive(Debug, Clone, Subcommand)]
-enum Commands {
-    Concat {
         #[arg(short, long)]
-        left: PathBuf,
+        out: PathBuf,
+    },
+    /// Serializes a (possibly edited) JSON export back into a 720 file.
+    Import {
+        #[arg(long)]
+        json: PathBuf,
 
         #[arg(short, long)]
-        right: PathBuf,
+        out: PathBuf,
+
+        /// Read the file back once written and check that it parses to exactly what was written
+        #[arg(long)]
+        verify: bool,
+    },
+    /// Compares the registros of two 720 files, e.g. a draft against the file submitted last year.
+    Diff {
+        #[arg(long)]
+        old: PathBuf,
+
+        #[arg(long)]
+        new: PathBuf,
+    },
+    /// Rewrites a 720 file with made-up NIFs, names, phone and account numbers and randomly scaled
+    /// valuations, so it can be shared in a bug report without leaking personal data.
+    Anonymize {
+        #[arg(long)]
+        file: PathBuf,
+
+        /// Seed of the random replacements, to anonymize a file the same way again
+        #[arg(long)]
+        seed: Option<u64>,
 
         #[arg(short, long)]
         out: PathBuf,
     },
-    Generate {
-        #[arg(value_enum)]
-        broker: SupportedBrokers,
+    /// Prints a 720 file field by field with totals and subtotals per clave.
+    Inspect {
+        #[arg(long)]
+        file: PathBuf,
+    },
+    /// Writes a plain-text summary of a 720 file with every asset and the totals of each clave, to be
+    /// archived alongside the submission.
+    Report {
+        #[arg(long)]
+        file: PathBuf,
 
+        /// Where to write the report. Printed to stdout if not given.
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Evaluates which bloques of a declaration must be submitted given the last submitted one.
+    CheckObligation {
+        #[arg(long)]
+        file: PathBuf,
+
+        /// The last 720 file submitted, if any
+        #[arg(long)]
+        previous_declaration: Option<PathBuf>,
+    },
+    /// Locates the errors of the response the AEAT returns after a failed upload in the 720 file, telling
+    /// the registro and field each one refers to.
+    CompareAeat {
+        /// The 720 file that was uploaded
+        #[arg(long)]
+        file: PathBuf,
+
+        /// File with the errors returned by the AEAT
+        #[arg(long)]
+        response: PathBuf,
+    },
+    /// Checks a 720 file against the AEAT record design and reports every problem found.
+    Validate {
+        #[arg(long)]
+        file: PathBuf,
+
+        /// The declaration submitted for an earlier ejercicio, to check the origen of every registro against
+        /// what it declared
+        #[arg(long)]
+        previous: Option<PathBuf>,
+
+        /// Print the issues as a JSON report with the code, severity, line, registro, field and message of
+        /// each, for scripts and other front-ends
+        #[arg(long)]
+        json: bool,
+    },
+    /// Generates the Modelo 721 for virtual currencies held abroad from TOML holdings files.
+    #[command(name = "generate-721")]
+    Generate721 {
         #[arg(long)]
         previous_statement: Option<PathBuf>,
 
@@ -204,73 +286,1455 @@ enum Commands {
         name: String,
 
         #[arg(long)]
-        nif: String,
+        nif: Nif,
+
+        #[arg(long)]
+        phone: Telefono,
+
+        /// Fail on unknown keys in the holdings files instead of warning about them
+        #[arg(long)]
+        strict: bool,
 
+        /// CSV file with date,symbol,price columns giving the EUR price of each currency at December 31, used
+        /// for the holdings without a valuation
         #[arg(long)]
-        phone: i64,
+        prices: Option<PathBuf>,
 
         #[arg(short, long)]
         out: PathBuf,
     },
 }
 
+#[derive(Debug, Clone, Default, clap::Args)]
+struct StatementArgs {
+    #[arg(value_enum, required_unless_present = "profile")]
+    broker: Option<SupportedBrokers>,
+
+    #[arg(long)]
+    previous_statement: Option<PathBuf>,
+
+    #[arg(long, required_unless_present_any = ["profile", "statements_dir"])]
+    current_statement: Option<PathBuf>,
+
+    /// Directory with a folder per year and broker, e.g. 2024/ibkr/, where the statements of the fiscal year
+    /// and the one before are looked for when not given
+    #[arg(long)]
+    statements_dir: Option<PathBuf>,
+
+    #[arg(long, value_parser = parse_fiscal_year)]
+    fiscal_year: i16,
+
+    /// Password for encrypted ZIP statements
+    #[arg(long, env = "MODELO720_STATEMENT_PASSWORD", hide_env_values = true)]
+    statement_password: Option<String>,
+
+    /// Prompt for the statement password instead of passing it as an argument
+    #[arg(long)]
+    ask_password: bool,
+
+    /// Fail on unknown keys in hand-written input files instead of warning about them
+    #[arg(long)]
+    strict: bool,
+
+    /// TOML file mapping the deposit country of the assets per account or ISIN and the country of their
+    /// issuer per ISIN, for brokers that hold assets through entities in several countries
+    #[arg(long)]
+    countries: Option<PathBuf>,
+
+    /// Fail when an ISIN is held more than once in the same deposit country instead of adding up the holdings
+    #[arg(long)]
+    fail_on_duplicates: bool,
+
+    /// Fail on short positions, held with a negative quantity, instead of leaving them out with a warning
+    #[arg(long)]
+    fail_on_shorts: bool,
+
+    /// Declare the cash balances of the statements, rows of asset class CASH for Interactive Brokers, as
+    /// accounts of the broker instead of leaving them out
+    #[arg(long)]
+    include_cash: bool,
+
+    /// TOML file with a [[split]] table per stock split (isin, date, ratio). Splits during the fiscal year
+    /// adjust the previous shares so they aren't declared as new acquisitions
+    #[arg(long)]
+    splits: Option<PathBuf>,
+
+    /// TOML file with an [isin_changes] table of old to new ISIN, for funds that changed ISIN during the year
+    #[arg(long)]
+    isin_changes: Option<PathBuf>,
+
+    /// TOML file with a [classification] table of ISIN or account code to clave, e.g. V2, and [[rule]] tables
+    /// (isin_prefix, description, depositary, clave), for assets declared with another clave than their kind of
+    /// asset gets, on top of the bundled one for common ETCs
+    #[arg(long)]
+    classification: Option<PathBuf>,
+
+    /// TOML file with a [[lot]] table per purchase (identifier, date, shares, cost) held at the start of the
+    /// year, so sales are declared per lot consumed first in first out
+    #[arg(long)]
+    lots: Option<PathBuf>,
+
+    /// Trades Flex query of Interactive Brokers (ISIN, TradeDate, Quantity), so the shares bought during the
+    /// year are declared per purchase dated on its trade date
+    #[arg(long)]
+    trades: Option<PathBuf>,
+
+    /// Download the ECB year-end reference rates to convert the positions not reported in euros
+    #[arg(long, conflicts_with = "offline_rates")]
+    fetch_rates: bool,
+
+    /// File where --fetch-rates keeps the downloaded rates, read instead of downloading them when it exists
+    #[arg(long, requires = "fetch_rates")]
+    rates_cache: Option<PathBuf>,
+
+    /// CSV file with date,currency,rate columns (units per euro) to convert the positions not reported in
+    /// euros without downloading anything
+    #[arg(long)]
+    offline_rates: Option<PathBuf>,
+
+    /// How the amounts converted to euros are rounded to cents
+    #[arg(long, value_enum, default_value_t = Rounding::HalfUp)]
+    rounding: Rounding,
+
+    /// What to do with the assets of the statements valued at zero or less
+    #[arg(long, value_enum, default_value_t = ValuationPolicy::Signed)]
+    valuation_policy: ValuationPolicy,
+
+    /// Client profile in --profiles-dir with the declarant data and input files, which the arguments given
+    /// on the command line override
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Directory with a TOML file per client profile
+    #[arg(long, env = "MODELO720_PROFILES_DIR", default_value = "profiles")]
+    profiles_dir: PathBuf,
+
+    // Rates already loaded by batch, so they are only downloaded once for all the profiles.
+    #[arg(skip)]
+    exchange_rates: Option<ExchangeRates>,
+}
+
+#[derive(Debug, Clone, Default, clap::Args)]
+struct GenerateArgs {
+    #[command(flatten)]
+    statements: StatementArgs,
+
+    #[arg(long, required_unless_present_any = ["declarante", "profile"])]
+    name: Option<String>,
+
+    #[arg(long, required_unless_present_any = ["declarante", "profile"])]
+    nif: Option<Nif>,
+
+    #[arg(long, required_unless_present_any = ["declarante", "profile"])]
+    phone: Option<Telefono>,
+
+    /// Co-owner declaring the same assets, as NIF,NAME,PHONE,PERCENTAGE. Repeat it to generate one
+    /// declaration per co-owner, each written next to --out with the NIF appended to the file name
+    #[arg(
+        long,
+        value_parser = parse_declarante,
+        conflicts_with_all = ["name", "nif", "phone", "ownership_pct"]
+    )]
+    declarante: Vec<Declarante>,
+
+    /// NIF of the person the assets belong to when declaring on their behalf, e.g. a minor child
+    #[arg(long, requires = "declarado_name")]
+    declarado_nif: Option<Nif>,
+
+    /// Name of the person the assets belong to when declaring on their behalf
+    #[arg(long, requires = "declarado_nif")]
+    declarado_name: Option<String>,
+
+    /// NIF of the legal representative of the declarado
+    #[arg(long, requires = "declarado_nif")]
+    representante_nif: Option<Nif>,
+
+    /// Número identificativo of this declaration, which must differ from any previously submitted one
+    #[arg(long)]
+    declaration_id: Option<i64>,
+
+    /// Tipo de soporte of the header. The Sede electrónica of the AEAT only accepts telematica, the default
+    #[arg(long, value_enum)]
+    tipo_soporte: Option<TipoSoporte>,
+
+    /// Persona de contacto of the header, when it isn't the declarante, e.g. the advisor filing it
+    #[arg(long)]
+    contact_name: Option<String>,
+
+    /// Generate a declaración sustitutiva replacing the submitted declaration with this número identificativo
+    #[arg(long, requires = "declaration_id")]
+    substitutes: Option<i64>,
+
+    /// Percentage of the assets owned by the declarante, for jointly held accounts and portfolios
+    #[arg(long, value_parser = parse_percentage)]
+    ownership_pct: Option<Decimal>,
+
+    /// Clave de condición del declarante for every asset, e.g. 3 for an authorised holder or
+    /// "8NUDA PROPIEDAD" for other forms of ownership followed by their description
+    #[arg(long)]
+    titularidad: Option<Titularidad>,
+
+    /// TOML file keyed by ISIN or account code with [ownership] and [titularidad] tables overriding how
+    /// assets are held, and [registro.<ISIN>] tables forcing any other field of their registros
+    #[arg(long, alias = "ownership-overrides")]
+    overrides: Option<PathBuf>,
+
+    /// TOML file with an [[entity]] table per entity (name, aliases, street, city, zip_code, country) whose
+    /// address fills the registros of that entity, in addition to the bundled ones
+    #[arg(long)]
+    entities: Option<PathBuf>,
+
+    /// TOML file with a [[sale]] table per transmission (identifier, date, proceeds) to value the
+    /// extinction registros at the amount received instead of the year-end price
+    #[arg(long)]
+    sales: Option<PathBuf>,
+
+    /// TOML file with an [acquisition] table of ISIN or account code to the date it was acquired, declared
+    /// as the fecha de incorporación of new assets
+    #[arg(long)]
+    acquisition_dates: Option<PathBuf>,
+
+    /// The last 720 file submitted, used to decide which bloques must be declared again
+    #[arg(long)]
+    previous_declaration: Option<PathBuf>,
+
+    /// JSON file remembering the declarations written, .modelo720/state.json if no path is given. The last
+    /// one stands for --previous-declaration and, without a previous statement, for the holdings at the end
+    /// of last year
+    #[arg(long, num_args = 0..=1, default_missing_value = DEFAULT_STATE_PATH)]
+    state: Option<PathBuf>,
+
+    /// Leave out the bloques (accounts, securities, real estate) that don't need to be declared
+    #[arg(long)]
+    only_if_obligated: bool,
+
+    /// Only emit what must be declared again since --previous-declaration or the one in --state: the
+    /// bloques that grew more than 20.000 € and the extinguished assets of the rest
+    #[arg(long, conflicts_with = "only_if_obligated")]
+    only_required: bool,
+
+    /// Run everything and print a summary of the declaration and its validation warnings, without writing it
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Read the file back once written and check that it parses to exactly what was written
+    #[arg(long, conflicts_with = "dry_run")]
+    verify: bool,
+
+    /// Order of the registros in the file. By default they are grouped by asset, ordered by ISIN and country
+    /// of deposit
+    #[arg(long, value_enum)]
+    sort: Option<SortOrder>,
+
+    /// Valores the número de valores may lose to its 2 decimals without a warning, e.g. 0.005 to accept any
+    /// rounding of fractional shares
+    #[arg(long, default_value = "0")]
+    shares_epsilon: Decimal,
+
+    #[command(flatten)]
+    claves: ClaveFilter,
+
+    /// Also write the summary printed after generating the declaration as JSON, with the NIF appended to the
+    /// file name like --out when there are several declarantes
+    #[arg(long, conflicts_with = "dry_run")]
+    summary_json: Option<PathBuf>,
+
+    /// Write the validation issues of the declaration and the warnings found while generating it as a JSON
+    /// report, with the NIF appended to the file name like --out when there are several declarantes
+    #[arg(long)]
+    report_json: Option<PathBuf>,
+
+    #[arg(short, long)]
+    out: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+struct Declarante {
+    nif: Nif,
+    name: String,
+    phone: Telefono,
+    percentage: Option<Decimal>,
+}
+
+fn parse_declarante(value: &str) -> Result<Declarante, String> {
+    let fields: Vec<&str> = value.split(',').map(str::trim).collect();
+    // Names can contain commas, so everything between the NIF and the phone is part of it.
+    match fields.as_slice() {
+        [nif, name @ .., phone, percentage] if !name.is_empty() => Ok(Declarante {
+            nif: Nif::new(nif)?,
+            name: name.join(","),
+            phone: Telefono::new(phone)?,
+            percentage: Some(parse_percentage(percentage)?),
+        }),
+        _ => Err("expected NIF,NAME,PHONE,PERCENTAGE".to_string()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ExportFormat {
+    /// Editable JSON that `import` turns back into a 720 file
+    Json,
+    /// CSV with the assets held at the end of the year by the apartados of the Modelo 714
+    #[value(name = "714")]
+    Modelo714,
+}
+
+/// Claves de bien whose registros are kept, e.g. to file securities and accounts through different advisors.
+#[derive(Debug, Clone, Default, clap::Args)]
+struct ClaveFilter {
+    /// Only keep the registros of these claves de bien (C, V, I, S or B). Repeat it or separate them with
+    /// commas, e.g. V,I
+    #[arg(long, value_delimiter = ',', value_parser = parse_clave)]
+    include_clave: Vec<char>,
+
+    /// Leave out the registros of these claves de bien
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = parse_clave,
+        conflicts_with = "include_clave"
+    )]
+    exclude_clave: Vec<char>,
+}
+
+impl ClaveFilter {
+    /// Drops the registros filtered out and recomputes the header totals.
+    fn apply(&self, modelo720: &mut Modelo720) {
+        if self.include_clave.is_empty() && self.exclude_clave.is_empty() {
+            return;
+        }
+        modelo720.retain_claves(&self.include_clave, &self.exclude_clave);
+    }
+}
+
+fn parse_clave(value: &str) -> Result<char, String> {
+    match value.trim().to_uppercase().as_str() {
+        clave @ ("C" | "V" | "I" | "S" | "B") => Ok(clave.chars().next().unwrap()),
+        _ => Err(format!(
+            "{} is not a clave de bien (C, V, I, S or B)",
+            value
+        )),
+    }
+}
+
+/// Fiscal year of a 720, which must have a record design to be written with.
+fn parse_fiscal_year(value: &str) -> Result<i16, String> {
+    let year: i16 = value.parse().map_err(|e| format!("{}", e))?;
+    RecordDesign::required_for(year).map_err(|e| e.to_string())?;
+    Ok(year)
+}
+
+fn parse_percentage(value: &str) -> Result<Decimal, String> {
+    let percentage: Decimal = value.parse().map_err(|e| format!("{}", e))?;
+    if percentage <= Decimal::ZERO || percentage > Decimal::ONE_HUNDRED {
+        return Err(format!("{} is not between 0 and 100", percentage));
+    }
+    Ok(percentage)
+}
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     #[command(subcommand)]
     subcommand: Commands,
+
+    /// Skip the records and statement rows that can't be read instead of stopping at the first one, listing
+    /// all their errors at the end
+    #[arg(long, global = true)]
+    lenient: bool,
+}
+
+/// Reads a 720 file, skipping the registros that can't be read when lenient.
+fn read_modelo_720(path: &Path, lenient: Option<&Lenient>) -> error::Result<Modelo720> {
+    match lenient {
+        Some(lenient) => Modelo720::from_path_lenient(path, lenient),
+        None => Modelo720::from_path(path),
+    }
+}
+
+/// The complete picture of an ejercicio from the original declaration and its complementarias in `inputs`,
+/// which must all be of the same declarante.
+fn read_complementarias(inputs: &[PathBuf], lenient: Option<&Lenient>) -> error::Result<Modelo720> {
+    let mut declarations = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        let declaration = read_modelo_720(input, lenient)?;
+        if let Some(first) = declarations.first() {
+            declaration.check_same_declarante(first).map_err(|e| {
+                Error::InvalidInput(format!(
+                    "{}: {} of {}",
+                    input.display(),
+                    e,
+                    inputs[0].display()
+                ))
+            })?;
+        }
+        declarations.push(declaration);
+    }
+    Modelo720::merge_complementarias(declarations)
+        .ok_or_else(|| Error::InvalidInput("no declaration given".to_string()))
+}
+
+fn concat_modelo_720(
+    inputs: &[PathBuf],
+    dedupe: Option<Dedupe>,
+    force: bool,
+    lenient: Option<&Lenient>,
+) -> error::Result<Modelo720> {
+    let mut result = read_modelo_720(&inputs[0], lenient)?;
+    for input in inputs[1..].iter() {
+        let mut other = read_modelo_720(input, lenient)?;
+        if let Err(e) = result.check_same_declarante(&other) {
+            if !force {
+                return Err(Error::InvalidInput(format!(
+                    "{}: {} of {}, pass --force to merge them anyway",
+                    input.display(),
+                    e,
+                    inputs[0].display()
+                )));
+            }
+            eprintln!(
+                "Warning: {}: {} of {}, merging it with the header of {}",
+                input.display(),
+                e,
+                inputs[0].display(),
+                inputs[0].display()
+            );
+        }
+        let shared = result.shared_registros(&other);
+        if !shared.is_empty() {
+            let keys = shared.iter().cloned().collect::<Vec<String>>().join(", ");
+            match dedupe {
+                None => {
+                    return Err(Error::InvalidInput(format!(
+                        "{}: {} already in an earlier file, which would be declared twice; pass --dedupe first \
+                         or last to keep only one of them",
+                        input.display(),
+                        keys
+                    )))
+                }
+                Some(Dedupe::First) => {
+                    eprintln!("Leaving out {} of {}", keys, input.display());
+                    other.remove_registros(&shared);
+                }
+                Some(Dedupe::Last) => {
+                    eprintln!("Leaving out {} of the files before {}", keys, input.display());
+                    result.remove_registros(&shared);
+                }
+            }
+        }
+        result.append(other);
+    }
+    Ok(result)
+}
+
+/// Replaces `{year}` and `{previous_year}` in the paths of a profile, so the same profile serves every campaign.
+fn for_year(path: &Path, year: i16) -> PathBuf {
+    PathBuf::from(
+        path.to_string_lossy()
+            .replace("{year}", &year.to_string())
+            .replace("{previous_year}", &(year - 1).to_string()),
+    )
+}
+
+fn missing_from_profile(profile: &str, argument: &str) -> Error {
+    Error::Usage(format!(
+        "{} is neither given nor set in the profile {}",
+        argument, profile
+    ))
+}
+
+impl StatementArgs {
+    fn load_profile(&self) -> error::Result<Option<Profile>> {
+        let Some(name) = self.profile.as_ref() else {
+            return Ok(None);
+        };
+        let path = self.profiles_dir.join(format!("{}.toml", name));
+        let options = ParseOptions {
+            strict: self.strict,
+            ..Default::default()
+        };
+        let mut profile = parse_profile(&path, &options)?;
+        for file in profile.files_mut().into_iter().flatten() {
+            *file = for_year(file, self.fiscal_year);
+        }
+        Ok(Some(profile))
+    }
+
+    fn apply_profile(&mut self, profile: &Profile) -> error::Result<()> {
+        let name = self.profile.clone().unwrap_or_default();
+        self.broker = self.broker.or(profile.broker);
+        self.previous_statement = self
+            .previous_statement
+            .take()
+            .or(profile.previous_statement.clone());
+        self.current_statement = self
+            .current_statement
+            .take()
+            .or(profile.current_statement.clone());
+        self.statements_dir = self
+            .statements_dir
+            .take()
+            .or(profile.statements_dir.clone());
+        self.countries = self.countries.take().or(profile.countries.clone());
+        self.lots = self.lots.take().or(profile.lots.clone());
+        self.trades = self.trades.take().or(profile.trades.clone());
+        self.splits = self.splits.take().or(profile.splits.clone());
+        self.isin_changes = self.isin_changes.take().or(profile.isin_changes.clone());
+        self.classification = self
+            .classification
+            .take()
+            .or(profile.classification.clone());
+        if self.broker.is_none() {
+            return Err(missing_from_profile(&name, "the broker"));
+        }
+        if self.current_statement.is_none() && self.statements_dir.is_none() {
+            return Err(missing_from_profile(&name, "--current-statement"));
+        }
+        Ok(())
+    }
+
+    /// Folder of the statements of the broker for `year` in --statements-dir, if given.
+    fn year_folder(&self, year: i16) -> Option<PathBuf> {
+        let broker = self.broker?;
+        Some(
+            self.statements_dir
+                .as_ref()?
+                .join(year.to_string())
+                .join(broker.folder_name()),
+        )
+    }
+
+    /// Fills the arguments not given on the command line from the profile, if any.
+    fn with_profile(mut self) -> error::Result<Self> {
+        if let Some(profile) = self.load_profile()? {
+            self.apply_profile(&profile)?;
+        }
+        Ok(self)
+    }
+}
+
+impl GenerateArgs {
+    /// Fills the arguments not given on the command line from the profile, if any.
+    fn with_profile(mut self) -> error::Result<Self> {
+        let Some(profile) = self.statements.load_profile()? else {
+            return Ok(self);
+        };
+        self.statements.apply_profile(&profile)?;
+        let name = self.statements.profile.clone().unwrap_or_default();
+        self.overrides = self.overrides.take().or(profile.overrides);
+        self.entities = self.entities.take().or(profile.entities);
+        self.sales = self.sales.take().or(profile.sales);
+        self.acquisition_dates = self.acquisition_dates.take().or(profile.acquisition_dates);
+        self.previous_declaration = self
+            .previous_declaration
+            .take()
+            .or(profile.previous_declaration);
+        self.state = self.state.take().or(profile.state);
+        if self.declarante.is_empty() {
+            self.name = self.name.take().or(profile.name);
+            self.contact_name = self.contact_name.take().or(profile.contact_name);
+            self.tipo_soporte = self.tipo_soporte.or(profile.tipo_soporte);
+            self.nif = self.nif.take().or(profile.nif);
+            self.phone = self.phone.take().or(profile.phone);
+            for (argument, missing) in [
+                ("--name", self.name.is_none()),
+                ("--nif", self.nif.is_none()),
+                ("--phone", self.phone.is_none()),
+            ] {
+                if missing {
+                    return Err(missing_from_profile(&name, argument));
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    fn declarantes(&self) -> Vec<Declarante> {
+        if !self.declarante.is_empty() {
+            return self.declarante.clone();
+        }
+        // clap guarantees these are present when no --declarante is given.
+        vec![Declarante {
+            nif: self.nif.clone().unwrap(),
+            name: self.name.clone().unwrap(),
+            phone: self.phone.clone().unwrap(),
+            percentage: self.ownership_pct,
+        }]
+    }
+
+    /// Where to write each declaration. With several declarantes the NIF is appended to the file name.
+    fn output_path(&self, modelo720: &Modelo720) -> PathBuf {
+        self.per_declarante(&self.out, modelo720)
+    }
+
+    /// `path`, with the NIF of the declarante appended to the file name when there are several.
+    fn per_declarante(&self, path: &Path, modelo720: &Modelo720) -> PathBuf {
+        if self.declarante.len() < 2 {
+            return path.to_path_buf();
+        }
+        with_suffix(path, modelo720.header.nif_declarante.as_str())
+    }
+}
+
+/// Appends `-suffix` to the file stem, keeping the extension, e.g. `declaration-C.720`.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut file_name = format!("{}-{}", stem, suffix);
+    if let Some(extension) = path.extension() {
+        file_name = format!("{}.{}", file_name, extension.to_string_lossy());
+    }
+    path.with_file_name(file_name)
+}
+
+/// Rates to convert the statements to euros: the ones given, read from --offline-rates or fetched from the ECB
+/// with --fetch-rates, or none when the statements are in euros.
+fn load_exchange_rates(args: &StatementArgs) -> error::Result<Option<ExchangeRates>> {
+    if let Some(rates) = &args.exchange_rates {
+        return Ok(Some(rates.clone()));
+    }
+    if let Some(path) = &args.offline_rates {
+        return Ok(Some(ExchangeRates::from_csv(path)?));
+    }
+    if !args.fetch_rates {
+        return Ok(None);
+    }
+    match &args.rates_cache {
+        Some(path) if path.exists() => Ok(Some(ExchangeRates::from_csv(path)?)),
+        cache => {
+            let rates = ExchangeRates::fetch_ecb(i32::from(args.fiscal_year))?;
+            if let Some(path) = cache {
+                rates.save_csv(path)?;
+            }
+            Ok(Some(rates))
+        }
+    }
+}
+
+/// The password of the statements, asked for when --ask-password is given.
+fn statement_password(args: &StatementArgs) -> error::Result<Option<String>> {
+    if !args.ask_password {
+        return Ok(args.statement_password.clone());
+    }
+    rpassword::prompt_password("Statement password: ")
+        .map(Some)
+        .map_err(|e| Error::Usage(format!("couldn't read the password: {}", e)))
 }
 
-fn concat_modelo_720(left: &Path, right: &Path) -> Modelo720 {
-    let mut a = Modelo720::from_path(left);
-    let b = Modelo720::from_path(right);
-    a.concat(b);
-    a
+/// Parses the previous and current statements with every adjustment given, ready to be compared.
+/// `previous_declaration` stands for the previous statement when there is none.
+fn load_portfolios(
+    args: &StatementArgs,
+    lenient: Option<&Lenient>,
+    previous_declaration: Option<&Modelo720>,
+) -> error::Result<(ParseOptions, Portfolio, Portfolio)> {
+    let password = statement_password(args)?;
+    let mut options = ParseOptions {
+        password,
+        strict: args.strict,
+        valuation_policy: args.valuation_policy,
+        lenient: lenient.cloned(),
+        fail_on_shorts: args.fail_on_shorts,
+        include_cash: args.include_cash,
+        ..Default::default()
+    };
+    if let Some(countries) = &args.countries {
+        options.countries = parse_country_mapping(countries, &options)?;
+    }
+    // Both are required unless a profile or --statements-dir gives them, which clap and with_profile check.
+    let broker = args.broker.unwrap();
+    let current_statement = match &args.current_statement {
+        Some(path) => path.clone(),
+        None => args.year_folder(args.fiscal_year).unwrap(),
+    };
+    // There is no folder for the year before the first declaration.
+    let previous_statement = args.previous_statement.clone().or_else(|| {
+        args.year_folder(args.fiscal_year - 1)
+            .filter(|x| x.is_dir())
+    });
+    // A folder stands for the statement of the broker in it.
+    let parse_statement = |path: &Path| {
+        let path = if path.is_dir() {
+            broker.statement_in(path)?
+        } else {
+            path.to_path_buf()
+        };
+        match broker {
+            SupportedBrokers::InteractiveBrokers => parse_ibkr_statement(&path, &options),
+            SupportedBrokers::Mintos => parse_mintos_statement(&path, &options),
+            SupportedBrokers::Manual => parse_manual_assets(&path, &options),
+        }
+    };
+    let parse_previous_statement = || -> error::Result<Portfolio> {
+        match &previous_statement {
+            // Last year's declaration describes the holdings at the end of the year just as well as the statement.
+            Some(path) if is_modelo_720_file(path) => {
+                let (portfolio, unidentified) =
+                    Portfolio::from_modelo_720(&read_modelo_720(path, lenient)?);
+                for registro in unidentified {
+                    eprintln!(
+                        "Warning: registro of clave {} in {} has no ISIN or account code and can't be matched, give it in a statement instead",
+                        registro.tipo_bien.code(),
+                        path.display()
+                    );
+                }
+                Ok(portfolio.with_source_file("Previous declaration", path))
+            }
+            Some(path) => parse_statement(path),
+            None => match previous_declaration {
+                Some(modelo720) => {
+                    let (portfolio, unidentified) = Portfolio::from_modelo_720(modelo720);
+                    if !unidentified.is_empty() {
+                        eprintln!(
+                            "Warning: {} registros of the previous declaration have no ISIN or account code and can't be matched, give them in a statement instead",
+                            unidentified.len()
+                        );
+                    }
+                    Ok(portfolio)
+                }
+                None => Ok(Portfolio::default()),
+            },
+        }
+    };
+    let (previous_portfolio, current_portfolio) = rayon::join(parse_previous_statement, || {
+        parse_statement(&current_statement)
+    });
+    let (previous_portfolio, current_portfolio) = (previous_portfolio?, current_portfolio?);
+    // Each statement is converted with the rates of the end of its own year.
+    let rates = load_exchange_rates(args)?;
+    let year_end_rates = |year: i32| rates.as_ref().map(|x| x.at_year_end(year));
+    let previous_portfolio = previous_portfolio.in_euros(
+        year_end_rates(i32::from(args.fiscal_year) - 1).as_ref(),
+        args.rounding,
+    )?;
+    let current_portfolio = current_portfolio.in_euros(
+        year_end_rates(i32::from(args.fiscal_year)).as_ref(),
+        args.rounding,
+    )?;
+    let previous_portfolio = match &args.lots {
+        Some(lots) => previous_portfolio.with_lots(parse_lots(lots, &options)?)?,
+        None => previous_portfolio,
+    };
+    // Only the purchases of the fiscal year can still be held as part of what was acquired in it.
+    let trades = args
+        .trades
+        .clone()
+        .or_else(|| Some(current_statement.join("trades.csv")).filter(|x| x.is_file()));
+    let current_portfolio = match &trades {
+        Some(trades) => {
+            let purchases = parse_ibkr_trades(trades, &options)?
+                .into_iter()
+                .map(|(key, lots)| {
+                    let lots = lots
+                        .into_iter()
+                        .filter(|x| x.date.map(|x| x.year()) == Some(i32::from(args.fiscal_year)))
+                        .collect();
+                    (key, lots)
+                })
+                .collect();
+            current_portfolio.with_lots(purchases)?
+        }
+        None => current_portfolio,
+    };
+    for (label, portfolio) in [
+        ("previous", &previous_portfolio),
+        ("current", &current_portfolio),
+    ] {
+        let duplicates = portfolio.duplicates();
+        if duplicates.is_empty() {
+            continue;
+        }
+        if args.fail_on_duplicates {
+            return Err(Error::InvalidInput(format!(
+                "the {} statement holds {} more than once",
+                label,
+                duplicates.join(", ")
+            )));
+        }
+        eprintln!(
+            "Warning: adding up the holdings of {} in the {} statement",
+            duplicates.join(", "),
+            label
+        );
+    }
+    let previous_portfolio = previous_portfolio.aggregated();
+    let current_portfolio = current_portfolio.aggregated();
+    let previous_portfolio = match &args.splits {
+        Some(splits) => {
+            let splits: Vec<StockSplit> = parse_splits(splits, &options)?
+                .into_iter()
+                .filter(|x| x.date.year() == i32::from(args.fiscal_year))
+                .collect();
+            previous_portfolio.with_splits(&splits)
+        }
+        None => previous_portfolio,
+    };
+    let previous_portfolio = match &args.isin_changes {
+        Some(changes) => {
+            previous_portfolio.with_isin_changes(&parse_isin_changes(changes, &options)?)
+        }
+        None => previous_portfolio,
+    };
+    let mut classification = Classification::bundled();
+    if let Some(path) = &args.classification {
+        classification.extend(parse_classification(path, &options)?);
+    }
+    let previous_portfolio = previous_portfolio.with_classification(&classification);
+    let current_portfolio = current_portfolio.with_classification(&classification);
+    Ok((options, previous_portfolio, current_portfolio))
+}
+
+/// Computes the declaration of every declarante, each with the warnings found while generating it located at
+/// the lines of its registros in the file.
+fn generate_modelo_720(
+    args: &GenerateArgs,
+    lenient: Option<&Lenient>,
+) -> error::Result<Vec<(Modelo720, Vec<ValidationIssue>)>> {
+    let state = args.state.as_deref().map(State::load).transpose()?;
+    // The state stands for the previous declaration of each declarante when none is given.
+    let fiscal_year = args.statements.fiscal_year;
+    let declarantes = args.declarantes();
+    let from_state = |nif: &Nif| {
+        let declaration = state.as_ref()?.previous(nif, fiscal_year)?;
+        Some(&declaration.modelo720)
+    };
+    // Without a previous statement, the holdings of each declarante at the start of the year are those of
+    // their own declaration in the state, so the statements are loaded once for each different one. The
+    // password is only asked for once.
+    let statements = StatementArgs {
+        statement_password: statement_password(&args.statements)?,
+        ask_password: false,
+        ..args.statements.clone()
+    };
+    let mut portfolios: Vec<(Option<&Modelo720>, Portfolio, Portfolio)> = Vec::new();
+    let mut portfolio_of = Vec::with_capacity(declarantes.len());
+    let mut options = ParseOptions::default();
+    for declarante in declarantes.iter() {
+        let previous = from_state(&declarante.nif);
+        let same = |(x, _, _): &(Option<&Modelo720>, _, _)| match (x, previous) {
+            (Some(x), Some(previous)) => std::ptr::eq(*x, previous),
+            (x, previous) => x.is_none() && previous.is_none(),
+        };
+        let index = match portfolios.iter().position(same) {
+            Some(index) => index,
+            None => {
+                let (parse_options, previous_portfolio, current_portfolio) =
+                    load_portfolios(&statements, lenient, previous)?;
+                options = parse_options;
+                portfolios.push((previous, previous_portfolio, current_portfolio));
+                portfolios.len() - 1
+            }
+        };
+        portfolio_of.push(index);
+    }
+    let overrides = args
+        .overrides
+        .as_ref()
+        .map(|x| parse_asset_overrides(x, &options))
+        .transpose()?
+        .unwrap_or_default();
+    let sales = args
+        .sales
+        .as_ref()
+        .map(|x| parse_sales(x, &options))
+        .transpose()?
+        .unwrap_or_default();
+    let acquisition_dates = args
+        .acquisition_dates
+        .as_ref()
+        .map(|x| parse_acquisition_dates(x, &options))
+        .transpose()?
+        .unwrap_or_default();
+    let mut entities = args
+        .entities
+        .as_ref()
+        .map(|x| parse_entities(x, &options))
+        .transpose()?
+        .unwrap_or_default();
+    // The ones given by the user go first so they take precedence.
+    entities.extend(bundled_entities());
+    let previous_declaration = args
+        .previous_declaration
+        .as_ref()
+        .map(|x| read_modelo_720(x, lenient))
+        .transpose()?;
+    let missing_previous = declarantes
+        .iter()
+        .any(|x| previous_declaration.is_none() && from_state(&x.nif).is_none());
+    if args.only_required && missing_previous {
+        return Err(Error::Usage(
+            "--only-required needs --previous-declaration or a --state with last year's declaration"
+                .to_string(),
+        ));
+    }
+    if args.tipo_soporte == Some(TipoSoporte::Dvd) {
+        eprintln!(
+            "Warning: tipo de soporte C (DVD) can't be presented through the Sede electrónica of the AEAT, \
+             which only accepts T"
+        );
+    }
+    // Declarantes with the same holdings get the same warnings, which are only printed once.
+    let mut printed: HashSet<String> = HashSet::new();
+    let result = declarantes
+        .into_iter()
+        .enumerate()
+        .map(|(index, declarante)| -> error::Result<_> {
+            let previous_declaration = previous_declaration
+                .as_ref()
+                .or_else(|| from_state(&declarante.nif));
+            let (_, previous_portfolio, current_portfolio) = &portfolios[portfolio_of[index]];
+            let mut modelo720 = compute_modelo720(
+                args.statements.fiscal_year,
+                &declarante.nif,
+                &declarante.name,
+                declarante.phone,
+                current_portfolio,
+                previous_portfolio,
+            );
+            // Sales are valued at what was received, so they are checked before.
+            let mut issues = valuation_warnings(&modelo720, &previous_portfolio.prices_per_share());
+            issues.extend(shares_precision_warnings(&modelo720, args.shares_epsilon));
+            if let Some(previous) = previous_declaration {
+                issues.extend(origin_issues(&modelo720, previous));
+            }
+            // Warnings keyed by the registro they are about, since its line changes as registros are sorted
+            // and left out.
+            let mut warnings: Vec<(String, ValidationIssue)> = Vec::new();
+            for issue in issues {
+                if printed.insert(issue.message.clone()) {
+                    eprintln!("Warning: {}", issue.message);
+                }
+                let key = registro_key(&modelo720.entries[issue.line - 2]);
+                warnings.push((key, issue));
+            }
+            modelo720.apply_entity_countries(&options.countries.entity_country_by_isin);
+            modelo720.apply_sales(&sales);
+            for identifier in modelo720.apply_acquisition_dates(&acquisition_dates) {
+                eprintln!(
+                    "Warning: {} was acquired this year but has no fecha de incorporación",
+                    identifier
+                );
+            }
+            modelo720
+                .apply_ownership(declarante.percentage, &overrides.ownership)
+                .map_err(Error::InvalidInput)?;
+            modelo720.apply_titularidad(args.titularidad.as_ref(), &overrides.titularidad);
+            modelo720
+                .apply_registro_overrides(&overrides.registro)
+                .map_err(Error::InvalidInput)?;
+            fill_entity_addresses(&mut modelo720, &entities);
+            if args.only_if_obligated {
+                for obligation in retain_obligated(&mut modelo720, previous_declaration) {
+                    if !obligation.obligated() {
+                        eprintln!("Leaving out {}", obligation);
+                    }
+                }
+            }
+            if let (true, Some(previous)) = (args.only_required, previous_declaration) {
+                for obligation in retain_required(&mut modelo720, previous) {
+                    if !obligation.obligated() {
+                        eprintln!("Leaving out {}", obligation);
+                    }
+                }
+            }
+            if let Some(id) = args.declaration_id {
+                modelo720.header.id_declaracion = id;
+            }
+            if let Some(tipo_soporte) = args.tipo_soporte {
+                modelo720.header.tipo_soporte = tipo_soporte;
+            }
+            if let Some(name) = &args.contact_name {
+                modelo720.header.nombre_persona_contacto = name.clone();
+            }
+            if let Some(id_anterior) = args.substitutes {
+                modelo720.mark_sustitutiva(id_anterior);
+            }
+            if let (Some(nif), Some(name)) = (&args.declarado_nif, &args.declarado_name) {
+                let representante = args.representante_nif.as_ref();
+                modelo720.set_declarado(nif, name, representante);
+            }
+            args.claves.apply(&mut modelo720);
+            let undated = undated_extinction_warnings(&modelo720);
+            if !undated.is_empty() {
+                let assets: BTreeSet<String> = undated
+                    .iter()
+                    .map(|x| registro_key(&modelo720.entries[x.line - 2]))
+                    .collect();
+                let message = format!(
+                    "{} were sold or cancelled during the year but have no fecha de extinción, which the AEAT \
+                     requires: give it in --sales (with the balance on that date for accounts) or as the \
+                     extinction_date of an override",
+                    assets.into_iter().collect::<Vec<_>>().join(", ")
+                );
+                if printed.insert(message.clone()) {
+                    eprintln!("Warning: {}", message);
+                }
+            }
+            for issue in undated {
+                warnings.push((registro_key(&modelo720.entries[issue.line - 2]), issue));
+            }
+            if let Some(order) = args.sort {
+                modelo720.sort_entries(order);
+            }
+            let lines: HashMap<String, usize> = modelo720
+                .entries
+                .iter()
+                .enumerate()
+                .map(|(index, x)| (registro_key(x), index + 2))
+                .collect();
+            // The warnings about registros left out don't apply to the declaration.
+            let issues = warnings
+                .iter()
+                .filter_map(|(key, issue)| {
+                    Some(ValidationIssue {
+                        line: *lines.get(key)?,
+                        ..issue.clone()
+                    })
+                })
+                .collect();
+            Ok((modelo720, issues))
+        })
+        .collect::<error::Result<Vec<_>>>()?;
+    Ok(result)
+}
+
+/// Prints what would be written to `out`: the registros per origen, the totals and the problems the
+/// validation finds in it.
+fn print_dry_run_summary(modelo720: &Modelo720, out: &Path) -> error::Result<()> {
+    println!("{} (not written)", out.display());
+    println!("  Registros: {}", modelo720.entries.len());
+    for origen in [Origen::Adquisicion, Origen::Modificacion, Origen::Extincion] {
+        let count = modelo720
+            .entries
+            .iter()
+            .filter(|x| x.origen_bien_derecho.code() == origen.code())
+            .count();
+        println!("  Origen {}: {}", origen.code(), count);
+    }
+    println!(
+        "  Suma de valoración 1: {}",
+        modelo720.header.suma_valoracion1.0
+    );
+    println!(
+        "  Suma de valoración 2: {}",
+        modelo720.header.suma_valoracion2.0
+    );
+    for issue in validate_modelo_720(&modelo720.to_bytes()?) {
+        println!("  Warning: {}", issue);
+    }
+    Ok(())
+}
+
+fn import_json(json: &Path, out: &Path) -> error::Result<()> {
+    let mut modelo720 = Modelo720::from_json_path(json)?;
+    // The JSON may have been edited by hand, so the header can't be trusted.
+    modelo720.recompute_totals();
+    modelo720.save_to_file(out)
 }
 
 fn main() {
     let cli = Args::parse();
-    let x = cli.subcommand;
-    match x {
-        Commands::Concat { left, right, out } => {
-            let result = concat_modelo_720(&left, &right);
-            result.save_to_file(&out);
-        }
-        Commands::Generate {
-            broker,
+    let lenient = cli.lenient.then(Lenient::default);
+    let result = run(cli.subcommand, lenient.as_ref());
+    let skipped = lenient.map(|x| x.take_errors()).unwrap_or_default();
+    if !skipped.is_empty() {
+        eprintln!("Skipped {} records that couldn't be read:", skipped.len());
+        for e in skipped {
+            eprintln!("  {}", e);
+        }
+    }
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn run(command: Commands, lenient: Option<&Lenient>) -> error::Result<()> {
+    match command {
+        Commands::Concat {
+            inputs,
+            dedupe,
+            force,
+            out,
+        } => concat_modelo_720(&inputs, dedupe, force, lenient)?.save_to_file(&out)?,
+        Commands::Split { file, out } => {
+            // Streamed, as the declarations worth splitting can be too big to load whole.
+            let mut reader = Modelo720Reader::open(&file)?;
+            let header = reader.header().clone();
+            let mut writers = BTreeMap::new();
+            loop {
+                for entry in reader.by_ref() {
+                    let Some(entry) = (match lenient {
+                        Some(lenient) => lenient.recover(entry),
+                        None => Some(entry?),
+                    }) else {
+                        continue;
+                    };
+                    let clave = entry.tipo_bien.clave();
+                    let writer = match writers.entry(clave) {
+                        Entry::Occupied(x) => x.into_mut(),
+                        Entry::Vacant(x) => x.insert(Modelo720Writer::create(
+                            &with_suffix(&out, &clave.to_string()),
+                            &header,
+                        )?),
+                    };
+                    writer.write(&entry)?;
+                }
+                if !reader.next_declaration() {
+                    break;
+                }
+                // The registros of every declaration of the same declarante end up together.
+                let next = reader.header();
+                if next.ejercicio != header.ejercicio
+                    || next.nif_declarante != header.nif_declarante
+                {
+                    return Err(Error::InvalidInput(format!(
+                        "{}: holds declarations of different declarantes or ejercicios",
+                        file.display()
+                    )));
+                }
+            }
+            for writer in writers.into_values() {
+                writer.finish()?;
+            }
+        }
+        Commands::FixTotals { file, out } => {
+            let mut modelo720 = read_modelo_720(&file, lenient)?;
+            let before = modelo720.header.clone();
+            modelo720.recompute_totals();
+            let after = &modelo720.header;
+            if before.numero_registros_tipo2 != after.numero_registros_tipo2 {
+                println!(
+                    "Número total de registros: {} -> {}",
+                    before.numero_registros_tipo2, after.numero_registros_tipo2
+                );
+            }
+            if before.suma_valoracion1.0 != after.suma_valoracion1.0 {
+                println!(
+                    "Suma total de valoración 1: {} -> {}",
+                    before.suma_valoracion1.0, after.suma_valoracion1.0
+                );
+            }
+            if before.suma_valoracion2.0 != after.suma_valoracion2.0 {
+                println!(
+                    "Suma total de valoración 2: {} -> {}",
+                    before.suma_valoracion2.0, after.suma_valoracion2.0
+                );
+            }
+            modelo720.save_to_file(&out)?;
+        }
+        Commands::Generate(args) => {
+            let args = args.with_profile()?;
+            let mut state = args.state.as_deref().map(State::load).transpose()?;
+            for (modelo720, warnings) in generate_modelo_720(&args, lenient)? {
+                let out = args.output_path(&modelo720);
+                if let Some(path) = &args.report_json {
+                    let mut issues = validate_modelo_720(&modelo720.to_bytes()?);
+                    issues.extend(warnings);
+                    ValidationReport::new(&out, issues)
+                        .save_to_json(&args.per_declarante(path, &modelo720))?;
+                }
+                if args.dry_run {
+                    print_dry_run_summary(&modelo720, &out)?;
+                    continue;
+                }
+                modelo720.save_to_file(&out)?;
+                if args.verify {
+                    Modelo720::verify_file(&out)?;
+                }
+                let summary = Summary::of_file(&modelo720, &out)?;
+                println!("{}", summary);
+                if let Some(path) = &args.summary_json {
+                    summary.save_to_json(&args.per_declarante(path, &modelo720))?;
+                }
+                if let Some(state) = &mut state {
+                    state.record(&summary, modelo720);
+                }
+            }
+            if let (Some(state), Some(path), false) = (&state, &args.state, args.dry_run) {
+                state.save(path)?;
+            }
+        }
+        Commands::Batch {
+            fiscal_year,
+            profiles_dir,
+            out_dir,
+            strict,
+            fetch_rates,
+            rates_cache,
+            offline_rates,
+            only_if_obligated,
+        } => {
+            let mut profiles: Vec<String> = std::fs::read_dir(&profiles_dir)
+                .map_err(|e| Error::io(&profiles_dir, e))?
+                .filter_map(|x| Some(x.ok()?.path()))
+                .filter(|x| x.extension().is_some_and(|x| x == "toml"))
+                .filter_map(|x| Some(x.file_stem()?.to_string_lossy().to_string()))
+                .collect();
+            profiles.sort();
+            std::fs::create_dir_all(&out_dir).map_err(|e| Error::io(&out_dir, e))?;
+            let exchange_rates = load_exchange_rates(&StatementArgs {
+                fiscal_year,
+                fetch_rates,
+                rates_cache,
+                offline_rates,
+                ..Default::default()
+            })?;
+            // The profiles are independent, so they are generated on every core.
+            let declarations = profiles
+                .par_iter()
+                .map(|profile| -> error::Result<Vec<(&str, Modelo720)>> {
+                    eprintln!("Generating the declaration of {}", profile);
+                    let args = GenerateArgs {
+                        statements: StatementArgs {
+                            fiscal_year,
+                            strict,
+                            exchange_rates: exchange_rates.clone(),
+                            profile: Some(profile.clone()),
+                            profiles_dir: profiles_dir.clone(),
+                            ..Default::default()
+                        },
+                        only_if_obligated,
+                        out: out_dir.join(format!("{}.720", profile)),
+                        ..Default::default()
+                    }
+                    .with_profile()?;
+                    let mut result = Vec::new();
+                    for (modelo720, _) in generate_modelo_720(&args, lenient)? {
+                        modelo720.save_to_file(&args.output_path(&modelo720))?;
+                        result.push((profile.as_str(), modelo720));
+                    }
+                    Ok(result)
+                })
+                .collect::<error::Result<Vec<_>>>()?;
+            // Clients are only checked against each other once all of them are generated.
+            let declarations: Vec<(&str, &Modelo720)> = declarations
+                .iter()
+                .flatten()
+                .map(|(profile, modelo720)| (*profile, modelo720))
+                .collect();
+            for warning in duplicate_declarant_warnings(&declarations) {
+                eprintln!("Warning: {}", warning);
+            }
+        }
+        Commands::Reconcile(args) => {
+            let args = args.with_profile()?;
+            let (_, previous, current) = load_portfolios(&args, lenient, None)?;
+            let changes = portfolio_changes(&current, &previous);
+            for change in changes.iter() {
+                println!("{}", change);
+            }
+            let count = |f: fn(&PortfolioChange) -> bool| changes.iter().filter(|x| f(x)).count();
+            println!(
+                "{} acquired, {} sold, {} changed",
+                count(|x| matches!(x, PortfolioChange::NewAcquisition(_))),
+                count(|x| matches!(x, PortfolioChange::Sold(_))),
+                count(|x| matches!(x, PortfolioChange::Changed(_, _))),
+            );
+        }
+        Commands::GenerateD6 { statements, out } => {
+            let statements = statements.with_profile()?;
+            let (options, _, current) = load_portfolios(&statements, lenient, None)?;
+            let positions = d6_positions(&current, &options.countries.entity_country_by_isin);
+            save_d6_csv(&positions, &out)?;
+            println!(
+                "{} positions valued at {} EUR at the end of {} written to {}",
+                positions.len(),
+                positions.iter().map(|x| x.valuation).sum::<Decimal>(),
+                statements.fiscal_year,
+                out.display()
+            );
+        }
+        Commands::Ete(args) => {
+            let args = args.with_profile()?;
+            let (_, previous, current) = load_portfolios(&args, lenient, None)?;
+            println!("{}", ete_summary(&current, &previous));
+        }
+        Commands::Plan(args) => {
+            let args = args.with_profile()?;
+            for (modelo720, _) in generate_modelo_720(&args, lenient)? {
+                let out = args.output_path(&modelo720);
+                if args.dry_run {
+                    print_dry_run_summary(&modelo720, &out)?;
+                } else {
+                    modelo720.save_to_json(&out)?;
+                }
+            }
+        }
+        Commands::Apply { plan, out } => {
+            import_json(&plan, &out)?;
+        }
+        Commands::Export {
+            file,
+            claves,
+            format,
+            out,
+        } => {
+            let mut modelo720 = read_modelo_720(&file, lenient)?;
+            claves.apply(&mut modelo720);
+            match format {
+                ExportFormat::Json => modelo720.save_to_json(&out)?,
+                ExportFormat::Modelo714 => {
+                    save_modelo_714_csv(&modelo_714_registros(&modelo720), &out)?
+                }
+            }
+        }
+        Commands::ExtractPortfolio { files, claves, out } => {
+            let mut modelo720 = read_complementarias(&files, lenient)?;
+            claves.apply(&mut modelo720);
+            let registros = modelo_714_registros(&modelo720);
+            save_modelo_714_csv(&registros, &out)?;
+            println!("{} assets written to {}", registros.len(), out.display());
+        }
+        Commands::Stats { files } => {
+            let modelo720 = read_complementarias(&files, lenient)?;
+            let (claves, origenes) = totals_by_clave(&modelo720);
+            println!(
+                "Modelo 720 del ejercicio {} de {}",
+                modelo720.header.ejercicio, modelo720.header.nif_declarante
+            );
+            println!("  Registros: {}", modelo720.header.numero_registros_tipo2);
+            for (clave, summary) in claves.iter() {
+                println!(
+                    "  Clave {}: {} registros, valoración 1 {}, valoración 2 {}",
+                    clave, summary.registros, summary.valoracion1, summary.valoracion2
+                );
+            }
+            for (origen, count) in origenes.iter() {
+                println!("  Origen {}: {}", origen, count);
+            }
+            println!(
+                "  Suma de valoración 1: {}",
+                modelo720.header.suma_valoracion1.0
+            );
+            println!(
+                "  Suma de valoración 2: {}",
+                modelo720.header.suma_valoracion2.0
+            );
+        }
+        Commands::ExportXlsx { file, out } => {
+            save_modelo_720_as_xlsx(&file, &out)?;
+        }
+        Commands::Import { json, out, verify } => {
+            import_json(&json, &out)?;
+            if verify {
+                Modelo720::verify_file(&out)?;
+            }
+        }
+        Commands::Diff { old, new } => {
+            let changes = diff_modelo_720(
+                &read_modelo_720(&old, lenient)?,
+                &read_modelo_720(&new, lenient)?,
+            );
+            for change in changes.iter() {
+                println!("{}", change);
+            }
+            if changes.is_empty() {
+                println!("Both declarations have the same registros");
+            }
+        }
+        Commands::Anonymize { file, seed, out } => {
+            let mut modelo720 = read_modelo_720(&file, lenient)?;
+            let mut rng = match seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            };
+            anonymize_modelo_720(&mut modelo720, &mut rng);
+            modelo720.save_to_file(&out)?;
+        }
+        Commands::Inspect { file } => {
+            print!("{}", inspect_modelo_720_file(&file)?);
+        }
+        Commands::Report { file, out } => {
+            let report = report_modelo_720_file(&file)?;
+            match out {
+                Some(out) => std::fs::write(&out, report).map_err(|e| Error::io(&out, e))?,
+                None => print!("{}", report),
+            }
+        }
+        Commands::CheckObligation {
+            file,
+            previous_declaration,
+        } => {
+            let previous = previous_declaration
+                .map(|x| read_modelo_720(&x, lenient))
+                .transpose()?;
+            for obligation in
+                evaluate_obligation(&read_modelo_720(&file, lenient)?, previous.as_ref())
+            {
+                println!("{}", obligation);
+            }
+        }
+        Commands::CompareAeat { file, response } => {
+            let errors = compare_aeat_response(&file, &response)?;
+            for error in errors.iter() {
+                println!("{}", error);
+            }
+            if errors.is_empty() {
+                println!("No error of {} refers to a registro", response.display());
+            }
+        }
+        Commands::Validate {
+            file,
+            previous,
+            json,
+        } => {
+            let mut issues = validate_modelo_720_file(&file)?;
+            // The registros can only be compared once the file follows the record design.
+            if let (true, Some(previous)) = (issues.is_empty(), previous) {
+                let previous = read_modelo_720(&previous, lenient)?;
+                issues.extend(origin_issues(&read_modelo_720(&file, lenient)?, &previous));
+            }
+            let count = issues.len();
+            if json {
+                let report = ValidationReport::new(&file, issues);
+                let report =
+                    serde_json::to_string_pretty(&report).map_err(|source| Error::Json {
+                        path: file.clone(),
+                        source,
+                    })?;
+                println!("{}", report);
+            } else {
+                for issue in issues.iter() {
+                    println!("{}", issue);
+                }
+                if count == 0 {
+                    println!("{} is valid", file.display());
+                }
+            }
+            if count > 0 {
+                return Err(Error::Invalid { path: file, count });
+            }
+        }
+        Commands::Generate721 {
             previous_statement,
             current_statement,
             fiscal_year,
             name,
             nif,
             phone,
+            strict,
+            prices,
             out,
         } => {
-            let (previous_portfolio, current_portfolio) = match broker {
-                SupportedBrokers::InteractiveBrokers => {
-                    let previous = previous_statement
-                        .and_then(|x| parse_ibkr_statement(&x).ok())
-                        .unwrap_or(Portfolio::default());
-                    let current = parse_ibkr_statement(&current_statement).unwrap();
-                    (previous, current)
-                }
-                SupportedBrokers::Mintos => {
-                    let previous = previous_statement
-                        .and_then(|x| parse_mintos_statement(&x).ok())
-                        .unwrap_or(Portfolio::default());
-                    let current = parse_mintos_statement(&current_statement).unwrap();
-                    (previous, current)
-                }
+            let options = ParseOptions {
+                strict,
+                ..Default::default()
             };
-            let modelo720 = compute_modelo720(
-                fiscal_year,
-                &nif,
-                &name,
-                phone,
-                &current_portfolio,
-                &previous_portfolio,
-            );
-            modelo720.save_to_file(&out);
+            let prices = prices.map(|x| PriceFile::from_csv(&x)).transpose()?;
+            let prices = prices.as_ref().map(|x| x as &dyn PriceSource);
+            let mut previous = previous_statement
+                .map(|x| parse_crypto_holdings(&x, &options))
+                .transpose()?
+                .unwrap_or_default();
+            let mut current = parse_crypto_holdings(&current_statement, &options)?;
+            value_at_year_end(&mut previous, i32::from(fiscal_year) - 1, prices)?;
+            value_at_year_end(&mut current, i32::from(fiscal_year), prices)?;
+            let modelo721 = compute_modelo721(fiscal_year, &nif, &name, phone, &current, &previous);
+            modelo721.save_to_file(&out)?;
         }
     }
+    Ok(())
 }
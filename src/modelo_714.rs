@@ -0,0 +1,154 @@
+use std::fmt::Display;
+use std::path::Path;
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+use crate::modelo_720::{ClaveIdentificacion, Modelo720, Origen, Registro2, TipoBien, TipoValor};
+
+/// Apartado of the Modelo 714 (Impuesto sobre el Patrimonio) where an asset of the 720 is declared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Categoria714 {
+    BienesInmuebles,
+    Depositos,
+    DeudaNegociada,
+    DeudaNoNegociada,
+    AccionesNegociadas,
+    AccionesNoNegociadas,
+    InstitucionesInversionColectiva,
+    Seguros,
+    OtrosBienes,
+}
+
+impl Categoria714 {
+    /// Securities identified by an ISIN are taken as traded in a market, the rest as not traded.
+    pub fn of(registro: &Registro2) -> Categoria714 {
+        let negociado = matches!(
+            registro.clave_identificacion.0,
+            Some(ClaveIdentificacion::Isin)
+        );
+        match registro.tipo_bien {
+            TipoBien::Cuenta(_) => Categoria714::Depositos,
+            TipoBien::Valores(TipoValor::ParticipacionEnEntidadJuridica) if negociado => {
+                Categoria714::AccionesNegociadas
+            }
+            TipoBien::Valores(TipoValor::ParticipacionEnEntidadJuridica) => {
+                Categoria714::AccionesNoNegociadas
+            }
+            TipoBien::Valores(TipoValor::CesionDeCapitalesATerceros) if negociado => {
+                Categoria714::DeudaNegociada
+            }
+            TipoBien::Valores(TipoValor::CesionDeCapitalesATerceros) => {
+                Categoria714::DeudaNoNegociada
+            }
+            TipoBien::Valores(TipoValor::AportadosParaGestion) => Categoria714::OtrosBienes,
+            TipoBien::AccionInstitucionInversionColectiva => {
+                Categoria714::InstitucionesInversionColectiva
+            }
+            TipoBien::Seguro(_) => Categoria714::Seguros,
+            TipoBien::BienInmbueble(_) => Categoria714::BienesInmuebles,
+        }
+    }
+}
+
+impl Display for Categoria714 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Categoria714::BienesInmuebles => write!(f, "Bienes inmuebles"),
+            Categoria714::Depositos => write!(f, "Depósitos en cuenta"),
+            Categoria714::DeudaNegociada => write!(
+                f,
+                "Valores representativos de la cesión a terceros de capitales propios negociados"
+            ),
+            Categoria714::DeudaNoNegociada => write!(
+                f,
+                "Valores representativos de la cesión a terceros de capitales propios no negociados"
+            ),
+            Categoria714::AccionesNegociadas => write!(
+                f,
+                "Acciones y participaciones en el capital de entidades negociadas"
+            ),
+            Categoria714::AccionesNoNegociadas => write!(
+                f,
+                "Acciones y participaciones en el capital de entidades no negociadas"
+            ),
+            Categoria714::InstitucionesInversionColectiva => {
+                write!(f, "Acciones y participaciones en IIC")
+            }
+            Categoria714::Seguros => write!(f, "Seguros de vida y rentas"),
+            Categoria714::OtrosBienes => write!(f, "Otros bienes y derechos"),
+        }
+    }
+}
+
+/// Row of the 714 export: an asset still held at the end of the year with the value to transcribe.
+#[derive(Debug, Clone, Serialize)]
+pub struct Registro714 {
+    pub categoria: String,
+    pub clave: String,
+    pub nif_declarado: String,
+    pub identificacion: String,
+    pub entidad: String,
+    pub codigo_pais: String,
+    /// Valuation of the whole asset according to the rules of the 714.
+    pub valoracion: Decimal,
+    pub porcentaje: Decimal,
+    /// Part of `valoracion` that belongs to the declarado, which is what is declared.
+    pub valor_declarado: Decimal,
+}
+
+/// Valuation of the whole asset for the 714. Accounts are valued at the greater of the balance at the end
+/// of the year and the average balance of the last quarter. Everything else at the valoración 1 of the
+/// 720: for traded securities the 714 asks for the average price of the last quarter instead, which the
+/// statements don't give, so it has to be checked by hand.
+fn valuation(registro: &Registro2) -> Decimal {
+    match registro.tipo_bien {
+        TipoBien::Cuenta(_) => registro.valoracion1.0.max(registro.valoracion2.0),
+        _ => registro.valoracion1.0,
+    }
+}
+
+/// Rows of the assets of `modelo720` held at the end of the year, leaving out the extinguished ones,
+/// sorted by category.
+pub fn modelo_714_registros(modelo720: &Modelo720) -> Vec<Registro714> {
+    let mut entries: Vec<&Registro2> = modelo720
+        .entries
+        .iter()
+        .filter(|x| !matches!(x.origen_bien_derecho, Origen::Extincion))
+        .collect();
+    entries.sort_by_key(|x| Categoria714::of(x));
+    entries
+        .into_iter()
+        .map(|entry| {
+            let valoracion = valuation(entry);
+            let porcentaje = Decimal::new(entry.porcentaje, 2);
+            Registro714 {
+                categoria: Categoria714::of(entry).to_string(),
+                clave: entry.tipo_bien.code().to_string(),
+                nif_declarado: entry.nif_declarado.to_string(),
+                identificacion: entry.identifier().unwrap_or_default().trim().to_string(),
+                entidad: entry
+                    .identificacion_entidad
+                    .as_deref()
+                    .unwrap_or_default()
+                    .trim()
+                    .to_string(),
+                codigo_pais: entry.codigo_pais.clone(),
+                valoracion: valoracion.round_dp(2),
+                porcentaje,
+                valor_declarado: (valoracion * porcentaje / Decimal::ONE_HUNDRED).round_dp(2),
+            }
+        })
+        .collect()
+}
+
+pub fn save_modelo_714_csv(registros: &[Registro714], path: &Path) -> Result<()> {
+    let mut writer = csv::Writer::from_path(path).map_err(|e| Error::csv(path, e))?;
+    for registro in registros {
+        writer
+            .serialize(registro)
+            .map_err(|e| Error::csv(path, e))?;
+    }
+    writer.flush().map_err(|e| Error::io(path, e))
+}
@@ -1,17 +1,27 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{BufRead, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom};
 use std::iter::Sum;
 use std::ops::AddAssign;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::{fs::File, io::Write};
 
 use chrono::NaiveDate;
-use fixed_width::Reader;
+use clap::ValueEnum;
 use fixed_width_derive::FixedWidth;
 use rust_decimal::prelude::ToPrimitive;
 use rust_decimal::Decimal;
 use serde::de::Visitor;
 use serde::{de, Deserialize, Serialize};
 
+use crate::assets::Sale;
+use crate::diff::registro_key;
+use crate::error::{self, Error, Lenient};
+use crate::inspect::field;
+use crate::layout::RecordDesign;
+use crate::provenance::{load_sources, save_sources, Sources};
+use crate::validation::missing_mandatory_fields;
+
 #[derive(Clone, Copy, Debug)]
 pub enum TipoCuenta {
     Corriente,
@@ -52,12 +62,21 @@ pub enum TipoBien {
     BienInmbueble(TipoInmueble),
 }
 
-impl Serialize for TipoBien {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        let string_representation = match self {
+impl TipoBien {
+    /// Clave alone, without the subclave.
+    pub fn clave(&self) -> char {
+        match self {
+            TipoBien::Cuenta(_) => 'C',
+            TipoBien::Valores(_) => 'V',
+            TipoBien::AccionInstitucionInversionColectiva => 'I',
+            TipoBien::Seguro(_) => 'S',
+            TipoBien::BienInmbueble(_) => 'B',
+        }
+    }
+
+    /// Clave and subclave as written in the declaration, e.g. "V2".
+    pub fn code(&self) -> &'static str {
+        match self {
             TipoBien::Cuenta(modelo720_tipo_cuenta) => match modelo720_tipo_cuenta {
                 TipoCuenta::Corriente => "C1",
                 TipoCuenta::Ahorro => "C2",
@@ -65,19 +84,15 @@ impl Serialize for TipoBien {
                 TipoCuenta::Credito => "C4",
                 TipoCuenta::Otra => "C5",
             },
-            TipoBien::Valores(modelo720_tipo_valor) => &{
-                match modelo720_tipo_valor {
-                    TipoValor::ParticipacionEnEntidadJuridica => "V1",
-                    TipoValor::CesionDeCapitalesATerceros => "V2",
-                    TipoValor::AportadosParaGestion => "V3",
-                }
+            TipoBien::Valores(modelo720_tipo_valor) => match modelo720_tipo_valor {
+                TipoValor::ParticipacionEnEntidadJuridica => "V1",
+                TipoValor::CesionDeCapitalesATerceros => "V2",
+                TipoValor::AportadosParaGestion => "V3",
             },
             TipoBien::AccionInstitucionInversionColectiva => "I0",
-            TipoBien::Seguro(modelo720_tipo_seguro) => &{
-                match modelo720_tipo_seguro {
-                    TipoSeguro::DeVidaOInvalidez => "S1",
-                    TipoSeguro::RentasTemporalesOVitalicias => "S2",
-                }
+            TipoBien::Seguro(modelo720_tipo_seguro) => match modelo720_tipo_seguro {
+                TipoSeguro::DeVidaOInvalidez => "S1",
+                TipoSeguro::RentasTemporalesOVitalicias => "S2",
             },
             TipoBien::BienInmbueble(modelo720_tipo_inmueble) => match modelo720_tipo_inmueble {
                 TipoInmueble::Titularidad => "B1",
@@ -86,8 +101,16 @@ impl Serialize for TipoBien {
                 TipoInmueble::Multipropiedad => "B4",
                 TipoInmueble::Otros => "B5",
             },
-        };
-        serializer.serialize_str(string_representation)
+        }
+    }
+}
+
+impl Serialize for TipoBien {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.code())
     }
 }
 
@@ -111,7 +134,7 @@ impl<'de> Visitor<'de> for Modelo720TipoBienVisitor {
             "V3" => Ok(TipoBien::Valores(TipoValor::AportadosParaGestion)),
             "I0" => Ok(TipoBien::AccionInstitucionInversionColectiva),
             "S1" => Ok(TipoBien::Seguro(TipoSeguro::DeVidaOInvalidez)),
-            "S2" => Ok(TipoBien::Seguro(TipoSeguro::DeVidaOInvalidez)),
+            "S2" => Ok(TipoBien::Seguro(TipoSeguro::RentasTemporalesOVitalicias)),
             "B1" => Ok(TipoBien::BienInmbueble(TipoInmueble::Titularidad)),
             "B2" => Ok(TipoBien::BienInmbueble(TipoInmueble::DerechosDeUso)),
             "B3" => Ok(TipoBien::BienInmbueble(TipoInmueble::NudaPropiedad)),
@@ -135,8 +158,208 @@ impl<'de> Deserialize<'de> for TipoBien {
     }
 }
 
+/// Serializes a single-character clave with its `clave()` and deserializes it with its `from_clave()`, so
+/// codes not in the record design are flagged when reading a file.
+macro_rules! clave_serde {
+    ($type:ty, $expecting:literal) => {
+        impl Serialize for $type {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer,
+            {
+                serializer.serialize_char(self.clave())
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $type {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where
+                D: de::Deserializer<'de>,
+            {
+                let value = String::deserialize(deserializer)?;
+                let mut chars = value.trim().chars();
+                match (chars.next(), chars.next()) {
+                    (Some(clave), None) => <$type>::from_clave(clave),
+                    _ => None,
+                }
+                .ok_or_else(|| de::Error::invalid_value(de::Unexpected::Str(&value), &$expecting))
+            }
+        }
+    };
+}
+
+/// Clave de identificación of valores and acciones: whether the identificación de valores is an ISIN.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClaveIdentificacion {
+    Isin,
+    Otra,
+}
+
+impl ClaveIdentificacion {
+    pub fn clave(&self) -> char {
+        match self {
+            ClaveIdentificacion::Isin => '1',
+            ClaveIdentificacion::Otra => '2',
+        }
+    }
+
+    pub fn from_clave(clave: char) -> Option<Self> {
+        match clave {
+            '1' => Some(ClaveIdentificacion::Isin),
+            '2' => Some(ClaveIdentificacion::Otra),
+            _ => None,
+        }
+    }
+}
+
+clave_serde!(ClaveIdentificacion, "clave de identificación 1 or 2");
+
+/// Clave de identificación of a registro, which only valores and acciones have.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ClaveIdentificacionValores(pub Option<ClaveIdentificacion>);
+
+impl Serialize for ClaveIdentificacionValores {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ClaveIdentificacionValores {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        // Registros without a clave de identificación, such as accounts, have it padded with a zero.
+        match Option::<String>::deserialize(deserializer)? {
+            Some(value) if !matches!(value.trim(), "" | "0") => {
+                ClaveIdentificacion::deserialize(de::value::StrDeserializer::new(&value))
+                    .map(|x| ClaveIdentificacionValores(Some(x)))
+            }
+            _ => Ok(ClaveIdentificacionValores(None)),
+        }
+    }
+}
+
+/// Clave de identificación de cuenta: the account code is either an IBAN or some other identification.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdentificacionCuenta {
+    Iban,
+    Otra,
+}
+
+impl IdentificacionCuenta {
+    pub fn clave(&self) -> char {
+        match self {
+            IdentificacionCuenta::Iban => 'I',
+            IdentificacionCuenta::Otra => 'O',
+        }
+    }
+
+    pub fn from_clave(clave: char) -> Option<Self> {
+        match clave {
+            'I' => Some(IdentificacionCuenta::Iban),
+            'O' => Some(IdentificacionCuenta::Otra),
+            _ => None,
+        }
+    }
+}
+
+clave_serde!(
+    IdentificacionCuenta,
+    "clave de identificación de cuenta I or O"
+);
+
+/// Clave de representación de valores: whether the valores are represented by book entries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RepresentacionValores {
+    Anotaciones,
+    SinAnotaciones,
+}
+
+impl RepresentacionValores {
+    pub fn clave(&self) -> char {
+        match self {
+            RepresentacionValores::Anotaciones => 'A',
+            RepresentacionValores::SinAnotaciones => 'B',
+        }
+    }
+
+    pub fn from_clave(clave: char) -> Option<Self> {
+        match clave {
+            'A' => Some(RepresentacionValores::Anotaciones),
+            'B' => Some(RepresentacionValores::SinAnotaciones),
+            _ => None,
+        }
+    }
+}
+
+clave_serde!(
+    RepresentacionValores,
+    "clave de representación de valores A or B"
+);
+
+/// Whether a property is urban or rustic according to the cadastre.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClaseInmueble {
+    Urbano,
+    Rustico,
+}
+
+impl ClaseInmueble {
+    pub fn clave(&self) -> char {
+        match self {
+            ClaseInmueble::Urbano => 'U',
+            ClaseInmueble::Rustico => 'R',
+        }
+    }
+
+    pub fn from_clave(clave: char) -> Option<Self> {
+        match clave {
+            'U' => Some(ClaseInmueble::Urbano),
+            'R' => Some(ClaseInmueble::Rustico),
+            _ => None,
+        }
+    }
+}
+
+clave_serde!(ClaseInmueble, "clave tipo de bien inmueble U or R");
+
+/// Tipo de soporte of the header: how the declaration is presented.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum TipoSoporte {
+    /// Transmisión telemática, the only one the Sede electrónica of the AEAT accepts
+    #[default]
+    #[value(alias = "T")]
+    Telematica,
+    /// Soporte directamente legible por ordenador (DVD)
+    #[value(alias = "C")]
+    Dvd,
+}
+
+impl TipoSoporte {
+    pub fn clave(&self) -> char {
+        match self {
+            TipoSoporte::Telematica => 'T',
+            TipoSoporte::Dvd => 'C',
+        }
+    }
+
+    pub fn from_clave(clave: char) -> Option<Self> {
+        match clave {
+            'T' => Some(TipoSoporte::Telematica),
+            'C' => Some(TipoSoporte::Dvd),
+            _ => None,
+        }
+    }
+}
+
+clave_serde!(TipoSoporte, "tipo de soporte T or C");
+
 #[derive(Clone, Copy, Debug)]
-pub struct FixedWidthNumber<const NUMBERS: usize>(Decimal);
+pub struct FixedWidthNumber<const NUMBERS: usize>(pub Decimal);
 
 impl<const N: usize> FixedWidthNumber<N> {
     pub fn rounded_to_cents(&self) -> Self {
@@ -227,18 +450,51 @@ impl<'de, const N: usize> Deserialize<'de> for FixedWidthNumber<N> {
     }
 }
 
+/// Decimals of the número de valores. The record design gives it 12 digits with an implied precision: 10 for
+/// the integer part and 2 for the fraction, so brokers reporting fractional shares with more decimals lose
+/// them when declared.
+pub const SHARES_DECIMALS: u32 = 2;
+const SHARES_DIGITS: u32 = 12;
+
+/// Número de valores, written with `DECIMALS` implied decimals in its 12 digits.
 #[derive(Clone, Copy, Debug)]
-pub struct Shares(pub Decimal);
+pub struct Shares<const DECIMALS: u32 = SHARES_DECIMALS>(pub Decimal);
+
+impl<const DECIMALS: u32> Shares<DECIMALS> {
+    /// Number of valores as declared, rounded to `DECIMALS`.
+    pub fn declared(&self) -> Decimal {
+        self.0.round_dp_with_strategy(
+            DECIMALS,
+            rust_decimal::RoundingStrategy::MidpointAwayFromZero,
+        )
+    }
 
-impl Serialize for Shares {
+    /// Valores left out by rounding to the declared precision.
+    pub fn rounding_loss(&self) -> Decimal {
+        (self.0 - self.declared()).abs()
+    }
+
+    /// Whether the declared number of valores fits in the field.
+    pub fn fits(&self) -> bool {
+        let limit = Decimal::from(10u64.pow(SHARES_DIGITS - DECIMALS));
+        let declared = self.declared();
+        !declared.is_sign_negative() && declared < limit
+    }
+}
+
+impl<const DECIMALS: u32> Serialize for Shares<DECIMALS> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        let rounded_to_cents = self
-            .0
-            .round_dp_with_strategy(2, rust_decimal::RoundingStrategy::MidpointAwayFromZero);
-        serializer.serialize_i64((rounded_to_cents * Decimal::from(100)).to_i64().unwrap())
+        if !self.fits() {
+            return Err(serde::ser::Error::custom(format!(
+                "{} valores don't fit in the {} digits of the número de valores",
+                self.0, SHARES_DIGITS
+            )));
+        }
+        let scale = Decimal::from(10i64.pow(DECIMALS));
+        serializer.serialize_i64((self.declared() * scale).to_i64().unwrap())
     }
 }
 
@@ -315,14 +571,14 @@ impl<'de> Visitor<'de> for SharesVisitor {
     //   - visit_u64
 }
 
-impl<'de> Deserialize<'de> for Shares {
+impl<'de, const DECIMALS: u32> Deserialize<'de> for Shares<DECIMALS> {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
         deserializer
             .deserialize_i64(SharesVisitor)
-            .map(|cents| Shares(cents / Decimal::from(100)))
+            .map(|x| Shares(x / Decimal::from(10i64.pow(DECIMALS))))
     }
 }
 
@@ -335,8 +591,9 @@ impl Serialize for Date {
         S: serde::Serializer,
     {
         match &self.0 {
-            Some(v) => serializer.serialize_some(v.format("%Y%m%d").to_string().as_str()),
-            None => serializer.serialize_none(), // This will output 00000000 into the file.
+            Some(v) => serializer.serialize_str(v.format("%Y%m%d").to_string().as_str()),
+            // Written explicitly instead of as a none so that formats other than fixed width can read it back.
+            None => serializer.serialize_str("00000000"),
         }
     }
 }
@@ -376,34 +633,407 @@ impl<'de> Visitor<'de> for DateVisitor {
     }
 }
 
+/// Spanish tax identifier (DNI, NIE or CIF) whose check character has been verified. A typo in it would
+/// otherwise only surface as an opaque rejection from the AEAT portal.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Nif(String);
+
+const DNI_LETTERS: &[u8; 23] = b"TRWAGMYFPDXBNJZSQVHLCKE";
+const CIF_LETTERS: &[u8; 10] = b"JABCDEFGHI";
+
+impl Nif {
+    pub fn new(value: &str) -> Result<Self, String> {
+        let normalized: String = value
+            .chars()
+            .filter(|x| !x.is_whitespace() && *x != '-')
+            .collect::<String>()
+            .to_uppercase();
+        if normalized.len() != 9 || !normalized.is_ascii() {
+            return Err(format!("NIF {} must have 9 characters", value));
+        }
+        let (first, rest) = normalized.split_at(1);
+        let (body, control) = rest.split_at(7);
+        let control = control.as_bytes()[0];
+        let digits_only = |x: &str| x.chars().all(|x| x.is_ascii_digit());
+        let valid = if digits_only(&normalized[..8]) {
+            // DNI
+            let number: usize = normalized[..8].parse().unwrap();
+            DNI_LETTERS[number % 23] == control
+        } else if matches!(first, "X" | "Y" | "Z" | "K" | "L" | "M") && digits_only(body) {
+            // NIE, whose leading letter stands for a digit, and the special NIFs of K, L and M.
+            let prefix = match first {
+                "Y" => "1",
+                "Z" => "2",
+                _ => "0",
+            };
+            let number: usize = format!("{}{}", prefix, body).parse().unwrap();
+            DNI_LETTERS[number % 23] == control
+        } else if "ABCDEFGHJNPQRSUVW".contains(first) && digits_only(body) {
+            // CIF
+            let sum: u32 = body
+                .chars()
+                .map(|x| x.to_digit(10).unwrap())
+                .enumerate()
+                .map(|(index, digit)| {
+                    if index % 2 == 0 {
+                        (digit * 2) / 10 + (digit * 2) % 10
+                    } else {
+                        digit
+                    }
+                })
+                .sum();
+            let digit = ((10 - sum % 10) % 10) as usize;
+            let letter = CIF_LETTERS[digit];
+            let digit = b'0' + digit as u8;
+            match first {
+                "P" | "Q" | "R" | "S" | "W" | "N" => control == letter,
+                "A" | "B" | "E" | "H" => control == digit,
+                _ => control == letter || control == digit,
+            }
+        } else {
+            return Err(format!("{} is not a DNI, NIE or CIF", value));
+        };
+        if !valid {
+            return Err(format!("NIF {} has the wrong check character", value));
+        }
+        Ok(Nif(normalized))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// DNI with the given number and its check letter.
+    pub fn dni(number: u32) -> Nif {
+        let number = number % 100_000_000;
+        Nif(format!(
+            "{:08}{}",
+            number,
+            DNI_LETTERS[number as usize % 23] as char
+        ))
+    }
+}
+
+impl FromStr for Nif {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Nif::new(s)
+    }
+}
+
+impl std::fmt::Display for Nif {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Serialize for Nif {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+struct NifVisitor;
+
+impl<'de> Visitor<'de> for NifVisitor {
+    type Value = Nif;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("Expected a valid NIF")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Nif::new(v).map_err(E::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Nif {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_str(NifVisitor)
+    }
+}
+
+/// Contact phone of the header, kept as text so leading zeros aren't lost. The field has room for a Spanish
+/// number of 9 digits, so the +34 or 0034 prefix is dropped and any other international number is rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Telefono(String);
+
+const TELEFONO_LENGTH: usize = 9;
+
+impl Telefono {
+    pub fn new(value: &str) -> Result<Self, String> {
+        let digits: String = value
+            .chars()
+            .filter(|x| !x.is_whitespace() && !matches!(x, '-' | '.' | '(' | ')'))
+            .collect();
+        let national = if digits.len() > TELEFONO_LENGTH {
+            digits
+                .strip_prefix("+34")
+                .or_else(|| digits.strip_prefix("0034"))
+                .unwrap_or(&digits)
+        } else {
+            &digits
+        };
+        if national.is_empty()
+            || national.len() > TELEFONO_LENGTH
+            || !national.chars().all(|x| x.is_ascii_digit())
+        {
+            return Err(format!(
+                "phone {} must be a Spanish number of up to {} digits",
+                value, TELEFONO_LENGTH
+            ));
+        }
+        Ok(Telefono(national.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for Telefono {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Telefono::new(s)
+    }
+}
+
+impl std::fmt::Display for Telefono {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Serialize for Telefono {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        // Numeric field, so shorter numbers are padded with zeros on the left.
+        serializer.serialize_str(&format!("{:0>width$}", self.0, width = TELEFONO_LENGTH))
+    }
+}
+
+struct TelefonoVisitor;
+
+impl<'de> Visitor<'de> for TelefonoVisitor {
+    type Value = Telefono;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("Expected a phone number")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Telefono::new(v).map_err(E::custom)
+    }
+
+    // Profiles written before phones were text give them as TOML integers.
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Telefono::new(&v.to_string()).map_err(E::custom)
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Telefono::new(&v.to_string()).map_err(E::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Telefono {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        // Fixed-width files can only be read field by field as text. TOML still hands the integers of old
+        // profiles to visit_i64 when asked for a string.
+        deserializer.deserialize_str(TelefonoVisitor)
+    }
+}
+
+// The AEAT reads the file as ISO-8859-1 and only accepts uppercase letters without accents besides Ñ and Ç.
+// While serializing those two are swapped for control characters so every character takes a single byte
+// and the columns don't shift, and they are written as their ISO-8859-1 bytes afterwards.
+const LATIN1_SUBSTITUTES: [(char, u8, u8); 2] = [('Ñ', 0x01, 0xD1), ('Ç', 0x02, 0xC7)];
+
+pub(crate) const RECORD_LENGTH: usize = 500;
+
+// Punctuation accepted in the text fields besides letters, digits and spaces. Anything else becomes a space.
+const ALLOWED_SYMBOLS: &str = "&'(),-./:;";
+
+/// Uppercases and transliterates text to the characters accepted in the declaration, one character per
+/// letter as the user sees it, so the width of a field can be counted in characters. Accents and other
+/// combining marks are dropped, whether precomposed or written after their letter, except for Ñ and Ç.
+pub(crate) fn encode_text(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+    while let Some(base) = chars.next() {
+        let mut letter = base;
+        while let Some(&mark) = chars.peek().filter(|x| is_combining_mark(**x)) {
+            letter = match (letter.to_ascii_uppercase(), mark) {
+                ('N', '\u{303}') => 'Ñ',
+                ('C', '\u{327}') => 'Ç',
+                _ => letter,
+            };
+            chars.next();
+        }
+        for x in letter.to_uppercase() {
+            match LATIN1_SUBSTITUTES.iter().find(|(y, _, _)| *y == x) {
+                Some((_, substitute, _)) => result.push(*substitute as char),
+                None => result.extend(
+                    deunicode::deunicode_char(x)
+                        .unwrap_or(" ")
+                        .chars()
+                        .map(|x| x.to_ascii_uppercase())
+                        .map(|x| {
+                            if x.is_ascii_alphanumeric() || x == ' ' || ALLOWED_SYMBOLS.contains(x)
+                            {
+                                x
+                            } else {
+                                ' '
+                            }
+                        }),
+                ),
+            }
+        }
+    }
+    result
+}
+
+/// Combining diacritical marks, written after the letter they go on.
+fn is_combining_mark(x: char) -> bool {
+    matches!(x, '\u{300}'..='\u{36F}' | '\u{1AB0}'..='\u{1AFF}' | '\u{1DC0}'..='\u{1DFF}' | '\u{20D0}'..='\u{20FF}' | '\u{FE20}'..='\u{FE2F}')
+}
+
+/// Cuts `value` down to `width` characters, describing the truncation in `truncations` if there was one.
+pub(crate) fn fit(field: &str, width: usize, value: &mut String, truncations: &mut Vec<String>) {
+    if value.chars().count() > width {
+        let truncated: String = value.chars().take(width).collect();
+        truncations.push(format!(
+            "{} \"{}\" truncated to \"{}\"",
+            field,
+            value.trim(),
+            truncated.trim()
+        ));
+        *value = truncated;
+    }
+}
+
+/// Reverts the substitutions of [`encode_text`] once a record has been read back.
+pub(crate) fn decode_text(value: &str) -> String {
+    value
+        .chars()
+        .map(|x| {
+            LATIN1_SUBSTITUTES
+                .iter()
+                .find(|(_, substitute, _)| *substitute as char == x)
+                .map_or(x, |(letter, _, _)| *letter)
+        })
+        .collect()
+}
+
+/// Turns serialized records into the ISO-8859-1 bytes of the file, checking every record is exactly 500 bytes.
+pub(crate) fn to_latin1(serialized: &[u8]) -> Vec<u8> {
+    for record in serialized.split(|x| *x == b'\n') {
+        assert_eq!(
+            record.len(),
+            RECORD_LENGTH,
+            "Records must be exactly {} bytes",
+            RECORD_LENGTH
+        );
+    }
+    serialized
+        .iter()
+        .map(|x| {
+            LATIN1_SUBSTITUTES
+                .iter()
+                .find(|(_, substitute, _)| substitute == x)
+                .map_or(*x, |(_, _, latin1)| *latin1)
+        })
+        .collect()
+}
+
+/// Whether the file is a 720 declaration rather than a broker statement, judging by its first registro.
+pub fn is_modelo_720_file(path: &Path) -> bool {
+    let mut start = Vec::new();
+    File::open(path)
+        .and_then(|x| x.take(7).read_to_end(&mut start))
+        .is_ok()
+        && strip_bom(&start).starts_with(b"1720")
+}
+
+/// Drops the UTF-8 byte order mark some editors add at the start of a file. It isn't part of the header.
+pub(crate) fn strip_bom(contents: &[u8]) -> &[u8] {
+    contents.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(contents)
+}
+
+/// Contents of a 720 file as text. ISO-8859-1 bytes are the first 256 Unicode code points, so every byte
+/// becomes a single character and the positions of the record design still apply to the characters.
+pub fn decode_latin1(contents: &[u8]) -> String {
+    strip_bom(contents).iter().map(|x| *x as char).collect()
+}
+
+/// Inverse of [`to_latin1`]. Any other byte outside ASCII can't have been written by us and is replaced.
+pub(crate) fn from_latin1(contents: &[u8]) -> Vec<u8> {
+    contents
+        .iter()
+        .map(|x| {
+            LATIN1_SUBSTITUTES
+                .iter()
+                .find(|(_, _, latin1)| latin1 == x)
+                .map_or(
+                    if x.is_ascii() { *x } else { b'?' },
+                    |(_, substitute, _)| *substitute,
+                )
+        })
+        .collect()
+}
+
 #[derive(Clone, Deserialize, Serialize, Debug, FixedWidth)]
 pub struct Registro1 {
     #[fixed_width(range = "0..1")]
-    tipo: i8,
+    pub tipo: i8,
 
     #[fixed_width(range = "1..4")]
-    modelo_declaracion: i16,
+    pub modelo_declaracion: i16,
 
     #[fixed_width(range = "4..8")]
-    ejercicio: i16,
+    pub ejercicio: i16,
 
     #[fixed_width(range = "8..17")]
-    nif_declarante: String,
+    pub nif_declarante: Nif,
 
     #[fixed_width(
         name = "APELLIDOS Y NOMBRE, RAZÓN SOCIAL O DENOMINACIÓN DEL DECLARADO",
         range = "17..57"
     )]
-    nombre: String,
+    pub nombre: String,
 
     #[fixed_width(name = "TIPO DE SOPORTE", range = "57..58")]
-    tipo_soporte: char,
+    pub tipo_soporte: TipoSoporte,
 
     #[fixed_width(name = "TELEFONO PERSONA CONTACTO", range = "58..67")]
-    telefono: i64,
+    pub telefono: Telefono,
 
     #[fixed_width(name = "APELLIDOS Y NOMBRE PERSONA CONTACTO", range = "67..107")]
-    nombre_persona_contacto: String,
+    pub nombre_persona_contacto: String,
 
     #[fixed_width(
         name = "NÚMERO IDENTIFICATIVO DE LA DECLARACIÓN",
@@ -411,13 +1041,13 @@ pub struct Registro1 {
         justify = "right",
         pad_with = "0"
     )]
-    id_declaracion: i64,
+    pub id_declaracion: i64,
 
     #[fixed_width(name = "DECLARACIÓN COMPLEMENTARIA", range = "120..121")]
-    declaracion_complementaria: Option<char>,
+    pub declaracion_complementaria: Option<char>,
 
     #[fixed_width(name = "DECLARACIÓN SUSTITUTIVA", range = "121..122")]
-    declaracion_sustitutiva: Option<char>,
+    pub declaracion_sustitutiva: Option<char>,
 
     #[fixed_width(
         name = "NÚMERO IDENTIFICATIVO DE LA DECLARACIÓN ANTERIOR",
@@ -425,7 +1055,7 @@ pub struct Registro1 {
         justify = "right",
         pad_with = "0"
     )]
-    id_declaracion_anterior: Option<i64>,
+    pub id_declaracion_anterior: Option<i64>,
 
     #[fixed_width(
         name = "NÚMERO TOTAL DE REGISTROS DECLARADOS",
@@ -433,27 +1063,48 @@ pub struct Registro1 {
         justify = "right",
         pad_with = "0"
     )]
-    numero_registros_tipo2: usize,
+    pub numero_registros_tipo2: usize,
 
     #[fixed_width(name = "SUMA TOTAL DE VALORACIÓN 1", range = "144..162")]
-    suma_valoracion1: FixedWidthNumber<{ 162 - 144 }>,
+    pub suma_valoracion1: FixedWidthNumber<{ 162 - 144 }>,
 
     #[fixed_width(name = "SUMA TOTAL DE VALORACIÓN 2", range = "162..180")]
-    suma_valoracion2: FixedWidthNumber<{ 180 - 162 }>,
+    pub suma_valoracion2: FixedWidthNumber<{ 180 - 162 }>,
 
     #[fixed_width(name = "BLANCOS", range = "180..500")]
-    blancos: String,
+    pub blancos: String,
 }
 
 impl Registro1 {
-    fn new(ejercicio: i16, nif: String, nombre: String, telefono: i64) -> Self {
+    /// Truncates the text fields longer than their width in the record, describing each truncation.
+    pub fn truncate_to_widths(&mut self) -> Vec<String> {
+        let mut truncations = Vec::new();
+        fit("nombre", 40, &mut self.nombre, &mut truncations);
+        fit(
+            "nombre_persona_contacto",
+            40,
+            &mut self.nombre_persona_contacto,
+            &mut truncations,
+        );
+        truncations
+    }
+
+    fn map_text(&self, f: impl Fn(&str) -> String) -> Self {
+        Registro1 {
+            nombre: f(&self.nombre),
+            nombre_persona_contacto: f(&self.nombre_persona_contacto),
+            ..self.clone()
+        }
+    }
+
+    fn new(ejercicio: i16, nif: Nif, nombre: String, telefono: Telefono) -> Self {
         Registro1 {
             tipo: 1,
             modelo_declaracion: 720,
             ejercicio: ejercicio,
             nif_declarante: nif.clone(),
             nombre: nombre.clone(),
-            tipo_soporte: 'T',
+            tipo_soporte: TipoSoporte::Telematica,
             telefono,
             nombre_persona_contacto: nombre,
             id_declaracion: 720_000_000_000_0,
@@ -501,6 +1152,31 @@ impl Serialize for Titularidad {
     }
 }
 
+impl FromStr for Titularidad {
+    type Err = String;
+
+    /// Parses the clave as written in the registro, e.g. `3` for an authorised holder or `8NUDA PROPIEDAD`
+    /// for other forms of ownership followed by their description.
+    fn from_str(v: &str) -> Result<Self, Self::Err> {
+        let ownership_type = v.get(..1).unwrap_or_default();
+        let potential_str = v.get(1..).unwrap_or_default();
+        match ownership_type {
+            "1" => Ok(Titularidad::Titular),
+            "2" => Ok(Titularidad::Representate),
+            "3" => Ok(Titularidad::Autorizado),
+            "4" => Ok(Titularidad::Beneficiario),
+            "5" => Ok(Titularidad::Usufructuario),
+            "6" => Ok(Titularidad::Tomador),
+            "7" => Ok(Titularidad::ConPoderDisposicion),
+            "8" if !potential_str.trim().is_empty() => {
+                Ok(Titularidad::Otros(potential_str.to_string()))
+            }
+            "8" => Err("Other forms of ownership need a description after the 8".to_string()),
+            _ => Err(format!("Invalid ownership type {}", v)),
+        }
+    }
+}
+
 struct TitularidadVisitor;
 
 impl<'de> Visitor<'de> for TitularidadVisitor {
@@ -514,18 +1190,7 @@ impl<'de> Visitor<'de> for TitularidadVisitor {
     where
         E: de::Error,
     {
-        let (ownership_type, potential_str) = v.split_at(1);
-        match ownership_type {
-            "1" => Ok(Titularidad::Titular),
-            "2" => Ok(Titularidad::Representate),
-            "3" => Ok(Titularidad::Autorizado),
-            "4" => Ok(Titularidad::Beneficiario),
-            "5" => Ok(Titularidad::Usufructuario),
-            "6" => Ok(Titularidad::Tomador),
-            "7" => Ok(Titularidad::ConPoderDisposicion),
-            "8" => Ok(Titularidad::Otros(potential_str.to_string())),
-            _ => Err(E::custom("Invalid ownership type")),
-        }
+        Titularidad::from_str(v).map_err(E::custom)
     }
 }
 
@@ -545,17 +1210,22 @@ pub enum Origen {
     Extincion,
 }
 
+impl Origen {
+    pub fn code(&self) -> char {
+        match self {
+            Origen::Adquisicion => 'A',
+            Origen::Modificacion => 'M',
+            Origen::Extincion => 'C',
+        }
+    }
+}
+
 impl Serialize for Origen {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        let code = match self {
-            Origen::Adquisicion => 'A',
-            Origen::Modificacion => 'M',
-            Origen::Extincion => 'C',
-        };
-        serializer.serialize_char(code)
+        serializer.serialize_char(self.code())
     }
 }
 
@@ -579,6 +1249,17 @@ impl<'de> Visitor<'de> for OrigenVisitor {
             _ => Err(E::invalid_value(de::Unexpected::Char(v), &self)),
         }
     }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        let mut chars = v.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => self.visit_char(c),
+            _ => Err(E::invalid_value(de::Unexpected::Str(v), &self)),
+        }
+    }
 }
 
 impl<'de> Deserialize<'de> for Origen {
@@ -603,13 +1284,13 @@ pub struct Registro2 {
     pub ejercicio: i16,
 
     #[fixed_width(range = "8..17")]
-    pub nif_declarante: String,
+    pub nif_declarante: Nif,
 
     #[fixed_width(range = "17..26")]
-    pub nif_declarado: String,
+    pub nif_declarado: Nif,
 
     #[fixed_width(name = "N.I.F. DEL REPRESENTANTE LEGAL", range = "26..35")]
-    pub nif_representante_legal: Option<String>,
+    pub nif_representante_legal: Option<Nif>,
 
     #[fixed_width(
         name = "APELLIDOS Y NOMBRE, RAZÓN SOCIAL O DENOMINACIÓN DEL DECLARADO",
@@ -640,13 +1321,13 @@ pub struct Registro2 {
         justify = "right",
         pad_with = "0"
     )]
-    pub clave_identificacion: Option<i8>,
+    pub clave_identificacion: ClaveIdentificacionValores,
 
     #[fixed_width(name = "IDENTIFICACIÓN DE VALORES", range = "131..143")]
     pub identificacion_valores: Option<String>,
 
     #[fixed_width(name = "CLAVE IDENTIFICACIÓN DE CUENTA", range = "143..144")]
-    pub clave_identificacion_cuenta: Option<char>,
+    pub clave_identificacion_cuenta: Option<IdentificacionCuenta>,
 
     #[fixed_width(name = "CÓDIGO BIC", range = "144..155")]
     pub codigo_bic: Option<String>,
@@ -709,7 +1390,7 @@ pub struct Registro2 {
     pub valoracion2: FixedWidthNumber<{ 461 - 446 }>,
 
     #[fixed_width(name = "CLAVE DE REPRESENTACIÓN DE VALORES", range = "461..462")]
-    pub clave_representacion_valores: Option<char>,
+    pub clave_representacion_valores: Option<RepresentacionValores>,
 
     #[fixed_width(
         name = "NÚMERO DE VALORES",
@@ -717,10 +1398,10 @@ pub struct Registro2 {
         justify = "right",
         pad_with = "0"
     )]
-    pub numero_valores: Option<Shares>,
+    pub numero_valores: Option<Shares<SHARES_DECIMALS>>,
 
     #[fixed_width(name = "CLAVE TIPO DE BIEN INMUEBLE", range = "474..475")]
-    pub clave_tipo_bien_inmueble: Option<char>,
+    pub clave_tipo_bien_inmueble: Option<ClaseInmueble>,
 
     #[fixed_width(
         name = "PORCENTAJE DE PARTICIPACIÓN",
@@ -735,7 +1416,7 @@ pub struct Registro2 {
 }
 
 impl Registro2 {
-    pub fn new(ejercicio: i16, nif: String, nombre: String, codigo_pais: String) -> Self {
+    pub fn new(ejercicio: i16, nif: Nif, nombre: String, codigo_pais: String) -> Self {
         Registro2 {
             tipo: 2,
             modelo_declaracion: 720,
@@ -748,7 +1429,7 @@ impl Registro2 {
             tipo_bien: TipoBien::AccionInstitucionInversionColectiva,
             tipo_derecho_real_sobre_inmueble: None,
             codigo_pais,
-            clave_identificacion: None,
+            clave_identificacion: ClaveIdentificacionValores(None),
             identificacion_valores: None,
             clave_identificacion_cuenta: None,
             codigo_bic: None,
@@ -775,86 +1456,1049 @@ impl Registro2 {
     }
 }
 
-#[derive(Debug)]
+impl Registro2 {
+    /// Truncates the text fields longer than their width in the record, describing each truncation.
+    pub fn truncate_to_widths(&mut self) -> Vec<String> {
+        let mut truncations = Vec::new();
+        fit("nombre", 40, &mut self.nombre, &mut truncations);
+        if let Titularidad::Otros(what) = &mut self.tipo_titularidad {
+            fit("tipo_titularidad", 25, what, &mut truncations);
+        }
+        let optional_fields = [
+            (
+                "tipo_derecho_real_sobre_inmueble",
+                25,
+                &mut self.tipo_derecho_real_sobre_inmueble,
+            ),
+            (
+                "identificacion_valores",
+                12,
+                &mut self.identificacion_valores,
+            ),
+            ("codigo_bic", 11, &mut self.codigo_bic),
+            ("codigo_cuenta", 34, &mut self.codigo_cuenta),
+            (
+                "identificacion_entidad",
+                41,
+                &mut self.identificacion_entidad,
+            ),
+            (
+                "nif_pais_residencia_fiscal",
+                20,
+                &mut self.nif_pais_residencia_fiscal,
+            ),
+            (
+                "nombre_via_publica_entidad",
+                52,
+                &mut self.nombre_via_publica_entidad,
+            ),
+            ("complemento_entidad", 40, &mut self.complemento_entidad),
+            ("poblacion_entidad", 30, &mut self.poblacion_entidad),
+            ("provincia_entidad", 30, &mut self.provincia_entidad),
+            ("codigo_postal_entidad", 10, &mut self.codigo_postal_entidad),
+        ];
+        for (field, width, value) in optional_fields {
+            if let Some(value) = value {
+                fit(field, width, value, &mut truncations);
+            }
+        }
+        truncations
+    }
+
+    fn map_text(&self, f: impl Fn(&str) -> String) -> Self {
+        let optional = |x: &Option<String>| x.as_deref().map(&f);
+        Registro2 {
+            nombre: f(&self.nombre),
+            tipo_titularidad: match &self.tipo_titularidad {
+                Titularidad::Otros(what) => Titularidad::Otros(f(what)),
+                other => other.clone(),
+            },
+            tipo_derecho_real_sobre_inmueble: optional(&self.tipo_derecho_real_sobre_inmueble),
+            identificacion_valores: optional(&self.identificacion_valores),
+            codigo_cuenta: optional(&self.codigo_cuenta),
+            identificacion_entidad: optional(&self.identificacion_entidad),
+            nif_pais_residencia_fiscal: optional(&self.nif_pais_residencia_fiscal),
+            nombre_via_publica_entidad: optional(&self.nombre_via_publica_entidad),
+            complemento_entidad: optional(&self.complemento_entidad),
+            poblacion_entidad: optional(&self.poblacion_entidad),
+            provincia_entidad: optional(&self.provincia_entidad),
+            codigo_postal_entidad: optional(&self.codigo_postal_entidad),
+            ..self.clone()
+        }
+    }
+
+    /// Identifier of the declared asset: the ISIN or equivalent for valores and the account code for accounts.
+    pub fn identifier(&self) -> Option<&str> {
+        self.identificacion_valores
+            .as_deref()
+            .or(self.codigo_cuenta.as_deref())
+    }
+
+    /// Sets the porcentaje de participación from a percentage such as `50` for half of the asset, failing
+    /// unless it's more than 0 and at most 100.
+    pub fn set_ownership_percentage(&mut self, percentage: Decimal) -> Result<(), String> {
+        self.porcentaje = (percentage * Decimal::ONE_HUNDRED)
+            .round()
+            .to_i64()
+            .filter(|x| (1..=10000).contains(x))
+            .ok_or_else(|| {
+                format!(
+                    "ownership percentage {} is not between 0 and 100",
+                    percentage
+                )
+            })?;
+        Ok(())
+    }
+
+    /// Identifies the asset and operation a registro declares regardless of its valuation, so the same
+    /// registro repeated across an original declaration and its complementarias can be recognised.
+    pub fn fingerprint(&self) -> String {
+        let date = |x: &Date| x.0.map(|d| d.to_string()).unwrap_or_default();
+        [
+            self.ejercicio.to_string(),
+            self.nif_declarado.to_string(),
+            self.tipo_bien.code().to_string(),
+            self.identificacion_valores.clone().unwrap_or_default(),
+            self.codigo_cuenta.clone().unwrap_or_default(),
+            self.identificacion_entidad.clone().unwrap_or_default(),
+            self.nombre_via_publica_entidad.clone().unwrap_or_default(),
+            self.origen_bien_derecho.code().to_string(),
+            date(&self.fecha_incorporacion),
+            date(&self.fecha_extincion),
+        ]
+        .map(|x| x.trim().to_uppercase())
+        .join("|")
+    }
+}
+
+/// Values forced on the registro of an asset when the broker data is incomplete or wrong. Fields left out
+/// keep the generated value.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RegistroOverride {
+    pub clave: Option<TipoBien>,
+    pub entity_name: Option<String>,
+    pub entity_nif: Option<String>,
+    pub street: Option<String>,
+    pub complement: Option<String>,
+    pub city: Option<String>,
+    pub province: Option<String>,
+    pub zip_code: Option<String>,
+    pub entity_country: Option<String>,
+    pub deposit_country: Option<String>,
+    pub titularidad: Option<Titularidad>,
+    pub acquisition_date: Option<NaiveDate>,
+    /// Only applies to the extinction registros of the asset.
+    pub extinction_date: Option<NaiveDate>,
+    pub ownership_percentage: Option<Decimal>,
+}
+
+impl Registro2 {
+    /// Forces the fields given on the registro, failing if the ownership percentage given is out of range.
+    pub fn apply_override(&mut self, fields: &RegistroOverride) -> Result<(), String> {
+        let text = |value: &Option<String>| value.as_ref().map(|x| x.to_uppercase());
+        if let Some(clave) = fields.clave {
+            self.tipo_bien = clave;
+        }
+        self.identificacion_entidad =
+            text(&fields.entity_name).or(self.identificacion_entidad.take());
+        self.nif_pais_residencia_fiscal =
+            text(&fields.entity_nif).or(self.nif_pais_residencia_fiscal.take());
+        self.nombre_via_publica_entidad =
+            text(&fields.street).or(self.nombre_via_publica_entidad.take());
+        self.complemento_entidad = text(&fields.complement).or(self.complemento_entidad.take());
+        self.poblacion_entidad = text(&fields.city).or(self.poblacion_entidad.take());
+        self.provincia_entidad = text(&fields.province).or(self.provincia_entidad.take());
+        self.codigo_postal_entidad = text(&fields.zip_code).or(self.codigo_postal_entidad.take());
+        self.codigo_pais_entidad = text(&fields.entity_country).or(self.codigo_pais_entidad.take());
+        if let Some(country) = text(&fields.deposit_country) {
+            self.codigo_pais = country;
+        }
+        if let Some(titularidad) = &fields.titularidad {
+            self.tipo_titularidad = titularidad.clone();
+        }
+        if fields.acquisition_date.is_some() {
+            self.fecha_incorporacion = Date(fields.acquisition_date);
+        }
+        if let (Origen::Extincion, Some(date)) = (&self.origen_bien_derecho, fields.extinction_date)
+        {
+            self.fecha_extincion = Date(Some(date));
+        }
+        if let Some(percentage) = fields.ownership_percentage {
+            self.set_ownership_percentage(percentage)?;
+        }
+        Ok(())
+    }
+}
+
+/// Which registro `concat` keeps when more than one file holds the same asset and origen, e.g. when the
+/// output of the same run is given twice.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Dedupe {
+    /// Keep the registros of the file given first
+    First,
+    /// Keep the registros of the file given last
+    Last,
+}
+
+/// Order of the registros of a declaration. The AEAT doesn't require any, it only makes files easier to
+/// review and compare from one year to the next.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SortOrder {
+    /// By ISIN or account code
+    Isin,
+    /// By clave de bien, then ISIN or account code
+    Clave,
+    /// By valoración 1, largest first
+    Valuation,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Modelo720 {
     // TODO: These should definitely be private
     pub header: Registro1,
     pub entries: Vec<Registro2>,
+    /// Where the asset of each registro was read from, keyed by the registro identifier. Kept next to the
+    /// file, see [`crate::provenance::sources_path`].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sources: Sources,
+}
+
+/// A registro by its [`registro_key`], the identificación de la entidad and the country where it is held: the
+/// same asset held at two brokers is two holdings rather than the same one declared twice.
+fn shared_registro_key(registro: &Registro2) -> String {
+    format!(
+        "{} [{}, {}]",
+        registro_key(registro),
+        registro
+            .identificacion_entidad
+            .as_deref()
+            .unwrap_or_default()
+            .trim(),
+        registro.codigo_pais
+    )
 }
 
 impl Modelo720 {
     pub fn new(
         ejercicio: i16,
-        nif: &str,
+        nif: &Nif,
         nombre: &str,
-        telefono: i64,
+        telefono: Telefono,
         entries: Vec<Registro2>,
     ) -> Modelo720 {
         let mut result = Modelo720 {
-            header: Registro1::new(ejercicio, nif.to_string(), nombre.to_string(), telefono),
+            header: Registro1::new(ejercicio, nif.clone(), nombre.to_string(), telefono),
             entries,
+            sources: Sources::new(),
         };
-        result.header.numero_registros_tipo2 = result.entries.len();
-        result.header.suma_valoracion1 = FixedWidthNumber(
-            result
-                .entries
+        result.recompute_totals();
+        result
+    }
+
+    /// Recomputes the header count and valuation sums from the current registros.
+    pub fn recompute_totals(&mut self) {
+        self.header.numero_registros_tipo2 = self.entries.len();
+        self.header.suma_valoracion1 = FixedWidthNumber(
+            self.entries
                 .iter()
                 .map(|x| x.valoracion1.rounded_to_cents())
                 .sum::<FixedWidthNumber<15>>()
                 .0,
         );
-        result.header.suma_valoracion2 = FixedWidthNumber(
-            result
-                .entries
+        self.header.suma_valoracion2 = FixedWidthNumber(
+            self.entries
                 .iter()
                 .map(|x| x.valoracion2.rounded_to_cents())
                 .sum::<FixedWidthNumber<15>>()
                 .0,
         );
-        result
     }
 
-    pub fn from_path(path: &Path) -> Modelo720 {
-        let mut reader = Reader::from_file(path)
-            .unwrap()
-            .width(500)
-            .linebreak(fixed_width::LineBreak::Newline);
-        let mut actual_reader = reader.byte_reader();
-        let header = actual_reader
-            .next()
-            .and_then(|x| fixed_width::from_bytes(&x.unwrap()).ok());
-        let mut tipo_2_entries: Vec<Registro2> = Vec::new();
-        while let Some(entry) = actual_reader.next() {
-            let entry_tipo_2 = entry
-                .ok()
-                .map(|x| fixed_width::from_bytes(&x).unwrap())
-                .unwrap();
-            tipo_2_entries.push(entry_tipo_2);
+    /// Reads a 720 file. Some software writes the registros in several declarations, each with its own header;
+    /// those of the same declarante and ejercicio are read as a single one.
+    pub fn from_path(path: &Path) -> error::Result<Modelo720> {
+        Modelo720::merged(path, Modelo720::read_declarations(path, None)?)
+    }
+
+    /// Like [`Modelo720::from_path`], skipping the registros that can't be read and collecting their errors in
+    /// `lenient`. The first header must still be readable.
+    pub fn from_path_lenient(path: &Path, lenient: &Lenient) -> error::Result<Modelo720> {
+        Modelo720::merged(path, Modelo720::read_declarations(path, Some(lenient))?)
+    }
+
+    /// Every declaration of a 720 file, each header with the registros that follow it.
+    pub fn from_path_all(path: &Path) -> error::Result<Vec<Modelo720>> {
+        Modelo720::read_declarations(path, None)
+    }
+
+    fn read_declarations(path: &Path, lenient: Option<&Lenient>) -> error::Result<Vec<Modelo720>> {
+        let mut reader = Modelo720Reader::open(path)?;
+        let sources = load_sources(path)?;
+        let mut result = Vec::new();
+        loop {
+            let header = reader.header().clone();
+            let entries = match lenient {
+                Some(lenient) => reader.by_ref().filter_map(|x| lenient.recover(x)).collect(),
+                None => reader.by_ref().collect::<error::Result<Vec<Registro2>>>()?,
+            };
+            result.push(Modelo720 {
+                header,
+                entries,
+                sources: sources.clone(),
+            });
+            if !reader.next_declaration() {
+                return Ok(result);
+            }
+        }
+    }
+
+    fn merged(path: &Path, declarations: Vec<Modelo720>) -> error::Result<Modelo720> {
+        let mut declarations = declarations.into_iter();
+        // read_declarations always returns at least the first one.
+        let mut result = declarations.next().unwrap();
+        for declaration in declarations {
+            result.concat(declaration).map_err(|e| {
+                Error::InvalidInput(format!(
+                    "{}: holds several declarations and {}, split it with a separate file per declaration",
+                    path.display(),
+                    e
+                ))
+            })?;
+        }
+        Ok(result)
+    }
+
+    pub fn save_to_file(&self, path: &Path) -> error::Result<()> {
+        let mut writer = Modelo720Writer::create(path, &self.header)?;
+        for entry in self.entries.iter() {
+            writer.write(entry)?;
         }
-        Modelo720 {
-            header: header.unwrap(),
-            entries: tipo_2_entries,
+        writer.finish()?;
+        save_sources(path, &self.sources_of_entries())
+    }
+
+    /// The sources of the registros of the declaration, leaving out those of registros no longer in it.
+    pub fn sources_of_entries(&self) -> Sources {
+        self.entries
+            .iter()
+            .filter_map(|x| x.identifier())
+            .filter_map(|x| {
+                let identifier = x.trim();
+                Some((
+                    identifier.to_string(),
+                    self.sources.get(identifier)?.clone(),
+                ))
+            })
+            .collect()
+    }
+
+    /// Reads back a file just written and checks that writing what was read gives the very same bytes, so
+    /// every field parses to the value it was written from. Fails with the first line and field that differ.
+    pub fn verify_file(path: &Path) -> error::Result<()> {
+        let written = std::fs::read(path).map_err(|e| Error::io(path, e))?;
+        let rewritten = Modelo720::from_path(path)?.to_bytes()?;
+        if written == rewritten {
+            return Ok(());
         }
+        let (written, rewritten) = (decode_latin1(&written), decode_latin1(&rewritten));
+        let design = RecordDesign::of_contents(&written);
+        let mismatch = written
+            .lines()
+            .zip(rewritten.lines())
+            .enumerate()
+            .find(|(_, (a, b))| a != b);
+        let message = match mismatch {
+            Some((index, (a, b))) => {
+                let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+                let fields = match a.first() {
+                    Some('1') => design.registro1_fields(),
+                    _ => design.registro2_fields(),
+                };
+                let (name, range) = fields
+                    .iter()
+                    .find(|(_, range)| field(&a, range) != field(&b, range))
+                    .map(|(name, range)| (*name, range.clone()))
+                    .unwrap_or(("LONGITUD DEL REGISTRO", 0..a.len().max(b.len())));
+                format!(
+                    "line {}, {} was written as {:?} but reads back as {:?}",
+                    index + 1,
+                    name,
+                    field(&a, &range),
+                    field(&b, &range)
+                )
+            }
+            None => format!(
+                "has {} records but reads back as {}",
+                written.lines().count(),
+                rewritten.lines().count()
+            ),
+        };
+        Err(Error::InvalidInput(format!(
+            "{}: {}",
+            path.display(),
+            message
+        )))
     }
 
-    pub fn save_to_file(&self, path: &Path) {
-        let file = File::create(path).unwrap();
+    /// The declaration as the ISO-8859-1 bytes of the file submitted to the AEAT. Registros missing
+    /// mandatory fields are kept, to be reported by [`crate::validation::validate_modelo_720`]. Fails like
+    /// writing the file would on values that don't fit in their field.
+    pub fn to_bytes(&self) -> error::Result<Vec<u8>> {
         let mut writer =
-            fixed_width::Writer::from_writer(file).linebreak(fixed_width::LineBreak::Newline);
-        writer
-            .write_serialized(std::iter::once(self.header.clone()))
-            .unwrap();
-        writer.write_linebreak().unwrap();
-        writer
-            .write_serialized(self.entries.iter().cloned())
-            .unwrap();
-        writer.flush().unwrap();
-    }
-
-    pub fn concat(&mut self, mut other: Modelo720) {
-        self.header.numero_registros_tipo2 += other.header.numero_registros_tipo2;
-        self.header.suma_valoracion1 += other.header.suma_valoracion1;
-        self.header.suma_valoracion2 += other.header.suma_valoracion2;
+            Modelo720Writer::new(Path::new("<memory>"), Cursor::new(Vec::new()), &self.header)?
+                .without_mandatory_checks();
+        for entry in self.entries.iter() {
+            writer.write(entry)?;
+        }
+        Ok(writer.finish()?.into_inner())
+    }
+
+    /// Reads a declaration previously written with [`Modelo720::save_to_json`].
+    pub fn from_json_path(path: &Path) -> error::Result<Modelo720> {
+        let file = File::open(path).map_err(|e| Error::io(path, e))?;
+        serde_json::from_reader(std::io::BufReader::new(file)).map_err(|source| Error::Json {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Writes the declaration as pretty-printed JSON so it can be reviewed and edited by hand.
+    pub fn save_to_json(&self, path: &Path) -> error::Result<()> {
+        let file = File::create(path).map_err(|e| Error::io(path, e))?;
+        serde_json::to_writer_pretty(file, self).map_err(|source| Error::Json {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Builds the complete picture of an ejercicio from the original declaration followed by its complementarias.
+    /// Registros present in several of them are kept once, with the values from the latest declaration.
+    pub fn merge_complementarias(declarations: Vec<Modelo720>) -> Option<Modelo720> {
+        let mut declarations = declarations.into_iter();
+        let mut result = declarations.next()?;
+        for mut declaration in declarations {
+            result.entries.append(&mut declaration.entries);
+        }
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        let mut entries: Vec<Registro2> = Vec::new();
+        for entry in result.entries {
+            match positions.get(&entry.fingerprint()) {
+                Some(&position) => entries[position] = entry,
+                None => {
+                    positions.insert(entry.fingerprint(), entries.len());
+                    entries.push(entry);
+                }
+            }
+        }
+        result.entries = entries;
+        result.header.declaracion_complementaria = None;
+        result.recompute_totals();
+        Some(result)
+    }
+
+    /// Declares the share of each asset the declarante owns. Valuations stay in full as the AEAT expects,
+    /// only the porcentaje de participación changes.
+    ///
+    /// `overrides` are keyed by the registro identifier and always win. `default` only applies to registros
+    /// still at 100%, so shares already stated in the input, such as for real estate, are kept.
+    pub fn apply_ownership(
+        &mut self,
+        default: Option<Decimal>,
+        overrides: &HashMap<String, Decimal>,
+    ) -> Result<(), String> {
+        for entry in self.entries.iter_mut() {
+            let percentage = entry
+                .identifier()
+                .and_then(|x| overrides.get(x.trim()))
+                .copied()
+                .or(default.filter(|_| entry.porcentaje == 10000));
+            if let Some(percentage) = percentage {
+                let identifier = entry.identifier().unwrap_or_default().trim().to_string();
+                entry
+                    .set_ownership_percentage(percentage)
+                    .map_err(|e| format!("{}: {}", identifier, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Forces the fields given in `overrides`, keyed by the registro identifier, on the registros of each asset.
+    pub fn apply_registro_overrides(
+        &mut self,
+        overrides: &HashMap<String, RegistroOverride>,
+    ) -> Result<(), String> {
+        for entry in self.entries.iter_mut() {
+            let Some((identifier, fields)) = entry
+                .identifier()
+                .and_then(|x| overrides.get_key_value(x.trim()))
+            else {
+                continue;
+            };
+            entry
+                .apply_override(fields)
+                .map_err(|e| format!("{}: {}", identifier, e))?;
+        }
+        self.recompute_totals();
+        Ok(())
+    }
+
+    /// Marks the declaration as replacing entirely the one identified by `id_declaracion_anterior`.
+    pub fn mark_sustitutiva(&mut self, id_declaracion_anterior: i64) {
+        self.header.declaracion_complementaria = None;
+        self.header.declaracion_sustitutiva = Some('S');
+        self.header.id_declaracion_anterior = Some(id_declaracion_anterior);
+    }
+
+    /// Values the extinction registros at what was actually received for them and dates them on the sale,
+    /// instead of the price of the remaining position at year end. `sales` are keyed by the registro identifier.
+    /// A sale split over several registros, one per lot, is shared out in proportion to their valores.
+    pub fn apply_sales(&mut self, sales: &HashMap<String, Sale>) {
+        let key = |entry: &Registro2| entry.identifier().map(|x| x.trim().to_uppercase());
+        let mut sold_shares: HashMap<String, (Decimal, usize)> = HashMap::new();
+        for entry in self.entries.iter() {
+            if let (Origen::Extincion, Some(key)) = (&entry.origen_bien_derecho, key(entry)) {
+                let total = sold_shares.entry(key).or_default();
+                total.0 += entry.numero_valores.map(|x| x.0).unwrap_or_default();
+                total.1 += 1;
+            }
+        }
+        for entry in self.entries.iter_mut() {
+            if !matches!(entry.origen_bien_derecho, Origen::Extincion) {
+                continue;
+            }
+            let Some(key) = key(entry) else {
+                continue;
+            };
+            if let (Some(sale), Some((shares, registros))) =
+                (sales.get(&key), sold_shares.get(&key))
+            {
+                let share = match entry.numero_valores {
+                    Some(x) if *shares > Decimal::ZERO => x.0 / shares,
+                    _ => Decimal::ONE / Decimal::from(*registros),
+                };
+                entry.valoracion1 = FixedWidthNumber(sale.proceeds * share);
+                entry.fecha_extincion = Date(Some(sale.date));
+            }
+        }
+        self.recompute_totals();
+    }
+
+    /// Declares the country of the entity of the registros in `countries`, keyed by the registro identifier.
+    pub fn apply_entity_countries(&mut self, countries: &HashMap<String, String>) {
+        for entry in self.entries.iter_mut() {
+            if let Some(country) = entry.identifier().and_then(|x| countries.get(x.trim())) {
+                entry.codigo_pais_entidad = Some(country.clone());
+            }
+        }
+    }
+
+    /// Fills the fecha de incorporación of the acquisition registros that don't have one yet, with `dates`
+    /// keyed by the registro identifier. Returns the identifiers of the acquisitions still without a date.
+    pub fn apply_acquisition_dates(&mut self, dates: &HashMap<String, NaiveDate>) -> Vec<String> {
+        let mut missing = Vec::new();
+        for entry in self.entries.iter_mut() {
+            if !matches!(entry.origen_bien_derecho, Origen::Adquisicion)
+                || entry.fecha_incorporacion.0.is_some()
+            {
+                continue;
+            }
+            let identifier = entry
+                .identifier()
+                .map(|x| x.trim().to_uppercase())
+                .unwrap_or_default();
+            match dates.get(&identifier) {
+                Some(date) => entry.fecha_incorporacion = Date(Some(*date)),
+                None => missing.push(identifier),
+            }
+        }
+        missing
+    }
+
+    /// Declares the registros on behalf of someone else, such as a minor child, instead of the declarante.
+    pub fn set_declarado(
+        &mut self,
+        nif: &Nif,
+        nombre: &str,
+        nif_representante_legal: Option<&Nif>,
+    ) {
+        for entry in self.entries.iter_mut() {
+            entry.nif_declarado = nif.clone();
+            entry.nombre = nombre.to_string();
+            entry.nif_representante_legal = nif_representante_legal.cloned();
+        }
+    }
+
+    /// Declares the condition under which the declarante holds each asset, with `overrides` keyed by the
+    /// registro identifier taking precedence over `default`.
+    pub fn apply_titularidad(
+        &mut self,
+        default: Option<&Titularidad>,
+        overrides: &HashMap<String, Titularidad>,
+    ) {
+        for entry in self.entries.iter_mut() {
+            let titularidad = entry
+                .identifier()
+                .and_then(|x| overrides.get(x.trim()))
+                .or(default);
+            if let Some(titularidad) = titularidad {
+                entry.tipo_titularidad = titularidad.clone();
+            }
+        }
+    }
+
+    /// Keeps the registros of the claves de bien in `include`, or of every clave if it's empty, except those
+    /// in `exclude`, and recomputes the totals.
+    pub fn retain_claves(&mut self, include: &[char], exclude: &[char]) {
+        self.entries.retain(|entry| {
+            let clave = entry.tipo_bien.clave();
+            (include.is_empty() || include.contains(&clave)) && !exclude.contains(&clave)
+        });
+        self.recompute_totals();
+    }
+
+    /// Sorts the registros by `order`. The sort is stable, so registros of the same asset such as an
+    /// acquisition and an extinction keep the order they were generated in.
+    pub fn sort_entries(&mut self, order: SortOrder) {
+        let identifier = |x: &Registro2| x.identifier().unwrap_or_default().trim().to_string();
+        match order {
+            SortOrder::Isin => self.entries.sort_by_key(identifier),
+            SortOrder::Clave => self
+                .entries
+                .sort_by_key(|x| (x.tipo_bien.code(), identifier(x))),
+            SortOrder::Valuation => self
+                .entries
+                .sort_by_key(|x| std::cmp::Reverse(x.valoracion1.0)),
+        }
+    }
+
+    /// Partitions the registros by clave de bien, each part with the same header and its own totals.
+    pub fn split_by_clave(self) -> BTreeMap<char, Modelo720> {
+        let mut result: BTreeMap<char, Modelo720> = BTreeMap::new();
+        for entry in self.entries {
+            result
+                .entry(entry.tipo_bien.clave())
+                .or_insert_with(|| Modelo720 {
+                    header: self.header.clone(),
+                    entries: Vec::new(),
+                    sources: self.sources.clone(),
+                })
+                .entries
+                .push(entry);
+        }
+        for modelo720 in result.values_mut() {
+            modelo720.recompute_totals();
+        }
+        result
+    }
+
+    /// Keys of the registros of `other` that this declaration already has, as given by their
+    /// [`crate::diff::registro_key`] along with the entity and the country where they are held, so the same
+    /// asset held at two brokers isn't taken for a duplicate. Registros sharing a key within the same
+    /// declaration, such as the extinctions of several lots, are fine.
+    pub fn shared_registros(&self, other: &Modelo720) -> BTreeSet<String> {
+        let keys: BTreeSet<String> = self.entries.iter().map(shared_registro_key).collect();
+        other
+            .entries
+            .iter()
+            .map(shared_registro_key)
+            .filter(|x| keys.contains(x))
+            .collect()
+    }
+
+    /// Removes the registros with any of the `keys` returned by [`Modelo720::shared_registros`] and
+    /// recomputes the totals.
+    pub fn remove_registros(&mut self, keys: &BTreeSet<String>) {
+        self.entries
+            .retain(|x| !keys.contains(&shared_registro_key(x)));
+        self.recompute_totals();
+    }
+
+    /// Appends the registros of another declaration of the same declarante and ejercicio. The totals are
+    /// recomputed from the merged registros, so headers broken by hand edits don't carry over.
+    pub fn concat(&mut self, other: Modelo720) -> Result<(), String> {
+        self.check_same_declarante(&other)?;
+        self.append(other);
+        Ok(())
+    }
+
+    /// Fails unless `other` has the same ejercicio, NIF and name of the declarante, telling which differs.
+    pub fn check_same_declarante(&self, other: &Modelo720) -> Result<(), String> {
+        if self.header.ejercicio != other.header.ejercicio {
+            return Err(format!(
+                "ejercicio {} doesn't match {}",
+                other.header.ejercicio, self.header.ejercicio
+            ));
+        }
+        if self.header.nif_declarante != other.header.nif_declarante {
+            return Err(format!(
+                "NIF del declarante {} doesn't match {}",
+                other.header.nif_declarante, self.header.nif_declarante
+            ));
+        }
+        if self.header.nombre.trim() != other.header.nombre.trim() {
+            return Err(format!(
+                "nombre del declarante {} doesn't match {}",
+                other.header.nombre.trim(),
+                self.header.nombre.trim()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Like [`Modelo720::concat`] without checking that both declarations are of the same declarante and
+    /// ejercicio. The header stays the one of this declaration.
+    pub fn append(&mut self, mut other: Modelo720) {
         self.entries.append(&mut other.entries);
+        for (identifier, sources) in other.sources {
+            let known = self.sources.entry(identifier).or_default();
+            for source in sources {
+                if !known.contains(&source) {
+                    known.push(source);
+                }
+            }
+        }
+        self.recompute_totals();
+    }
+}
+
+/// Reads the registros of a 720 file one at a time, so declarations with hundreds of thousands of them can
+/// be processed without loading them whole. Records are told apart by their tipo: registros are yielded
+/// under the last header read, and records of other tipos, such as the padding some software adds, are
+/// skipped.
+pub struct Modelo720Reader<R: BufRead> {
+    path: PathBuf,
+    records: R,
+    design: RecordDesign,
+    header: Registro1,
+    // Header of the next declaration of the file, once the registros of the current one are over.
+    next_header: Option<(RecordDesign, Registro1)>,
+    line: usize,
+}
+
+impl Modelo720Reader<BufReader<File>> {
+    pub fn open(path: &Path) -> error::Result<Self> {
+        let file = File::open(path).map_err(|e| Error::io(path, e))?;
+        Modelo720Reader::new(path, BufReader::new(file))
+    }
+}
+
+impl<R: BufRead> Modelo720Reader<R> {
+    /// Reads the header from `records`. The `path` is only used to report errors.
+    pub fn new(path: &Path, mut records: R) -> error::Result<Self> {
+        let mut line = 0;
+        let header_error = |line: usize, message: String| Error::Record {
+            path: path.to_path_buf(),
+            line,
+            field: "REGISTRO DE TIPO 1".to_string(),
+            message,
+        };
+        let (design, header) = loop {
+            let Some(record) = read_record(&mut records, path, &mut line, "REGISTRO DE TIPO 1")?
+            else {
+                return Err(header_error(1, "the file is empty".to_string()));
+            };
+            match record[0] {
+                b'1' => break parse_header(path, line, &record)?,
+                b'2' => {
+                    return Err(header_error(
+                        line,
+                        "the file doesn't start with a header".to_string(),
+                    ))
+                }
+                tipo => skip_record(path, line, tipo),
+            }
+        };
+        Ok(Modelo720Reader {
+            path: path.to_path_buf(),
+            records,
+            design,
+            header,
+            next_header: None,
+            line,
+        })
+    }
+
+    /// Header of the declaration whose registros are being read.
+    pub fn header(&self) -> &Registro1 {
+        &self.header
+    }
+
+    /// Record design of the declaration being read, the one of its ejercicio.
+    pub fn design(&self) -> RecordDesign {
+        self.design
+    }
+
+    /// Moves on to the next declaration of the file, once all the registros of the current one have been
+    /// read. Returns whether there was one.
+    pub fn next_declaration(&mut self) -> bool {
+        match self.next_header.take() {
+            Some((design, header)) => {
+                self.design = design;
+                self.header = header;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Modelo720Reader<R> {
+    type Item = error::Result<Registro2>;
+
+    /// The next registro of the current declaration, `None` once a header or the end of the file is reached.
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_header.is_some() {
+            return None;
+        }
+        loop {
+            let record = match read_record(
+                &mut self.records,
+                &self.path,
+                &mut self.line,
+                "REGISTRO DE TIPO 2",
+            ) {
+                Ok(record) => record?,
+                Err(e) => return Some(Err(e)),
+            };
+            match record[0] {
+                b'2' => {
+                    let entry = match self.design {
+                        RecordDesign::Orden72_2013 => fixed_width::from_bytes::<Registro2>(&record),
+                    }
+                    .map_err(|e| Error::Record {
+                        path: self.path.clone(),
+                        line: self.line,
+                        field: "REGISTRO DE TIPO 2".to_string(),
+                        message: e.to_string(),
+                    });
+                    return Some(entry.map(|x| x.map_text(decode_text)));
+                }
+                b'1' => {
+                    return match parse_header(&self.path, self.line, &record) {
+                        Ok(next) => {
+                            self.next_header = Some(next);
+                            None
+                        }
+                        Err(e) => Some(Err(e)),
+                    }
+                }
+                tipo => skip_record(&self.path, self.line, tipo),
+            }
+        }
+    }
+}
+
+/// Parses a header with the record design of its ejercicio, which every design keeps in the same position.
+fn parse_header(
+    path: &Path,
+    line: usize,
+    record: &[u8],
+) -> error::Result<(RecordDesign, Registro1)> {
+    let record_error = |field: &str, message: String| Error::Record {
+        path: path.to_path_buf(),
+        line,
+        field: field.to_string(),
+        message,
+    };
+    let ejercicio = std::str::from_utf8(&record[4..8])
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| record_error("EJERCICIO", "is not numeric".to_string()))?;
+    let design = RecordDesign::required_for(ejercicio)
+        .map_err(|e| record_error("EJERCICIO", e.to_string()))?;
+    let header: Registro1 = match design {
+        RecordDesign::Orden72_2013 => fixed_width::from_bytes(record),
+    }
+    .map_err(|e| record_error("REGISTRO DE TIPO 1", e.to_string()))?;
+    Ok((design, header.map_text(decode_text)))
+}
+
+fn skip_record(path: &Path, line: usize, tipo: u8) {
+    // Blank records are padding and not worth a warning.
+    if tipo != b' ' {
+        eprintln!(
+            "Warning: {}: line {}: skipping a record of tipo {}",
+            path.display(),
+            line,
+            tipo as char
+        );
+    }
+}
+
+/// Reads the next record of a 720 file without its line break, or `None` at the end of the file. `line` is the
+/// number of the last line read. Files saved on Windows or downloaded from the AEAT portal may have CRLF line
+/// breaks, a UTF-8 byte order mark and blank lines at the end, which are skipped.
+fn read_record(
+    records: &mut impl BufRead,
+    path: &Path,
+    line: &mut usize,
+    field: &str,
+) -> error::Result<Option<Vec<u8>>> {
+    let record_error = |line: usize, message: String| Error::Record {
+        path: path.to_path_buf(),
+        line,
+        field: field.to_string(),
+        message,
+    };
+    let mut blank_lines = 0;
+    loop {
+        let mut record = Vec::with_capacity(RECORD_LENGTH + 2);
+        if records
+            .read_until(b'\n', &mut record)
+            .map_err(|e| Error::io(path, e))?
+            == 0
+        {
+            return Ok(None);
+        }
+        *line += 1;
+        while matches!(record.last(), Some(b'\n' | b'\r')) {
+            record.pop();
+        }
+        if *line == 1 {
+            record = strip_bom(&record).to_vec();
+        }
+        // A whole record of blanks is padding, but shorter blank lines are only accepted at the end.
+        if record.len() < RECORD_LENGTH && record.iter().all(u8::is_ascii_whitespace) {
+            blank_lines += 1;
+            continue;
+        }
+        if blank_lines > 0 {
+            return Err(record_error(
+                *line - blank_lines,
+                "blank line between records".to_string(),
+            ));
+        }
+        if record.len() != RECORD_LENGTH {
+            return Err(record_error(
+                *line,
+                format!(
+                    "expected {} characters but found {}",
+                    RECORD_LENGTH,
+                    record.len()
+                ),
+            ));
+        }
+        return Ok(Some(from_latin1(&record)));
+    }
+}
+
+/// Writes a 720 file one registro at a time. The header goes first in the file but its totals are only known
+/// once every registro has been written, so [`Modelo720Writer::finish`] goes back and rewrites it.
+pub struct Modelo720Writer<W: Write + Seek> {
+    path: PathBuf,
+    writer: W,
+    design: RecordDesign,
+    header: Registro1,
+    /// Whether registros missing a field their clave makes mandatory are refused.
+    check_mandatory: bool,
+}
+
+impl Modelo720Writer<BufWriter<File>> {
+    pub fn create(path: &Path, header: &Registro1) -> error::Result<Self> {
+        let file = File::create(path).map_err(|e| Error::io(path, e))?;
+        Modelo720Writer::new(path, BufWriter::new(file), header)
+    }
+}
+
+impl<W: Write + Seek> Modelo720Writer<W> {
+    /// Writes `header` with its totals zeroed. The `path` is only used to report errors.
+    pub fn new(path: &Path, writer: W, header: &Registro1) -> error::Result<Self> {
+        let mut header = header.map_text(encode_text);
+        for truncation in header.truncate_to_widths() {
+            eprintln!("Warning: header: {}", truncation);
+        }
+        header.numero_registros_tipo2 = 0;
+        header.suma_valoracion1 = FixedWidthNumber(Decimal::ZERO);
+        header.suma_valoracion2 = FixedWidthNumber(Decimal::ZERO);
+        let design = RecordDesign::required_for(header.ejercicio)?;
+        let mut result = Modelo720Writer {
+            path: path.to_path_buf(),
+            writer,
+            design,
+            header,
+            check_mandatory: true,
+        };
+        result.write_header()?;
+        Ok(result)
+    }
+
+    /// Writes the registros missing fields their clave makes mandatory too, so they can be validated.
+    pub fn without_mandatory_checks(mut self) -> Self {
+        self.check_mandatory = false;
+        self
+    }
+
+    /// The header with the totals of the registros written so far.
+    pub fn header(&self) -> &Registro1 {
+        &self.header
+    }
+
+    pub fn write(&mut self, registro: &Registro2) -> error::Result<()> {
+        let index = self.header.numero_registros_tipo2 + 1;
+        let mut registro = registro.map_text(encode_text);
+        for truncation in registro.truncate_to_widths() {
+            eprintln!("Warning: registro {}: {}", index, truncation);
+        }
+        let serialized = self.serialize(&registro, index + 1, "REGISTRO DE TIPO 2")?;
+        if self.check_mandatory {
+            let chars: Vec<char> = decode_latin1(&serialized).chars().collect();
+            if let Some(missing) = missing_mandatory_fields(&chars).first() {
+                return Err(Error::Record {
+                    path: self.path.clone(),
+                    line: index + 1,
+                    field: missing.to_string(),
+                    message: format!(
+                        "is mandatory for clave {} of {}",
+                        registro.tipo_bien.code(),
+                        registro.identifier().unwrap_or_default().trim()
+                    ),
+                });
+            }
+        }
+        self.writer
+            .write_all(b"\n")
+            .and_then(|_| self.writer.write_all(&serialized))
+            .map_err(|e| Error::io(&self.path, e))?;
+        self.header.numero_registros_tipo2 = index;
+        self.header.suma_valoracion1.0 += registro.valoracion1.rounded_to_cents().0;
+        self.header.suma_valoracion2.0 += registro.valoracion2.rounded_to_cents().0;
+        Ok(())
+    }
+
+    /// Rewrites the header with the totals of every registro written and returns the underlying writer.
+    pub fn finish(mut self) -> error::Result<W> {
+        self.writer
+            .seek(SeekFrom::Start(0))
+            .map_err(|e| Error::io(&self.path, e))?;
+        self.write_header()?;
+        self.writer.flush().map_err(|e| Error::io(&self.path, e))?;
+        Ok(self.writer)
+    }
+
+    fn write_header(&mut self) -> error::Result<()> {
+        let serialized = self.serialize(&self.header, 1, "REGISTRO DE TIPO 1")?;
+        self.writer
+            .write_all(&serialized)
+            .map_err(|e| Error::io(&self.path, e))
+    }
+
+    fn serialize(
+        &self,
+        record: &(impl Serialize + fixed_width::FixedWidth),
+        line: usize,
+        field: &str,
+    ) -> error::Result<Vec<u8>> {
+        // Registro1 and Registro2 follow the current design. A revision would map them to records of its own
+        // here, keeping this one for the ejercicios before it.
+        let serialized = match self.design {
+            RecordDesign::Orden72_2013 => fixed_width::to_bytes(record),
+        }
+        .map_err(|e| Error::Record {
+            path: self.path.clone(),
+            line,
+            field: field.to_string(),
+            message: e.to_string(),
+        })?;
+        Ok(to_latin1(&serialized))
     }
 }
@@ -0,0 +1,441 @@
+use std::path::Path;
+use std::str::FromStr;
+use std::{fs::File, io::Write};
+
+use fixed_width_derive::FixedWidth;
+use rust_decimal::Decimal;
+use serde::de::Visitor;
+use serde::{de, Deserialize, Serialize};
+
+use crate::error::{self, Error};
+use crate::modelo_720::{
+    encode_text, fit, to_latin1, Date, FixedWidthNumber, Nif, Origen, Telefono, TipoSoporte,
+    Titularidad,
+};
+
+/// Number of decimals the 721 keeps for the amount of coins, which are far more divisible than shares.
+const MONEDAS_DECIMALS: u32 = 10;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Monedas(pub Decimal);
+
+impl Serialize for Monedas {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        // Amounts can overflow an i64 once scaled, so the digits are written as a string.
+        let scaled = (self.0.abs() * Decimal::from(10_u64.pow(MONEDAS_DECIMALS))).round();
+        serializer.serialize_str(&scaled.to_string())
+    }
+}
+
+struct MonedasVisitor;
+
+impl<'de> Visitor<'de> for MonedasVisitor {
+    type Value = Monedas;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("Expected an amount of coins")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        let mut value = Decimal::from_str(v.trim()).map_err(|e| E::custom(e.to_string()))?;
+        value
+            .set_scale(value.scale() + MONEDAS_DECIMALS)
+            .map_err(|e| E::custom(e.to_string()))?;
+        Ok(Monedas(value.normalize()))
+    }
+}
+
+impl<'de> Deserialize<'de> for Monedas {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_str(MonedasVisitor)
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug, FixedWidth)]
+pub struct Registro1Modelo721 {
+    #[fixed_width(range = "0..1")]
+    pub tipo: i8,
+
+    #[fixed_width(range = "1..4")]
+    pub modelo_declaracion: i16,
+
+    #[fixed_width(range = "4..8")]
+    pub ejercicio: i16,
+
+    #[fixed_width(range = "8..17")]
+    pub nif_declarante: Nif,
+
+    #[fixed_width(
+        name = "APELLIDOS Y NOMBRE, RAZÓN SOCIAL O DENOMINACIÓN DEL DECLARADO",
+        range = "17..57"
+    )]
+    pub nombre: String,
+
+    #[fixed_width(name = "TIPO DE SOPORTE", range = "57..58")]
+    pub tipo_soporte: TipoSoporte,
+
+    #[fixed_width(name = "TELEFONO PERSONA CONTACTO", range = "58..67")]
+    pub telefono: Telefono,
+
+    #[fixed_width(name = "APELLIDOS Y NOMBRE PERSONA CONTACTO", range = "67..107")]
+    pub nombre_persona_contacto: String,
+
+    #[fixed_width(
+        name = "NÚMERO IDENTIFICATIVO DE LA DECLARACIÓN",
+        range = "107..120",
+        justify = "right",
+        pad_with = "0"
+    )]
+    pub id_declaracion: i64,
+
+    #[fixed_width(name = "DECLARACIÓN COMPLEMENTARIA", range = "120..121")]
+    pub declaracion_complementaria: Option<char>,
+
+    #[fixed_width(name = "DECLARACIÓN SUSTITUTIVA", range = "121..122")]
+    pub declaracion_sustitutiva: Option<char>,
+
+    #[fixed_width(
+        name = "NÚMERO IDENTIFICATIVO DE LA DECLARACIÓN ANTERIOR",
+        range = "122..135",
+        justify = "right",
+        pad_with = "0"
+    )]
+    pub id_declaracion_anterior: Option<i64>,
+
+    #[fixed_width(
+        name = "NÚMERO TOTAL DE REGISTROS DECLARADOS",
+        range = "135..144",
+        justify = "right",
+        pad_with = "0"
+    )]
+    pub numero_registros_tipo2: usize,
+
+    #[fixed_width(name = "SUMA TOTAL DE VALORACIÓN", range = "144..162")]
+    pub suma_valoracion: FixedWidthNumber<{ 162 - 144 }>,
+
+    #[fixed_width(name = "BLANCOS", range = "162..500")]
+    pub blancos: String,
+}
+
+impl Registro1Modelo721 {
+    /// Truncates the text fields longer than their width in the record, describing each truncation.
+    pub fn truncate_to_widths(&mut self) -> Vec<String> {
+        let mut truncations = Vec::new();
+        fit("nombre", 40, &mut self.nombre, &mut truncations);
+        fit(
+            "nombre_persona_contacto",
+            40,
+            &mut self.nombre_persona_contacto,
+            &mut truncations,
+        );
+        truncations
+    }
+
+    fn map_text(&self, f: impl Fn(&str) -> String) -> Self {
+        Registro1Modelo721 {
+            nombre: f(&self.nombre),
+            nombre_persona_contacto: f(&self.nombre_persona_contacto),
+            ..self.clone()
+        }
+    }
+
+    fn new(ejercicio: i16, nif: Nif, nombre: String, telefono: Telefono) -> Self {
+        Registro1Modelo721 {
+            tipo: 1,
+            modelo_declaracion: 721,
+            ejercicio,
+            nif_declarante: nif,
+            nombre: nombre.clone(),
+            tipo_soporte: TipoSoporte::Telematica,
+            telefono,
+            nombre_persona_contacto: nombre,
+            id_declaracion: 7_210_000_000_000,
+            declaracion_complementaria: None,
+            declaracion_sustitutiva: None,
+            id_declaracion_anterior: None,
+            numero_registros_tipo2: 0,
+            suma_valoracion: FixedWidthNumber(Decimal::ZERO),
+            blancos: String::default(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug, FixedWidth)]
+pub struct Registro2Modelo721 {
+    #[fixed_width(range = "0..1")]
+    pub tipo: i8,
+
+    #[fixed_width(range = "1..4")]
+    pub modelo_declaracion: i16,
+
+    #[fixed_width(range = "4..8")]
+    pub ejercicio: i16,
+
+    #[fixed_width(range = "8..17")]
+    pub nif_declarante: Nif,
+
+    #[fixed_width(range = "17..26")]
+    pub nif_declarado: Nif,
+
+    #[fixed_width(name = "N.I.F. DEL REPRESENTANTE LEGAL", range = "26..35")]
+    pub nif_representante_legal: Option<Nif>,
+
+    #[fixed_width(
+        name = "APELLIDOS Y NOMBRE, RAZÓN SOCIAL O DENOMINACIÓN DEL DECLARADO",
+        range = "35..75"
+    )]
+    pub nombre: String,
+
+    #[fixed_width(
+        name = "CLAVE DE CONDICIÓN DEL DECLARANTE Y TIPO DE TITULARIDAD SOBRE LA MONEDA VIRTUAL",
+        range = "75..101",
+        pad_with = " ",
+        justify = "left"
+    )]
+    pub tipo_titularidad: Titularidad,
+
+    #[fixed_width(name = "CLAVE TIPO DE BIEN O DERECHO", range = "101..102")]
+    pub clave_tipo_bien: char,
+
+    #[fixed_width(name = "SUBCLAVE TIPO DE BIEN O DERECHO", range = "102..103")]
+    pub subclave_tipo_bien: i8,
+
+    #[fixed_width(name = "DENOMINACIÓN DE LA MONEDA VIRTUAL", range = "103..203")]
+    pub denominacion_moneda: String,
+
+    #[fixed_width(name = "IDENTIFICACIÓN DE LA ENTIDAD", range = "203..244")]
+    pub identificacion_entidad: Option<String>,
+
+    #[fixed_width(
+        name = "NÚMERO DE IDENTIFICACIÓN FISCAL EN EL PAÍS DE RESIDENCIA FISCAl",
+        range = "244..264"
+    )]
+    pub nif_pais_residencia_fiscal: Option<String>,
+
+    #[fixed_width(name = "NOMBRE VÍA PUBLICA Y NÚMERO DE CASA", range = "264..316")]
+    pub nombre_via_publica_entidad: Option<String>,
+
+    #[fixed_width(name = "COMPLEMENTO", range = "316..356")]
+    pub complemento_entidad: Option<String>,
+
+    #[fixed_width(name = "POBLACIÓN/CIUDAD", range = "356..386")]
+    pub poblacion_entidad: Option<String>,
+
+    #[fixed_width(name = "PROVINCIA/REGIÓN/ESTADO", range = "386..416")]
+    pub provincia_entidad: Option<String>,
+
+    #[fixed_width(name = "CÓDIGO POSTAL (ZIP CODE)", range = "416..426")]
+    pub codigo_postal_entidad: Option<String>,
+
+    #[fixed_width(name = "CÓDIGO PAÍS", range = "426..428")]
+    pub codigo_pais_entidad: Option<String>,
+
+    #[fixed_width(
+        name = "FECHA DE INCORPORACIÓN",
+        range = "428..436",
+        justify = "right",
+        pad_with = "0"
+    )]
+    pub fecha_incorporacion: Date,
+
+    #[fixed_width(name = "ORIGEN DEL BIEN O DERECHO", range = "436..437")]
+    pub origen_bien_derecho: Origen,
+
+    #[fixed_width(
+        name = "FECHA DE EXTINCIÓN",
+        range = "437..445",
+        justify = "right",
+        pad_with = "0"
+    )]
+    pub fecha_extincion: Date,
+
+    #[fixed_width(name = "VALORACIÓN", range = "445..460")]
+    pub valoracion: FixedWidthNumber<{ 460 - 445 }>,
+
+    #[fixed_width(
+        name = "NÚMERO DE MONEDAS VIRTUALES",
+        range = "460..482",
+        justify = "right",
+        pad_with = "0"
+    )]
+    pub numero_monedas: Monedas,
+
+    #[fixed_width(
+        name = "PORCENTAJE DE PARTICIPACIÓN",
+        range = "482..487",
+        justify = "right",
+        pad_with = "0"
+    )]
+    pub porcentaje: i64,
+
+    #[fixed_width(name = "BLANCOS", range = "487..500")]
+    pub blancos: String,
+}
+
+impl Registro2Modelo721 {
+    /// Truncates the text fields longer than their width in the record, describing each truncation.
+    pub fn truncate_to_widths(&mut self) -> Vec<String> {
+        let mut truncations = Vec::new();
+        fit("nombre", 40, &mut self.nombre, &mut truncations);
+        if let Titularidad::Otros(what) = &mut self.tipo_titularidad {
+            fit("tipo_titularidad", 25, what, &mut truncations);
+        }
+        fit(
+            "denominacion_moneda",
+            100,
+            &mut self.denominacion_moneda,
+            &mut truncations,
+        );
+        let optional_fields = [
+            (
+                "identificacion_entidad",
+                41,
+                &mut self.identificacion_entidad,
+            ),
+            (
+                "nif_pais_residencia_fiscal",
+                20,
+                &mut self.nif_pais_residencia_fiscal,
+            ),
+            (
+                "nombre_via_publica_entidad",
+                52,
+                &mut self.nombre_via_publica_entidad,
+            ),
+            ("complemento_entidad", 40, &mut self.complemento_entidad),
+            ("poblacion_entidad", 30, &mut self.poblacion_entidad),
+            ("provincia_entidad", 30, &mut self.provincia_entidad),
+            ("codigo_postal_entidad", 10, &mut self.codigo_postal_entidad),
+        ];
+        for (field, width, value) in optional_fields {
+            if let Some(value) = value {
+                fit(field, width, value, &mut truncations);
+            }
+        }
+        truncations
+    }
+
+    fn map_text(&self, f: impl Fn(&str) -> String) -> Self {
+        let optional = |x: &Option<String>| x.as_deref().map(&f);
+        Registro2Modelo721 {
+            nombre: f(&self.nombre),
+            tipo_titularidad: match &self.tipo_titularidad {
+                Titularidad::Otros(what) => Titularidad::Otros(f(what)),
+                other => other.clone(),
+            },
+            denominacion_moneda: f(&self.denominacion_moneda),
+            identificacion_entidad: optional(&self.identificacion_entidad),
+            nif_pais_residencia_fiscal: optional(&self.nif_pais_residencia_fiscal),
+            nombre_via_publica_entidad: optional(&self.nombre_via_publica_entidad),
+            complemento_entidad: optional(&self.complemento_entidad),
+            poblacion_entidad: optional(&self.poblacion_entidad),
+            provincia_entidad: optional(&self.provincia_entidad),
+            codigo_postal_entidad: optional(&self.codigo_postal_entidad),
+            ..self.clone()
+        }
+    }
+
+    pub fn new(ejercicio: i16, nif: Nif, nombre: String, denominacion_moneda: String) -> Self {
+        Registro2Modelo721 {
+            tipo: 2,
+            modelo_declaracion: 721,
+            ejercicio,
+            nif_declarante: nif.clone(),
+            nif_declarado: nif,
+            nif_representante_legal: None,
+            nombre,
+            tipo_titularidad: Titularidad::Titular,
+            // Virtual currencies held by a custodian abroad, the only ones the 721 covers.
+            clave_tipo_bien: 'M',
+            subclave_tipo_bien: 1,
+            denominacion_moneda,
+            identificacion_entidad: None,
+            nif_pais_residencia_fiscal: None,
+            nombre_via_publica_entidad: None,
+            complemento_entidad: None,
+            poblacion_entidad: None,
+            provincia_entidad: None,
+            codigo_postal_entidad: None,
+            codigo_pais_entidad: None,
+            fecha_incorporacion: Date(None),
+            origen_bien_derecho: Origen::Adquisicion,
+            fecha_extincion: Date(None),
+            valoracion: FixedWidthNumber(Decimal::ZERO),
+            numero_monedas: Monedas(Decimal::ZERO),
+            porcentaje: 10000,
+            blancos: String::default(),
+        }
+    }
+}
+
+/// Declaration of virtual currencies held abroad. It shares the 720's layout conventions but has a single valuation.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Modelo721 {
+    pub header: Registro1Modelo721,
+    pub entries: Vec<Registro2Modelo721>,
+}
+
+impl Modelo721 {
+    pub fn new(
+        ejercicio: i16,
+        nif: &Nif,
+        nombre: &str,
+        telefono: Telefono,
+        entries: Vec<Registro2Modelo721>,
+    ) -> Self {
+        let mut result = Modelo721 {
+            header: Registro1Modelo721::new(ejercicio, nif.clone(), nombre.to_string(), telefono),
+            entries,
+        };
+        result.recompute_totals();
+        result
+    }
+
+    /// Recomputes the header count and valuation sum from the current registros.
+    pub fn recompute_totals(&mut self) {
+        self.header.numero_registros_tipo2 = self.entries.len();
+        self.header.suma_valoracion = FixedWidthNumber(
+            self.entries
+                .iter()
+                .map(|x| x.valoracion.rounded_to_cents())
+                .sum::<FixedWidthNumber<15>>()
+                .0,
+        );
+    }
+
+    /// Writes the declaration in ISO-8859-1, like [`crate::modelo_720::Modelo720::save_to_file`].
+    pub fn save_to_file(&self, path: &Path) -> error::Result<()> {
+        let mut serialized = Vec::new();
+        {
+            let mut writer = fixed_width::Writer::from_writer(&mut serialized)
+                .linebreak(fixed_width::LineBreak::Newline);
+            let mut header = self.header.map_text(encode_text);
+            for truncation in header.truncate_to_widths() {
+                eprintln!("Warning: header: {}", truncation);
+            }
+            let entries = self.entries.iter().enumerate().map(|(index, x)| {
+                let mut entry = x.map_text(encode_text);
+                for truncation in entry.truncate_to_widths() {
+                    eprintln!("Warning: registro {}: {}", index + 1, truncation);
+                }
+                entry
+            });
+            writer.write_serialized(std::iter::once(header)).unwrap();
+            writer.write_linebreak().unwrap();
+            writer.write_serialized(entries).unwrap();
+            writer.flush().unwrap();
+        }
+        let mut file = File::create(path).map_err(|e| Error::io(path, e))?;
+        file.write_all(&to_latin1(&serialized))
+            .map_err(|e| Error::io(path, e))
+    }
+}
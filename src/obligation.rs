@@ -0,0 +1,365 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use rust_decimal::Decimal;
+
+use crate::modelo_720::{Modelo720, Origen, Registro2};
+
+/// Each of the three groups of assets whose obligation to be declared is evaluated separately.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Bloque {
+    Cuentas,
+    ValoresYSeguros,
+    Inmuebles,
+}
+
+impl Bloque {
+    pub fn of(registro: &Registro2) -> Bloque {
+        match registro.tipo_bien.clave() {
+            'C' => Bloque::Cuentas,
+            'B' => Bloque::Inmuebles,
+            _ => Bloque::ValoresYSeguros,
+        }
+    }
+}
+
+impl Display for Bloque {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Bloque::Cuentas => write!(f, "Cuentas (C)"),
+            Bloque::ValoresYSeguros => write!(f, "Valores, IIC y seguros (V, I, S)"),
+            Bloque::Inmuebles => write!(f, "Inmuebles (B)"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reason {
+    /// First declaration of the bloque and its value exceeds 50.000 €.
+    ExceedsThreshold,
+    /// Declared before and its value grew more than 20.000 € since.
+    Increased,
+    /// Declared before and some of its assets were extinguished during the year.
+    Extinguished,
+}
+
+impl Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reason::ExceedsThreshold => write!(f, "value exceeds 50.000 €"),
+            Reason::Increased => write!(
+                f,
+                "value grew more than 20.000 € since the last declaration"
+            ),
+            Reason::Extinguished => write!(f, "assets declared before were extinguished"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Obligation {
+    pub bloque: Bloque,
+    pub total: Decimal,
+    /// Value of the bloque in the last submitted declaration, if it was declared.
+    pub previous_total: Option<Decimal>,
+    /// Why the bloque must be declared, `None` if it doesn't need to.
+    pub reason: Option<Reason>,
+}
+
+impl Obligation {
+    pub fn obligated(&self) -> bool {
+        self.reason.is_some()
+    }
+}
+
+impl Display for Obligation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.bloque, self.total)?;
+        if let Some(previous_total) = self.previous_total {
+            write!(f, " (previously {})", previous_total)?;
+        }
+        match &self.reason {
+            Some(reason) => write!(f, ", must be declared: {}", reason),
+            None => write!(f, ", doesn't need to be declared"),
+        }
+    }
+}
+
+// Value of each bloque that counts towards the thresholds. For accounts it's the greater of the
+// balances at year end and the average balance of the last quarter. What was extinguished during the year
+// is no longer held, so it doesn't count.
+fn totals(modelo720: &Modelo720) -> BTreeMap<Bloque, Decimal> {
+    let mut result: BTreeMap<Bloque, (Decimal, Decimal)> = BTreeMap::new();
+    for entry in modelo720.entries.iter() {
+        let total = result.entry(Bloque::of(entry)).or_default();
+        if matches!(entry.origen_bien_derecho, Origen::Extincion) {
+            continue;
+        }
+        total.0 += entry.valoracion1.0;
+        total.1 += entry.valoracion2.0;
+    }
+    result
+        .into_iter()
+        .map(|(bloque, (valoracion1, valoracion2))| match bloque {
+            Bloque::Cuentas => (bloque, valoracion1.max(valoracion2)),
+            _ => (bloque, valoracion1),
+        })
+        .collect()
+}
+
+/// Decides which bloques of `current` must be declared. Without a previous declaration a bloque is declared
+/// when it exceeds 50.000 €. Once declared, it only needs to be declared again when it grew more than
+/// 20.000 € since `previous` or when any of its assets were extinguished.
+pub fn evaluate_obligation(current: &Modelo720, previous: Option<&Modelo720>) -> Vec<Obligation> {
+    let threshold = Decimal::new(50_000, 0);
+    let increase = Decimal::new(20_000, 0);
+    let current_totals = totals(current);
+    let previous_totals = previous.map(totals).unwrap_or_default();
+    let mut bloques: Vec<Bloque> = current_totals
+        .keys()
+        .chain(previous_totals.keys())
+        .copied()
+        .collect();
+    bloques.sort();
+    bloques.dedup();
+    bloques
+        .into_iter()
+        .map(|bloque| {
+            let total = current_totals.get(&bloque).copied().unwrap_or_default();
+            let previous_total = previous_totals.get(&bloque).copied();
+            let extinguished = current.entries.iter().any(|x| {
+                Bloque::of(x) == bloque && matches!(x.origen_bien_derecho, Origen::Extincion)
+            });
+            let reason = match previous_total {
+                None if total > threshold => Some(Reason::ExceedsThreshold),
+                None => None,
+                Some(previous_total) if total - previous_total > increase => {
+                    Some(Reason::Increased)
+                }
+                Some(_) if extinguished => Some(Reason::Extinguished),
+                Some(_) => None,
+            };
+            Obligation {
+                bloque,
+                total,
+                previous_total,
+                reason,
+            }
+        })
+        .collect()
+}
+
+/// Removes the registros of the bloques that don't need to be declared, returning the obligation of
+/// each bloque so the caller can report what was left out.
+pub fn retain_obligated(
+    modelo720: &mut Modelo720,
+    previous: Option<&Modelo720>,
+) -> Vec<Obligation> {
+    let obligations = evaluate_obligation(modelo720, previous);
+    modelo720.entries.retain(|entry| {
+        obligations
+            .iter()
+            .any(|x| x.bloque == Bloque::of(entry) && x.obligated())
+    });
+    modelo720.recompute_totals();
+    obligations
+}
+
+/// Keeps only what must be declared given the last submitted declaration: bloques that exceed the
+/// thresholds in full, and of the bloques that didn't grow enough only the extinguished assets.
+pub fn retain_required(modelo720: &mut Modelo720, previous: &Modelo720) -> Vec<Obligation> {
+    let obligations = evaluate_obligation(modelo720, Some(previous));
+    modelo720.entries.retain(|entry| {
+        let obligation = obligations.iter().find(|x| x.bloque == Bloque::of(entry));
+        match obligation.and_then(|x| x.reason.as_ref()) {
+            Some(Reason::Extinguished) => {
+                matches!(entry.origen_bien_derecho, Origen::Extincion)
+            }
+            Some(_) => true,
+            None => false,
+        }
+    });
+    modelo720.recompute_totals();
+    obligations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modelo_720::{FixedWidthNumber, Nif, Telefono, TipoBien, TipoCuenta};
+
+    fn registro(tipo_bien: TipoBien, valoracion1: i64, origen: Origen) -> Registro2 {
+        let nif = Nif::new("12345678Z").unwrap();
+        let mut result = Registro2::new(2024, nif, "DOE JOHN".to_string(), "IE".to_string());
+        result.tipo_bien = tipo_bien;
+        result.valoracion1 = FixedWidthNumber(Decimal::new(valoracion1, 0));
+        result.origen_bien_derecho = origen;
+        result
+    }
+
+    fn fund(valoracion1: i64, origen: Origen) -> Registro2 {
+        registro(
+            TipoBien::AccionInstitucionInversionColectiva,
+            valoracion1,
+            origen,
+        )
+    }
+
+    fn declaration(entries: Vec<Registro2>) -> Modelo720 {
+        let nif = Nif::new("12345678Z").unwrap();
+        Modelo720::new(
+            2024,
+            &nif,
+            "DOE JOHN",
+            Telefono::new("600000000").unwrap(),
+            entries,
+        )
+    }
+
+    fn reason(obligations: &[Obligation], bloque: Bloque) -> Option<Reason> {
+        obligations
+            .iter()
+            .find(|x| x.bloque == bloque)
+            .and_then(|x| x.reason.clone())
+    }
+
+    #[test]
+    fn first_declaration_depends_on_the_threshold() {
+        let above = declaration(vec![
+            fund(30_000, Origen::Adquisicion),
+            fund(20_001, Origen::Adquisicion),
+        ]);
+        let obligations = evaluate_obligation(&above, None);
+        assert_eq!(
+            reason(&obligations, Bloque::ValoresYSeguros),
+            Some(Reason::ExceedsThreshold)
+        );
+
+        let at = declaration(vec![fund(50_000, Origen::Adquisicion)]);
+        let obligations = evaluate_obligation(&at, None);
+        assert_eq!(reason(&obligations, Bloque::ValoresYSeguros), None);
+    }
+
+    #[test]
+    fn sales_dont_count_towards_the_thresholds() {
+        let first = declaration(vec![
+            fund(40_000, Origen::Adquisicion),
+            fund(15_000, Origen::Extincion),
+        ]);
+        let obligations = evaluate_obligation(&first, None);
+        assert_eq!(reason(&obligations, Bloque::ValoresYSeguros), None);
+
+        let previous = declaration(vec![
+            fund(60_000, Origen::Adquisicion),
+            fund(30_000, Origen::Extincion),
+        ]);
+        let current = declaration(vec![fund(85_000, Origen::Modificacion)]);
+        let obligations = evaluate_obligation(&current, Some(&previous));
+        assert_eq!(
+            reason(&obligations, Bloque::ValoresYSeguros),
+            Some(Reason::Increased)
+        );
+    }
+
+    #[test]
+    fn growth_over_20000_must_be_declared_in_full() {
+        let previous = declaration(vec![fund(60_000, Origen::Adquisicion)]);
+        let mut current = declaration(vec![
+            fund(70_000, Origen::Modificacion),
+            fund(10_001, Origen::Adquisicion),
+        ]);
+        let obligations = retain_required(&mut current, &previous);
+        assert_eq!(
+            reason(&obligations, Bloque::ValoresYSeguros),
+            Some(Reason::Increased)
+        );
+        assert_eq!(current.entries.len(), 2);
+        assert_eq!(current.header.numero_registros_tipo2, 2);
+    }
+
+    #[test]
+    fn growth_of_20000_or_less_is_left_out() {
+        let previous = declaration(vec![fund(60_000, Origen::Adquisicion)]);
+        let mut current = declaration(vec![
+            fund(70_000, Origen::Modificacion),
+            fund(10_000, Origen::Adquisicion),
+        ]);
+        let obligations = retain_required(&mut current, &previous);
+        assert_eq!(reason(&obligations, Bloque::ValoresYSeguros), None);
+        assert!(current.entries.is_empty());
+        assert_eq!(current.header.numero_registros_tipo2, 0);
+        assert_eq!(current.header.suma_valoracion1.0, Decimal::ZERO);
+    }
+
+    #[test]
+    fn unchanged_bloques_are_left_out() {
+        let previous = declaration(vec![
+            fund(60_000, Origen::Adquisicion),
+            registro(
+                TipoBien::Cuenta(TipoCuenta::Corriente),
+                55_000,
+                Origen::Adquisicion,
+            ),
+        ]);
+        let mut current = declaration(vec![
+            fund(60_000, Origen::Modificacion),
+            registro(
+                TipoBien::Cuenta(TipoCuenta::Corriente),
+                55_000,
+                Origen::Modificacion,
+            ),
+        ]);
+        let obligations = retain_required(&mut current, &previous);
+        assert!(obligations.iter().all(|x| !x.obligated()));
+        assert!(current.entries.is_empty());
+    }
+
+    #[test]
+    fn sales_keep_only_the_extinguished_registros() {
+        let previous = declaration(vec![
+            fund(60_000, Origen::Adquisicion),
+            fund(20_000, Origen::Adquisicion),
+            registro(
+                TipoBien::Cuenta(TipoCuenta::Corriente),
+                55_000,
+                Origen::Adquisicion,
+            ),
+        ]);
+        let mut current = declaration(vec![
+            fund(65_000, Origen::Modificacion),
+            fund(20_000, Origen::Extincion),
+            registro(
+                TipoBien::Cuenta(TipoCuenta::Corriente),
+                55_000,
+                Origen::Modificacion,
+            ),
+        ]);
+        let obligations = retain_required(&mut current, &previous);
+        assert_eq!(
+            reason(&obligations, Bloque::ValoresYSeguros),
+            Some(Reason::Extinguished)
+        );
+        assert_eq!(reason(&obligations, Bloque::Cuentas), None);
+        assert_eq!(current.entries.len(), 1);
+        assert!(matches!(
+            current.entries[0].origen_bien_derecho,
+            Origen::Extincion
+        ));
+        assert_eq!(current.header.suma_valoracion1.0, Decimal::new(20_000, 0));
+    }
+
+    #[test]
+    fn growth_takes_precedence_over_sales() {
+        let previous = declaration(vec![fund(60_000, Origen::Adquisicion)]);
+        let mut current = declaration(vec![
+            fund(90_000, Origen::Adquisicion),
+            fund(5_000, Origen::Extincion),
+        ]);
+        let obligations = retain_required(&mut current, &previous);
+        assert_eq!(
+            reason(&obligations, Bloque::ValoresYSeguros),
+            Some(Reason::Increased)
+        );
+        assert_eq!(current.entries.len(), 2);
+    }
+}
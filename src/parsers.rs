@@ -1,14 +1,423 @@
-use std::collections::HashMap;
-use std::path::Path;
-use std::rc::Rc;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 
+use calamine::{open_workbook_auto_from_rs, Data, Reader};
+use chrono::{NaiveDate, NaiveTime};
 use clap::ValueEnum;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252};
 use once_cell::sync::Lazy;
+use rayon::prelude::*;
 use regex::Regex;
 use rust_decimal::Decimal;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 
-use crate::assets::{AssetWithValuation, Etf, MintosNote, Portfolio};
+use crate::assets::{
+    Address, AssetWithValuation, BankAccount, Bond, BondListing, CryptoAsset, Etf, Identification,
+    Insurance, Lot, LotsByIsin, MintosNote, OtherAsset, PensionPlan, Portfolio, RealEstate, Sale,
+    StockSplit, UnlistedShares, ValuationPolicy,
+};
+use crate::classification::Classification;
+use crate::countries::is_valid_country;
+use crate::entities::EntityAddress;
+use crate::error::{Error, Lenient, Result};
+use crate::modelo_720::{
+    ClaseInmueble, IdentificacionCuenta, Nif, RegistroOverride, Telefono, TipoBien, TipoCuenta,
+    TipoSoporte, Titularidad,
+};
+use crate::provenance::Provenance;
+use crate::validation::{looks_like_iban, normalize_bic, normalize_iban, normalize_isin};
+
+/// Options shared by all the statement parsers.
+#[derive(Clone, Debug, Default)]
+pub struct ParseOptions {
+    /// Password used to open encrypted ZIP statements.
+    pub password: Option<String>,
+    /// Reject hand-written input files with unknown keys instead of only warning about them.
+    pub strict: bool,
+    /// Where the assets of the statements are deposited when it differs from the broker default.
+    pub countries: CountryMapping,
+    /// What to do with the assets valued at zero or less.
+    pub valuation_policy: ValuationPolicy,
+    /// Skip the rows of the statements that can't be read, collecting their errors, instead of failing.
+    pub lenient: Option<Lenient>,
+    /// Fail on short positions instead of leaving them out with a warning.
+    pub fail_on_shorts: bool,
+    /// Declare the cash balances of the statements as accounts instead of leaving them out.
+    pub include_cash: bool,
+}
+
+impl ParseOptions {
+    /// The row, or in lenient mode `None` once its error is collected so the row is skipped.
+    fn recover<T>(&self, row: Result<T>) -> Result<Option<T>> {
+        match &self.lenient {
+            Some(lenient) => Ok(lenient.recover(row)),
+            None => row.map(Some),
+        }
+    }
+}
+
+/// Countries to declare for the assets of a statement. Brokers such as IBKR operate through entities in
+/// several countries, so the deposit country depends on the account, and some ISINs aren't issued in the
+/// country of their prefix.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct CountryMapping {
+    /// Deposit country of every asset in the statements, instead of the broker default.
+    pub deposit_country: Option<String>,
+    /// Deposit country of the assets of each account, for brokers that report it.
+    #[serde(default)]
+    pub deposit_country_by_account: HashMap<String, String>,
+    /// Deposit country of specific ISINs, which always wins.
+    #[serde(default)]
+    pub deposit_country_by_isin: HashMap<String, String>,
+    /// Country of the issuer of specific ISINs, instead of the ISIN prefix.
+    #[serde(default)]
+    pub entity_country_by_isin: HashMap<String, String>,
+}
+
+impl CountryMapping {
+    pub fn deposit_country(&self, isin: &str, account: Option<&str>, default: &str) -> String {
+        self.deposit_country_by_isin
+            .get(isin)
+            .or_else(|| account.and_then(|x| self.deposit_country_by_account.get(x)))
+            .or(self.deposit_country.as_ref())
+            .map(|x| x.as_str())
+            .unwrap_or(default)
+            .to_string()
+    }
+}
+
+/// Reads the country mapping from a TOML file with a `deposit_country` key and `[deposit_country_by_account]`,
+/// `[deposit_country_by_isin]` and `[entity_country_by_isin]` tables.
+pub fn parse_country_mapping(path: &Path, options: &ParseOptions) -> Result<CountryMapping> {
+    let contents = String::from_utf8(read_statement(path, options)?)
+        .map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))?;
+    let mapping: CountryMapping = parse_input_file(path, &contents, options)?;
+    let countries = mapping
+        .deposit_country
+        .iter()
+        .map(|x| ("deposit_country", x))
+        .chain(
+            mapping
+                .deposit_country_by_account
+                .iter()
+                .map(|(k, v)| (k.as_str(), v)),
+        )
+        .chain(
+            mapping
+                .deposit_country_by_isin
+                .iter()
+                .map(|(k, v)| (k.as_str(), v)),
+        )
+        .chain(
+            mapping
+                .entity_country_by_isin
+                .iter()
+                .map(|(k, v)| (k.as_str(), v)),
+        );
+    for (key, country) in countries {
+        check_country(key, country).map_err(|e| {
+            invalid_data(format!(
+                "{}: {}",
+                location(path, toml_key_line(&contents, key)),
+                e
+            ))
+        })?;
+    }
+    Ok(mapping)
+}
+
+fn mintos_note(isin: String, euro_valuation: Decimal, options: &ParseOptions) -> MintosNote {
+    let mut note = MintosNote::new(isin, euro_valuation);
+    note.deposit_country =
+        options
+            .countries
+            .deposit_country(&note.isin, None, &note.deposit_country);
+    note
+}
+
+fn invalid_data(message: String) -> Error {
+    Error::InvalidInput(message)
+}
+
+fn zip_error(path: &Path, error: zip::result::ZipError) -> Error {
+    invalid_data(format!("{}: {}", path.display(), error))
+}
+
+fn is_zip_archive(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|x| x.eq_ignore_ascii_case("zip"))
+}
+
+// Statements may be distributed inside a ZIP archive, in which case the archive must contain a single file.
+fn read_statement(path: &Path, options: &ParseOptions) -> Result<Vec<u8>> {
+    if !is_zip_archive(path) {
+        return std::fs::read(path).map_err(|e| Error::io(path, e));
+    }
+    let file = File::open(path).map_err(|e| Error::io(path, e))?;
+    let mut archive = zip::ZipArchive::new(file).map_err(|e| zip_error(path, e))?;
+    let mut files = Vec::new();
+    for index in 0..archive.len() {
+        let entry = archive
+            .by_index_raw(index)
+            .map_err(|e| zip_error(path, e))?;
+        if !entry.is_dir() {
+            files.push((index, entry.encrypted()));
+        }
+    }
+    let (index, encrypted) = match files.as_slice() {
+        [file] => *file,
+        _ => {
+            return Err(invalid_data(format!(
+                "{}: expected a single statement inside the archive but found {} files",
+                path.display(),
+                files.len()
+            )))
+        }
+    };
+    let mut entry = match (encrypted, &options.password) {
+        (false, _) => archive.by_index(index).map_err(|e| zip_error(path, e))?,
+        (true, Some(password)) => archive
+            .by_index_decrypt(index, password.as_bytes())
+            .map_err(|e| zip_error(path, e))?,
+        (true, None) => {
+            return Err(invalid_data(format!(
+                "{}: the archive is password protected but no password was given",
+                path.display()
+            )))
+        }
+    };
+    let mut contents = Vec::new();
+    entry
+        .read_to_end(&mut contents)
+        .map_err(|e| Error::io(path, e))?;
+    Ok(contents)
+}
+
+/// Validates the ISIN of the `index`-th row of a CSV statement, whose line is offset by the header.
+fn row_isin(path: &Path, index: usize, isin: &str) -> Result<String> {
+    normalize_isin(isin)
+        .map_err(|e| invalid_data(format!("{}: {}", location(path, Some(index + 2)), e)))
+}
+
+fn is_spreadsheet(path: &Path) -> bool {
+    path.extension().is_some_and(|x| {
+        ["xlsx", "xlsm", "xls", "ods"]
+            .iter()
+            .any(|y| x.eq_ignore_ascii_case(y))
+    })
+}
+
+/// Reader of a CSV statement. Spreadsheets, which some brokers offer as their only download, are read from
+/// their first sheet.
+fn csv_reader(path: &Path, options: &ParseOptions) -> Result<csv::Reader<Cursor<Vec<u8>>>> {
+    let mut contents = read_statement(path, options)?;
+    if is_spreadsheet(path) {
+        contents = spreadsheet_to_csv(path, contents)?;
+    }
+    Ok(csv_reader_from(contents))
+}
+
+/// Reader of CSV contents separated by commas, semicolons or tabs, whichever the header uses the most.
+/// Spreadsheets configured in Spanish export CSV separated by semicolons, as the comma is their decimal
+/// separator.
+pub(crate) fn csv_reader_from(contents: Vec<u8>) -> csv::Reader<Cursor<Vec<u8>>> {
+    let contents = to_utf8(contents);
+    csv::ReaderBuilder::new()
+        .delimiter(sniff_delimiter(&contents))
+        .from_reader(Cursor::new(contents))
+}
+
+/// Transcodes text to UTF-8. Excel saves "Unicode text" as UTF-16 and plain CSV in Windows-1252 when
+/// configured in Spanish, which would otherwise fail with errors about invalid UTF-8 in some field. Text with
+/// a byte order mark is decoded accordingly, UTF-16 without one is told apart by its zero bytes, and anything
+/// else that isn't valid UTF-8 is taken as Windows-1252.
+pub(crate) fn to_utf8(contents: Vec<u8>) -> Vec<u8> {
+    let (encoding, bom_length) = match Encoding::for_bom(&contents) {
+        Some(x) => x,
+        None if std::str::from_utf8(&contents).is_ok() => return contents,
+        None => (guess_encoding(&contents), 0),
+    };
+    if encoding == UTF_8 {
+        return contents[bom_length..].to_vec();
+    }
+    let (decoded, _) = encoding.decode_without_bom_handling(&contents[bom_length..]);
+    decoded.into_owned().into_bytes()
+}
+
+fn guess_encoding(contents: &[u8]) -> &'static Encoding {
+    // ASCII characters in UTF-16 have a zero byte, the high one, which comes second in little endian.
+    let zeros_at = |parity: usize| {
+        contents
+            .iter()
+            .skip(parity)
+            .step_by(2)
+            .filter(|x| **x == 0)
+            .count()
+    };
+    let pairs = contents.len() / 2;
+    match (zeros_at(0), zeros_at(1)) {
+        (even, odd) if odd > pairs / 2 && even == 0 => UTF_16LE,
+        (even, odd) if even > pairs / 2 && odd == 0 => UTF_16BE,
+        _ => WINDOWS_1252,
+    }
+}
+
+fn sniff_delimiter(contents: &[u8]) -> u8 {
+    let header = contents
+        .split(|x| *x == b'\n')
+        .find(|x| !x.iter().all(u8::is_ascii_whitespace))
+        .unwrap_or_default();
+    let mut counts = [(b',', 0), (b';', 0), (b'\t', 0)];
+    let mut quoted = false;
+    for x in header {
+        if *x == b'"' {
+            quoted = !quoted;
+        } else if !quoted {
+            if let Some((_, count)) = counts.iter_mut().find(|(delimiter, _)| delimiter == x) {
+                *count += 1;
+            }
+        }
+    }
+    // The first one wins ties, so files with a single column are read as usual.
+    counts
+        .iter()
+        .rev()
+        .max_by_key(|(_, count)| *count)
+        .map_or(b',', |(delimiter, _)| *delimiter)
+}
+
+/// Parses an amount in English or Spanish notation: `1234.56`, `1,234.56`, `1234,56` or `1.234,56`. When
+/// both separators appear the last one is the decimal separator, a separator repeated is a thousands separator
+/// and a single one is taken as the decimal separator, so `1.234` and `1,234` are both read as 1.234.
+pub(crate) fn parse_decimal(value: &str) -> std::result::Result<Decimal, String> {
+    let compact: String = value.chars().filter(|x| !x.is_whitespace()).collect();
+    let normalized = match (compact.rfind(','), compact.rfind('.')) {
+        (Some(comma), Some(point)) if comma > point => compact.replace('.', "").replace(',', "."),
+        (Some(_), Some(_)) => compact.replace(',', ""),
+        (Some(_), None) if compact.matches(',').count() == 1 => compact.replace(',', "."),
+        (Some(_), None) => compact.replace(',', ""),
+        (None, Some(_)) if compact.matches('.').count() > 1 => compact.replace('.', ""),
+        _ => compact,
+    };
+    Decimal::from_str(&normalized)
+        .or_else(|_| Decimal::from_scientific(&normalized))
+        .map_err(|_| format!("\"{}\" is not a number", value.trim()))
+}
+
+/// Deserializes an amount of a CSV file with [`parse_decimal`].
+pub(crate) fn deserialize_decimal<'de, D>(deserializer: D) -> std::result::Result<Decimal, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_decimal(&value).map_err(serde::de::Error::custom)
+}
+
+/// Writes the first sheet of a spreadsheet as CSV, one line per row of the sheet so errors still point to the
+/// right row. Dates are written like in CSV statements and numbers in full, without the cell format.
+fn spreadsheet_to_csv(path: &Path, contents: Vec<u8>) -> Result<Vec<u8>> {
+    let spreadsheet_error =
+        |message: String| invalid_data(format!("{}: {}", path.display(), message));
+    let mut workbook = open_workbook_auto_from_rs(Cursor::new(contents))
+        .map_err(|e| spreadsheet_error(e.to_string()))?;
+    let sheet = workbook
+        .worksheet_range_at(0)
+        .ok_or_else(|| spreadsheet_error("the spreadsheet has no sheets".to_string()))?
+        .map_err(|e| spreadsheet_error(e.to_string()))?;
+    // Rows above the first cell in use are left out of the range but still count as lines.
+    let (first_row, _) = sheet.start().unwrap_or_default();
+    let mut result = vec![b'\n'; first_row as usize];
+    for row in sheet.rows() {
+        // Blank lines are skipped by the CSV reader, while a line of empty fields would be a row.
+        if row.iter().all(|x| *x == Data::Empty) {
+            result.push(b'\n');
+            continue;
+        }
+        let mut writer = csv::Writer::from_writer(&mut result);
+        writer
+            .write_record(row.iter().map(cell_text))
+            .and_then(|_| writer.flush().map_err(csv::Error::from))
+            .map_err(|e| spreadsheet_error(e.to_string()))?;
+    }
+    Ok(result)
+}
+
+fn cell_text(cell: &Data) -> String {
+    match cell {
+        Data::DateTime(x) => match x.as_datetime() {
+            Some(x) if x.time() == NaiveTime::MIN => x.date().to_string(),
+            Some(x) => x.to_string(),
+            None => x.to_string(),
+        },
+        x => x.to_string(),
+    }
+}
+
+// Rows deserialized at once by parse_rows, enough to keep every thread busy without holding whole
+// statements of hundreds of thousands of rows.
+const ROWS_PER_CHUNK: usize = 10_000;
+
+/// Deserializes the rows of a statement and turns each one into an asset with `f`, given the index of the
+/// row, on every core. The assets keep the order of the rows.
+fn parse_rows<R, T, U>(
+    path: &Path,
+    reader: &mut csv::Reader<R>,
+    options: &ParseOptions,
+    f: impl Fn(usize, T) -> Result<U> + Sync,
+) -> Result<Vec<U>>
+where
+    R: Read,
+    T: DeserializeOwned,
+    U: Send,
+{
+    let headers = reader.headers().map_err(|e| Error::csv(path, e))?.clone();
+    let mut records = reader.records().enumerate();
+    let mut result = Vec::new();
+    loop {
+        let chunk: Vec<_> = records.by_ref().take(ROWS_PER_CHUNK).collect();
+        if chunk.is_empty() {
+            return Ok(result);
+        }
+        let assets: Vec<Result<U>> = chunk
+            .into_par_iter()
+            .map(|(index, row)| {
+                let row = row
+                    .and_then(|x| x.deserialize(Some(&headers)))
+                    .map_err(|e| Error::csv(path, e))?;
+                f(index, row)
+            })
+            .collect();
+        // Gone through in order so the first error, or every error when lenient, is reported in the order of
+        // the rows.
+        for asset in assets {
+            result.extend(options.recover(asset)?);
+        }
+    }
+}
+
+/// Portfolio of the assets of a statement with a row per asset, each given with the line it was read from.
+fn statement_portfolio(
+    broker: &str,
+    path: &Path,
+    rows: Vec<(Arc<dyn AssetWithValuation>, usize)>,
+    options: &ParseOptions,
+) -> Result<Portfolio> {
+    let mut sources: HashMap<String, Vec<Provenance>> = HashMap::new();
+    let mut assets = Vec::with_capacity(rows.len());
+    for (asset, line) in rows {
+        sources
+            .entry(asset.isin().to_uppercase())
+            .or_default()
+            .push(Provenance::new(broker, path, Some(line)));
+        assets.push(asset);
+    }
+    Ok(Portfolio::from_assets(assets, options.valuation_policy)?.with_sources(sources))
+}
 
 #[derive(Debug, Deserialize)]
 struct IbkrStatementEntry {
@@ -16,26 +425,234 @@ struct IbkrStatementEntry {
     description: String,
     #[serde(rename = "ISIN")]
     isin: String,
-    #[serde(rename = "Quantity")]
+    #[serde(rename = "Quantity", deserialize_with = "deserialize_decimal")]
     quantity: Decimal,
-    #[serde(rename = "PositionValue")]
+    #[serde(rename = "PositionValue", deserialize_with = "deserialize_decimal")]
     position_value: Decimal,
+    #[serde(rename = "Issuer", default)]
+    issuer: Option<String>,
+    #[serde(rename = "AssetClass", default)]
+    asset_class: Option<String>,
+    #[serde(rename = "ClientAccountID", default)]
+    account: Option<String>,
+    #[serde(rename = "CurrencyPrimary", default)]
+    currency: Option<String>,
 }
 
-pub fn parse_ibkr_statement(path: &Path) -> std::io::Result<Portfolio> {
-    let mut reader = csv::Reader::from_path(path)?;
-    let mut assets: Vec<Rc<dyn AssetWithValuation>> = Vec::new();
-    for row in reader.deserialize() {
-        let ibkr_entry: IbkrStatementEntry = row?;
-        assets.push(Rc::new(Etf {
-            isin: ibkr_entry.isin,
-            euro_valuation: ibkr_entry.position_value,
-            shares: ibkr_entry.quantity,
-            deposit_country: "IE".to_string(),
-            description: ibkr_entry.description,
-        }));
+/// A position of a statement, with the line it was read from.
+enum Position {
+    Long(Arc<dyn AssetWithValuation>, usize),
+    /// Negative quantity of an ISIN, which is owed rather than held.
+    Short(String, usize),
+    /// Cash balance in a currency, left out unless asked to declare it as an account.
+    Cash(String),
+}
+
+/// Leaves out the short positions of a statement, reporting them, or fails on the first one when `options`
+/// say so. Shorts aren't assets, so there is nothing to declare for them. The cash balances left out are
+/// reported too.
+fn held_positions(
+    path: &Path,
+    positions: Vec<Position>,
+    options: &ParseOptions,
+) -> Result<Vec<(Arc<dyn AssetWithValuation>, usize)>> {
+    let mut assets = Vec::with_capacity(positions.len());
+    let mut shorts = Vec::new();
+    let mut cash = Vec::new();
+    for position in positions {
+        match position {
+            Position::Long(asset, line) => assets.push((asset, line)),
+            Position::Short(isin, line) if options.fail_on_shorts => {
+                return Err(invalid_data(format!(
+                    "{}: {} is a short position, which can't be declared",
+                    location(path, Some(line)),
+                    isin
+                )));
+            }
+            Position::Short(isin, _) => shorts.push(isin),
+            Position::Cash(currency) => cash.push(currency),
+        }
+    }
+    if !cash.is_empty() {
+        eprintln!(
+            "Warning: leaving out the cash balances in {} of {}, pass --include-cash to declare them as accounts",
+            cash.join(", "),
+            path.display()
+        );
+    }
+    if !shorts.is_empty() {
+        eprintln!(
+            "Warning: leaving out {} short positions of {}: {}",
+            shorts.len(),
+            path.display(),
+            shorts.join(", ")
+        );
     }
-    Ok(Portfolio::from_assets(assets))
+    Ok(assets)
+}
+
+/// Cash balance of an IBKR statement as an account of Interactive Brokers, identified by the account and
+/// currency, or only its currency when cash isn't declared. The statement has no average balance of the last
+/// quarter, so it's left at zero.
+fn ibkr_cash_balance(
+    entry: IbkrStatementEntry,
+    options: &ParseOptions,
+) -> std::result::Result<Arc<dyn AssetWithValuation>, String> {
+    let currency = entry
+        .currency
+        .filter(|x| !x.is_empty())
+        .map(|x| x.trim().to_uppercase())
+        .unwrap_or_else(|| "EUR".to_string());
+    if !options.include_cash {
+        return Err(currency);
+    }
+    let account = entry
+        .account
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| "IBKR".to_string());
+    let balance = entry.position_value;
+    Ok(Arc::new(BankAccount {
+        tipo_cuenta: TipoCuenta::Otra,
+        identificacion_cuenta: IdentificacionCuenta::Otra,
+        bic: String::new(),
+        account_code: format!("{}{}", account.trim().to_uppercase(), currency),
+        entity_name: "INTERACTIVE BROKERS".to_string(),
+        entity_nif: None,
+        country: options.countries.deposit_country("", Some(&account), "IE"),
+        opening_date: None,
+        closing_date: None,
+        year_end_balance: balance,
+        q4_average_balance: Decimal::ZERO,
+        currency,
+    }))
+}
+
+pub fn parse_ibkr_statement(path: &Path, options: &ParseOptions) -> Result<Portfolio> {
+    let mut reader = csv_reader(path, options)?;
+    let positions = parse_rows(
+        path,
+        &mut reader,
+        options,
+        |index, mut ibkr_entry: IbkrStatementEntry| {
+            if ibkr_entry.asset_class.as_deref() == Some("CASH") {
+                return Ok(match ibkr_cash_balance(ibkr_entry, options) {
+                    Ok(account) => Position::Long(account, index + 2),
+                    Err(currency) => Position::Cash(currency),
+                });
+            }
+            ibkr_entry.isin = row_isin(path, index, &ibkr_entry.isin)?;
+            if ibkr_entry.quantity < Decimal::ZERO {
+                return Ok(Position::Short(ibkr_entry.isin, index + 2));
+            }
+            let issuer = ibkr_entry.issuer.filter(|x| !x.is_empty());
+            let deposit_country = options.countries.deposit_country(
+                &ibkr_entry.isin,
+                ibkr_entry.account.as_deref(),
+                "IE",
+            );
+            // Statements without the currency column are assumed to be in euros, as they used to be.
+            let currency = ibkr_entry
+                .currency
+                .filter(|x| !x.is_empty())
+                .map(|x| x.trim().to_uppercase())
+                .unwrap_or_else(|| "EUR".to_string());
+            if ibkr_entry.asset_class.as_deref() == Some("BOND") {
+                // IBKR reports the quantity of bonds as their face value.
+                let bond = Arc::new(Bond {
+                    identification: Identification::Isin(ibkr_entry.isin),
+                    description: ibkr_entry.description,
+                    issuer,
+                    entity_nif: None,
+                    depositary: Some("INTERACTIVE BROKERS".to_string()),
+                    deposit_country,
+                    currency,
+                    nominal_units: ibkr_entry.quantity,
+                    nominal_value_per_unit: Decimal::ONE,
+                    listing: BondListing::Listed {
+                        market_value: ibkr_entry.position_value,
+                    },
+                });
+                return Ok(Position::Long(bond, index + 2));
+            }
+            let etf = Arc::new(Etf {
+                isin: ibkr_entry.isin,
+                position_value: ibkr_entry.position_value,
+                shares: ibkr_entry.quantity,
+                deposit_country,
+                currency,
+                description: ibkr_entry.description,
+                entity_name: issuer,
+                depositary: Some("INTERACTIVE BROKERS".to_string()),
+            });
+            Ok(Position::Long(etf, index + 2))
+        },
+    )?;
+    let assets = held_positions(path, positions, options)?;
+    statement_portfolio("Interactive Brokers", path, assets, options)
+}
+
+#[derive(Debug, Deserialize)]
+struct IbkrTradeEntry {
+    #[serde(rename = "ISIN", default)]
+    isin: String,
+    #[serde(rename = "TradeDate")]
+    trade_date: String,
+    #[serde(rename = "Quantity", deserialize_with = "deserialize_decimal")]
+    quantity: Decimal,
+    #[serde(rename = "Proceeds", default)]
+    proceeds: Option<String>,
+    #[serde(rename = "FXRateToBase", default)]
+    fx_rate_to_base: Option<String>,
+}
+
+/// Parses the trade date of a Flex query, given as `20230301` by default or as `2023-03-01`.
+fn parse_trade_date(value: &str) -> std::result::Result<NaiveDate, String> {
+    let value = value.trim();
+    NaiveDate::parse_from_str(value, "%Y%m%d")
+        .or_else(|_| NaiveDate::parse_from_str(value, "%Y-%m-%d"))
+        .map_err(|_| format!("invalid trade date {}", value))
+}
+
+/// Reads the purchases of a Trades Flex query of Interactive Brokers as acquisition lots keyed by ISIN, so the
+/// shares acquired during the year can be dated. Sales and trades without an ISIN, such as currency
+/// conversions, are left out. The cost is taken from the proceeds converted to the base currency.
+pub fn parse_ibkr_trades(path: &Path, options: &ParseOptions) -> Result<LotsByIsin> {
+    let mut reader = csv_reader(path, options)?;
+    let purchases = parse_rows(
+        path,
+        &mut reader,
+        options,
+        |index, entry: IbkrTradeEntry| {
+            if entry.isin.trim().is_empty() || entry.quantity <= Decimal::ZERO {
+                return Ok(None);
+            }
+            let error =
+                |e: String| invalid_data(format!("{}: {}", location(path, Some(index + 2)), e));
+            let isin = row_isin(path, index, &entry.isin)?;
+            let date = parse_trade_date(&entry.trade_date).map_err(error)?;
+            let decimal = |value: Option<String>, default: Decimal| match value {
+                Some(x) if !x.trim().is_empty() => parse_decimal(&x).map_err(error),
+                _ => Ok(default),
+            };
+            let proceeds = decimal(entry.proceeds, Decimal::ZERO)?;
+            let fx_rate = decimal(entry.fx_rate_to_base, Decimal::ONE)?;
+            Ok(Some((
+                isin,
+                Lot {
+                    date: Some(date),
+                    shares: entry.quantity,
+                    cost: (proceeds * fx_rate).abs(),
+                },
+            )))
+        },
+    )?;
+    let mut lots = LotsByIsin::new();
+    for (isin, lot) in purchases.into_iter().flatten() {
+        lots.entry((isin, Some("INTERACTIVE BROKERS".to_string())))
+            .or_default()
+            .push(lot);
+    }
+    Ok(lots)
 }
 
 #[derive(Debug, Deserialize)]
@@ -44,6 +661,7 @@ struct MintosStatementEntry {
     isin: String,
     #[serde(rename = "Outstanding Principal")]
     #[serde(alias = "Principal pendiente")]
+    #[serde(deserialize_with = "deserialize_decimal")]
     pending_principal: Decimal,
 }
 
@@ -72,6 +690,7 @@ struct MintosActivityStatementEntry {
     details: String,
     #[serde(rename = "Turnover")]
     #[serde(alias = "Volumen de negocios")]
+    #[serde(deserialize_with = "deserialize_decimal")]
     turnover: Decimal,
     #[serde(rename = "Payment Type")]
     #[serde(alias = "Tipo de pago")]
@@ -87,72 +706,1170 @@ impl MintosActivityStatementEntry {
 
 // Mintos current portfolio statement may not reflect the state from a previous point in time.
 // This method reverses operation from the activity statement in order to reconstruct the previous state by applying the inverse operation.
+// The activity is replayed row by row keeping only the principal of each note, and notes are dropped as soon as they
+// net to zero so big portfolios don't hold every note ever touched. Along with the portfolio it returns the ISINs of
+// the notes that were fully repaid at the time, which aren't held and so aren't declared. Notes are kept sorted by
+// ISIN so every run gives the same result.
 pub fn parse_mintos_statement_with_reverted_changes(
     statement_path: &Path,
     activity_statement_path: &Path,
-) -> std::io::Result<Portfolio> {
-    let current_portfolio = parse_mintos_statement_as_is(statement_path)?;
-    let mut isin_notes = HashMap::new();
+    options: &ParseOptions,
+) -> Result<(Portfolio, Vec<String>)> {
+    let current_portfolio = parse_mintos_statement_as_is(statement_path, options)?;
+    let mut principal: BTreeMap<String, Decimal> = BTreeMap::new();
     for note in current_portfolio.assets {
-        isin_notes.insert(note.isin().to_string(), note);
+        principal.insert(note.isin().to_string(), note.native_valuation());
     }
-    let mut reader = csv::Reader::from_path(activity_statement_path)?;
+    let mut repaid = BTreeSet::new();
+    let mut reader = csv_reader(activity_statement_path, options)?;
     for row in reader.deserialize() {
-        let parsed: MintosActivityStatementEntry = row?;
-        match parsed.payment_type {
-            PaymentType::Unknown(_) => continue, // We ignore activity that doesn't affect the principal.
-            _ => {}
+        let Some(parsed): Option<MintosActivityStatementEntry> =
+            options.recover(row.map_err(|e| Error::csv(activity_statement_path, e)))?
+        else {
+            continue;
         };
+        if let PaymentType::Unknown(_) = parsed.payment_type {
+            continue; // We ignore activity that doesn't affect the principal.
+        }
         let isin = match parsed.isin() {
             Some(x) => x,
             None => continue, // This is a legacy loan without ISIN, as such it can be ignored.
         };
-        if !isin_notes.contains_key(isin) {
-            isin_notes.insert(
-                isin.to_string(),
-                Rc::new(MintosNote::new(isin.to_string(), Decimal::from(0))),
-            );
+        // turnover is positive when we've received capital and negative when making an investment, these are the signs we want for reversing the operations.
+        let balance = match principal.get_mut(isin) {
+            Some(balance) => {
+                *balance += parsed.turnover;
+                *balance
+            }
+            None => {
+                principal.insert(isin.to_string(), parsed.turnover);
+                parsed.turnover
+            }
+        };
+        if balance.is_zero() {
+            principal.remove(isin);
+            repaid.insert(isin.to_string());
+        } else {
+            repaid.remove(isin);
         }
-        let old_value = isin_notes[isin].clone();
-        isin_notes.insert(
-            isin.to_string(),
-            // turnover is positive when we've received capital and negative when making an investment, these are the signs we want for reversing the operations.
-            Rc::new(MintosNote::new(
-                isin.to_string(),
-                old_value.valuation() + parsed.turnover,
-            )),
-        );
     }
-    let fixed_portfolio: Vec<Rc<dyn AssetWithValuation>> = isin_notes.values().cloned().collect();
-    Ok(Portfolio::from_assets(fixed_portfolio))
+    let fixed_portfolio: Vec<Arc<dyn AssetWithValuation>> = principal
+        .into_iter()
+        .map(|(isin, balance)| {
+            Arc::new(mintos_note(isin, balance, options)) as Arc<dyn AssetWithValuation>
+        })
+        .collect();
+    let repaid: Vec<String> = repaid.into_iter().collect();
+    // The notes are rebuilt from both statements, so there is no single row to point to.
+    Ok((
+        Portfolio::from_assets(fixed_portfolio, options.valuation_policy)?
+            .with_source_file("Mintos", activity_statement_path),
+        repaid,
+    ))
 }
 
-pub fn parse_mintos_statement(path: &Path) -> std::io::Result<Portfolio> {
+pub fn parse_mintos_statement(path: &Path, options: &ParseOptions) -> Result<Portfolio> {
     if path.is_file() {
-        parse_mintos_statement_as_is(path)
+        return parse_mintos_statement_as_is(path, options);
+    }
+    let (portfolio, repaid) = parse_mintos_statement_with_reverted_changes(
+        &path.join("statement.csv"),
+        &path.join("activity.csv"),
+        options,
+    )?;
+    if !repaid.is_empty() {
+        eprintln!(
+            "Leaving out {} notes of {} repaid in full: {}",
+            repaid.len(),
+            path.display(),
+            repaid.join(", ")
+        );
+    }
+    Ok(portfolio)
+}
+
+pub fn parse_mintos_statement_as_is(path: &Path, options: &ParseOptions) -> Result<Portfolio> {
+    let mut reader = csv_reader(path, options)?;
+    let assets = parse_rows(
+        path,
+        &mut reader,
+        options,
+        |index, mintos_entry: MintosStatementEntry| {
+            let note = Arc::new(mintos_note(
+                row_isin(path, index, &mintos_entry.isin)?,
+                mintos_entry.pending_principal,
+                options,
+            ));
+            Ok((note as Arc<dyn AssetWithValuation>, index + 2))
+        },
+    )?;
+    statement_portfolio("Mintos", path, assets, options)
+}
+
+fn default_ownership_percentage() -> Decimal {
+    Decimal::ONE_HUNDRED
+}
+
+#[derive(Debug, Deserialize)]
+struct ManualRealEstateEntry {
+    reference: String,
+    description: String,
+    /// One of the B1..B5 subclaves.
+    clave: TipoBien,
+    #[serde(default)]
+    rustico: bool,
+    tipo_derecho_real: Option<String>,
+    street: Option<String>,
+    complement: Option<String>,
+    city: Option<String>,
+    province: Option<String>,
+    zip_code: Option<String>,
+    country: String,
+    acquisition_date: Option<NaiveDate>,
+    acquisition_value: Decimal,
+    #[serde(default = "default_ownership_percentage")]
+    ownership_percentage: Decimal,
+}
+
+#[derive(Debug, Deserialize)]
+struct ManualInsuranceEntry {
+    policy_number: String,
+    /// Either S1 for life insurance or S2 for annuities.
+    clave: TipoBien,
+    insurer: String,
+    /// Tax identification number of the insurer in its country of residence.
+    entity_nif: Option<String>,
+    street: Option<String>,
+    complement: Option<String>,
+    city: Option<String>,
+    province: Option<String>,
+    zip_code: Option<String>,
+    country: String,
+    subscription_date: Option<NaiveDate>,
+    surrender_value: Decimal,
+}
+
+fn default_nominal_value_per_unit() -> Decimal {
+    Decimal::ONE
+}
+
+fn default_currency() -> String {
+    "EUR".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+struct ManualBondEntry {
+    isin: Option<String>,
+    /// Code of bonds without an ISIN, such as private placements, instead of `isin`.
+    identifier: Option<String>,
+    description: String,
+    issuer: Option<String>,
+    /// Tax identification number of the issuer in its country of residence.
+    entity_nif: Option<String>,
+    /// Broker or bank the bond is held at, to tell it apart from the same bond held elsewhere.
+    depositary: Option<String>,
+    deposit_country: String,
+    nominal_units: Decimal,
+    #[serde(default = "default_nominal_value_per_unit")]
+    nominal_value_per_unit: Decimal,
+    /// Only listed bonds have a market value, the rest are valued at their nominal value.
+    market_value: Option<Decimal>,
+    #[serde(default = "default_currency")]
+    currency: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ManualUnlistedSharesEntry {
+    identifier: String,
+    company_name: String,
+    /// Tax identification number of the company in its country of residence.
+    entity_nif: Option<String>,
+    street: Option<String>,
+    complement: Option<String>,
+    city: Option<String>,
+    province: Option<String>,
+    zip_code: Option<String>,
+    country: String,
+    shares: Decimal,
+    book_value: Decimal,
+    acquisition_date: Option<NaiveDate>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ManualPensionPlanEntry {
+    identifier: String,
+    administrator: String,
+    /// Tax identification number of the administrator in its country of residence.
+    entity_nif: Option<String>,
+    street: Option<String>,
+    complement: Option<String>,
+    city: Option<String>,
+    province: Option<String>,
+    zip_code: Option<String>,
+    country: String,
+    consolidated_rights: Decimal,
+    membership_date: Option<NaiveDate>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ManualAccountEntry {
+    /// One of the C1..C5 subclaves.
+    clave: TipoBien,
+    /// IBAN, or the account number for accounts that don't have one.
+    account_code: String,
+    bic: String,
+    entity_name: String,
+    /// Tax identification number of the entity in its country of residence.
+    entity_nif: Option<String>,
+    country: String,
+    opening_date: Option<NaiveDate>,
+    /// Date the account was cancelled during the year, declared with `closing_balance` instead of the balances
+    /// at the end of the year.
+    closing_date: Option<NaiveDate>,
+    closing_balance: Option<Decimal>,
+    year_end_balance: Option<Decimal>,
+    q4_average_balance: Option<Decimal>,
+    #[serde(default = "default_currency")]
+    currency: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ManualOtherAssetEntry {
+    /// Any V, I or S subclave.
+    clave: TipoBien,
+    /// ISIN or any other code of securities and funds, or the reference of insurance.
+    identifier: String,
+    description: String,
+    entity_name: String,
+    /// Tax identification number of the entity in its country of residence.
+    entity_nif: Option<String>,
+    street: Option<String>,
+    complement: Option<String>,
+    city: Option<String>,
+    province: Option<String>,
+    zip_code: Option<String>,
+    /// Country of the entity, the country of deposit by default.
+    entity_country: Option<String>,
+    country: String,
+    shares: Option<Decimal>,
+    valuation: Decimal,
+    second_valuation: Option<Decimal>,
+    #[serde(default = "default_currency")]
+    currency: String,
+    acquisition_date: Option<NaiveDate>,
+    #[serde(default = "default_ownership_percentage")]
+    ownership_percentage: Decimal,
+}
+
+#[derive(Debug, Deserialize)]
+struct ManualAssetsFile {
+    #[serde(default)]
+    account: Vec<ManualAccountEntry>,
+    #[serde(default)]
+    real_estate: Vec<ManualRealEstateEntry>,
+    #[serde(default)]
+    insurance: Vec<ManualInsuranceEntry>,
+    #[serde(default)]
+    bond: Vec<ManualBondEntry>,
+    #[serde(default)]
+    unlisted_shares: Vec<ManualUnlistedSharesEntry>,
+    #[serde(default)]
+    pension_plan: Vec<ManualPensionPlanEntry>,
+    #[serde(default)]
+    asset: Vec<ManualOtherAssetEntry>,
+}
+
+/// Line of the `index`-th `[[table]]` header of a TOML file, used to point at the offending entry.
+fn toml_table_line(contents: &str, table: &str, index: usize) -> Option<usize> {
+    let header = format!("[[{}]]", table);
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.trim() == header)
+        .nth(index)
+        .map(|(number, _)| number + 1)
+}
+
+/// Best effort line of a key given its path, e.g. `real_estate.0.procentaje`.
+fn toml_key_line(contents: &str, key_path: &str) -> Option<usize> {
+    let parts: Vec<&str> = key_path.split('.').collect();
+    let (start, key) = match parts.as_slice() {
+        [table, index, key] => (toml_table_line(contents, table, index.parse().ok()?)?, *key),
+        [key] => (1, *key),
+        _ => return None,
+    };
+    contents
+        .lines()
+        .enumerate()
+        .skip(start - 1)
+        .find(|(_, line)| line.split('=').next().map(str::trim) == Some(key))
+        .map(|(number, _)| number + 1)
+}
+
+fn location(path: &Path, line: Option<usize>) -> String {
+    match line {
+        Some(line) => format!("{}:{}", path.display(), line),
+        None => path.display().to_string(),
+    }
+}
+
+fn is_yaml(path: &Path) -> bool {
+    path.extension()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| x.eq_ignore_ascii_case("yaml") || x.eq_ignore_ascii_case("yml"))
+}
+
+// Hand-written files are prone to typos which serde would otherwise silently replace by the defaults.
+// Unknown keys are reported as warnings, or as errors in strict mode. They are TOML, or YAML when their
+// extension says so, with the same tables as lists of mappings.
+fn parse_input_file<T: DeserializeOwned>(
+    path: &Path,
+    contents: &str,
+    options: &ParseOptions,
+) -> Result<T> {
+    let mut unknown_keys = Vec::new();
+    let on_unknown_key = |key: serde_ignored::Path| unknown_keys.push(key.to_string());
+    let value: T = if is_yaml(path) {
+        serde_ignored::deserialize(serde_yaml::Deserializer::from_str(contents), on_unknown_key)
+            .map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))?
     } else {
-        parse_mintos_statement_with_reverted_changes(
-            &path.join("statement.csv"),
-            &path.join("activity.csv"),
-        )
+        serde_ignored::deserialize(toml::Deserializer::new(contents), on_unknown_key)
+            .map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))?
+    };
+    for key in unknown_keys {
+        let message = format!(
+            "{}: unknown key `{}`",
+            location(path, toml_key_line(contents, &key)),
+            key
+        );
+        if options.strict {
+            return Err(invalid_data(message));
+        }
+        eprintln!("Warning: {}", message);
     }
+    Ok(value)
 }
 
-pub fn parse_mintos_statement_as_is(path: &Path) -> std::io::Result<Portfolio> {
-    let mut reader = csv::Reader::from_path(path)?;
-    let mut assets: Vec<Rc<dyn AssetWithValuation>> = Vec::new();
-    for row in reader.deserialize() {
-        let mintos_entry: MintosStatementEntry = row?;
-        assets.push(Rc::new(MintosNote::new(
-            mintos_entry.isin,
-            mintos_entry.pending_principal,
-        )));
+fn check_percentage(name: &str, value: Decimal) -> std::result::Result<(), String> {
+    if value <= Decimal::ZERO || value > Decimal::ONE_HUNDRED {
+        return Err(format!(
+            "{} must be between 0 and 100 but is {}",
+            name, value
+        ));
+    }
+    Ok(())
+}
+
+fn check_non_negative(name: &str, value: Decimal) -> std::result::Result<(), String> {
+    if value.is_sign_negative() {
+        return Err(format!("{} can't be negative but is {}", name, value));
+    }
+    Ok(())
+}
+
+fn check_currency(value: &str) -> std::result::Result<(), String> {
+    if value.len() != 3 || !value.chars().all(|x| x.is_ascii_uppercase()) {
+        return Err(format!(
+            "currency must be a three letter uppercase ISO 4217 code but is \"{}\"",
+            value
+        ));
+    }
+    Ok(())
+}
+
+fn check_country(name: &str, value: &str) -> std::result::Result<(), String> {
+    if !is_valid_country(value) {
+        return Err(format!(
+            "{} must be a two letter uppercase country code but is \"{}\"",
+            name, value
+        ));
+    }
+    Ok(())
+}
+
+// Assets that no broker reports, such as real estate, are described by hand in a TOML file.
+pub fn parse_manual_assets(path: &Path, options: &ParseOptions) -> Result<Portfolio> {
+    let contents = String::from_utf8(read_statement(path, options)?)
+        .map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))?;
+    let file: ManualAssetsFile = parse_input_file(path, &contents, options)?;
+    let entry_error = |table: &str, index: usize, message: String| {
+        invalid_data(format!(
+            "{}: {}",
+            location(path, toml_table_line(&contents, table, index)),
+            message
+        ))
+    };
+    let mut assets: Vec<Arc<dyn AssetWithValuation>> = Vec::new();
+    for (index, entry) in file.account.into_iter().enumerate() {
+        let tipo_cuenta = match entry.clave {
+            TipoBien::Cuenta(tipo) => tipo,
+            _ => {
+                return Err(entry_error(
+                    "account",
+                    index,
+                    format!("{} must use one of the C claves", entry.account_code),
+                ))
+            }
+        };
+        check_country("country", &entry.country)
+            .and(check_currency(&entry.currency))
+            .map_err(|e| entry_error("account", index, e))?;
+        // Codes that look like an IBAN must be a valid one, anything else is declared as another identification.
+        let (identificacion_cuenta, account_code) = if looks_like_iban(&entry.account_code) {
+            let iban = normalize_iban(&entry.account_code)
+                .map_err(|e| entry_error("account", index, e))?;
+            (IdentificacionCuenta::Iban, iban)
+        } else {
+            (
+                IdentificacionCuenta::Otra,
+                entry.account_code.trim().to_uppercase(),
+            )
+        };
+        let (year_end_balance, q4_average_balance) = match (
+            entry.closing_date,
+            entry.closing_balance,
+            entry.year_end_balance,
+            entry.q4_average_balance,
+        ) {
+            (Some(_), Some(closing_balance), None, None) => (closing_balance, Decimal::ZERO),
+            (None, None, Some(year_end_balance), Some(q4_average_balance)) => {
+                (year_end_balance, q4_average_balance)
+            }
+            _ => {
+                return Err(entry_error(
+                    "account",
+                    index,
+                    format!(
+                    "{} needs either year_end_balance and q4_average_balance, or closing_date and \
+                         closing_balance if it was cancelled",
+                    entry.account_code
+                ),
+                ))
+            }
+        };
+        assets.push(Arc::new(BankAccount {
+            tipo_cuenta,
+            identificacion_cuenta,
+            bic: normalize_bic(&entry.bic).map_err(|e| entry_error("account", index, e))?,
+            account_code,
+            entity_name: entry.entity_name,
+            entity_nif: entry.entity_nif,
+            country: entry.country,
+            opening_date: entry.opening_date,
+            closing_date: entry.closing_date,
+            year_end_balance,
+            q4_average_balance,
+            currency: entry.currency,
+        }));
+    }
+    for (index, entry) in file.real_estate.into_iter().enumerate() {
+        let tipo_inmueble = match entry.clave {
+            TipoBien::BienInmbueble(tipo) => tipo,
+            _ => {
+                return Err(entry_error(
+                    "real_estate",
+                    index,
+                    format!("{} must use one of the B claves", entry.reference),
+                ))
+            }
+        };
+        check_country("country", &entry.country)
+            .and(check_non_negative(
+                "acquisition_value",
+                entry.acquisition_value,
+            ))
+            .and(check_percentage(
+                "ownership_percentage",
+                entry.ownership_percentage,
+            ))
+            .map_err(|e| entry_error("real_estate", index, e))?;
+        assets.push(Arc::new(RealEstate {
+            reference: entry.reference,
+            description: entry.description,
+            tipo_inmueble,
+            clase: if entry.rustico {
+                ClaseInmueble::Rustico
+            } else {
+                ClaseInmueble::Urbano
+            },
+            tipo_derecho_real: entry.tipo_derecho_real,
+            address: Address {
+                street: entry.street,
+                complement: entry.complement,
+                city: entry.city,
+                province: entry.province,
+                zip_code: entry.zip_code,
+            },
+            country: entry.country,
+            acquisition_date: entry.acquisition_date,
+            acquisition_value: entry.acquisition_value,
+            ownership_percentage: entry.ownership_percentage,
+        }));
+    }
+    for (index, entry) in file.insurance.into_iter().enumerate() {
+        let tipo_seguro = match entry.clave {
+            TipoBien::Seguro(tipo) => tipo,
+            _ => {
+                return Err(entry_error(
+                    "insurance",
+                    index,
+                    format!("{} must use one of the S claves", entry.policy_number),
+                ))
+            }
+        };
+        check_country("country", &entry.country)
+            .and(check_non_negative("surrender_value", entry.surrender_value))
+            .map_err(|e| entry_error("insurance", index, e))?;
+        assets.push(Arc::new(Insurance {
+            policy_number: entry.policy_number,
+            tipo_seguro,
+            insurer: entry.insurer,
+            entity_nif: entry.entity_nif,
+            insurer_address: Address {
+                street: entry.street,
+                complement: entry.complement,
+                city: entry.city,
+                province: entry.province,
+                zip_code: entry.zip_code,
+            },
+            country: entry.country,
+            subscription_date: entry.subscription_date,
+            surrender_value: entry.surrender_value,
+        }));
+    }
+    for (index, entry) in file.bond.into_iter().enumerate() {
+        check_country("deposit_country", &entry.deposit_country)
+            .and(check_non_negative("nominal_units", entry.nominal_units))
+            .and(check_non_negative(
+                "nominal_value_per_unit",
+                entry.nominal_value_per_unit,
+            ))
+            .and(check_currency(&entry.currency))
+            .map_err(|e| entry_error("bond", index, e))?;
+        let identification = match (entry.isin, entry.identifier) {
+            (Some(isin), None) => Identification::Isin(
+                normalize_isin(&isin).map_err(|e| entry_error("bond", index, e))?,
+            ),
+            (None, Some(identifier)) => Identification::Other(identifier.trim().to_uppercase()),
+            _ => {
+                return Err(entry_error(
+                    "bond",
+                    index,
+                    "needs either an isin or an identifier".to_string(),
+                ))
+            }
+        };
+        assets.push(Arc::new(Bond {
+            identification,
+            description: entry.description,
+            issuer: entry.issuer,
+            entity_nif: entry.entity_nif,
+            depositary: entry.depositary.map(|x| x.to_uppercase()),
+            deposit_country: entry.deposit_country,
+            currency: entry.currency,
+            nominal_units: entry.nominal_units,
+            nominal_value_per_unit: entry.nominal_value_per_unit,
+            listing: match entry.market_value {
+                Some(market_value) => BondListing::Listed { market_value },
+                None => BondListing::Unlisted,
+            },
+        }));
+    }
+    for (index, entry) in file.unlisted_shares.into_iter().enumerate() {
+        check_country("country", &entry.country)
+            .and(check_non_negative("shares", entry.shares))
+            .and(check_non_negative("book_value", entry.book_value))
+            .map_err(|e| entry_error("unlisted_shares", index, e))?;
+        if entry.identifier.len() > 12 {
+            return Err(entry_error(
+                "unlisted_shares",
+                index,
+                format!(
+                    "identifier {} can't be longer than 12 characters",
+                    entry.identifier
+                ),
+            ));
+        }
+        assets.push(Arc::new(UnlistedShares {
+            identifier: entry.identifier,
+            company_name: entry.company_name,
+            entity_nif: entry.entity_nif,
+            company_address: Address {
+                street: entry.street,
+                complement: entry.complement,
+                city: entry.city,
+                province: entry.province,
+                zip_code: entry.zip_code,
+            },
+            country: entry.country,
+            shares: entry.shares,
+            book_value: entry.book_value,
+            acquisition_date: entry.acquisition_date,
+        }));
+    }
+    for (index, entry) in file.pension_plan.into_iter().enumerate() {
+        check_country("country", &entry.country)
+            .and(check_non_negative(
+                "consolidated_rights",
+                entry.consolidated_rights,
+            ))
+            .map_err(|e| entry_error("pension_plan", index, e))?;
+        if entry.identifier.len() > 12 {
+            return Err(entry_error(
+                "pension_plan",
+                index,
+                format!(
+                    "identifier {} can't be longer than 12 characters",
+                    entry.identifier
+                ),
+            ));
+        }
+        assets.push(Arc::new(PensionPlan {
+            identifier: entry.identifier,
+            administrator: entry.administrator,
+            entity_nif: entry.entity_nif,
+            administrator_address: Address {
+                street: entry.street,
+                complement: entry.complement,
+                city: entry.city,
+                province: entry.province,
+                zip_code: entry.zip_code,
+            },
+            country: entry.country,
+            consolidated_rights: entry.consolidated_rights,
+            membership_date: entry.membership_date,
+        }));
     }
-    Ok(Portfolio::from_assets(assets))
+    for (index, entry) in file.asset.into_iter().enumerate() {
+        let error = |e: String| entry_error("asset", index, e);
+        // Accounts and real estate have claves of their own that these fields don't cover.
+        let identification = match entry.clave {
+            TipoBien::Valores(_) | TipoBien::AccionInstitucionInversionColectiva => {
+                let code = entry.identifier.trim().to_uppercase();
+                // Something shaped like an ISIN that fails its check digit is most likely mistyped.
+                let isin_like = code.len() == 12
+                    && code.chars().all(|x| x.is_ascii_alphanumeric())
+                    && code[..2].chars().all(|x| x.is_ascii_alphabetic());
+                match normalize_isin(&code) {
+                    Ok(isin) => Identification::Isin(isin),
+                    Err(e) if isin_like => return Err(error(e)),
+                    Err(_) => Identification::Other(code),
+                }
+            }
+            TipoBien::Seguro(_) => Identification::None,
+            TipoBien::Cuenta(_) | TipoBien::BienInmbueble(_) => {
+                return Err(error(format!(
+                "{} must use a V, I or S clave, accounts and real estate have tables of their own",
+                entry.identifier
+            )))
+            }
+        };
+        let entity_country = entry
+            .entity_country
+            .unwrap_or_else(|| entry.country.clone());
+        check_country("country", &entry.country)
+            .and(check_country("entity_country", &entity_country))
+            .and(check_currency(&entry.currency))
+            .and(check_non_negative("valuation", entry.valuation))
+            .and(check_percentage(
+                "ownership_percentage",
+                entry.ownership_percentage,
+            ))
+            .map_err(error)?;
+        if let Some(shares) = entry.shares {
+            check_non_negative("shares", shares).map_err(error)?;
+        }
+        if identification.as_str().len() > 12 {
+            return Err(error(format!(
+                "identifier {} can't be longer than 12 characters",
+                entry.identifier
+            )));
+        }
+        assets.push(Arc::new(OtherAsset {
+            clave: entry.clave,
+            identifier: entry.identifier.trim().to_uppercase(),
+            identification,
+            description: entry.description,
+            entity_name: entry.entity_name,
+            entity_nif: entry.entity_nif,
+            entity_address: Address {
+                street: entry.street,
+                complement: entry.complement,
+                city: entry.city,
+                province: entry.province,
+                zip_code: entry.zip_code,
+            },
+            entity_country,
+            country: entry.country,
+            shares: entry.shares,
+            valuation: entry.valuation,
+            second_valuation: entry.second_valuation,
+            currency: entry.currency.trim().to_uppercase(),
+            acquisition_date: entry.acquisition_date,
+            ownership_percentage: entry.ownership_percentage,
+        }));
+    }
+    Ok(Portfolio::from_assets(assets, options.valuation_policy)?
+        .with_source_file("Manual assets", path))
+}
+
+#[derive(Debug, Deserialize)]
+struct ManualCryptoEntry {
+    symbol: String,
+    name: String,
+    custodian: String,
+    custodian_nif: Option<String>,
+    street: Option<String>,
+    complement: Option<String>,
+    city: Option<String>,
+    province: Option<String>,
+    zip_code: Option<String>,
+    country: String,
+    units: Decimal,
+    /// Value in euros, priced from the price file when missing.
+    valuation: Option<Decimal>,
+    acquisition_date: Option<NaiveDate>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CryptoHoldingsFile {
+    #[serde(default)]
+    crypto: Vec<ManualCryptoEntry>,
+}
+
+// Crypto custodians have no common statement format, so holdings for the 721 are described by hand like manual assets.
+pub fn parse_crypto_holdings(path: &Path, options: &ParseOptions) -> Result<Vec<CryptoAsset>> {
+    let contents = String::from_utf8(read_statement(path, options)?)
+        .map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))?;
+    let file: CryptoHoldingsFile = parse_input_file(path, &contents, options)?;
+    let mut holdings = Vec::new();
+    for (index, entry) in file.crypto.into_iter().enumerate() {
+        check_country("country", &entry.country)
+            .and(check_non_negative("units", entry.units))
+            .and(check_non_negative(
+                "valuation",
+                entry.valuation.unwrap_or_default(),
+            ))
+            .map_err(|e| {
+                invalid_data(format!(
+                    "{}: {}",
+                    location(path, toml_table_line(&contents, "crypto", index)),
+                    e
+                ))
+            })?;
+        holdings.push(CryptoAsset {
+            symbol: entry.symbol,
+            name: entry.name,
+            custodian: entry.custodian,
+            custodian_nif: entry.custodian_nif,
+            custodian_address: Address {
+                street: entry.street,
+                complement: entry.complement,
+                city: entry.city,
+                province: entry.province,
+                zip_code: entry.zip_code,
+            },
+            custodian_country: entry.country,
+            units: entry.units,
+            valuation: entry.valuation,
+            acquisition_date: entry.acquisition_date,
+        });
+    }
+    Ok(holdings)
+}
+
+/// Per-asset corrections to the generated registros, keyed by ISIN or account code.
+#[derive(Debug, Default, Deserialize)]
+pub struct AssetOverrides {
+    /// Percentage of the asset owned by the declarante.
+    #[serde(default)]
+    pub ownership: HashMap<String, Decimal>,
+    /// Clave de condición del declarante, e.g. `3` for an authorised holder.
+    #[serde(default)]
+    pub titularidad: HashMap<String, Titularidad>,
+    /// Any other field of the registro, for when the broker data is incomplete or wrong.
+    #[serde(default)]
+    pub registro: HashMap<String, RegistroOverride>,
+}
+
+/// Reads the per-asset overrides, given as an `[ownership]` table with the percentage owned, a `[titularidad]`
+/// table with the clave de condición and `[registro.<ISIN>]` tables forcing other fields of the registro.
+pub fn parse_asset_overrides(path: &Path, options: &ParseOptions) -> Result<AssetOverrides> {
+    let contents = String::from_utf8(read_statement(path, options)?)
+        .map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))?;
+    let file: AssetOverrides = parse_input_file(path, &contents, options)?;
+    let error = |identifier: &str, e: String| {
+        invalid_data(format!(
+            "{}: {}",
+            location(path, toml_key_line(&contents, identifier)),
+            e
+        ))
+    };
+    for (identifier, percentage) in file.ownership.iter() {
+        check_percentage(identifier, *percentage).map_err(|e| error(identifier, e))?;
+    }
+    for (identifier, registro) in file.registro.iter() {
+        let countries = [
+            ("entity_country", &registro.entity_country),
+            ("deposit_country", &registro.deposit_country),
+        ];
+        for (name, country) in countries {
+            if let Some(country) = country {
+                check_country(name, country).map_err(|e| error(identifier, e))?;
+            }
+        }
+        if let Some(percentage) = registro.ownership_percentage {
+            check_percentage("ownership_percentage", percentage)
+                .map_err(|e| error(identifier, e))?;
+        }
+    }
+    Ok(file)
+}
+
+#[derive(Debug, Deserialize)]
+struct EntitiesFile {
+    #[serde(default)]
+    entity: Vec<EntityAddress>,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+/// Reads the addresses of entities missing from the bundled ones from a TOML file with one `[[entity]]`
+/// table each.
+pub fn parse_entities(path: &Path, options: &ParseOptions) -> Result<Vec<EntityAddress>> {
+    let contents = String::from_utf8(read_statement(path, options)?)
+        .map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))?;
+    let file: EntitiesFile = parse_input_file(path, &contents, options)?;
+    for (index, entity) in file.entity.iter().enumerate() {
+        check_country("country", &entity.country).map_err(|e| {
+            invalid_data(format!(
+                "{}: {}",
+                location(path, toml_table_line(&contents, "entity", index)),
+                e
+            ))
+        })?;
+    }
+    Ok(file.entity)
+}
+
+#[derive(Debug, Deserialize)]
+struct ManualSaleEntry {
+    identifier: String,
+    date: NaiveDate,
+    proceeds: Decimal,
+}
+
+#[derive(Debug, Deserialize)]
+struct SalesFile {
+    #[serde(default)]
+    sale: Vec<ManualSaleEntry>,
+}
+
+/// Reads the sales of the year from a TOML file with one `[[sale]]` table per transmission, keyed by the ISIN
+/// or account code. Several sales of the same asset add up their proceeds and keep the latest date.
+pub fn parse_sales(path: &Path, options: &ParseOptions) -> Result<HashMap<String, Sale>> {
+    let contents = String::from_utf8(read_statement(path, options)?)
+        .map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))?;
+    let file: SalesFile = parse_input_file(path, &contents, options)?;
+    let mut sales: HashMap<String, Sale> = HashMap::new();
+    for (index, entry) in file.sale.into_iter().enumerate() {
+        check_non_negative("proceeds", entry.proceeds).map_err(|e| {
+            invalid_data(format!(
+                "{}: {}",
+                location(path, toml_table_line(&contents, "sale", index)),
+                e
+            ))
+        })?;
+        sales
+            .entry(entry.identifier.trim().to_uppercase())
+            .and_modify(|sale| {
+                sale.date = sale.date.max(entry.date);
+                sale.proceeds += entry.proceeds;
+            })
+            .or_insert(Sale {
+                date: entry.date,
+                proceeds: entry.proceeds,
+            });
+    }
+    Ok(sales)
+}
+
+#[derive(Debug, Deserialize)]
+struct ManualLotEntry {
+    identifier: String,
+    /// Broker the lot was bought at, needed when the ISIN is held at several.
+    depositary: Option<String>,
+    date: Option<NaiveDate>,
+    shares: Decimal,
+    cost: Decimal,
+}
+
+#[derive(Debug, Deserialize)]
+struct LotsFile {
+    #[serde(default)]
+    lot: Vec<ManualLotEntry>,
+}
+
+/// Reads the acquisition lots held at the start of the year from a TOML file with one `[[lot]]` table per
+/// purchase, grouped by ISIN and depositary.
+pub fn parse_lots(path: &Path, options: &ParseOptions) -> Result<LotsByIsin> {
+    let contents = String::from_utf8(read_statement(path, options)?)
+        .map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))?;
+    let file: LotsFile = parse_input_file(path, &contents, options)?;
+    let mut lots = LotsByIsin::new();
+    for (index, entry) in file.lot.into_iter().enumerate() {
+        let error = |e: String| {
+            invalid_data(format!(
+                "{}: {}",
+                location(path, toml_table_line(&contents, "lot", index)),
+                e
+            ))
+        };
+        check_non_negative("cost", entry.cost).map_err(error)?;
+        if entry.shares <= Decimal::ZERO {
+            return Err(error(format!(
+                "shares must be positive but is {}",
+                entry.shares
+            )));
+        }
+        let depositary = entry
+            .depositary
+            .map(|x| x.trim().to_uppercase())
+            .filter(|x| !x.is_empty());
+        lots.entry((entry.identifier.trim().to_uppercase(), depositary))
+            .or_default()
+            .push(Lot {
+                date: entry.date,
+                shares: entry.shares,
+                cost: entry.cost,
+            });
+    }
+    Ok(lots)
+}
+
+#[derive(Debug, Deserialize)]
+struct ManualSplitEntry {
+    isin: String,
+    date: NaiveDate,
+    ratio: Decimal,
+}
+
+#[derive(Debug, Deserialize)]
+struct SplitsFile {
+    #[serde(default)]
+    split: Vec<ManualSplitEntry>,
+}
+
+/// Reads stock splits from a TOML file with one `[[split]]` table per split, with `ratio` new shares per
+/// old share, e.g. 2 for a 2:1 split or 0.1 for a 1:10 reverse split.
+pub fn parse_splits(path: &Path, options: &ParseOptions) -> Result<Vec<StockSplit>> {
+    let contents = String::from_utf8(read_statement(path, options)?)
+        .map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))?;
+    let file: SplitsFile = parse_input_file(path, &contents, options)?;
+    let mut splits = Vec::new();
+    for (index, entry) in file.split.into_iter().enumerate() {
+        let error = |e: String| {
+            invalid_data(format!(
+                "{}: {}",
+                location(path, toml_table_line(&contents, "split", index)),
+                e
+            ))
+        };
+        if entry.ratio <= Decimal::ZERO {
+            return Err(error(format!(
+                "ratio must be positive but is {}",
+                entry.ratio
+            )));
+        }
+        splits.push(StockSplit {
+            isin: normalize_isin(&entry.isin).map_err(error)?,
+            date: entry.date,
+            ratio: entry.ratio,
+        });
+    }
+    Ok(splits)
+}
+
+#[derive(Debug, Deserialize)]
+struct IsinChangesFile {
+    #[serde(default)]
+    isin_changes: HashMap<String, String>,
+}
+
+/// Reads the ISINs that changed during the year from an `[isin_changes]` table of old to new ISIN.
+pub fn parse_isin_changes(path: &Path, options: &ParseOptions) -> Result<HashMap<String, String>> {
+    let contents = String::from_utf8(read_statement(path, options)?)
+        .map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))?;
+    let file: IsinChangesFile = parse_input_file(path, &contents, options)?;
+    let mut changes = HashMap::new();
+    for (old, new) in file.isin_changes {
+        let normalize = |isin: &str| {
+            normalize_isin(isin).map_err(|e| {
+                invalid_data(format!(
+                    "{}: {}",
+                    location(path, toml_key_line(&contents, &old)),
+                    e
+                ))
+            })
+        };
+        changes.insert(normalize(&old)?, normalize(&new)?);
+    }
+    Ok(changes)
+}
+
+/// Reads the clave to declare some assets with from a `[classification]` table of ISIN or account code to
+/// clave and subclave, e.g. `V2`, and `[[rule]]` tables giving it to the assets with an `isin_prefix`, a
+/// `description` or a `depositary`.
+pub fn parse_classification(path: &Path, options: &ParseOptions) -> Result<Classification> {
+    let contents = String::from_utf8(read_statement(path, options)?)
+        .map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))?;
+    let file: Classification = parse_input_file(path, &contents, options)?;
+    for (index, rule) in file.rules.iter().enumerate() {
+        if !rule.has_conditions() {
+            return Err(invalid_data(format!(
+                "{}: a rule needs an isin_prefix, a description or a depositary",
+                location(path, toml_table_line(&contents, "rule", index))
+            )));
+        }
+    }
+    Ok(Classification {
+        identifiers: file
+            .identifiers
+            .into_iter()
+            .map(|(identifier, clave)| (identifier.trim().to_uppercase(), clave))
+            .collect(),
+        rules: file.rules,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct AcquisitionDatesFile {
+    #[serde(default)]
+    acquisition: HashMap<String, NaiveDate>,
+}
+
+/// Reads when each asset was acquired from an `[acquisition]` table of ISIN or account code to date, since
+/// broker statements only give the position at year end.
+pub fn parse_acquisition_dates(
+    path: &Path,
+    options: &ParseOptions,
+) -> Result<HashMap<String, NaiveDate>> {
+    let contents = String::from_utf8(read_statement(path, options)?)
+        .map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))?;
+    let file: AcquisitionDatesFile = parse_input_file(path, &contents, options)?;
+    Ok(file
+        .acquisition
+        .into_iter()
+        .map(|(identifier, date)| (identifier.trim().to_uppercase(), date))
+        .collect())
+}
+
+/// Declarant data and default input files of a client, so a gestoría can generate each declaration by name.
+#[derive(Debug, Default, Deserialize)]
+pub struct Profile {
+    pub name: Option<String>,
+    pub nif: Option<Nif>,
+    pub phone: Option<Telefono>,
+    pub contact_name: Option<String>,
+    pub tipo_soporte: Option<TipoSoporte>,
+    pub broker: Option<SupportedBrokers>,
+    pub previous_statement: Option<PathBuf>,
+    pub current_statement: Option<PathBuf>,
+    pub previous_declaration: Option<PathBuf>,
+    pub countries: Option<PathBuf>,
+    pub overrides: Option<PathBuf>,
+    pub entities: Option<PathBuf>,
+    pub sales: Option<PathBuf>,
+    pub acquisition_dates: Option<PathBuf>,
+    pub lots: Option<PathBuf>,
+    pub trades: Option<PathBuf>,
+    pub splits: Option<PathBuf>,
+    pub isin_changes: Option<PathBuf>,
+    pub classification: Option<PathBuf>,
+    pub statements_dir: Option<PathBuf>,
+    pub state: Option<PathBuf>,
+}
+
+impl Profile {
+    /// Every input file of the profile.
+    pub fn files_mut(&mut self) -> [&mut Option<PathBuf>; 15] {
+        [
+            &mut self.previous_statement,
+            &mut self.current_statement,
+            &mut self.previous_declaration,
+            &mut self.countries,
+            &mut self.overrides,
+            &mut self.entities,
+            &mut self.sales,
+            &mut self.acquisition_dates,
+            &mut self.lots,
+            &mut self.trades,
+            &mut self.splits,
+            &mut self.isin_changes,
+            &mut self.classification,
+            &mut self.statements_dir,
+            &mut self.state,
+        ]
+    }
+}
+
+/// Reads a client profile. Relative paths in it are relative to the profile itself, so a client's files can
+/// be kept next to their profile.
+pub fn parse_profile(path: &Path, options: &ParseOptions) -> Result<Profile> {
+    let contents = String::from_utf8(read_statement(path, options)?)
+        .map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))?;
+    let mut profile: Profile = parse_input_file(path, &contents, options)?;
+    let base = path.parent().unwrap_or(Path::new(""));
+    for file in profile.files_mut().into_iter().flatten() {
+        *file = base.join(&*file);
+    }
+    Ok(profile)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum SupportedBrokers {
     InteractiveBrokers,
     Mintos,
+    /// Assets described by hand in a TOML or YAML file
+    Manual,
+}
+
+impl SupportedBrokers {
+    /// Folder with the statements of the broker in the folder of a year, e.g. `2024/ibkr/`.
+    pub fn folder_name(&self) -> &'static str {
+        match self {
+            SupportedBrokers::InteractiveBrokers => "ibkr",
+            SupportedBrokers::Mintos => "mintos",
+            SupportedBrokers::Manual => "manual",
+        }
+    }
+
+    // Names of the statement in the folder of the broker, in order of preference.
+    fn statement_names(&self) -> &'static [&'static str] {
+        match self {
+            SupportedBrokers::InteractiveBrokers | SupportedBrokers::Mintos => {
+                &["statement.csv", "statement.xlsx", "statement.zip"]
+            }
+            SupportedBrokers::Manual => &["assets.toml", "assets.yaml", "assets.yml"],
+        }
+    }
+
+    /// The statement of the broker in `folder`. Mintos folders with an `activity.csv` are parsed as a whole, so
+    /// the operations after the end of the year are reverted.
+    pub fn statement_in(&self, folder: &Path) -> Result<PathBuf> {
+        if *self == SupportedBrokers::Mintos && folder.join("activity.csv").is_file() {
+            return Ok(folder.to_path_buf());
+        }
+        let names = self.statement_names();
+        names
+            .iter()
+            .map(|name| folder.join(name))
+            .find(|path| path.is_file())
+            .ok_or_else(|| {
+                invalid_data(format!(
+                    "{}: no statement found, expected one of {}",
+                    folder.display(),
+                    names.join(", ")
+                ))
+            })
+    }
 }
@@ -0,0 +1,87 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use chrono::NaiveDate;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::Deserialize;
+
+use crate::assets::CryptoAsset;
+use crate::error::{Error, Result};
+use crate::parsers::{csv_reader_from, deserialize_decimal};
+
+/// Where the EUR price of a virtual currency on a given date comes from.
+pub trait PriceSource {
+    fn price(&self, symbol: &str, date: NaiveDate) -> Result<Decimal>;
+}
+
+/// Prices given by the user in a CSV file with `date,symbol,price` columns, the price being in euros.
+#[derive(Clone, Debug, Default)]
+pub struct PriceFile {
+    prices: HashMap<String, BTreeMap<NaiveDate, Decimal>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PriceRecord {
+    date: NaiveDate,
+    symbol: String,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    price: Decimal,
+}
+
+impl PriceFile {
+    pub fn from_csv(path: &Path) -> Result<PriceFile> {
+        let mut reader = csv_reader_from(std::fs::read(path).map_err(|e| Error::io(path, e))?);
+        let mut result = PriceFile::default();
+        for (index, row) in reader.deserialize().enumerate() {
+            let record: PriceRecord = row.map_err(|e| Error::csv(path, e))?;
+            if record.price < Decimal::ZERO {
+                return Err(Error::Csv {
+                    path: path.to_path_buf(),
+                    line: index as u64 + 2,
+                    message: "price can't be negative".to_string(),
+                });
+            }
+            result
+                .prices
+                .entry(record.symbol.trim().to_uppercase())
+                .or_default()
+                .insert(record.date, record.price);
+        }
+        Ok(result)
+    }
+}
+
+impl PriceSource for PriceFile {
+    // Virtual currencies trade every day, so only the price of the exact date is valid.
+    fn price(&self, symbol: &str, date: NaiveDate) -> Result<Decimal> {
+        self.prices
+            .get(&symbol.trim().to_uppercase())
+            .and_then(|x| x.get(&date))
+            .copied()
+            .ok_or_else(|| Error::InvalidInput(format!("no price for {} on {}", symbol, date)))
+    }
+}
+
+/// Values the holdings that have no valuation at the December 31 price of `year`, rounded to cents.
+/// Holdings with a valuation given by hand keep it.
+pub fn value_at_year_end(
+    holdings: &mut [CryptoAsset],
+    year: i32,
+    source: Option<&dyn PriceSource>,
+) -> Result<()> {
+    let year_end = NaiveDate::from_ymd_opt(year, 12, 31).unwrap();
+    for holding in holdings.iter_mut().filter(|x| x.valuation.is_none()) {
+        let Some(source) = source else {
+            return Err(Error::InvalidInput(format!(
+                "{} held at {} has no valuation, give one or pass --prices",
+                holding.symbol, holding.custodian
+            )));
+        };
+        let price = source.price(&holding.symbol, year_end)?;
+        holding.valuation = Some(
+            (holding.units * price)
+                .round_dp_with_strategy(2, RoundingStrategy::MidpointAwayFromZero),
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::inspect::field;
+
+/// Where an asset was read from, to trace each registro back to the statement row that produced it.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Provenance {
+    pub broker: String,
+    pub file: PathBuf,
+    /// Line of the statement, for statements with a row per asset.
+    pub line: Option<usize>,
+}
+
+impl Provenance {
+    pub fn new(broker: &str, file: &Path, line: Option<usize>) -> Provenance {
+        Provenance {
+            broker: broker.to_string(),
+            file: file.to_path_buf(),
+            line,
+        }
+    }
+}
+
+impl Display for Provenance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.broker, self.file.display())?;
+        if let Some(line) = self.line {
+            write!(f, ", line {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Provenance of the registros of a declaration, keyed by the registro identifier (ISIN or account).
+pub type Sources = BTreeMap<String, Vec<Provenance>>;
+
+/// The 720 has no room for where each registro comes from, so it's kept in a JSON file next to it,
+/// e.g. `declaration.720.sources.json`.
+pub fn sources_path(file: &Path) -> PathBuf {
+    let mut result = file.as_os_str().to_owned();
+    result.push(".sources.json");
+    PathBuf::from(result)
+}
+
+/// Sources kept next to `file`, empty if there are none, e.g. for files not generated by us.
+pub fn load_sources(file: &Path) -> Result<Sources> {
+    let path = sources_path(file);
+    if !path.is_file() {
+        return Ok(Sources::new());
+    }
+    let contents = File::open(&path).map_err(|e| Error::io(&path, e))?;
+    serde_json::from_reader(std::io::BufReader::new(contents))
+        .map_err(|source| Error::Json { path, source })
+}
+
+/// Writes `sources` next to `file`, removing a stale one left by an earlier version of the file if there
+/// are none.
+pub fn save_sources(file: &Path, sources: &Sources) -> Result<()> {
+    let path = sources_path(file);
+    if sources.is_empty() {
+        return match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::io(&path, e)),
+            _ => Ok(()),
+        };
+    }
+    let contents = File::create(&path).map_err(|e| Error::io(&path, e))?;
+    serde_json::to_writer_pretty(contents, sources).map_err(|source| Error::Json { path, source })
+}
+
+/// Identifier of a registro de tipo 2 as read from the file, its ISIN or else its account code.
+pub(crate) fn record_identifier(chars: &[char]) -> String {
+    let isin = field(chars, &(131..143));
+    match isin.trim() {
+        "" => field(chars, &(155..189)).trim().to_string(),
+        isin => isin.to_string(),
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::inspect::{field, write_subtotals, Subtotal};
+use crate::modelo_720::decode_latin1;
+use crate::validation::parse_amount;
+
+fn clave_description(clave: &str) -> &'static str {
+    match clave {
+        "C" => "Cuentas en entidades financieras",
+        "V" => "Valores y derechos representativos",
+        "I" => "Acciones y participaciones en IIC",
+        "S" => "Seguros de vida o invalidez y rentas temporales o vitalicias",
+        "B" => "Bienes inmuebles y derechos sobre bienes inmuebles",
+        _ => "Clave desconocida",
+    }
+}
+
+fn origen_description(origen: &str) -> &'static str {
+    match origen {
+        "A" => "Alta",
+        "M" => "Mantenimiento",
+        "C" => "Extinción",
+        _ => "",
+    }
+}
+
+/// Summarizes a 720 file for archiving alongside the submission: the declarant, a line per asset with
+/// its origen and valuations, and the totals of each clave.
+pub fn report_modelo_720_file(path: &Path) -> Result<String> {
+    let contents = decode_latin1(&std::fs::read(path).map_err(|e| Error::io(path, e))?);
+    let mut lines = contents.lines();
+    let mut output = String::new();
+    if let Some(header) = lines.next() {
+        let chars: Vec<char> = header.chars().collect();
+        writeln!(
+            output,
+            "Modelo {} del ejercicio {}",
+            field(&chars, &(1..4)),
+            field(&chars, &(4..8))
+        )
+        .unwrap();
+        writeln!(
+            output,
+            "Declarante: {} ({})",
+            field(&chars, &(17..57)).trim_end(),
+            field(&chars, &(8..17)).trim()
+        )
+        .unwrap();
+        writeln!(
+            output,
+            "Número identificativo de la declaración: {}",
+            field(&chars, &(107..120))
+        )
+        .unwrap();
+        if field(&chars, &(121..122)) == "S" {
+            writeln!(
+                output,
+                "Sustituye a la declaración {}",
+                field(&chars, &(122..135))
+            )
+            .unwrap();
+        }
+    }
+
+    let mut by_clave: BTreeMap<String, Vec<Vec<char>>> = BTreeMap::new();
+    for line in lines {
+        let chars: Vec<char> = line.chars().collect();
+        by_clave
+            .entry(field(&chars, &(101..102)))
+            .or_default()
+            .push(chars);
+    }
+
+    let mut subtotals: BTreeMap<String, Subtotal> = BTreeMap::new();
+    for (clave, registros) in by_clave.iter() {
+        writeln!(output).unwrap();
+        writeln!(output, "{} ({})", clave_description(clave), clave).unwrap();
+        let subtotal = subtotals.entry(clave.clone()).or_default();
+        for chars in registros {
+            let identifier = [field(chars, &(131..143)), field(chars, &(155..189))]
+                .into_iter()
+                .map(|x| x.trim().to_string())
+                .find(|x| !x.is_empty())
+                .unwrap_or_default();
+            let valoracion1 = parse_amount(&field(chars, &(431..446))).unwrap_or_default();
+            let valoracion2 = parse_amount(&field(chars, &(446..461))).unwrap_or_default();
+            writeln!(
+                output,
+                "  {:<34} {:<40} {:2} {:<13} {:>16} {:>16}",
+                identifier,
+                field(chars, &(189..230)).trim_end(),
+                field(chars, &(128..130)),
+                origen_description(&field(chars, &(422..423))),
+                valoracion1,
+                valoracion2
+            )
+            .unwrap();
+            subtotal.registros += 1;
+            subtotal.valoracion1 += valoracion1;
+            subtotal.valoracion2 += valoracion2;
+        }
+    }
+
+    writeln!(output).unwrap();
+    write_subtotals(&mut output, &subtotals).unwrap();
+    Ok(output)
+}
@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::modelo_720::{Modelo720, Nif};
+use crate::summary::{ClaveSummary, Summary};
+
+/// Where the state is kept unless another path is given, relative to the working directory.
+pub const DEFAULT_STATE_PATH: &str = ".modelo720/state.json";
+
+/// Declarations generated in previous years, so the next one can be computed from them instead of from
+/// the old broker statements.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    pub declarations: Vec<DeclarationState>,
+}
+
+/// A declaration as it was written: its número identificativo, the totals of each clave de bien and every
+/// registro in it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeclarationState {
+    pub nif: Nif,
+    pub ejercicio: i16,
+    pub id_declaracion: i64,
+    pub file: PathBuf,
+    pub sha256: String,
+    pub claves: BTreeMap<char, ClaveSummary>,
+    pub modelo720: Modelo720,
+}
+
+impl State {
+    /// Reads the state at `path`, which is empty until the first declaration is recorded in it.
+    pub fn load(path: &Path) -> Result<State> {
+        if !path.exists() {
+            return Ok(State::default());
+        }
+        let file = File::open(path).map_err(|e| Error::io(path, e))?;
+        serde_json::from_reader(std::io::BufReader::new(file)).map_err(|source| Error::Json {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Writes the state to `path`, creating its folder if needed.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent).map_err(|e| Error::io(parent, e))?;
+        }
+        let file = File::create(path).map_err(|e| Error::io(path, e))?;
+        serde_json::to_writer_pretty(file, self).map_err(|source| Error::Json {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Records `modelo720`, written as summarized in `summary`. It replaces any declaration of the same
+    /// declarante and ejercicio, as a sustitutiva does.
+    pub fn record(&mut self, summary: &Summary, modelo720: Modelo720) {
+        let nif = modelo720.header.nif_declarante.clone();
+        let ejercicio = modelo720.header.ejercicio;
+        self.declarations
+            .retain(|x| x.nif != nif || x.ejercicio != ejercicio);
+        self.declarations.push(DeclarationState {
+            nif,
+            ejercicio,
+            id_declaracion: modelo720.header.id_declaracion,
+            file: summary.file.clone(),
+            sha256: summary.sha256.clone(),
+            claves: summary.claves.clone(),
+            modelo720,
+        });
+        self.declarations
+            .sort_by(|a, b| (&a.nif, a.ejercicio).cmp(&(&b.nif, b.ejercicio)));
+    }
+
+    /// The latest declaration of `nif` before `ejercicio`, which is the one the 20.000 € increase is
+    /// measured against.
+    pub fn previous(&self, nif: &Nif, ejercicio: i16) -> Option<&DeclarationState> {
+        self.declarations
+            .iter()
+            .filter(|x| &x.nif == nif && x.ejercicio < ejercicio)
+            .max_by_key(|x| x.ejercicio)
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result};
+use crate::modelo_720::Modelo720;
+
+/// Digest of a written 720 file, to keep a record of exactly what was submitted.
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub file: PathBuf,
+    /// SHA-256 of the file as written, in hexadecimal.
+    pub sha256: String,
+    pub registros: usize,
+    pub suma_valoracion1: Decimal,
+    pub suma_valoracion2: Decimal,
+    /// Registros and valuations of each clave de bien.
+    pub claves: BTreeMap<char, ClaveSummary>,
+    /// Number of registros of each origen.
+    pub origenes: BTreeMap<char, usize>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ClaveSummary {
+    pub registros: usize,
+    pub valoracion1: Decimal,
+    pub valoracion2: Decimal,
+}
+
+impl Summary {
+    /// Summarizes `modelo720` once written to `path`, whose contents are hashed.
+    pub fn of_file(modelo720: &Modelo720, path: &Path) -> Result<Summary> {
+        let contents = std::fs::read(path).map_err(|e| Error::io(path, e))?;
+        let (claves, origenes) = totals_by_clave(modelo720);
+        Ok(Summary {
+            file: path.to_path_buf(),
+            sha256: format!("{:x}", Sha256::digest(&contents)),
+            registros: modelo720.header.numero_registros_tipo2,
+            suma_valoracion1: modelo720.header.suma_valoracion1.0,
+            suma_valoracion2: modelo720.header.suma_valoracion2.0,
+            claves,
+            origenes,
+        })
+    }
+
+    pub fn save_to_json(&self, path: &Path) -> Result<()> {
+        let file = File::create(path).map_err(|e| Error::io(path, e))?;
+        serde_json::to_writer_pretty(file, self).map_err(|source| Error::Json {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
+
+/// Registros and valuations of each clave de bien of `modelo720`, and the number of registros of each origen.
+pub fn totals_by_clave(
+    modelo720: &Modelo720,
+) -> (BTreeMap<char, ClaveSummary>, BTreeMap<char, usize>) {
+    let mut claves: BTreeMap<char, ClaveSummary> = BTreeMap::new();
+    let mut origenes = BTreeMap::new();
+    for entry in modelo720.entries.iter() {
+        let clave = claves.entry(entry.tipo_bien.clave()).or_default();
+        clave.registros += 1;
+        clave.valoracion1 += entry.valoracion1.rounded_to_cents().0;
+        clave.valoracion2 += entry.valoracion2.rounded_to_cents().0;
+        *origenes
+            .entry(entry.origen_bien_derecho.code())
+            .or_default() += 1;
+    }
+    (claves, origenes)
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.file.display())?;
+        writeln!(f, "  SHA-256: {}", self.sha256)?;
+        writeln!(f, "  Registros: {}", self.registros)?;
+        for (clave, summary) in self.claves.iter() {
+            writeln!(
+                f,
+                "  Clave {}: {} registros, valoración 1 {}, valoración 2 {}",
+                clave, summary.registros, summary.valoracion1, summary.valoracion2
+            )?;
+        }
+        for (origen, count) in self.origenes.iter() {
+            writeln!(f, "  Origen {}: {}", origen, count)?;
+        }
+        writeln!(f, "  Suma de valoración 1: {}", self.suma_valoracion1)?;
+        write!(f, "  Suma de valoración 2: {}", self.suma_valoracion2)
+    }
+}
@@ -0,0 +1,883 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+
+use crate::countries::is_valid_country;
+use crate::diff::{asset_key, registro_key};
+use crate::error::{self, Error};
+use crate::inspect::field;
+use crate::layout::RecordDesign;
+use crate::modelo_720::{decode_latin1, Modelo720, Nif, Origen, SHARES_DECIMALS};
+use crate::provenance::{load_sources, record_identifier, Provenance};
+
+/// Uppercases and trims an ISIN, checking its length, country prefix and Luhn check digit.
+pub fn normalize_isin(value: &str) -> Result<String, String> {
+    let isin = value.trim().to_uppercase();
+    if isin.len() != 12 || !isin.chars().all(|x| x.is_ascii_alphanumeric()) {
+        return Err(format!("ISIN \"{}\" must have 12 letters or digits", value));
+    }
+    if !isin[..2].chars().all(|x| x.is_ascii_alphabetic()) {
+        return Err(format!("ISIN {} must start with a country code", value));
+    }
+    if !isin[11..].chars().all(|x| x.is_ascii_digit()) {
+        return Err(format!("ISIN {} must end with a check digit", value));
+    }
+    // Letters stand for two digits, A being 10, and the Luhn algorithm is run over the expanded number.
+    let digits: Vec<u32> = isin[..11]
+        .chars()
+        .map(|x| x.to_digit(36).unwrap())
+        .flat_map(|x| {
+            if x >= 10 {
+                vec![x / 10, x % 10]
+            } else {
+                vec![x]
+            }
+        })
+        .collect();
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(index, digit)| {
+            if index % 2 == 0 {
+                (digit * 2) / 10 + (digit * 2) % 10
+            } else {
+                *digit
+            }
+        })
+        .sum();
+    if Some((10 - sum % 10) % 10) != isin[11..].chars().next().and_then(|x| x.to_digit(10)) {
+        return Err(format!("ISIN {} has the wrong check digit", value));
+    }
+    Ok(isin)
+}
+
+/// Whether an account code is meant to be an IBAN, i.e. starts with a country code and two check digits.
+pub fn looks_like_iban(value: &str) -> bool {
+    let code: Vec<char> = value
+        .chars()
+        .filter(|x| !x.is_whitespace())
+        .take(4)
+        .collect();
+    code.len() == 4
+        && code[..2].iter().all(|x| x.is_ascii_alphabetic())
+        && code[2..].iter().all(|x| x.is_ascii_digit())
+}
+
+/// Removes the spaces an IBAN is usually printed with and checks its mod-97 checksum.
+pub fn normalize_iban(value: &str) -> Result<String, String> {
+    let iban: String = value
+        .chars()
+        .filter(|x| !x.is_whitespace())
+        .collect::<String>()
+        .to_uppercase();
+    if !(15..=34).contains(&iban.len()) || !iban.chars().all(|x| x.is_ascii_alphanumeric()) {
+        return Err(format!(
+            "IBAN {} must have between 15 and 34 letters or digits",
+            value
+        ));
+    }
+    if iban_remainder(iban[4..].chars().chain(iban[..4].chars())) != 1 {
+        return Err(format!("IBAN {} has the wrong check digits", value));
+    }
+    Ok(iban)
+}
+
+/// Remainder modulo 97 of the number an IBAN stands for, with letters worth 10 to 35.
+fn iban_remainder(chars: impl Iterator<Item = char>) -> u32 {
+    chars
+        .map(|x| x.to_digit(36).unwrap())
+        .fold(0, |remainder, x| {
+            if x >= 10 {
+                (remainder * 100 + x) % 97
+            } else {
+                (remainder * 10 + x) % 97
+            }
+        })
+}
+
+/// Builds a valid IBAN from its country and the account number within it, computing the check digits.
+pub fn iban_with_check_digits(country: &str, bban: &str) -> String {
+    let remainder = iban_remainder(bban.chars().chain(country.chars()).chain("00".chars()));
+    format!("{}{:02}{}", country, 98 - remainder, bban)
+}
+
+/// Checks a BIC has the bank, country and location codes, optionally followed by the branch.
+pub fn normalize_bic(value: &str) -> Result<String, String> {
+    let bic = value.trim().to_uppercase();
+    let valid = (bic.len() == 8 || bic.len() == 11)
+        && bic.chars().all(|x| x.is_ascii_alphanumeric())
+        && bic[..6].chars().all(|x| x.is_ascii_alphabetic());
+    if !valid {
+        return Err(format!("{} is not a valid BIC", value));
+    }
+    Ok(bic)
+}
+
+/// Looks for the classic double-declaration mistakes when generating declarations for several clients at once:
+/// two clients sharing a NIF, or the same account/ISIN with identical values declared at 100% by more than one client.
+///
+/// Each declaration is paired with the name of the client it was generated for, which is only used in the messages.
+pub fn duplicate_declarant_warnings(declarations: &[(&str, &Modelo720)]) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    let mut clients_by_nif: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (client, modelo720) in declarations {
+        clients_by_nif
+            .entry(modelo720.header.nif_declarante.as_str())
+            .or_default()
+            .push(client);
+    }
+    let mut shared_nifs: Vec<_> = clients_by_nif
+        .into_iter()
+        .filter(|(_, clients)| clients.len() > 1)
+        .collect();
+    shared_nifs.sort();
+    for (nif, clients) in shared_nifs {
+        warnings.push(format!(
+            "NIF {} is declared by more than one client: {}",
+            nif,
+            clients.join(", ")
+        ));
+    }
+
+    let mut clients_by_asset: HashMap<(&str, String), Vec<&str>> = HashMap::new();
+    for (client, modelo720) in declarations {
+        for registro in modelo720.entries.iter().filter(|x| x.porcentaje == 10000) {
+            let identifier = match registro.identifier() {
+                Some(x) => x,
+                None => continue,
+            };
+            let values = format!(
+                "{} {:?}",
+                registro.valoracion1.rounded_to_cents().0,
+                registro.numero_valores.map(|x| x.0)
+            );
+            let clients = clients_by_asset.entry((identifier, values)).or_default();
+            if !clients.contains(client) {
+                clients.push(client);
+            }
+        }
+    }
+    let mut shared_assets: Vec<_> = clients_by_asset
+        .into_iter()
+        .filter(|(_, clients)| clients.len() > 1)
+        .collect();
+    shared_assets.sort();
+    for ((identifier, _), clients) in shared_assets {
+        warnings.push(format!(
+            "{} is declared at 100% with identical values by more than one client: {}",
+            identifier,
+            clients.join(", ")
+        ));
+    }
+    // Several lots of the same asset are only reported once.
+    warnings.dedup();
+
+    warnings
+}
+
+/// Whether an issue gets the file rejected by the AEAT or only deserves a look.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// Problem found in a 720 file, located at the line and field of the AEAT record design where it appears.
+#[derive(Debug, Clone)]
+pub struct ValidationIssue {
+    /// Stable name of the check that failed, e.g. `invalid-isin`, for scripts to act on.
+    pub code: &'static str,
+    pub severity: Severity,
+    pub line: usize,
+    pub field: &'static str,
+    pub message: String,
+    /// Where the registro at `line` was read from, when the file has its sources next to it.
+    pub sources: Vec<Provenance>,
+}
+
+impl std::fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, {}: {}", self.line, self.field, self.message)?;
+        for source in self.sources.iter() {
+            write!(f, " (from {})", source)?;
+        }
+        Ok(())
+    }
+}
+
+impl ValidationIssue {
+    /// Number of the registro de tipo 2 the issue is in, counting from 1, or `None` for the header.
+    pub fn registro(&self) -> Option<usize> {
+        self.line.checked_sub(1).filter(|x| *x > 0)
+    }
+}
+
+impl Serialize for ValidationIssue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("ValidationIssue", 7)?;
+        state.serialize_field("code", self.code)?;
+        state.serialize_field("severity", &self.severity)?;
+        state.serialize_field("line", &self.line)?;
+        state.serialize_field("registro", &self.registro())?;
+        state.serialize_field("field", self.field)?;
+        state.serialize_field("message", &self.message)?;
+        state.serialize_field("sources", &self.sources)?;
+        state.end()
+    }
+}
+
+/// Issues found in a declaration, as written by `--json` for scripts and other front-ends.
+#[derive(Debug, Serialize)]
+pub struct ValidationReport {
+    pub file: PathBuf,
+    pub errors: usize,
+    pub warnings: usize,
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+    pub fn new(file: &Path, issues: Vec<ValidationIssue>) -> ValidationReport {
+        let count = |severity| issues.iter().filter(|x| x.severity == severity).count();
+        ValidationReport {
+            file: file.to_path_buf(),
+            errors: count(Severity::Error),
+            warnings: count(Severity::Warning),
+            issues,
+        }
+    }
+
+    pub fn save_to_json(&self, path: &Path) -> error::Result<()> {
+        let file = File::create(path).map_err(|e| Error::io(path, e))?;
+        serde_json::to_writer_pretty(file, self).map_err(|source| Error::Json {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
+
+const RECORD_LENGTH: usize = 500;
+
+const CLAVES: [&str; 16] = [
+    "C1", "C2", "C3", "C4", "C5", "V1", "V2", "V3", "I0", "S1", "S2", "B1", "B2", "B3", "B4", "B5",
+];
+
+/// Fields the AEAT requires for a clave de bien, besides the ones every registro has, with their position in
+/// the record design.
+fn mandatory_fields(clave: &str) -> &'static [(&'static str, Range<usize>)] {
+    match clave {
+        "C1" | "C2" | "C3" | "C4" | "C5" => &[
+            ("CLAVE IDENTIFICACIÓN DE CUENTA", 143..144),
+            ("CÓDIGO DE CUENTA", 155..189),
+            ("IDENTIFICACIÓN DE LA ENTIDAD", 189..230),
+        ],
+        "V1" | "V2" | "V3" | "I0" => &[
+            ("CLAVE DE IDENTIFICACIÓN", 130..131),
+            ("IDENTIFICACIÓN DE VALORES", 131..143),
+            ("IDENTIFICACIÓN DE LA ENTIDAD", 189..230),
+            ("CLAVE DE REPRESENTACIÓN DE VALORES", 461..462),
+            ("NÚMERO DE VALORES", 462..474),
+        ],
+        "S1" | "S2" => &[("IDENTIFICACIÓN DE LA ENTIDAD", 189..230)],
+        // The address of real estate is declared in the fields of the address of the entity.
+        "B1" | "B3" | "B4" => &[
+            ("NOMBRE VÍA PUBLICA Y NÚMERO DE CASA", 250..302),
+            ("POBLACIÓN/CIUDAD", 342..372),
+            ("CLAVE TIPO DE BIEN INMUEBLE", 474..475),
+        ],
+        // Rights other than ownership say which one they are.
+        "B2" | "B5" => &[
+            ("TIPO DE DERECHO REAL SOBRE INMUEBLE", 103..128),
+            ("NOMBRE VÍA PUBLICA Y NÚMERO DE CASA", 250..302),
+            ("POBLACIÓN/CIUDAD", 342..372),
+            ("CLAVE TIPO DE BIEN INMUEBLE", 474..475),
+        ],
+        _ => &[],
+    }
+}
+
+/// Fields of a registro de tipo 2 its clave makes mandatory that are left blank. Checked by the writer
+/// too, so no registro missing them is ever written.
+pub(crate) fn missing_mandatory_fields(chars: &[char]) -> Vec<&'static str> {
+    let clave = field(chars, &(101..103));
+    mandatory_fields(&clave)
+        .iter()
+        .filter(|(_, range)| field(chars, range).trim().is_empty())
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+struct Record<'a> {
+    line: usize,
+    chars: Vec<char>,
+    issues: &'a mut Vec<ValidationIssue>,
+}
+
+impl Record<'_> {
+    fn field(&self, range: Range<usize>) -> String {
+        self.chars
+            .get(range)
+            .map(|x| x.iter().collect())
+            .unwrap_or_default()
+    }
+
+    fn issue(&mut self, code: &'static str, field: &'static str, message: String) {
+        self.issues.push(ValidationIssue {
+            code,
+            severity: Severity::Error,
+            line: self.line,
+            field,
+            message,
+            sources: Vec::new(),
+        });
+    }
+
+    fn expect(&mut self, field: &'static str, range: Range<usize>, expected: &str) {
+        let value = self.field(range);
+        if value != expected {
+            self.issue(
+                "unexpected-value",
+                field,
+                format!("expected \"{}\" but found \"{}\"", expected, value),
+            );
+        }
+    }
+
+    fn numeric(&mut self, field: &'static str, range: Range<usize>) -> Option<u64> {
+        let value = self.field(range);
+        match value.parse() {
+            Ok(x) if value.chars().all(|x| x.is_ascii_digit()) => Some(x),
+            _ => {
+                self.issue(
+                    "not-numeric",
+                    field,
+                    format!("\"{}\" is not numeric", value),
+                );
+                None
+            }
+        }
+    }
+
+    fn mandatory(&mut self, field: &'static str, range: Range<usize>) {
+        if self.field(range).trim().is_empty() {
+            self.issue("mandatory", field, "is mandatory".to_string());
+        }
+    }
+
+    fn country(&mut self, field: &'static str, range: Range<usize>) {
+        let value = self.field(range);
+        if !is_valid_country(&value) {
+            self.issue(
+                "invalid-country",
+                field,
+                format!("\"{}\" is not a country code", value),
+            );
+        }
+    }
+
+    fn nif(&mut self, field: &'static str, range: Range<usize>) {
+        if let Err(e) = Nif::new(&self.field(range)) {
+            self.issue("invalid-nif", field, e);
+        }
+    }
+
+    fn date(&mut self, field: &'static str, range: Range<usize>) -> Option<NaiveDate> {
+        let value = self.field(range);
+        if value == "00000000" {
+            return None;
+        }
+        match NaiveDate::parse_from_str(&value, "%Y%m%d") {
+            Ok(x) => Some(x),
+            Err(_) => {
+                self.issue(
+                    "invalid-date",
+                    field,
+                    format!("\"{}\" is not a valid date", value),
+                );
+                None
+            }
+        }
+    }
+
+    fn amount(&mut self, field: &'static str, range: Range<usize>) -> Option<Decimal> {
+        let value = self.field(range);
+        let amount = parse_amount(&value);
+        if amount.is_none() {
+            self.issue(
+                "invalid-amount",
+                field,
+                format!("\"{}\" is not a valid amount", value),
+            );
+        }
+        amount
+    }
+}
+
+// How many times higher or lower than the year before a price per valor has to be to look like a mistake
+// rather than a market move.
+const PRICE_DEVIATION: Decimal = Decimal::from_parts(3, 0, 0, false, 0);
+
+/// Flags the registros whose valoración 1 is wildly off their number of valores at the price per valor of the
+/// same asset the year before, given by `previous_prices` in euros keyed by ISIN. These are usually statements
+/// reporting the value in a different currency than assumed, such as pence instead of pounds.
+pub fn valuation_warnings(
+    modelo720: &Modelo720,
+    previous_prices: &HashMap<String, Decimal>,
+) -> Vec<ValidationIssue> {
+    let mut warnings = Vec::new();
+    for (index, registro) in modelo720.entries.iter().enumerate() {
+        let (Some(identifier), Some(shares)) = (registro.identifier(), registro.numero_valores)
+        else {
+            continue;
+        };
+        let identifier = identifier.trim();
+        let Some(previous_price) = previous_prices
+            .get(&identifier.to_uppercase())
+            .filter(|x| x.is_sign_positive() && !x.is_zero())
+        else {
+            continue;
+        };
+        // Negative valuations, such as margin accounts, have no price to compare.
+        if shares.0.is_zero() || !registro.valoracion1.0.is_sign_positive() {
+            continue;
+        }
+        let price = registro.valoracion1.0 / shares.0;
+        let ratio = price / previous_price;
+        if ratio > PRICE_DEVIATION || ratio * PRICE_DEVIATION < Decimal::ONE {
+            let message = format!(
+                "{} (clave {}, {} valores) is valued at {} per valor against {} the year before, check the \
+                 currency of the statement",
+                identifier,
+                registro.tipo_bien.code(),
+                shares.0,
+                price.round_dp(4),
+                previous_price.round_dp(4)
+            );
+            warnings.push(registro_warning(
+                modelo720,
+                index,
+                "valuation-deviation",
+                "VALORACIÓN 1",
+                message,
+            ));
+        }
+    }
+    warnings
+}
+
+/// Checks the origen of the registros of `current` against `previous`, the declaration submitted for an
+/// earlier ejercicio: assets acquired (A) that were already declared without also being kept (M), assets
+/// kept (M) that weren't declared, and assets extinguished (C) that were neither declared nor acquired
+/// during the year. Registros are numbered by their line in the file of `current`.
+pub fn origin_issues(current: &Modelo720, previous: &Modelo720) -> Vec<ValidationIssue> {
+    let held_before: HashSet<String> = previous
+        .entries
+        .iter()
+        .filter(|x| !matches!(x.origen_bien_derecho, Origen::Extincion))
+        .map(asset_key)
+        .collect();
+    let mut origens: HashMap<String, HashSet<char>> = HashMap::new();
+    for registro in current.entries.iter() {
+        origens
+            .entry(asset_key(registro))
+            .or_default()
+            .insert(registro.origen_bien_derecho.code());
+    }
+    let ejercicio = previous.header.ejercicio;
+    let mut issues = Vec::new();
+    for (index, registro) in current.entries.iter().enumerate() {
+        let key = asset_key(registro);
+        let declared = held_before.contains(&key);
+        let has = |origen: char| origens.get(&key).is_some_and(|x| x.contains(&origen));
+        let message = match registro.origen_bien_derecho {
+            Origen::Adquisicion if declared && !has('M') => format!(
+                "{} was already declared in {} and is not kept with origen M",
+                key, ejercicio
+            ),
+            Origen::Modificacion if !declared => {
+                format!("{} is kept but was not declared in {}", key, ejercicio)
+            }
+            Origen::Extincion if !declared && !has('A') => format!(
+                "{} is extinguished but was neither declared in {} nor acquired this year",
+                key, ejercicio
+            ),
+            _ => continue,
+        };
+        issues.push(registro_warning(
+            current,
+            index,
+            "inconsistent-origen",
+            "ORIGEN DEL BIEN O DERECHO",
+            message,
+        ));
+    }
+    issues
+}
+
+/// Warning about the registro at `index` of `modelo720`, numbered by its line in the file and traced back to
+/// where it was read from.
+fn registro_warning(
+    modelo720: &Modelo720,
+    index: usize,
+    code: &'static str,
+    field: &'static str,
+    message: String,
+) -> ValidationIssue {
+    let registro = &modelo720.entries[index];
+    ValidationIssue {
+        code,
+        severity: Severity::Warning,
+        line: index + 2,
+        field,
+        message,
+        sources: registro
+            .identifier()
+            .and_then(|x| modelo720.sources.get(x.trim()))
+            .cloned()
+            .unwrap_or_default(),
+    }
+}
+
+/// Flags the registros whose número de valores can't be declared as is: those with more decimals than the
+/// field holds, when rounding leaves out more than `epsilon` valores, such as the fractional shares of some
+/// brokers, and those too large for the field.
+pub fn shares_precision_warnings(modelo720: &Modelo720, epsilon: Decimal) -> Vec<ValidationIssue> {
+    let mut warnings = Vec::new();
+    for (index, registro) in modelo720.entries.iter().enumerate() {
+        let Some(shares) = registro.numero_valores else {
+            continue;
+        };
+        let identifier = registro.identifier().unwrap_or_default();
+        let message = if !shares.fits() {
+            format!(
+                "{} (clave {}) has {} valores, which don't fit in the número de valores",
+                identifier.trim(),
+                registro.tipo_bien.code(),
+                shares.0
+            )
+        } else if shares.rounding_loss() > epsilon {
+            format!(
+                "{} (clave {}) has {} valores, declared as {} since the número de valores only has {} decimals",
+                identifier.trim(),
+                registro.tipo_bien.code(),
+                shares.0.normalize(),
+                shares.declared(),
+                SHARES_DECIMALS
+            )
+        } else {
+            continue;
+        };
+        warnings.push(registro_warning(
+            modelo720,
+            index,
+            "shares-precision",
+            "NÚMERO DE VALORES",
+            message,
+        ));
+    }
+    warnings
+}
+
+/// Flags the registros sold or cancelled during the year without a fecha de extinción, which the AEAT
+/// rejects. Statements only give the closing date of some accounts, so the rest have to be given as sales or
+/// overrides.
+pub fn undated_extinction_warnings(modelo720: &Modelo720) -> Vec<ValidationIssue> {
+    modelo720
+        .entries
+        .iter()
+        .enumerate()
+        .filter(|(_, x)| matches!(x.origen_bien_derecho, Origen::Extincion))
+        .filter(|(_, x)| x.fecha_extincion.0.is_none())
+        .map(|(index, registro)| {
+            registro_warning(
+                modelo720,
+                index,
+                "undated-extinction",
+                "FECHA DE EXTINCIÓN",
+                format!(
+                    "{} was sold or cancelled during the year but has no fecha de extinción",
+                    registro_key(registro)
+                ),
+            )
+        })
+        .collect()
+}
+
+/// Amounts are a sign, blank or N, followed by the value in cents.
+pub(crate) fn parse_amount(value: &str) -> Option<Decimal> {
+    let (sign, digits) = value.split_at(value.chars().next().map_or(0, char::len_utf8));
+    let cents = match (sign, digits.parse::<i64>()) {
+        (" ", Ok(x)) if digits.chars().all(|x| x.is_ascii_digit()) => x,
+        ("N", Ok(x)) if digits.chars().all(|x| x.is_ascii_digit()) => -x,
+        _ => return None,
+    };
+    Some(Decimal::new(cents, 2))
+}
+
+/// Checks a 720 file against the AEAT record design: record lengths, numeric fields, allowed claves,
+/// the fields each clave makes mandatory and the consistency of the header with the registros. The issues
+/// of registros are given with where they were read from when the file has its sources next to it.
+pub fn validate_modelo_720_file(path: &Path) -> error::Result<Vec<ValidationIssue>> {
+    let bytes = std::fs::read(path).map_err(|e| Error::io(path, e))?;
+    let mut issues = validate_modelo_720(&bytes);
+    let sources = load_sources(path)?;
+    if !sources.is_empty() {
+        let records: Vec<Vec<char>> = decode_latin1(&bytes)
+            .lines()
+            .map(|x| x.chars().collect())
+            .collect();
+        for issue in issues.iter_mut().filter(|x| x.line > 1) {
+            if let Some(x) = records
+                .get(issue.line - 1)
+                .and_then(|x| sources.get(&record_identifier(x)))
+            {
+                issue.sources = x.clone();
+            }
+        }
+    }
+    Ok(issues)
+}
+
+/// Same as [`validate_modelo_720_file`] for a declaration that hasn't been written yet.
+pub fn validate_modelo_720(bytes: &[u8]) -> Vec<ValidationIssue> {
+    let contents = decode_latin1(bytes);
+    let mut issues = Vec::new();
+    let mut lines: Vec<&str> = contents.lines().collect();
+    // Blank lines at the end, as left by many editors, aren't records.
+    while lines.last().is_some_and(|x| x.trim().is_empty()) {
+        lines.pop();
+    }
+    if lines.is_empty() {
+        issues.push(ValidationIssue {
+            code: "no-header",
+            severity: Severity::Error,
+            line: 1,
+            field: "TIPO DE REGISTRO",
+            message: "the file has no header".to_string(),
+            sources: Vec::new(),
+        });
+        return issues;
+    }
+
+    let mut ejercicio = None;
+    let mut declared_count = None;
+    let mut declared_sums = (None, None);
+    let mut sums = (Decimal::ZERO, Decimal::ZERO);
+    for (index, text) in lines.iter().enumerate() {
+        let mut record = Record {
+            line: index + 1,
+            chars: text.chars().collect(),
+            issues: &mut issues,
+        };
+        if record.chars.len() != RECORD_LENGTH {
+            let length = record.chars.len();
+            record.issue(
+                "record-length",
+                "LONGITUD DEL REGISTRO",
+                format!("expected {} characters but found {}", RECORD_LENGTH, length),
+            );
+            continue;
+        }
+        record.expect("MODELO DECLARACIÓN", 1..4, "720");
+        if index == 0 {
+            record.expect("TIPO DE REGISTRO", 0..1, "1");
+            ejercicio = record.numeric("EJERCICIO", 4..8);
+            if let Some(Err(e)) = ejercicio.map(|x| RecordDesign::required_for(x as i16)) {
+                record.issue("unsupported-ejercicio", "EJERCICIO", e.to_string());
+            }
+            record.nif("NIF DEL DECLARANTE", 8..17);
+            record.mandatory("APELLIDOS Y NOMBRE DEL DECLARANTE", 17..57);
+            // Files are checked before being uploaded, and the Sede electrónica only takes telematic ones.
+            match record.field(57..58).as_str() {
+                "T" => {}
+                "C" => record.issue(
+                    "tipo-soporte-dvd",
+                    "TIPO DE SOPORTE",
+                    "C (DVD) can't be presented electronically, which requires T".to_string(),
+                ),
+                value => {
+                    let value = value.to_string();
+                    record.issue(
+                        "invalid-tipo-soporte",
+                        "TIPO DE SOPORTE",
+                        format!("\"{}\" is not T or C", value),
+                    )
+                }
+            }
+            record.numeric("TELEFONO PERSONA CONTACTO", 58..67);
+            record.mandatory("APELLIDOS Y NOMBRE PERSONA CONTACTO", 67..107);
+            let id_declaracion =
+                record.numeric("NÚMERO IDENTIFICATIVO DE LA DECLARACIÓN", 107..120);
+            match (
+                record.field(120..121).as_str(),
+                record.field(121..122).as_str(),
+            ) {
+                (" ", " ") | ("C", " ") => {}
+                (" ", "S") => {
+                    let anterior = record
+                        .numeric("NÚMERO IDENTIFICATIVO DE LA DECLARACIÓN ANTERIOR", 122..135);
+                    if anterior == Some(0) || anterior == id_declaracion {
+                        record.issue(
+                            "invalid-declaracion-anterior",
+                            "NÚMERO IDENTIFICATIVO DE LA DECLARACIÓN ANTERIOR",
+                            "must identify another declaration".to_string(),
+                        );
+                    }
+                }
+                (complementaria, sustitutiva) => record.issue(
+                    "invalid-complementaria-sustitutiva",
+                    "DECLARACIÓN COMPLEMENTARIA O SUSTITUTIVA",
+                    format!(
+                        "\"{}\" and \"{}\" are not a valid combination",
+                        complementaria, sustitutiva
+                    ),
+                ),
+            }
+            declared_count = record.numeric("NÚMERO TOTAL DE REGISTROS DECLARADOS", 135..144);
+            declared_sums = (
+                record.amount("SUMA TOTAL DE VALORACIÓN 1", 144..162),
+                record.amount("SUMA TOTAL DE VALORACIÓN 2", 162..180),
+            );
+            continue;
+        }
+
+        record.expect("TIPO DE REGISTRO", 0..1, "2");
+        if let Some(ejercicio) = ejercicio {
+            record.expect("EJERCICIO", 4..8, &format!("{:04}", ejercicio));
+        }
+        record.nif("NIF DEL DECLARANTE", 8..17);
+        record.nif("NIF DEL DECLARADO", 17..26);
+        if !record.field(26..35).trim().is_empty() {
+            record.nif("NIF DEL REPRESENTANTE LEGAL", 26..35);
+        }
+        record.mandatory("APELLIDOS Y NOMBRE DEL DECLARADO", 35..75);
+        if !matches!(
+            record.field(75..76).as_str(),
+            "1" | "2" | "3" | "4" | "5" | "6" | "7" | "8"
+        ) {
+            let value = record.field(75..76);
+            record.issue(
+                "invalid-clave-condicion",
+                "CLAVE DE CONDICIÓN DEL DECLARANTE",
+                format!("\"{}\" is not a valid clave", value),
+            );
+        } else if record.field(75..76) == "8" {
+            record.mandatory("TIPO DE TITULARIDAD", 76..101);
+        }
+        let clave = record.field(101..103);
+        if !CLAVES.contains(&clave.as_str()) {
+            record.issue(
+                "invalid-clave",
+                "CLAVE Y SUBCLAVE TIPO DE BIEN O DERECHO",
+                format!("\"{}\" is not a valid clave", clave),
+            );
+        }
+        record.country("CÓDIGO DE PAÍS", 128..130);
+        if !record.field(412..414).trim().is_empty() {
+            record.country("CÓDIGO PAÍS DE LA ENTIDAD", 412..414);
+        }
+        for name in missing_mandatory_fields(&record.chars) {
+            record.issue(
+                "mandatory",
+                name,
+                format!("is mandatory for clave {}", clave),
+            );
+        }
+        match clave.get(..1) {
+            Some("C") => {
+                if record.field(143..144) == "I" {
+                    if let Err(e) = normalize_iban(&record.field(155..189)) {
+                        record.issue("invalid-iban", "CÓDIGO DE CUENTA", e);
+                    }
+                }
+                let bic = record.field(144..155);
+                if !bic.trim().is_empty() {
+                    if let Err(e) = normalize_bic(&bic) {
+                        record.issue("invalid-bic", "CÓDIGO BIC", e);
+                    }
+                }
+            }
+            Some("V") | Some("I") if record.field(130..131) == "1" => {
+                if let Err(e) = normalize_isin(&record.field(131..143)) {
+                    record.issue("invalid-isin", "IDENTIFICACIÓN DE VALORES", e);
+                }
+            }
+            _ => {}
+        }
+        record.date("FECHA DE INCORPORACIÓN", 414..422);
+        let fecha_extincion = record.date("FECHA DE EXTINCIÓN", 423..431);
+        match record.field(422..423).as_str() {
+            "A" | "M" => {}
+            "C" if fecha_extincion.is_none() => {
+                record.issue(
+                    "mandatory",
+                    "FECHA DE EXTINCIÓN",
+                    "is mandatory for origen C".to_string(),
+                );
+            }
+            "C" => {}
+            value => {
+                let message = format!("\"{}\" is not A, M or C", value);
+                record.issue("invalid-origen", "ORIGEN DEL BIEN O DERECHO", message);
+            }
+        }
+        if let Some(x) = record.amount("VALORACIÓN 1", 431..446) {
+            sums.0 += x;
+        }
+        if let Some(x) = record.amount("VALORACIÓN 2", 446..461) {
+            sums.1 += x;
+        }
+        if !record.field(462..474).trim().is_empty() {
+            record.numeric("NÚMERO DE VALORES", 462..474);
+        }
+        if let Some(porcentaje) = record.numeric("PORCENTAJE DE PARTICIPACIÓN", 475..480) {
+            if porcentaje == 0 || porcentaje > 10000 {
+                record.issue(
+                    "invalid-percentage",
+                    "PORCENTAJE DE PARTICIPACIÓN",
+                    format!("{} is not between 0,01% and 100%", porcentaje),
+                );
+            }
+        }
+    }
+
+    let mut header = Record {
+        line: 1,
+        chars: Vec::new(),
+        issues: &mut issues,
+    };
+    let registros = lines.len() as u64 - 1;
+    if let Some(count) = declared_count.filter(|x| *x != registros) {
+        header.issue(
+            "registros-count-mismatch",
+            "NÚMERO TOTAL DE REGISTROS DECLARADOS",
+            format!(
+                "declares {} registros but the file has {}",
+                count, registros
+            ),
+        );
+    }
+    if let Some(sum) = declared_sums.0.filter(|x| *x != sums.0) {
+        header.issue(
+            "totals-mismatch",
+            "SUMA TOTAL DE VALORACIÓN 1",
+            format!("declares {} but the registros add up to {}", sum, sums.0),
+        );
+    }
+    if let Some(sum) = declared_sums.1.filter(|x| *x != sums.1) {
+        header.issue(
+            "totals-mismatch",
+            "SUMA TOTAL DE VALORACIÓN 2",
+            format!("declares {} but the registros add up to {}", sum, sums.1),
+        );
+    }
+    issues
+}
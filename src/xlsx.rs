@@ -0,0 +1,77 @@
+use std::ops::Range;
+use std::path::Path;
+
+use rust_decimal::prelude::ToPrimitive;
+use rust_xlsxwriter::{Format, Workbook, Worksheet, XlsxError};
+
+use crate::error::{self, Error};
+use crate::layout::RecordDesign;
+use crate::modelo_720::decode_latin1;
+use crate::validation::parse_amount;
+
+fn write_header(
+    worksheet: &mut Worksheet,
+    fields: &[(&str, Range<usize>)],
+) -> Result<(), XlsxError> {
+    let bold = Format::new().set_bold();
+    for (column, (name, _)) in fields.iter().enumerate() {
+        worksheet.write_string_with_format(0, column as u16, *name, &bold)?;
+    }
+    Ok(())
+}
+
+fn write_record(
+    worksheet: &mut Worksheet,
+    row: u32,
+    line: &str,
+    fields: &[(&str, Range<usize>)],
+) -> Result<(), XlsxError> {
+    let chars: Vec<char> = line.chars().collect();
+    for (column, (name, range)) in fields.iter().enumerate() {
+        let value: String = chars
+            .get(range.clone())
+            .map(|x| x.iter().collect())
+            .unwrap_or_default();
+        // Valuations are written as numbers so that they can be added up in the spreadsheet.
+        match parse_amount(&value).filter(|_| name.contains("VALORACIÓN")) {
+            Some(amount) => {
+                worksheet.write_number(row, column as u16, amount.to_f64().unwrap())?;
+            }
+            None => {
+                worksheet.write_string(row, column as u16, value.trim())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes a 720 file as a workbook with a sheet for the header and another for the registros, with a
+/// column per field named as in the record design, so it can be handed out for review.
+pub fn save_modelo_720_as_xlsx(path: &Path, out: &Path) -> error::Result<()> {
+    let contents = decode_latin1(&std::fs::read(path).map_err(|e| Error::io(path, e))?);
+    let design = RecordDesign::of_contents(&contents);
+    let mut lines = contents.lines();
+    let mut workbook = Workbook::new();
+    let write = |workbook: &mut Workbook| -> Result<(), XlsxError> {
+        let header = workbook.add_worksheet();
+        header.set_name("Registro de tipo 1")?;
+        write_header(header, design.registro1_fields())?;
+        if let Some(line) = lines.next() {
+            write_record(header, 1, line, design.registro1_fields())?;
+        }
+        header.autofit();
+
+        let registros = workbook.add_worksheet();
+        registros.set_name("Registros de tipo 2")?;
+        write_header(registros, design.registro2_fields())?;
+        for (index, line) in lines.enumerate() {
+            write_record(registros, index as u32 + 1, line, design.registro2_fields())?;
+        }
+        registros.autofit();
+        workbook.save(out)
+    };
+    write(&mut workbook).map_err(|source| Error::Xlsx {
+        path: out.to_path_buf(),
+        source,
+    })
+}